@@ -76,26 +76,47 @@
             craneLib = (craneLib'.overrideArgs {
               pname = projectName;
               src = buildSrc;
+              cargoExtraArgs = "--locked --features cli";
               buildInputs = [
                 pkgs.openssl
 
               ];
               nativeBuildInputs = [
                 pkgs.pkg-config
+                pkgs.installShellFiles
               ];
             });
           in
           {
-            npcnix = craneLib.buildPackage { };
+            npcnix = craneLib.buildPackage {
+              postInstall = pkgs.lib.optionalString (pkgs.stdenv.buildPlatform.canExecute pkgs.stdenv.hostPlatform) ''
+                installShellCompletion --cmd npcnix \
+                  --bash <($out/bin/npcnix completions bash) \
+                  --fish <($out/bin/npcnix completions fish) \
+                  --zsh <($out/bin/npcnix completions zsh)
+                mkdir man
+                $out/bin/npcnix man --dir man
+                installManPage man/*.1
+              '';
+            };
           });
 
-      npcnixPkgWrapped = pkgs.writeShellScriptBin "npcnix" ''
+      npcnixWrapper = pkgs.writeShellScriptBin "npcnix" ''
         exec env \
           NPCNIX_AWS_CLI=''${NPCNIX_AWS_CLI:-${pkgs.awscli2}/bin/aws} \
           NPCNIX_NIXOS_REBUILD=''${NPCNIX_NIXOS_REBUILD:-${pkgs.nixos-rebuild}/bin/nixos-rebuild} \
           PATH="${pkgs.git}/bin:$PATH" \
           ${multiBuild.npcnix}/bin/npcnix "$@"
       '';
+
+      # with the completions and man pages of the unwrapped package
+      npcnixPkgWrapped = pkgs.symlinkJoin {
+        name = "npcnix";
+        paths = [ npcnixWrapper ];
+        postBuild = ''
+          ln -s ${multiBuild.npcnix}/share $out/share
+        '';
+      };
     in
     {
       packages = {
@@ -24,7 +24,7 @@ with lib;
     systemd.services.npcnix = {
       # restart after successful activation to reload itself, without blocking/terminating whole system activation
       script = ''
-        ${config.npcnix.package}/bin/npcnix follow --once=activate
+        exec ${config.npcnix.package}/bin/npcnix follow --once=activate
       '';
 
       wantedBy = [ "multi-user.target" ];
@@ -35,6 +35,13 @@ with lib;
       restartIfChanged = false; # we don't want to kill daemon currently running `nixos-rebuild`
 
       serviceConfig = {
+        Type = "notify";
+        # `script` runs npcnix in a child of the wrapper shell
+        NotifyAccess = "all";
+        # let npcnix finish the current activation on stop, instead of killing it
+        KillMode = "mixed";
+        # `systemctl reload npcnix` checks the remote right away
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
         Restart = "always";
         RestartSec = 15;
       };
@@ -0,0 +1,1390 @@
+//! Activating NixOS configurations
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt as _;
+use std::path::{Path, PathBuf};
+use std::{fmt, process, thread, time};
+
+use anyhow::{bail, format_err, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::cancel::{self, CancellationToken};
+use crate::closure::ClosureRef;
+use crate::config::Config;
+use crate::data_dir::DataDir;
+use crate::engine::Activator;
+use crate::gc;
+use crate::hooks::{self, Hook, HookEnv};
+use crate::logs;
+use crate::misc::{is_root, wait_timeout, RunningChild};
+use crate::notify::{self, NotifyEvent};
+use crate::{
+    darwin_rebuild_path, home_manager_path, nix_env_path, nix_path, nix_store_path,
+    nixos_rebuild_path, verify_flake_src, CommandExt,
+};
+
+/// What `nixos-rebuild` should do with the built configuration
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivationMode {
+    /// Build, activate and make the boot default
+    #[default]
+    Switch,
+    /// Build and make the boot default, but don't activate
+    Boot,
+    /// Build and activate, but don't make the boot default
+    Test,
+    /// Build and show what would be changed by the activation
+    DryActivate,
+}
+
+impl ActivationMode {
+    pub fn as_nixos_rebuild_arg(self) -> &'static str {
+        match self {
+            ActivationMode::Switch => "switch",
+            ActivationMode::Boot => "boot",
+            ActivationMode::Test => "test",
+            ActivationMode::DryActivate => "dry-activate",
+        }
+    }
+}
+
+impl fmt::Display for ActivationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_nixos_rebuild_arg())
+    }
+}
+
+/// Tool used to build and activate the configuration
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivationBackend {
+    /// `darwin-rebuild` on macOS, `nixos-rebuild` otherwise
+    #[default]
+    Auto,
+    /// `nixos-rebuild`, activating `nixosConfigurations`
+    #[serde(alias = "nixos-rebuild")]
+    NixosRebuild,
+    /// `home-manager`, activating `homeConfigurations` of the current user
+    #[serde(alias = "home-manager")]
+    HomeManager,
+    /// `darwin-rebuild`, activating `darwinConfigurations`
+    #[serde(alias = "darwin-rebuild")]
+    DarwinRebuild,
+}
+
+impl ActivationBackend {
+    /// Does activating with this backend require root privileges
+    fn needs_root(self) -> bool {
+        !matches!(self.resolve(), ActivationBackend::HomeManager)
+    }
+
+    /// Resolve [`ActivationBackend::Auto`] for the current platform
+    pub fn resolve(self) -> Self {
+        match self {
+            ActivationBackend::Auto if cfg!(target_os = "macos") => {
+                ActivationBackend::DarwinRebuild
+            }
+            ActivationBackend::Auto => ActivationBackend::NixosRebuild,
+            backend => backend,
+        }
+    }
+
+    /// The command used to activate
+    pub fn program(self) -> OsString {
+        match self.resolve() {
+            ActivationBackend::Auto => unreachable!(),
+            ActivationBackend::NixosRebuild => nixos_rebuild_path(),
+            ActivationBackend::HomeManager => home_manager_path(),
+            ActivationBackend::DarwinRebuild => darwin_rebuild_path(),
+        }
+    }
+
+    /// The flake output with the configurations activated by this backend
+    pub fn flake_output(self) -> &'static str {
+        match self.resolve() {
+            ActivationBackend::Auto => unreachable!(),
+            ActivationBackend::NixosRebuild => "nixosConfigurations",
+            ActivationBackend::HomeManager => "homeConfigurations",
+            ActivationBackend::DarwinRebuild => "darwinConfigurations",
+        }
+    }
+
+    /// The attribute of a configuration with its system (or home)
+    /// derivation
+    pub fn system_attr(self) -> &'static str {
+        match self.resolve() {
+            ActivationBackend::Auto => unreachable!(),
+            ActivationBackend::NixosRebuild => "config.system.build.toplevel",
+            ActivationBackend::HomeManager => "activationPackage",
+            ActivationBackend::DarwinRebuild => "system",
+        }
+    }
+
+    fn mode_args(self, mode: ActivationMode) -> Result<&'static [&'static str], anyhow::Error> {
+        Ok(match (self.resolve(), mode) {
+            (ActivationBackend::NixosRebuild, ActivationMode::Switch) => &["switch"],
+            (ActivationBackend::NixosRebuild, ActivationMode::Boot) => &["boot"],
+            (ActivationBackend::NixosRebuild, ActivationMode::Test) => &["test"],
+            (ActivationBackend::NixosRebuild, ActivationMode::DryActivate) => &["dry-activate"],
+            (ActivationBackend::HomeManager, ActivationMode::Switch) => &["switch"],
+            (ActivationBackend::HomeManager, ActivationMode::DryActivate) => {
+                &["switch", "--dry-run"]
+            }
+            (ActivationBackend::DarwinRebuild, ActivationMode::Switch) => &["switch"],
+            (ActivationBackend::DarwinRebuild, ActivationMode::DryActivate) => &["check"],
+            (backend, mode) => bail!("Activation mode {mode} is not supported by {backend}"),
+        })
+    }
+}
+
+impl fmt::Display for ActivationBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ActivationBackend::Auto => "auto",
+            ActivationBackend::NixosRebuild => "nixos-rebuild",
+            ActivationBackend::HomeManager => "home-manager",
+            ActivationBackend::DarwinRebuild => "darwin-rebuild",
+        })
+    }
+}
+
+/// How to gain root privileges when not running as root
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Escalation {
+    Sudo,
+    Doas,
+}
+
+impl Escalation {
+    pub fn program(self) -> OsString {
+        match self {
+            Escalation::Sudo => {
+                std::env::var_os("NPCNIX_SUDO").unwrap_or_else(|| OsString::from("sudo"))
+            }
+            Escalation::Doas => {
+                std::env::var_os("NPCNIX_DOAS").unwrap_or_else(|| OsString::from("doas"))
+            }
+        }
+    }
+}
+
+/// The backend to activate with
+///
+/// Not running as root and without escalation configured (user mode),
+/// [`ActivationBackend::Auto`] is `home-manager`.
+pub fn effective_backend(activate_opts: &ActivateOpts, config: &Config) -> ActivationBackend {
+    match activate_opts.backend.unwrap_or(config.activation_backend()) {
+        ActivationBackend::Auto
+            if !is_root() && activate_opts.escalation.or(config.escalation()).is_none() =>
+        {
+            ActivationBackend::HomeManager
+        }
+        backend => backend.resolve(),
+    }
+}
+
+/// Command running `program` as root, using `escalation` if needed
+pub fn privileged_command(
+    program: impl AsRef<OsStr>,
+    escalation: Option<Escalation>,
+) -> process::Command {
+    match escalation {
+        Some(escalation) if !is_root() => {
+            let mut cmd = process::Command::new(escalation.program());
+            cmd.arg(program);
+            cmd
+        }
+        _ => process::Command::new(program),
+    }
+}
+
+/// Activation settings passed explicitly (e.g. on the command line)
+///
+/// `Option` fields override the corresponding [`Config`] values.
+#[derive(Debug, Clone, Default)]
+pub struct ActivateOpts {
+    pub extra_substituters: Vec<String>,
+    pub extra_trusted_public_keys: Vec<String>,
+    pub mode: Option<ActivationMode>,
+    pub backend: Option<ActivationBackend>,
+    /// Build on this host (`nixos-rebuild --build-host`)
+    pub build_host: Option<String>,
+    /// Use `sudo` on the build host (only enables, never disables the config)
+    pub use_remote_sudo: bool,
+    /// Build the system closure first, then switch to it (only enables,
+    /// never disables the config)
+    pub two_phase: bool,
+    /// Kill the activation if it takes longer than this
+    pub timeout: Option<time::Duration>,
+    /// Append the output of activation commands to this file (by default a
+    /// new file in the data dir logs)
+    pub log_file: Option<PathBuf>,
+    /// How to gain root privileges when not running as root
+    pub escalation: Option<Escalation>,
+    /// Activate this pre-built system closure instead of evaluating the flake
+    pub store_path: Option<PathBuf>,
+    /// Don't switch outside of the configured activation windows (only
+    /// checked right before switching, in two-phase activation)
+    pub enforce_activation_windows: bool,
+    pub flake_attr: Option<String>,
+    /// Passed to the rebuild command verbatim, after the ones from config
+    pub extra_nixos_rebuild_args: Vec<String>,
+    /// Kill the activation commands once cancelled
+    pub cancel: Option<CancellationToken>,
+}
+
+/// What to activate from a flake, see [`flake_target`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlakeTarget {
+    /// A configuration of the flake output of the backend (e.g.
+    /// `nixosConfigurations.<name>`), activated by the rebuild tool
+    Configuration { flake: String, name: String },
+    /// The full attribute path of a configuration in the flake (e.g.
+    /// `nixosConfigurations.host.config.specialisation.gpu.configuration`),
+    /// built with `nix build` and activated with `switch-to-configuration`
+    AttrPath { flake: String, path: String },
+}
+
+impl FlakeTarget {
+    pub fn flake(&self) -> &str {
+        match self {
+            FlakeTarget::Configuration { flake, .. } | FlakeTarget::AttrPath { flake, .. } => flake,
+        }
+    }
+
+    /// Installable of the configuration itself
+    pub fn configuration_installable(&self, backend: ActivationBackend) -> String {
+        match self {
+            FlakeTarget::Configuration { flake, name } => {
+                format!("{flake}#{}.{}", backend.flake_output(), quote_attr(name))
+            }
+            FlakeTarget::AttrPath { flake, path } => format!("{flake}#{path}"),
+        }
+    }
+
+    /// Installable of the system (or home) of the configuration, activated
+    /// by `backend`
+    pub fn system_installable(&self, backend: ActivationBackend) -> String {
+        format!(
+            "{}.{}",
+            self.configuration_installable(backend),
+            backend.system_attr()
+        )
+    }
+}
+
+/// The flake reference, e.g. as passed to `nixos-rebuild --flake`
+impl fmt::Display for FlakeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlakeTarget::Configuration { flake, name: attr }
+            | FlakeTarget::AttrPath { flake, path: attr } => write!(f, "{flake}#{attr}"),
+        }
+    }
+}
+
+/// Quote the attribute name `name` if it's not a valid identifier
+fn quote_attr(name: &str) -> String {
+    let is_identifier = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''));
+    if is_identifier {
+        name.to_owned()
+    } else {
+        format!("{name:?}")
+    }
+}
+
+/// Split the attribute path `path` on its dots, except for quoted ones
+fn split_attr_path(path: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut start, mut quoted) = (0, false);
+    for (i, c) in path.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => {
+                parts.push(&path[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&path[start..]);
+    parts
+}
+
+/// What to activate for `configuration`
+///
+/// By default it's the configuration of the flake in the source directory.
+/// `flake_attr` overrides it: `[<flake>#]<name>` is another configuration,
+/// and `[<flake>#]<attribute path>` any configuration in the flake (e.g.
+/// `nixosConfigurations.host.config.specialisation.gpu.configuration`).
+/// `{configuration}` in `flake_attr` is replaced with the configuration
+/// name.
+pub fn flake_target(configuration: &str, flake_attr: Option<&str>) -> FlakeTarget {
+    let Some(flake_attr) = flake_attr else {
+        return FlakeTarget::Configuration {
+            flake: ".".into(),
+            name: configuration.to_owned(),
+        };
+    };
+    let flake_attr = flake_attr.replace("{configuration}", configuration);
+    let (flake, attr) = flake_attr.split_once('#').unwrap_or((".", &flake_attr));
+    let flake = if flake.is_empty() { "." } else { flake }.to_owned();
+    match split_attr_path(attr).as_slice() {
+        [""] => FlakeTarget::Configuration {
+            flake,
+            name: configuration.to_owned(),
+        },
+        [name] => FlakeTarget::Configuration {
+            flake,
+            name: name
+                .strip_prefix('"')
+                .and_then(|name| name.strip_suffix('"'))
+                .unwrap_or(name)
+                .to_owned(),
+        },
+        _ => FlakeTarget::AttrPath {
+            flake,
+            path: attr.to_owned(),
+        },
+    }
+}
+
+/// Expand placeholders in a custom activation command template
+///
+/// Supported placeholders: `{src}`, `{configuration}`, `{flake_ref}` and
+/// `{mode}`. They can appear anywhere inside of each argument.
+pub fn expand_command_template(
+    template: &[String],
+    src: &Path,
+    configuration: &str,
+    flake_ref: &str,
+    mode: ActivationMode,
+) -> Vec<String> {
+    let src = src.display().to_string();
+    template
+        .iter()
+        .map(|arg| {
+            arg.replace("{src}", &src)
+                .replace("{configuration}", configuration)
+                .replace("{flake_ref}", flake_ref)
+                .replace("{mode}", mode.as_nixos_rebuild_arg())
+        })
+        .collect()
+}
+
+/// What an activation did
+#[derive(Debug, Clone)]
+pub struct ActivationResult {
+    /// The new system (as activated, or as the boot default), if known
+    pub system: Option<PathBuf>,
+    pub duration: time::Duration,
+}
+
+/// A command of the activation exited unsuccessfully, see
+/// [`crate::NpcnixError::exit_code`]
+#[derive(thiserror::Error, Debug)]
+#[error("{what} returned exit code={code:?}")]
+pub struct CommandFailed {
+    pub what: String,
+    /// `None` if killed by a signal
+    pub code: Option<i32>,
+}
+
+/// Activates with `nixos-rebuild`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NixosRebuildActivator;
+
+impl Activator for NixosRebuildActivator {
+    fn activate(
+        &self,
+        src: &Path,
+        configuration: &str,
+        etag: Option<&str>,
+        data_dir: Option<&DataDir>,
+        activate_opts: &ActivateOpts,
+        config: &Config,
+    ) -> anyhow::Result<ActivationResult> {
+        activate_with(
+            ActivationBackend::NixosRebuild,
+            None,
+            src,
+            configuration,
+            etag,
+            data_dir,
+            activate_opts,
+            config,
+        )
+    }
+
+    fn supports_prebuild(&self, _activate_opts: &ActivateOpts, _config: &Config) -> bool {
+        true
+    }
+
+    fn prebuild(
+        &self,
+        src: &Path,
+        configuration: &str,
+        activate_opts: &ActivateOpts,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        let activate_opts = &ActivateOpts {
+            timeout: activate_opts.timeout.or(config.activation_timeout()),
+            ..activate_opts.clone()
+        };
+        let system = match ClosureRef::load_from(src)? {
+            Some(closure) => {
+                realise_store_path(&closure.store_path, activate_opts)?;
+                closure.store_path
+            }
+            None => build_system(
+                src,
+                &flake_target(
+                    configuration,
+                    activate_opts.flake_attr.as_deref().or(config.flake_attr()),
+                ),
+                activate_opts,
+            )?,
+        };
+        info!(system = %system.display(), "Pre-built the new configuration");
+        Ok(())
+    }
+}
+
+/// Activates `homeConfigurations` with `home-manager`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HomeManagerActivator;
+
+impl Activator for HomeManagerActivator {
+    fn activate(
+        &self,
+        src: &Path,
+        configuration: &str,
+        etag: Option<&str>,
+        data_dir: Option<&DataDir>,
+        activate_opts: &ActivateOpts,
+        config: &Config,
+    ) -> anyhow::Result<ActivationResult> {
+        activate_with(
+            ActivationBackend::HomeManager,
+            None,
+            src,
+            configuration,
+            etag,
+            data_dir,
+            activate_opts,
+            config,
+        )
+    }
+}
+
+/// Activates `darwinConfigurations` with `darwin-rebuild`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DarwinRebuildActivator;
+
+impl Activator for DarwinRebuildActivator {
+    fn activate(
+        &self,
+        src: &Path,
+        configuration: &str,
+        etag: Option<&str>,
+        data_dir: Option<&DataDir>,
+        activate_opts: &ActivateOpts,
+        config: &Config,
+    ) -> anyhow::Result<ActivationResult> {
+        activate_with(
+            ActivationBackend::DarwinRebuild,
+            None,
+            src,
+            configuration,
+            etag,
+            data_dir,
+            activate_opts,
+            config,
+        )
+    }
+}
+
+/// Runs a custom command instead of a rebuild tool (see
+/// [`expand_command_template`] for the placeholders of the template)
+///
+/// The hooks, health checks and the bookkeeping of the configured backend
+/// still apply.
+#[derive(Debug, Clone)]
+pub struct CommandActivator {
+    pub command: Vec<String>,
+}
+
+impl Activator for CommandActivator {
+    fn activate(
+        &self,
+        src: &Path,
+        configuration: &str,
+        etag: Option<&str>,
+        data_dir: Option<&DataDir>,
+        activate_opts: &ActivateOpts,
+        config: &Config,
+    ) -> anyhow::Result<ActivationResult> {
+        if self.command.is_empty() {
+            bail!("The activation command is empty");
+        }
+        activate_with(
+            activate_opts
+                .backend
+                .unwrap_or(config.activation_backend())
+                .resolve(),
+            Some(&self.command),
+            src,
+            configuration,
+            etag,
+            data_dir,
+            activate_opts,
+            config,
+        )
+    }
+}
+
+/// The activator selected by the settings: the `activation_command`
+/// (unless activating a pre-built store path), else the activation backend
+/// (see [`effective_backend`])
+pub fn activator_for(activate_opts: &ActivateOpts, config: &Config) -> Box<dyn Activator> {
+    if let Some(command) = config
+        .activation_command()
+        .filter(|_| activate_opts.store_path.is_none())
+    {
+        return Box::new(CommandActivator {
+            command: command.to_vec(),
+        });
+    }
+    match effective_backend(activate_opts, config) {
+        ActivationBackend::HomeManager => Box::new(HomeManagerActivator),
+        ActivationBackend::DarwinRebuild => Box::new(DarwinRebuildActivator),
+        ActivationBackend::Auto | ActivationBackend::NixosRebuild => {
+            Box::new(NixosRebuildActivator)
+        }
+    }
+}
+
+/// Activate with `backend` (or the custom `command`), with everything
+/// around it: logs, hooks, flake check, health checks and the bookkeeping
+#[allow(clippy::too_many_arguments)]
+fn activate_with(
+    backend: ActivationBackend,
+    command: Option<&[String]>,
+    src: &Path,
+    configuration: &str,
+    etag: Option<&str>,
+    data_dir: Option<&DataDir>,
+    activate_opts: &ActivateOpts,
+    config: &Config,
+) -> Result<ActivationResult, anyhow::Error> {
+    let start = time::Instant::now();
+    if activate_opts.store_path.is_none() {
+        verify_flake_src(src)?;
+    }
+    if backend.needs_root()
+        && !is_root()
+        && (activate_opts.store_path.is_some() || command.is_none())
+        && activate_opts.escalation.or(config.escalation()).is_none()
+    {
+        bail!(
+            "Activating with {backend} requires root privileges; run as root, or configure \
+             `sudo` or `doas` escalation"
+        );
+    }
+    let log_file = match (&activate_opts.log_file, data_dir) {
+        (Some(log_file), _) => Some(log_file.clone()),
+        (None, Some(data_dir)) => {
+            let logs_dir = data_dir.logs_dir();
+            let log_file = logs::create(&logs_dir, etag)?;
+            if let Err(e) = logs::rotate(&logs_dir, config.activation_logs_keep()) {
+                warn!(error = %e, "Failed to rotate activation logs");
+            }
+            data_dir.update_last_activation_log(&log_file)?;
+            Some(log_file)
+        }
+        (None, None) => None,
+    };
+    let activate_opts = &ActivateOpts {
+        timeout: activate_opts.timeout.or(config.activation_timeout()),
+        log_file,
+        escalation: activate_opts.escalation.or(config.escalation()),
+        ..activate_opts.clone()
+    };
+    let mode = activate_opts.mode.unwrap_or(config.activation_mode());
+    let flake_target = flake_target(
+        configuration,
+        activate_opts.flake_attr.as_deref().or(config.flake_attr()),
+    );
+    info!(
+        configuration,
+        src = %src.display(),
+        %mode,
+        %backend,
+        flake_ref = %flake_target,
+        store_path = activate_opts
+            .store_path
+            .as_deref()
+            .map(|path| path.display().to_string()),
+        log_file = activate_opts
+            .log_file
+            .as_deref()
+            .map(|path| path.display().to_string()),
+        "Activating configuration"
+    );
+
+    let hooks_dir = data_dir.map(DataDir::hooks_dir);
+    let hooks_dir = hooks_dir.as_deref();
+    let hook_env = HookEnv {
+        configuration,
+        src,
+        etag,
+        mode,
+        error: None,
+    };
+    let res = config
+        .flake_check()
+        .filter(|_| activate_opts.store_path.is_none())
+        .map(|args| flake_check(src, args, activate_opts).context("Flake check failed"))
+        .transpose()
+        .and_then(|_| {
+            hooks_dir
+                .map(|hooks_dir| {
+                    hooks::run_hooks(hooks_dir, Hook::PreActivate, &hook_env)
+                        .context("Pre-activate hook failed")
+                })
+                .transpose()
+        })
+        .and_then(|_| {
+            activate_and_check(
+                src,
+                configuration,
+                etag,
+                &flake_target,
+                mode,
+                backend,
+                command,
+                activate_opts,
+                config,
+            )
+        });
+
+    if let Some(hooks_dir) = hooks_dir {
+        let error = res.as_ref().err().map(|e| format!("{e:#}"));
+        let hook_env = HookEnv {
+            error: error.as_deref(),
+            ..hook_env
+        };
+        hooks::run_hooks_logged(hooks_dir, Hook::PostActivate, &hook_env);
+        if error.is_some() {
+            hooks::run_hooks_logged(hooks_dir, Hook::OnFailure, &hook_env);
+        }
+    }
+
+    if let (Ok(()), Some(data_dir)) = (&res, data_dir) {
+        if backend == ActivationBackend::NixosRebuild
+            && matches!(mode, ActivationMode::Switch | ActivationMode::Test)
+        {
+            data_dir.update_expected_system(running_system().as_deref())?;
+        }
+        if backend == ActivationBackend::NixosRebuild
+            && matches!(mode, ActivationMode::Switch | ActivationMode::Boot)
+        {
+            if let Some(generation) = current_generation(configuration, etag) {
+                data_dir.record_generation(generation)?;
+            }
+            if let Err(e) = gc::collect_garbage(&config.gc(), activate_opts.escalation) {
+                warn!(error = %e, "Garbage collection failed");
+            }
+        }
+    }
+    res?;
+    Ok(ActivationResult {
+        system: match mode {
+            _ if backend != ActivationBackend::NixosRebuild => None,
+            ActivationMode::Switch | ActivationMode::Test => running_system(),
+            ActivationMode::Boot => fs::canonicalize(SYSTEM_PROFILE).ok(),
+            ActivationMode::DryActivate => None,
+        },
+        duration: start.elapsed(),
+    })
+}
+
+/// A system generation created by npcnix
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Generation {
+    pub number: u64,
+    pub configuration: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Number of the generation the system profile currently points to
+pub fn current_generation_number() -> Option<u64> {
+    // the profile links to e.g. `system-42-link`
+    let link = fs::read_link(SYSTEM_PROFILE).ok()?;
+    link.file_name()?
+        .to_str()?
+        .strip_prefix("system-")?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+fn current_generation(configuration: &str, etag: Option<&str>) -> Option<Generation> {
+    let Some(number) = current_generation_number() else {
+        warn!("Can't determine the current system generation");
+        return None;
+    };
+    Some(Generation {
+        number,
+        configuration: configuration.to_owned(),
+        etag: etag.map(ToOwned::to_owned),
+        timestamp: chrono::Utc::now(),
+    })
+}
+
+/// Switch the system profile to `generation` (or the previous one) and
+/// activate it
+///
+/// Returns the activated system.
+pub fn rollback_to(
+    generation: Option<u64>,
+    activate_opts: &ActivateOpts,
+) -> Result<PathBuf, anyhow::Error> {
+    let mut cmd = privileged_command(nix_env_path(), activate_opts.escalation);
+    cmd.args(["--profile", SYSTEM_PROFILE]);
+    match generation {
+        Some(generation) => cmd.args(["--switch-generation", &generation.to_string()]),
+        None => cmd.arg("--rollback"),
+    };
+    run_command(&mut cmd, "nix-env", false, activate_opts)?;
+
+    let system = fs::canonicalize(SYSTEM_PROFILE)
+        .with_context(|| format!("Failed to resolve {SYSTEM_PROFILE}"))?;
+    info!(system = %system.display(), ?generation, "Rolling back");
+    run_command(
+        privileged_command(
+            system.join("bin/switch-to-configuration"),
+            activate_opts.escalation,
+        )
+        .arg("switch"),
+        "switch-to-configuration",
+        false,
+        activate_opts,
+    )?;
+    Ok(system)
+}
+
+/// Activate and run health checks, rolling back if they fail
+#[allow(clippy::too_many_arguments)]
+fn activate_and_check(
+    src: &Path,
+    configuration: &str,
+    etag: Option<&str>,
+    flake_target: &FlakeTarget,
+    mode: ActivationMode,
+    backend: ActivationBackend,
+    command: Option<&[String]>,
+    activate_opts: &ActivateOpts,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let health_check = config.health_check();
+    let previous_system = if !health_check.is_empty()
+        && health_check.rollback
+        && matches!(mode, ActivationMode::Switch | ActivationMode::Test)
+        && backend == ActivationBackend::NixosRebuild
+    {
+        current_system()
+    } else {
+        None
+    };
+
+    run_activation(
+        src,
+        configuration,
+        flake_target,
+        mode,
+        backend,
+        command,
+        activate_opts,
+        config,
+    )?;
+
+    if matches!(mode, ActivationMode::Boot | ActivationMode::DryActivate) {
+        return Ok(());
+    }
+    if let Err(e) = health_check.run() {
+        error!(error = %e, "Health check failed");
+        let Some(previous_system) = previous_system else {
+            return Err(e.context("Health check failed, not rolled back"));
+        };
+        switch_to_configuration(&previous_system, mode, activate_opts)
+            .context("Rolling back after a failed health check failed")?;
+        let e = e.context(format!(
+            "Health check failed, rolled back to {}",
+            previous_system.display()
+        ));
+        notify::notify(
+            config,
+            NotifyEvent::Rollback,
+            configuration,
+            etag.unwrap_or_default(),
+            Some(&e),
+        );
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// The currently running NixOS system, if any
+pub fn running_system() -> Option<PathBuf> {
+    fs::canonicalize(CURRENT_SYSTEM).ok()
+}
+
+/// Like [`running_system`], but warns about the rollback being impossible
+fn current_system() -> Option<PathBuf> {
+    match fs::canonicalize(CURRENT_SYSTEM) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            warn!(error = %e, "Can't determine the current system, rollback disabled");
+            None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_activation(
+    src: &Path,
+    configuration: &str,
+    flake_target: &FlakeTarget,
+    mode: ActivationMode,
+    backend: ActivationBackend,
+    command: Option<&[String]>,
+    activate_opts: &ActivateOpts,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    if let Some(store_path) = &activate_opts.store_path {
+        if backend != ActivationBackend::NixosRebuild {
+            bail!("Activating a store path is only supported by nixos-rebuild, not {backend}");
+        }
+        realise_store_path(store_path, activate_opts)?;
+        return switch_to_configuration(store_path, mode, activate_opts);
+    }
+
+    if let Some(template) = command {
+        return run_custom_command(
+            template,
+            src,
+            configuration,
+            &flake_target.to_string(),
+            mode,
+            activate_opts,
+        );
+    }
+
+    // the rebuild tools only take configuration names, build attribute paths
+    // (e.g. of specialisations) ourselves
+    let attr_path = matches!(flake_target, FlakeTarget::AttrPath { .. });
+    if attr_path || activate_opts.two_phase || config.two_phase_activation() {
+        let what = if attr_path {
+            "Activating a flake attribute path"
+        } else {
+            "Two-phase activation"
+        };
+        if backend != ActivationBackend::NixosRebuild {
+            bail!("{what} is only supported by nixos-rebuild, not {backend}");
+        }
+        if activate_opts.build_host.is_some() || config.build_host().is_some() {
+            bail!("{what} does not support remote building");
+        }
+        let system = build_system(src, flake_target, activate_opts)?;
+        if activate_opts.enforce_activation_windows
+            && !config.is_in_activation_window(chrono::Utc::now())
+        {
+            bail!("The activation window closed while building, not switching");
+        }
+        if let Some(current_system) = current_system() {
+            match diff_closures(&current_system, &system, activate_opts) {
+                Ok(diff) => info!(%diff, "Closure changes"),
+                Err(e) => warn!(error = %e, "Failed to diff closures"),
+            }
+        }
+        return switch_to_configuration(&system, mode, activate_opts);
+    }
+
+    let mut cmd = if backend.needs_root() {
+        privileged_command(backend.program(), activate_opts.escalation)
+    } else {
+        process::Command::new(backend.program())
+    };
+    cmd.args(backend.mode_args(mode)?).arg("-L");
+
+    for subscriber in &activate_opts.extra_substituters {
+        cmd.args(["--option", "extra-substituters", subscriber]);
+    }
+    for key in &activate_opts.extra_trusted_public_keys {
+        cmd.args(["--option", "extra-trusted-public-keys", key]);
+    }
+
+    let build_host = activate_opts.build_host.as_deref().or(config.build_host());
+    let use_remote_sudo = activate_opts.use_remote_sudo || config.use_remote_sudo();
+    if build_host.is_some() || use_remote_sudo {
+        if backend != ActivationBackend::NixosRebuild {
+            bail!("Remote building is only supported by nixos-rebuild, not {backend}");
+        }
+        if let Some(build_host) = build_host {
+            cmd.args(["--build-host", build_host]);
+        }
+        if use_remote_sudo {
+            cmd.arg("--use-remote-sudo");
+        }
+    }
+
+    cmd.args(config.extra_nixos_rebuild_args())
+        .args(&activate_opts.extra_nixos_rebuild_args);
+
+    cmd.arg("--flake")
+        .arg(flake_target.to_string())
+        .current_dir(src);
+
+    run_command(&mut cmd, &backend.to_string(), false, activate_opts)?;
+    Ok(())
+}
+
+/// Run `cmd` to completion, returning its stdout if `capture_stdout`
+///
+/// Output is also appended to the `log_file`, and after the `timeout` (or on
+/// cancellation) the whole process group of `cmd` is killed.
+fn run_command(
+    cmd: &mut process::Command,
+    what: &str,
+    capture_stdout: bool,
+    activate_opts: &ActivateOpts,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let log = activate_opts
+        .log_file
+        .as_deref()
+        .map(|path| {
+            fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file: {}", path.display()))
+        })
+        .transpose()?;
+    if let Some(mut log) = log.as_ref() {
+        writeln!(log, "$ {cmd:?}")?;
+        cmd.stderr(process::Stdio::piped());
+    }
+    if capture_stdout || log.is_some() {
+        cmd.stdout(process::Stdio::piped());
+    }
+    let cancel = activate_opts.cancel.as_ref();
+    let process_group = activate_opts.timeout.is_some() || cancel.is_some();
+    if process_group {
+        cmd.process_group(0);
+    }
+
+    cancel::check(cancel)?;
+    let mut child = cmd
+        .log_debug()
+        .spawn()
+        .with_context(|| format!("Calling `{what}` failed"))?;
+    let _running = RunningChild::register(&child, process_group);
+
+    let stdout_copier = child.stdout.take().map(|stdout| {
+        let out: Option<Box<dyn Write + Send>> = if capture_stdout {
+            None
+        } else {
+            Some(Box::new(io::stdout()))
+        };
+        spawn_copier(stdout, log.as_ref(), out, capture_stdout)
+    });
+    let stderr_copier = child
+        .stderr
+        .take()
+        .map(|stderr| spawn_copier(stderr, log.as_ref(), Some(Box::new(io::stderr())), false));
+
+    let status = cancel::kill_on_cancel(child.id(), process_group, cancel, || match activate_opts
+        .timeout
+    {
+        Some(timeout) => wait_timeout(&mut child, timeout),
+        None => child.wait().map(Some),
+    });
+    let stdout = join_copier(stdout_copier)?;
+    join_copier(stderr_copier)?;
+    let status = status??;
+
+    match status {
+        Some(status) if status.success() => Ok(stdout),
+        Some(status) => Err(CommandFailed {
+            what: what.to_owned(),
+            code: status.code(),
+        }
+        .into()),
+        None => bail!(
+            "{what} timed out after {}s and was killed",
+            activate_opts.timeout.unwrap_or_default().as_secs()
+        ),
+    }
+}
+
+type Copier = thread::JoinHandle<io::Result<Vec<u8>>>;
+
+/// Copy `reader` to `log` and `out` in a thread, optionally collecting it
+fn spawn_copier(
+    mut reader: impl Read + Send + 'static,
+    log: Option<&fs::File>,
+    mut out: Option<Box<dyn Write + Send>>,
+    collect: bool,
+) -> Copier {
+    let log = log.map(fs::File::try_clone).transpose();
+    thread::spawn(move || {
+        let mut log = log?;
+        let mut collected = vec![];
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                return Ok(collected);
+            }
+            let chunk = &buf[..len];
+            if let Some(log) = log.as_mut() {
+                log.write_all(chunk)?;
+            }
+            if let Some(out) = out.as_mut() {
+                out.write_all(chunk)?;
+            }
+            if collect {
+                collected.extend_from_slice(chunk);
+            }
+        }
+    })
+}
+
+fn join_copier(copier: Option<Copier>) -> Result<Vec<u8>, anyhow::Error> {
+    Ok(copier
+        .map(|copier| {
+            copier
+                .join()
+                .map_err(|_| format_err!("Output copying thread panicked"))?
+                .context("Failed to copy output")
+        })
+        .transpose()?
+        .unwrap_or_default())
+}
+
+/// Run `nix flake check` (with extra `args`) in the `src` directory
+pub fn flake_check(
+    src: &Path,
+    args: &[String],
+    activate_opts: &ActivateOpts,
+) -> Result<(), anyhow::Error> {
+    run_command(
+        process::Command::new(nix_path())
+            .args(["flake", "check", "-L"])
+            .args(args)
+            .current_dir(src),
+        "nix flake check",
+        false,
+        activate_opts,
+    )?;
+    Ok(())
+}
+
+/// Profile of the NixOS system generations
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// The currently activated NixOS system
+pub const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// Evaluate the derivation of the system (or home) of `flake_target`,
+/// activated by `backend`, without building it
+pub fn eval_drv_path(
+    src: &Path,
+    flake_target: &FlakeTarget,
+    backend: ActivationBackend,
+) -> Result<String, anyhow::Error> {
+    let installable = format!("{}.drvPath", flake_target.system_installable(backend));
+    let output = process::Command::new(nix_path())
+        .args(["eval", "--raw", &installable])
+        .current_dir(src)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .log_debug()
+        .output()
+        .context("Calling `nix` failed")?;
+    if !output.status.success() {
+        bail!("nix eval returned exit code={:?}", output.status.code());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Build the system closure of the NixOS configuration `flake_target` and
+/// return its store path
+pub fn build_system(
+    src: &Path,
+    flake_target: &FlakeTarget,
+    activate_opts: &ActivateOpts,
+) -> Result<PathBuf, anyhow::Error> {
+    let installable = flake_target.system_installable(ActivationBackend::NixosRebuild);
+
+    let mut cmd = process::Command::new(nix_path());
+    cmd.args(["build", "-L", "--no-link", "--print-out-paths"]);
+    for subscriber in &activate_opts.extra_substituters {
+        cmd.args(["--option", "extra-substituters", subscriber]);
+    }
+    for key in &activate_opts.extra_trusted_public_keys {
+        cmd.args(["--option", "extra-trusted-public-keys", key]);
+    }
+    cmd.arg(&installable).current_dir(src);
+    let stdout = run_command(&mut cmd, "nix build", true, activate_opts)?;
+
+    let out_path = String::from_utf8(stdout)?;
+    let out_path = out_path
+        .lines()
+        .next()
+        .ok_or_else(|| format_err!("nix build did not print an output path"))?;
+    Ok(PathBuf::from(out_path))
+}
+
+/// Make sure the system closure at `store_path` is in the local store,
+/// fetching it from substituters if needed
+pub fn realise_store_path(
+    store_path: &Path,
+    activate_opts: &ActivateOpts,
+) -> Result<(), anyhow::Error> {
+    let mut cmd = process::Command::new(nix_store_path());
+    cmd.arg("--realise");
+    for subscriber in &activate_opts.extra_substituters {
+        cmd.args(["--option", "extra-substituters", subscriber]);
+    }
+    for key in &activate_opts.extra_trusted_public_keys {
+        cmd.args(["--option", "extra-trusted-public-keys", key]);
+    }
+    cmd.arg(store_path);
+    run_command(&mut cmd, "nix-store --realise", true, activate_opts)?;
+
+    if !store_path.join("bin/switch-to-configuration").exists() {
+        bail!(
+            "Store path {} is not a NixOS system closure",
+            store_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Describe the changes between the `from` and `to` closures (`nix store
+/// diff-closures`)
+pub fn diff_closures(
+    from: &Path,
+    to: &Path,
+    activate_opts: &ActivateOpts,
+) -> Result<String, anyhow::Error> {
+    let stdout = run_command(
+        process::Command::new(nix_path())
+            .args(["store", "diff-closures"])
+            .arg(from)
+            .arg(to),
+        "nix store diff-closures",
+        true,
+        activate_opts,
+    )?;
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Build the configuration and diff it against the current system, without
+/// activating anything
+pub(crate) fn preview_inner(
+    src: &Path,
+    configuration: &str,
+    activate_opts: &ActivateOpts,
+    config: &Config,
+) -> Result<String, anyhow::Error> {
+    verify_flake_src(src)?;
+    let backend = activate_opts
+        .backend
+        .unwrap_or(config.activation_backend())
+        .resolve();
+    if backend != ActivationBackend::NixosRebuild {
+        bail!("Closure diff is only supported by nixos-rebuild, not {backend}");
+    }
+    let current_system = fs::canonicalize(CURRENT_SYSTEM)
+        .with_context(|| format!("Can't determine the current system ({CURRENT_SYSTEM})"))?;
+    let flake_target = flake_target(
+        configuration,
+        activate_opts.flake_attr.as_deref().or(config.flake_attr()),
+    );
+    let activate_opts = &ActivateOpts {
+        timeout: activate_opts.timeout.or(config.activation_timeout()),
+        ..activate_opts.clone()
+    };
+
+    let system = build_system(src, &flake_target, activate_opts)?;
+    diff_closures(&current_system, &system, activate_opts)
+}
+
+/// Activate an already built system closure
+///
+/// For modes that change the boot default, the system profile is updated
+/// first, like `nixos-rebuild` does.
+pub fn switch_to_configuration(
+    system: &Path,
+    mode: ActivationMode,
+    activate_opts: &ActivateOpts,
+) -> Result<(), anyhow::Error> {
+    info!(system = %system.display(), %mode, "Switching to configuration");
+    if matches!(mode, ActivationMode::Switch | ActivationMode::Boot) {
+        run_command(
+            privileged_command(nix_env_path(), activate_opts.escalation)
+                .args(["--profile", SYSTEM_PROFILE, "--set"])
+                .arg(system),
+            "nix-env",
+            false,
+            activate_opts,
+        )?;
+    }
+
+    run_command(
+        privileged_command(
+            system.join("bin/switch-to-configuration"),
+            activate_opts.escalation,
+        )
+        .arg(mode.as_nixos_rebuild_arg()),
+        "switch-to-configuration",
+        false,
+        activate_opts,
+    )?;
+    Ok(())
+}
+
+fn run_custom_command(
+    template: &[String],
+    src: &Path,
+    configuration: &str,
+    flake_ref: &str,
+    mode: ActivationMode,
+    activate_opts: &ActivateOpts,
+) -> Result<(), anyhow::Error> {
+    let args = expand_command_template(template, src, configuration, flake_ref, mode);
+    let (program, args) = args.split_first().expect("activation_command is not empty");
+
+    run_command(
+        process::Command::new(program).args(args).current_dir(src),
+        program,
+        false,
+        activate_opts,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{flake_target, ActivationBackend, FlakeTarget};
+
+    const NIXOS: ActivationBackend = ActivationBackend::NixosRebuild;
+
+    #[test]
+    fn defaults_to_the_configuration() {
+        let target = flake_target("host", None);
+        assert_eq!(
+            target,
+            FlakeTarget::Configuration {
+                flake: ".".into(),
+                name: "host".into()
+            }
+        );
+        assert_eq!(target.to_string(), ".#host");
+        assert_eq!(
+            target.system_installable(NIXOS),
+            ".#nixosConfigurations.host.config.system.build.toplevel"
+        );
+        assert_eq!(
+            target.system_installable(ActivationBackend::HomeManager),
+            ".#homeConfigurations.host.activationPackage"
+        );
+    }
+
+    #[test]
+    fn passes_configuration_names_to_the_rebuild_tool_as_is() {
+        for (flake_attr, flake_ref, installable) in [
+            ("other", ".#other", ".#nixosConfigurations.other"),
+            (
+                "{configuration}-vm",
+                ".#host-vm",
+                ".#nixosConfigurations.host-vm",
+            ),
+            (
+                "github:org/repo#host",
+                "github:org/repo#host",
+                "github:org/repo#nixosConfigurations.host",
+            ),
+            (
+                "path:/etc/nixos#host",
+                "path:/etc/nixos#host",
+                "path:/etc/nixos#nixosConfigurations.host",
+            ),
+            ("#", ".#host", ".#nixosConfigurations.host"),
+            (
+                r#".#"my.host""#,
+                ".#my.host",
+                r#".#nixosConfigurations."my.host""#,
+            ),
+        ] {
+            let target = flake_target("host", Some(flake_attr));
+            assert!(
+                matches!(target, FlakeTarget::Configuration { .. }),
+                "{flake_attr}"
+            );
+            assert_eq!(target.to_string(), flake_ref, "{flake_attr}");
+            assert_eq!(
+                target.configuration_installable(NIXOS),
+                installable,
+                "{flake_attr}"
+            );
+        }
+    }
+
+    #[test]
+    fn builds_full_attribute_paths() {
+        for (flake_attr, installable) in [
+            (
+                ".#nixosConfigurations.myhost",
+                ".#nixosConfigurations.myhost.config.system.build.toplevel",
+            ),
+            (
+                "nixosConfigurations.{configuration}",
+                ".#nixosConfigurations.host.config.system.build.toplevel",
+            ),
+            (
+                ".#nixosConfigurations.host.config.specialisation.gpu.configuration",
+                ".#nixosConfigurations.host.config.specialisation.gpu.configuration.config.\
+                 system.build.toplevel",
+            ),
+            (
+                "github:org/repo#hosts.x86_64-linux.host",
+                "github:org/repo#hosts.x86_64-linux.host.config.system.build.toplevel",
+            ),
+        ] {
+            let target = flake_target("host", Some(flake_attr));
+            assert!(
+                matches!(target, FlakeTarget::AttrPath { .. }),
+                "{flake_attr}"
+            );
+            assert_eq!(target.system_installable(NIXOS), installable);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Client-side encryption of archives using the `age` cli
+
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{self, Stdio};
+use std::thread;
+
+use anyhow::{bail, format_err, Context};
+
+use crate::CommandExt;
+
+pub fn age_cli_path() -> OsString {
+    std::env::var_os("NPCNIX_AGE").unwrap_or_else(|| OsString::from("age"))
+}
+
+/// A running `age` process (along with a thread feeding its stdin, if any)
+pub struct AgeChild {
+    child: process::Child,
+    feeder: Option<thread::JoinHandle<io::Result<u64>>>,
+}
+
+impl AgeChild {
+    pub fn wait(mut self) -> anyhow::Result<()> {
+        let status = self.child.wait()?;
+        if let Some(feeder) = self.feeder {
+            feeder
+                .join()
+                .map_err(|_| format_err!("age input thread panicked"))?
+                .context("Failed to feed input to `age`")?;
+        }
+        if !status.success() {
+            bail!("age returned exit code={:?}", status.code());
+        }
+        Ok(())
+    }
+}
+
+/// Spawn `age` encrypting its stdin to `recipients`, writing to `output`
+pub fn spawn_encrypt(
+    recipients: &[String],
+    output: impl Into<Stdio>,
+) -> anyhow::Result<(process::ChildStdin, AgeChild)> {
+    assert!(!recipients.is_empty());
+
+    let mut cmd = process::Command::new(age_cli_path());
+    cmd.arg("--encrypt");
+    for recipient in recipients {
+        cmd.args(["--recipient", recipient]);
+    }
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(output)
+        .log_debug()
+        .spawn()
+        .context("`age` cli failed")?;
+
+    let stdin = child.stdin.take().unwrap();
+
+    Ok((
+        stdin,
+        AgeChild {
+            child,
+            feeder: None,
+        },
+    ))
+}
+
+/// Spawn `age` decrypting `input` using the `identity` file
+pub fn spawn_decrypt(
+    identity: &Path,
+    mut input: impl Read + Send + 'static,
+) -> anyhow::Result<(process::ChildStdout, AgeChild)> {
+    let mut child = process::Command::new(age_cli_path())
+        .arg("--decrypt")
+        .arg("--identity")
+        .arg(identity)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .log_debug()
+        .spawn()
+        .context("`age` cli failed")?;
+
+    let stdout = child.stdout.take().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let feeder = thread::spawn(move || io::copy(&mut input, &mut stdin));
+
+    Ok((
+        stdout,
+        AgeChild {
+            child,
+            feeder: Some(feeder),
+        },
+    ))
+}
@@ -0,0 +1,157 @@
+//! Approval gate for rollouts (e.g. to production hosts)
+//!
+//! Hosts requiring approval only activate an etag of the remote if the
+//! `<remote>.approved` object contains it. With `allowed_signers` set, the
+//! approval also needs a valid SSH signature in `<remote>.approved.sig`
+//! (`ssh-keygen -Y sign`), so write access to the bucket is not enough to
+//! approve a rollout.
+
+use std::ffi::OsString;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::{s3, CommandExt};
+
+/// Signature namespace, so approval signatures can't be reused elsewhere
+const SIGNATURE_NAMESPACE: &str = "npcnix-approval";
+
+pub fn ssh_keygen_path() -> OsString {
+    std::env::var_os("NPCNIX_SSH_KEYGEN").unwrap_or_else(|| OsString::from("ssh-keygen"))
+}
+
+fn default_principal() -> String {
+    "npcnix".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ApprovalOpts {
+    /// `ssh-keygen` allowed signers file, to require signed approvals
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_signers: Option<PathBuf>,
+    /// Principal the approvals are signed as
+    #[serde(default = "default_principal")]
+    pub principal: String,
+}
+
+impl Default for ApprovalOpts {
+    fn default() -> Self {
+        Self {
+            allowed_signers: None,
+            principal: default_principal(),
+        }
+    }
+}
+
+fn suffixed_url(remote: &Url, suffix: &str) -> Url {
+    let mut url = remote.clone();
+    url.set_path(&format!("{}{suffix}", remote.path()));
+    url
+}
+
+/// Location of the approval object of `remote`
+pub fn approval_url(remote: &Url) -> Url {
+    suffixed_url(remote, ".approved")
+}
+
+fn signature_url(remote: &Url) -> Url {
+    suffixed_url(remote, ".approved.sig")
+}
+
+fn normalize_etag(etag: &str) -> &str {
+    etag.trim().trim_matches('"')
+}
+
+fn download(url: &Url) -> anyhow::Result<Option<std::fs::File>> {
+    if !s3::exists(url)? {
+        return Ok(None);
+    }
+    let mut file = tempfile::tempfile()?;
+    s3::download_to(url, &file, None)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(Some(file))
+}
+
+/// Is `etag` of `remote` approved
+pub fn is_approved(remote: &Url, etag: &str, opts: &ApprovalOpts) -> anyhow::Result<bool> {
+    let url = approval_url(remote);
+    if url.scheme() != "s3" {
+        bail!("Protocol not supported: {}", url.scheme());
+    }
+    let Some(mut file) = download(&url).context("Failed to download the approval")? else {
+        debug!(%url, "No approval");
+        return Ok(false);
+    };
+    let mut approved = String::new();
+    file.read_to_string(&mut approved)?;
+    if normalize_etag(&approved) != normalize_etag(etag) {
+        debug!(%url, approved = normalize_etag(&approved), "Approval is for another etag");
+        return Ok(false);
+    }
+
+    let Some(ref allowed_signers) = opts.allowed_signers else {
+        return Ok(true);
+    };
+    let sig_url = signature_url(remote);
+    let Some(mut sig_file) = download(&sig_url).context("Failed to download the signature")? else {
+        info!(url = %sig_url, "Approval is not signed");
+        return Ok(false);
+    };
+    let mut sig = tempfile::NamedTempFile::new()?;
+    std::io::copy(&mut sig_file, &mut sig)?;
+    file.seek(SeekFrom::Start(0))?;
+    let status = process::Command::new(ssh_keygen_path())
+        .args([
+            "-Y",
+            "verify",
+            "-n",
+            SIGNATURE_NAMESPACE,
+            "-I",
+            &opts.principal,
+            "-f",
+        ])
+        .arg(allowed_signers)
+        .arg("-s")
+        .arg(sig.path())
+        .stdin(file)
+        .stdout(process::Stdio::null())
+        .log_debug()
+        .status()
+        .context("`ssh-keygen` failed")?;
+    if !status.success() {
+        info!(url = %sig_url, "Approval signature is not valid");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Approve `etag` of `remote`, signing the approval with `sign_key` if given
+pub fn approve(remote: &Url, etag: &str, sign_key: Option<&Path>) -> anyhow::Result<()> {
+    let content = format!("{}\n", normalize_etag(etag));
+    if let Some(sign_key) = sign_key {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("approved");
+        std::fs::File::create(&path)?.write_all(content.as_bytes())?;
+        let status = process::Command::new(ssh_keygen_path())
+            .args(["-Y", "sign", "-n", SIGNATURE_NAMESPACE, "-f"])
+            .arg(sign_key)
+            .arg(&path)
+            .log_debug()
+            .status()
+            .context("`ssh-keygen` failed")?;
+        if !status.success() {
+            bail!("ssh-keygen returned exit code={:?}", status.code());
+        }
+        // the approval must not be visible before its signature
+        s3::upload_file(&path.with_extension("sig"), &signature_url(remote), None)?;
+    }
+    s3::upload_bytes(content.as_bytes(), &approval_url(remote))?;
+    info!(etag = normalize_etag(etag), url = %approval_url(remote), "Approved");
+    Ok(())
+}
@@ -0,0 +1,751 @@
+//! Packing and unpacking of flake archives (`tar` + `zstd`)
+//!
+//! Archives start with a single [`ArchiveHeader`] line, identifying the
+//! format version and features used, so incompatible archives can be
+//! detected before even trying to decompress them. The rest is the
+//! compressed tar of the flake, see [`pack_archive_from`] and
+//! [`unpack_archive_to`].
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, trace, warn};
+
+use crate::meta::{ArchiveMeta, META_FILE_NAME};
+
+fn default_max_entries() -> u64 {
+    100_000
+}
+
+fn default_max_size_bytes() -> u64 {
+    4 * 1024 * 1024 * 1024
+}
+
+/// Modification time of all the entries of deterministic archives (the same
+/// as `tar`'s own deterministic headers)
+const DETERMINISTIC_MTIME: u64 = 1153704088;
+
+/// Current archive format version
+pub const FORMAT_VERSION: u32 = 1;
+
+const HEADER_MAGIC: &[u8] = b"npcnix-archive/";
+const HEADER_MAX_LEN: u64 = 256;
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+const AGE_ARMORED_MAGIC: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Archive header: `npcnix-archive/<version>[ <feature>...]\n`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveHeader {
+    pub version: u32,
+    /// Content following the header is encrypted with `age`
+    pub encrypted: bool,
+}
+
+impl ArchiveHeader {
+    pub fn new(encrypted: bool) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            encrypted,
+        }
+    }
+
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut line = format!("npcnix-archive/{}", self.version);
+        if self.encrypted {
+            line.push_str(" age");
+        }
+        line.push('\n');
+        writer.write_all(line.as_bytes())
+    }
+
+    /// Read the header, if there is one
+    ///
+    /// Archives created before the header was introduced have none.
+    pub fn read_from(reader: &mut impl BufRead) -> anyhow::Result<Option<Self>> {
+        let first_bytes = reader.fill_buf()?;
+        if !first_bytes.starts_with(HEADER_MAGIC) {
+            if !first_bytes.starts_with(ZSTD_MAGIC)
+                && !first_bytes.starts_with(AGE_MAGIC)
+                && !first_bytes.starts_with(AGE_ARMORED_MAGIC)
+            {
+                bail!("Unrecognized archive format (not created by npcnix?)");
+            }
+            return Ok(None);
+        }
+
+        let mut line = String::new();
+        reader.take(HEADER_MAX_LEN).read_line(&mut line)?;
+        let line = line
+            .strip_suffix('\n')
+            .context("Archive header too long or truncated")?;
+        let mut parts = line["npcnix-archive/".len()..].split(' ');
+        let version: u32 = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .with_context(|| format!("Invalid archive header: {line}"))?;
+
+        if FORMAT_VERSION < version {
+            bail!(
+                "Archive format version {version} is newer than supported ({FORMAT_VERSION}); npcnix is too old and needs to be upgraded"
+            );
+        }
+
+        let mut encrypted = false;
+        for feature in parts {
+            match feature {
+                "age" => encrypted = true,
+                other => bail!(
+                    "Archive uses unsupported feature `{other}`; npcnix is too old and needs to be upgraded"
+                ),
+            }
+        }
+
+        Ok(Some(Self { version, encrypted }))
+    }
+}
+
+/// Limits enforced when unpacking (remote, untrusted) archives
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct UnpackLimits {
+    /// Maximum number of entries in the archive
+    #[serde(default = "default_max_entries")]
+    pub max_entries: u64,
+    /// Maximum total size of the unpacked files
+    #[serde(default = "default_max_size_bytes")]
+    pub max_size_bytes: u64,
+}
+
+impl Default for UnpackLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: default_max_entries(),
+            max_size_bytes: default_max_size_bytes(),
+        }
+    }
+}
+
+/// How to unpack an archive, see [`unpack_archive_to`]
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    pub limits: UnpackLimits,
+}
+
+/// How to pack an archive, see [`pack_archive_from`]
+#[derive(Debug, Clone, Default)]
+pub struct PackOptions {
+    /// Top-level directories to pack (empty: all), top-level files are always
+    /// packed
+    pub include: HashSet<OsString>,
+    /// Paths (relative to the source directory) to leave out, with
+    /// everything under them
+    pub exclude: HashSet<PathBuf>,
+    /// `zstd` compression level (`0`: the `zstd` default)
+    pub compression_level: i32,
+    /// Pack the files in a stable order, with fixed modification times and
+    /// no owners, so the same source always packs to the same archive
+    pub deterministic: bool,
+    /// Metadata to add to the archive (replacing any `.npcnix-meta.json` of the
+    /// source)
+    pub meta: Option<ArchiveMeta>,
+}
+
+/// What [`pack_archive_from`] packed
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct PackSummary {
+    /// Files and symlinks packed
+    pub files: usize,
+    /// Paths of the source left out (excluded, not included, or not
+    /// packable), relative to it
+    pub excluded: Vec<PathBuf>,
+}
+
+/// Is `path` a relative path that stays within the directory it's relative
+/// to
+fn is_contained_path(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(new_depth) => depth = new_depth,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Reject any path that is absolute or contains `..`
+fn is_normal_path(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Does `path` go through one of the already unpacked `symlinks`, other than
+/// as its last component
+///
+/// The lexical checks above only hold as long as it doesn't: with `a/b ->
+/// ..` unpacked, `a/b/c -> ..` points outside.
+fn crosses_symlink(path: &Path, symlinks: &HashSet<PathBuf>) -> bool {
+    let mut prefix = PathBuf::new();
+    for component in path.parent().unwrap_or(Path::new("")).components() {
+        match component {
+            Component::Normal(name) => {
+                prefix.push(name);
+                if symlinks.contains(&prefix) {
+                    return true;
+                }
+            }
+            Component::ParentDir => {
+                prefix.pop();
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    false
+}
+
+/// `path` without its `.` components
+fn normalized(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+/// Unpack the compressed tar of an archive (after its [`ArchiveHeader`]) to
+/// `dst`
+///
+/// Rejects entries escaping `dst` (absolute paths, `..`, links outside,
+/// paths through symlinks), special files, and archives above
+/// `opts.limits`.
+pub fn unpack_archive_to(
+    reader: impl Read,
+    dst: &Path,
+    opts: &UnpackOptions,
+) -> anyhow::Result<()> {
+    let limits = &opts.limits;
+    fs::create_dir_all(dst)?;
+
+    let decoder = zstd::stream::Decoder::new(reader)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut entries_count = 0u64;
+    let mut total_size = 0u64;
+    let mut symlinks = HashSet::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        entries_count += 1;
+        if limits.max_entries < entries_count {
+            bail!("Archive contains more than {} entries", limits.max_entries);
+        }
+
+        let path = entry.path()?.into_owned();
+        if !is_normal_path(&path) {
+            bail!("Archive entry with invalid path: {}", path.display());
+        }
+        let path = normalized(&path);
+        if crosses_symlink(&path, &symlinks) {
+            bail!("Archive entry inside of a symlink: {}", path.display());
+        }
+
+        let entry_type = entry.header().entry_type();
+        match entry_type {
+            tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::Directory => {}
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name()?
+                    .with_context(|| format!("Symlink without a target: {}", path.display()))?;
+                // symlink target is relative to the directory containing it
+                let resolved = path.parent().unwrap_or_else(|| Path::new("")).join(&target);
+                if !is_contained_path(&resolved) || crosses_symlink(&resolved, &symlinks) {
+                    bail!(
+                        "Archive symlink pointing outside of the archive: {} -> {}",
+                        path.display(),
+                        target.display()
+                    );
+                }
+            }
+            tar::EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .with_context(|| format!("Hard link without a target: {}", path.display()))?;
+                // hard link target is relative to the archive root; a link to
+                // a symlink would be one in another directory
+                if !is_normal_path(&target)
+                    || crosses_symlink(&target, &symlinks)
+                    || symlinks.contains(&normalized(&target))
+                {
+                    bail!(
+                        "Archive hard link pointing outside of the archive: {} -> {}",
+                        path.display(),
+                        target.display()
+                    );
+                }
+            }
+            other => bail!(
+                "Archive entry of unsupported type {other:?}: {}",
+                path.display()
+            ),
+        }
+
+        total_size = total_size.saturating_add(entry.header().size()?);
+        if limits.max_size_bytes < total_size {
+            bail!(
+                "Archive unpacks to more than {} bytes",
+                limits.max_size_bytes
+            );
+        }
+
+        trace!(path = %path.display(), "Unpacking");
+        if !entry.unpack_in(dst)? {
+            bail!("Archive entry outside of destination: {}", path.display());
+        }
+        if entry_type == tar::EntryType::Symlink {
+            symlinks.insert(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Entries of the directory `dir`, sorted by name
+fn sorted_dir_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Append the symlink `path` as `name`, unless it points outside of the
+/// packed tree (it would be rejected when unpacking)
+fn append_symlink<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    summary: &mut PackSummary,
+) -> io::Result<()> {
+    let target = path.read_link()?;
+    // symlink target is relative to the directory containing it
+    let resolved = name.parent().unwrap_or(Path::new("")).join(&target);
+    if is_contained_path(&resolved) {
+        trace!(src = %path.display(), target = %target.display(), "Packing symlink");
+        builder.append_path_with_name(path, name)?;
+        summary.files += 1;
+    } else {
+        warn!(
+            src = %path.display(),
+            target = %target.display(),
+            "Ignoring symlink pointing outside of the flake"
+        );
+        summary.excluded.push(name.to_owned());
+    }
+    Ok(())
+}
+
+/// Append the directory `path` as `name`, recursively (symlinks are packed
+/// as symlinks)
+fn append_dir_tree<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    opts: &PackOptions,
+    summary: &mut PackSummary,
+) -> io::Result<()> {
+    builder.append_dir(name, path)?;
+    for path in sorted_dir_entries(path)? {
+        let name = name.join(
+            path.file_name()
+                .expect("read_dir must return only items with valid file_name"),
+        );
+        if opts.exclude.contains(&name) {
+            debug!(src = %path.display(), "Ignoring excluded path");
+            summary.excluded.push(name);
+            continue;
+        }
+        let metadata = path.symlink_metadata()?;
+        if metadata.is_dir() {
+            append_dir_tree(builder, &path, &name, opts, summary)?;
+        } else if metadata.is_symlink() {
+            append_symlink(builder, &path, &name, summary)?;
+        } else if metadata.is_file() {
+            builder.append_path_with_name(&path, &name)?;
+            summary.files += 1;
+        } else {
+            warn!(src = %path.display(), "Ignoring unknown file type");
+            summary.excluded.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Pack the flake in `src` to `writer`, as the compressed tar following the
+/// [`ArchiveHeader`] of an archive
+pub fn pack_archive_from(
+    src: &Path,
+    opts: &PackOptions,
+    writer: impl Write,
+) -> io::Result<PackSummary> {
+    let mut summary = PackSummary::default();
+    let include = &opts.include;
+    let meta = opts.meta.as_ref();
+    let encoder = zstd::stream::Encoder::new(writer, opts.compression_level)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    if opts.deterministic {
+        builder.mode(tar::HeaderMode::Deterministic);
+    }
+    for path in sorted_dir_entries(src)? {
+        let file_name = path
+            .file_name()
+            .expect("read_dir must return only items with valid file_name");
+        if opts.exclude.contains(Path::new(file_name)) {
+            debug!(src = %path.display(), "Ignoring excluded path");
+            summary.excluded.push(file_name.into());
+            continue;
+        }
+        if meta.is_some() && file_name == META_FILE_NAME {
+            debug!(src = %path.display(), "Ignoring existing metadata file");
+            continue;
+        }
+        let metadata = path.symlink_metadata()?;
+        trace!(
+            src = %path.display(),
+            "Considering path for archive inclusion"
+        );
+        if metadata.is_dir() {
+            if include.is_empty() || include.contains(file_name) {
+                trace!(src = %path.display(), "Packing directory");
+                append_dir_tree(
+                    &mut builder,
+                    &path,
+                    Path::new(file_name),
+                    opts,
+                    &mut summary,
+                )?;
+            } else {
+                debug!(
+                    src = %path.display(),
+                    "Ignoring directory with no 'include'"
+                );
+                summary.excluded.push(file_name.into());
+            }
+        } else if metadata.is_symlink() {
+            append_symlink(&mut builder, &path, Path::new(file_name), &mut summary)?;
+        } else if metadata.is_file() {
+            trace!(src = %path.display(), "Packing file");
+            builder.append_path_with_name(&path, file_name)?;
+            summary.files += 1;
+        } else {
+            warn!(src = %path.display(), "Ignoring unknown file type");
+            summary.excluded.push(file_name.into());
+        }
+    }
+    if let Some(meta) = meta {
+        let meta = serde_json::to_vec_pretty(meta)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(meta.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(if opts.deterministic {
+            DETERMINISTIC_MTIME
+        } else {
+            chrono::Utc::now()
+                .timestamp()
+                .try_into()
+                .unwrap_or_default()
+        });
+        header.set_cksum();
+        builder.append_data(&mut header, META_FILE_NAME, meta.as_slice())?;
+    }
+    builder.into_inner()?.finish()?;
+
+    Ok(summary)
+}
+
+/// `sha256` of the deterministic, unencrypted archive of `src` (limited to
+/// `include`) without any metadata
+///
+/// Unlike the archive pushed, it only changes when the packed files do.
+pub fn content_sha256(src: &Path, include: &HashSet<OsString>) -> io::Result<String> {
+    let opts = PackOptions {
+        include: include.clone(),
+        deterministic: true,
+        ..Default::default()
+    };
+    let mut hasher = Sha256::new();
+    pack_archive_from(src, &opts, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{unpack_archive_to, UnpackLimits, UnpackOptions};
+
+    /// One entry of a hand-crafted tar, with its path and link target
+    /// written as is (`tar::Builder` refuses to write malicious ones)
+    struct Entry<'a> {
+        entry_type: tar::EntryType,
+        path: &'a str,
+        link: Option<&'a str>,
+        data: &'a [u8],
+    }
+
+    fn file<'a>(path: &'a str, data: &'a [u8]) -> Entry<'a> {
+        Entry {
+            entry_type: tar::EntryType::Regular,
+            path,
+            link: None,
+            data,
+        }
+    }
+
+    fn link<'a>(entry_type: tar::EntryType, path: &'a str, target: &'a str) -> Entry<'a> {
+        Entry {
+            entry_type,
+            path,
+            link: Some(target),
+            data: b"",
+        }
+    }
+
+    /// The compressed tar of `entries`, as following an archive header
+    fn archive(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for entry in entries {
+            let mut header = tar::Header::new_gnu();
+            let old = header.as_old_mut();
+            old.name[..entry.path.len()].copy_from_slice(entry.path.as_bytes());
+            if let Some(link) = entry.link {
+                old.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            header.set_entry_type(entry.entry_type);
+            header.set_mode(0o644);
+            header.set_size(entry.data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, entry.data).unwrap();
+        }
+        zstd::encode_all(builder.into_inner().unwrap().as_slice(), 0).unwrap()
+    }
+
+    /// Unpack `entries` to a `dst` directory inside of a temporary one, so
+    /// escapes to its parent can be checked
+    fn unpack(entries: &[Entry], limits: UnpackLimits) -> (tempfile::TempDir, anyhow::Result<()>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let res = unpack_archive_to(
+            archive(entries).as_slice(),
+            &tmp.path().join("dst"),
+            &UnpackOptions { limits },
+        );
+        (tmp, res)
+    }
+
+    /// Assert unpacking `entries` fails with `reason`, without writing
+    /// `escape` (or `outside`, next to `dst`)
+    fn assert_rejected(
+        entries: &[Entry],
+        limits: UnpackLimits,
+        reason: &str,
+        escape: Option<&Path>,
+    ) {
+        let (tmp, res) = unpack(entries, limits);
+        let e = res.expect_err("unpacked a malicious archive");
+        assert!(format!("{e:#}").contains(reason), "{e:#}");
+        for path in escape
+            .into_iter()
+            .chain([tmp.path().join("outside").as_path()])
+        {
+            assert!(
+                path.symlink_metadata().is_err(),
+                "{} was written",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn unpacks_archives() {
+        let (tmp, res) = unpack(
+            &[
+                file("flake.nix", b"{}"),
+                link(tar::EntryType::Symlink, "sub/up", ".."),
+                link(tar::EntryType::Link, "copy.nix", "flake.nix"),
+            ],
+            UnpackLimits::default(),
+        );
+        res.unwrap();
+        let dst = tmp.path().join("dst");
+        assert_eq!(fs::read(dst.join("copy.nix")).unwrap(), b"{}");
+        assert_eq!(fs::read(dst.join("sub/up/flake.nix")).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn rejects_parent_paths() {
+        for path in ["../outside", "sub/../../outside"] {
+            assert_rejected(
+                &[file(path, b"x")],
+                UnpackLimits::default(),
+                "Archive entry with invalid path",
+                None,
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("absolute");
+        assert_rejected(
+            &[file(path.to_str().unwrap(), b"x")],
+            UnpackLimits::default(),
+            "Archive entry with invalid path",
+            Some(&path),
+        );
+    }
+
+    #[test]
+    fn rejects_symlinks_outside() {
+        let tmp = tempfile::tempdir().unwrap();
+        for entries in [
+            [
+                link(tar::EntryType::Symlink, "etc", tmp.path().to_str().unwrap()),
+                file("etc/escaped", b"x"),
+            ],
+            [
+                link(tar::EntryType::Symlink, "sub/up", "../.."),
+                file("sub/up/outside", b"x"),
+            ],
+        ] {
+            assert_rejected(
+                &entries,
+                UnpackLimits::default(),
+                "Archive symlink pointing outside of the archive",
+                Some(&tmp.path().join("escaped")),
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_paths_through_symlinks() {
+        for (entries, reason, rejected) in [
+            (
+                vec![
+                    link(tar::EntryType::Symlink, "a/b", ".."),
+                    link(tar::EntryType::Symlink, "a/b/c", ".."),
+                ],
+                "Archive entry inside of a symlink",
+                "c",
+            ),
+            (
+                vec![
+                    link(tar::EntryType::Symlink, "a/b", ".."),
+                    link(tar::EntryType::Symlink, "./a/b/./c", ".."),
+                ],
+                "Archive entry inside of a symlink",
+                "c",
+            ),
+            (
+                vec![
+                    link(tar::EntryType::Symlink, "a/b", ".."),
+                    link(tar::EntryType::Symlink, "a/c", "b/.."),
+                ],
+                "Archive symlink pointing outside of the archive",
+                "a/c",
+            ),
+            (
+                vec![
+                    link(tar::EntryType::Symlink, "a/b", ".."),
+                    link(tar::EntryType::Link, "up", "a/b"),
+                ],
+                "Archive hard link pointing outside of the archive",
+                "up",
+            ),
+            (
+                vec![
+                    file("x/secret", b"x"),
+                    link(tar::EntryType::Symlink, "a/b", "../x"),
+                    link(tar::EntryType::Link, "copy", "a/b/secret"),
+                ],
+                "Archive hard link pointing outside of the archive",
+                "copy",
+            ),
+        ] {
+            let (tmp, res) = unpack(&entries, UnpackLimits::default());
+            let e = res.expect_err("unpacked a malicious archive");
+            assert!(format!("{e:#}").contains(reason), "{e:#}");
+            let rejected = tmp.path().join("dst").join(rejected);
+            assert!(
+                rejected.symlink_metadata().is_err(),
+                "{} was written",
+                rejected.display()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_hard_links_outside() {
+        let tmp = tempfile::tempdir().unwrap();
+        let secret = tmp.path().join("secret");
+        fs::write(&secret, b"secret").unwrap();
+        for target in [secret.to_str().unwrap(), "../outside"] {
+            assert_rejected(
+                &[link(tar::EntryType::Link, "secret", target)],
+                UnpackLimits::default(),
+                "Archive hard link pointing outside of the archive",
+                None,
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_special_files() {
+        let fifo = Entry {
+            entry_type: tar::EntryType::Fifo,
+            ..file("fifo", b"")
+        };
+        assert_rejected(
+            &[fifo],
+            UnpackLimits::default(),
+            "Archive entry of unsupported type",
+            None,
+        );
+    }
+
+    #[test]
+    fn rejects_archives_over_the_limits() {
+        let entries = [file("a", b"12345"), file("b", b"12345"), file("c", b"1")];
+        let limits = |max_entries, max_size_bytes| UnpackLimits {
+            max_entries,
+            max_size_bytes,
+        };
+        unpack(&entries, limits(3, 11)).1.unwrap();
+        assert_rejected(
+            &entries,
+            limits(2, 11),
+            "Archive contains more than 2 entries",
+            None,
+        );
+        assert_rejected(
+            &entries,
+            limits(3, 10),
+            "Archive unpacks to more than 10 bytes",
+            None,
+        );
+    }
+}
@@ -1,22 +1,116 @@
 #![doc = include_str!("../../README.md")]
+use std::collections::BTreeSet;
 use std::ffi::OsString;
 use std::io;
-use std::io::Write as _;
-use std::path::PathBuf;
+use std::io::{Read as _, Write as _};
+use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand, ValueEnum};
+use anyhow::Context as _;
+use clap::{CommandFactory as _, Parser, Subcommand, ValueEnum};
 use npcnix::data_dir::DataDir;
-use tracing::trace;
+use tracing::{debug, trace, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Layer};
 use url::Url;
 
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  Success
+  1  Any other failure
+  2  Invalid command line
+  3  Invalid or missing config
+  4  Remote unreachable
+  5  Verification failed (`verify`, `doctor`, invalid archive)
+  6  Activation failed
+  7  Nothing to do (`push --exit-code`, when the remote already has the
+     same content)
+  8  `follow --once cycle` activated a new configuration";
+
+/// See [`EXIT_CODES_HELP`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ExitCode {
+    Failure = 1,
+    // 2 is what clap exits with on usage errors
+    Config = 3,
+    RemoteUnavailable = 4,
+    VerificationFailed = 5,
+    ActivationFailed = 6,
+    NothingToDo = 7,
+    Changed = 8,
+}
+
+impl ExitCode {
+    fn of(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if let Some(exit) = cause.downcast_ref::<Exit>() {
+                return exit.code;
+            }
+            if let Some(e) = cause.downcast_ref::<npcnix::error::NpcnixError>() {
+                return Self::of_npcnix(e);
+            }
+        }
+        ExitCode::Failure
+    }
+
+    fn of_npcnix(e: &npcnix::error::NpcnixError) -> Self {
+        use npcnix::error::NpcnixError;
+        match e {
+            NpcnixError::ConfigInvalid(_) | NpcnixError::NotConfigured(_) => ExitCode::Config,
+            NpcnixError::RemoteUnavailable { .. } | NpcnixError::EtagFetch { .. } => {
+                ExitCode::RemoteUnavailable
+            }
+            NpcnixError::Unpack(_) => ExitCode::VerificationFailed,
+            NpcnixError::ActivationFailed { .. } => ExitCode::ActivationFailed,
+            NpcnixError::Cancelled | NpcnixError::Other(_) => ExitCode::Failure,
+        }
+    }
+}
+
+/// Failure exiting with `code`
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+struct Exit {
+    code: ExitCode,
+    message: String,
+}
+
+impl Exit {
+    fn error(code: ExitCode, message: impl Into<String>) -> anyhow::Error {
+        Self {
+            code,
+            message: message.into(),
+        }
+        .into()
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
+#[command(after_long_help = EXIT_CODES_HELP)]
 struct Opts {
     #[clap(flatten)]
     common: npcnix::opts::Common,
 
+    /// Format of the logs (by default from the config, or `text`)
+    #[arg(long, global = true, env = "NPCNIX_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Format of the command output (default: `text`)
+    #[arg(long, global = true, env = "NPCNIX_OUTPUT")]
+    output: Option<OutputFormat>,
+
+    /// Log more (`-vv`: even more)
+    ///
+    /// The logs can also be filtered with `NPCNIX_LOG` directives (like
+    /// `RUST_LOG`), which take the `remote`, `archive`, `activate` and
+    /// `daemon` subsystems as targets, e.g. `NPCNIX_LOG=remote=debug`
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log only warnings (`-qq`: only errors)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    quiet: u8,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -25,41 +119,291 @@ impl Opts {
     pub fn data_dir(&self) -> DataDir {
         self.common.data_dir()
     }
+
+    /// Print JSON, as requested by the command's `--json`, or `--output json`
+    fn json(&self, json: bool) -> bool {
+        json || self.output == Some(OutputFormat::Json)
+    }
+
+    /// Default level of the logs, from `-v`/`-q`
+    fn log_level(&self) -> &'static str {
+        match i16::from(self.verbose) - i16::from(self.quiet) {
+            ..=-2 => "error",
+            -1 => "warn",
+            0 => "info",
+            1 => "debug",
+            2.. => "trace",
+        }
+    }
+
+    fn log_format(&self) -> npcnix::config::LogFormat {
+        if let Some(log_format) = self.log_format {
+            return log_format.into();
+        }
+        // logging is not set up yet, so quietly fall back to the default
+        let data_dir = self.data_dir();
+        match data_dir.config_exist() {
+            Ok(true) => data_dir
+                .load_config()
+                .map(|config| config.log_format())
+                .unwrap_or_default(),
+            _ => Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable tables and messages
+    Text,
+    /// JSON, stable for scripting
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum NotifyEvent {
+    /// A new configuration was activated
+    Success,
+    /// Activating a new configuration failed
+    Failure,
+    /// The system was rolled back
+    Rollback,
+    /// An etag was quarantined after too many failed activations
+    Quarantine,
+}
+
+impl From<NotifyEvent> for npcnix::notify::NotifyEvent {
+    fn from(value: NotifyEvent) -> Self {
+        match value {
+            NotifyEvent::Success => npcnix::notify::NotifyEvent::Success,
+            NotifyEvent::Failure => npcnix::notify::NotifyEvent::Failure,
+            NotifyEvent::Rollback => npcnix::notify::NotifyEvent::Rollback,
+            NotifyEvent::Quarantine => npcnix::notify::NotifyEvent::Quarantine,
+        }
+    }
+}
+
+impl From<LogFormat> for npcnix::config::LogFormat {
+    fn from(value: LogFormat) -> Self {
+        match value {
+            LogFormat::Text => npcnix::config::LogFormat::Text,
+            LogFormat::Json => npcnix::config::LogFormat::Json,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// Configuration options
     Config {
+        /// Show where each setting comes from
+        #[arg(long)]
+        show_origin: bool,
+
+        /// Check that the config is valid
+        #[arg(long, conflicts_with = "show_origin")]
+        check: bool,
+
         #[command(subcommand)]
         command: Option<ConfigOpts>,
     },
-    /// Status
-    Status,
+    /// Initialize the config of this host
+    Init(InitOpts),
+    /// Show the state of npcnix on this host
+    Status(StatusOpts),
+    /// Diagnose the environment: required binaries, data dir permissions,
+    /// access to the remote, and the configuration in the archive
+    Doctor(DoctorOpts),
     /// Activate a NixOS configuration from a Nix Flake in a local directory
     Activate(ActivateOpts),
     /// Pack a Nix Flake in a local directory into a remote-like packed Nix
     /// Flake file
     Pack(PackOpts),
+    /// Unpack a packed Nix Flake file to a local directory
+    Unpack(UnpackOpts),
     /// Pull a packed Nix Flake from a remote and extra to a directory
     Pull(PullOpts),
     /// Pack a Nix Flake in a local directory into a packed Nix Flake file and
     /// upload to a remote
     Push(PushOpts),
+    /// Upload an archive referencing a pre-built NixOS system closure, to
+    /// activate it without evaluation
+    PushClosure(PushClosureOpts),
+    /// Point a content-addressed remote back at one of its previously pushed
+    /// archives (`by-hash/<sha256>.tar.zst`), e.g. to roll the hosts back
+    Repoint(RepointOpts),
+    /// Show build metadata of a packed Nix Flake (remote or local file)
+    Inspect(InspectOpts),
+    /// Print the current etag of the remote, without pulling it
+    GetEtag(HeadOpts),
+    /// Show the metadata of the remote object (size, etag, last
+    /// modification, version), without pulling it
+    Head(HeadOpts),
+    /// Show what pushing a local Nix Flake would change in the remote
+    Diff(DiffOpts),
+    /// Download and check the archive of the remote, without activating it
+    Verify(VerifyOpts),
+    /// List the archives under a prefix-style remote (ending with `/`)
+    List(ListOpts),
+    /// Delete the old archives of timestamped pushes under a prefix-style
+    /// remote
+    Prune(PruneOpts),
+    /// List the configurations in a Nix Flake (the remote, or a local
+    /// directory)
+    ListConfigurations(ListConfigurationsOpts),
     /// Install npcnix on the machine
     Install(InstallOpts),
     /// Run as a daemon periodically activating NixOS configuration from the
     /// remote
+    #[command(visible_alias = "daemon")]
     Follow(FollowOpts),
     /// Permanently or temporarily pause the npcnix daemon
     Pause(PauseOpts),
     /// Unpause the npcnix daemon
     Unpause,
+    /// Control the running npcnix daemon through its control socket
+    Ctl(CtlOpts),
+    /// Roll the system back to a previous generation
+    Rollback(RollbackOpts),
+    /// Allow the daemon to activate an etag held after a rollback again
+    Unhold,
+    /// Let the daemon retry an etag quarantined after too many failed
+    /// activations
+    Unquarantine,
+    /// Show the history of the daemon cycles
+    History(HistoryOpts),
+    /// Show the statuses reported by the hosts of the fleet
+    FleetStatus(FleetStatusOpts),
+    /// Wait until the hosts of the fleet report an etag (e.g. in CI, after
+    /// pushing)
+    Wait(WaitOpts),
+    /// Copy the archive of one release channel to another (e.g. `canary` to
+    /// `stable`)
+    Promote(PromoteOpts),
+    /// Approve the current etag of the remote, for the hosts requiring
+    /// approval
+    Approve(ApproveOpts),
+    /// Generate the shell completions
+    Completions(CompletionsOpts),
+    /// Generate the man pages
+    Man(ManOpts),
+    /// Generate the deployment of the daemon, for hosts not using the NixOS
+    /// module of the flake
+    Generate {
+        #[command(subcommand)]
+        command: GenerateOpts,
+    },
+    /// List the known configuration names, for the shell completions
+    #[command(hide = true)]
+    CompleteConfigurations {
+        /// The command line being completed
+        #[arg(last = true)]
+        words: Vec<String>,
+    },
+    /// Helpers for CI environments
+    Ci {
+        #[command(subcommand)]
+        command: CiOpts,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CiOpts {
+    /// Check, build, sign, cache, push and promote a Nix Flake in one go
+    Publish(CiPublishOpts),
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct CiPublishOpts {
+    #[command(flatten)]
+    pack: PackCommonOpts,
+
+    #[command(flatten)]
+    push: PushCommonOpts,
+
+    /// Remote to push to
+    #[arg(long)]
+    remote: Url,
+
+    /// Region to use for the remote access (typically s3 bucket)
+    #[arg(long, env = "AWS_REGION")]
+    remote_region: Option<String>,
+
+    /// Assume this AWS role using the GitHub Actions OIDC web identity
+    #[arg(long, env = "NPCNIX_CI_ROLE_ARN")]
+    role_arn: Option<String>,
+
+    /// Session name to use when assuming the role
+    #[arg(long, default_value = "npcnix-ci")]
+    role_session_name: String,
+
+    /// Audience to request the OIDC token for
+    #[arg(long, default_value = "sts.amazonaws.com")]
+    oidc_audience: String,
+
+    /// Run `nix flake check` before publishing
+    #[arg(long)]
+    check: bool,
+
+    /// Build this configuration before publishing (can be specified
+    /// multiple times)
+    #[arg(long)]
+    build: Vec<String>,
+
+    /// Sign built closures with this Nix signing key (requires `--cache`)
+    #[arg(long, env = "NPCNIX_CI_SIGN_KEY_FILE")]
+    sign_key_file: Option<PathBuf>,
+
+    /// Copy built closures to this binary cache (`nix copy --to`, e.g.
+    /// `s3://bucket?region=eu-west-1`), for the hosts to substitute them
+    #[arg(long, env = "NPCNIX_CI_CACHE")]
+    cache: Option<String>,
+
+    /// After publishing, copy the archive to this remote (e.g. a channel)
+    #[arg(long)]
+    promote_to: Option<Url>,
+
+    /// Print the result as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+impl From<CiPublishOpts> for npcnix::ci::PublishOpts {
+    fn from(value: CiPublishOpts) -> Self {
+        npcnix::ci::PublishOpts {
+            src: value.pack.src,
+            include: value.pack.include.into_iter().collect(),
+            remote: value.remote,
+            remote_region: value.remote_region,
+            role_arn: value.role_arn,
+            role_session_name: value.role_session_name,
+            oidc_audience: value.oidc_audience,
+            check: value.check,
+            build: value.build,
+            sign_key_file: value.sign_key_file,
+            cache: value.cache,
+            promote_to: value.promote_to,
+            push: value.push.to_push_opts(&Default::default()),
+        }
+    }
 }
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum ConfigOpts {
-    Show,
+    Show {
+        /// Don't redact the sensitive settings (e.g. webhook URLs)
+        #[arg(long)]
+        show_secrets: bool,
+    },
     /// Change daemon settings
     Set {
         /// Only update if not already set
@@ -69,17 +413,102 @@ pub enum ConfigOpts {
         #[command(subcommand)]
         value: SetOpts,
     },
+    /// Remove a daemon setting (back to its default)
+    Unset { setting: UnsetSetting },
+    /// Manage the named profiles of settings
+    #[command(subcommand)]
+    Profile(ProfileOpts),
+    /// Manage the secrets the settings can refer to as `secret:<NAME>`
+    #[command(subcommand)]
+    Secret(SecretOpts),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum SecretOpts {
+    List,
+    /// Store a secret, read from stdin
+    Set {
+        name: String,
+    },
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ProfileOpts {
+    List,
+    /// Add an empty profile, to fill with `npcnix --profile <NAME> config set`
+    Add {
+        name: String,
+    },
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone)]
+pub enum UnsetSetting {
+    Remote,
+    RemoteRegion,
+    Configuration,
+    Channel,
+    DecryptIdentity,
+    FlakeAttr,
+    BuildHost,
+    ActivationTimeout,
+    ActivationWindows,
+    QuietHours,
+    Webhooks,
+    MetricsTextfile,
+    StatusReportPrefix,
+    Approval,
+    Coordination,
 }
 
 #[derive(Parser, Debug, Clone)]
 pub struct PullOpts {
     /// Override the remote from config
-    #[arg(long)]
+    #[arg(long, env = "NPCNIX_REMOTE")]
     remote: Option<Url>,
 
     #[arg(long)]
-    /// Destination directory
+    /// Destination directory (`-` to write the packed archive to stdout)
     dst: PathBuf,
+
+    /// Override the `age` identity file used to decrypt the archive
+    #[arg(long, env = "NPCNIX_DECRYPT_IDENTITY")]
+    decrypt_identity: Option<PathBuf>,
+
+    /// Keep the previous content of the destination directory in
+    /// `<dst>.bak`
+    #[arg(long)]
+    backup: bool,
+}
+
+impl PullOpts {
+    fn backup_path(&self) -> Option<PathBuf> {
+        self.backup.then(|| {
+            let mut path = self.dst.clone().into_os_string();
+            path.push(".bak");
+            PathBuf::from(path)
+        })
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct InspectOpts {
+    /// Override the remote from config (ignored with `--archive`)
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
+
+    /// Inspect a local packed Nix Flake file instead of a remote
+    #[arg(long)]
+    archive: Option<PathBuf>,
+
+    /// Override the `age` identity file used to decrypt the archive
+    #[arg(long, env = "NPCNIX_DECRYPT_IDENTITY")]
+    decrypt_identity: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -90,197 +519,2094 @@ pub struct PauseOpts {
 
     #[arg(long, group("duration"))]
     minutes: Option<u64>,
+
+    /// Pause until this time (RFC 3339, e.g. `2024-01-01T00:00:00Z`)
+    #[arg(long, group("duration"))]
+    until: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Why the daemon is paused, shown in `status`
+    #[arg(long)]
+    reason: Option<String>,
+}
+
+impl PauseOpts {
+    /// End of the pause, `None` if indefinite
+    fn until(&self) -> anyhow::Result<Option<chrono::DateTime<chrono::Utc>>> {
+        if let Some(until) = self.until {
+            return Ok(Some(until));
+        }
+        let secs = match (self.minutes, self.hours) {
+            (Some(minutes), _) => minutes.saturating_mul(60),
+            (None, Some(hours)) => hours.saturating_mul(60 * 60),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(
+            chrono::Utc::now() + chrono::Duration::seconds(TryFrom::try_from(secs)?),
+        ))
+    }
 }
 
 #[derive(Parser, Debug, Clone)]
-pub struct ActivateCommonOpts {
+pub struct CtlOpts {
+    /// Control socket of the daemon
+    #[arg(long, env = "NPCNIX_CONTROL_SOCKET", default_value_os_t = npcnix::control::default_socket_path())]
+    socket: PathBuf,
+
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CtlCommand {
+    /// Show the daemon status
+    Status,
+    /// Check the remote right away
+    Trigger,
+    /// Pause the daemon
+    Pause(PauseOpts),
+    /// Unpause the daemon and check the remote right away
+    Resume,
+    /// Let the daemon activate the update staged in staged mode
+    Approve,
+    /// Re-activate the remote right away, even if unchanged
+    Force,
+    /// Retry activating the quarantined etag right away
+    Unquarantine,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct StatusOpts {
+    /// Check if the daemon is running on this control socket
+    #[arg(long, env = "NPCNIX_CONTROL_SOCKET", default_value_os_t = npcnix::control::default_socket_path())]
+    socket: PathBuf,
+
+    /// Don't check if the daemon is running
     #[arg(long)]
-    extra_substituters: Vec<String>,
+    no_daemon_check: bool,
 
+    /// Print the status as JSON
     #[arg(long)]
-    extra_trusted_public_keys: Vec<String>,
+    json: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
-pub struct ActivateOpts {
-    #[arg(long, default_value = ".")]
+pub struct DiffOpts {
     /// Source directory
+    #[arg(long, default_value = ".")]
     src: PathBuf,
 
+    /// Include this subdirectory (can be specified multiple times; default:
+    /// all)
     #[arg(long)]
-    /// Configuration to apply
-    configuration: Option<String>,
+    include: Vec<OsString>,
 
-    #[command(flatten)]
-    activate: ActivateCommonOpts,
-}
+    /// Override the remote from config
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
 
-#[derive(Parser, Debug, Clone)]
-pub struct InstallOpts {
+    /// Show the changes of the contents too
     #[arg(long)]
-    /// Remote to use for the host
-    remote: Url,
+    content: bool,
 
+    /// Fail if there are differences
     #[arg(long)]
-    /// Region to use for the remote access (typically s3 bucket)
-    remote_region: Option<String>,
+    exit_code: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct VerifyOpts {
+    /// Override the remote from config
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
+
+    /// Configuration that has to be in the flake (default: from the config)
+    #[arg(long, env = "NPCNIX_CONFIGURATION")]
+    configuration: Option<String>,
 
+    /// Also evaluate the system of the configuration
     #[arg(long)]
-    /// Configuration to use for the host
-    configuration: String,
+    evaluate: bool,
+
+    /// Override the `age` identity file from config
+    #[arg(long, env = "NPCNIX_DECRYPT_IDENTITY")]
+    decrypt_identity: Option<PathBuf>,
 
+    /// Print the report as JSON
     #[arg(long)]
-    /// Configuration to activate (as an intermediate step)
-    initial_configuration: Option<String>,
+    json: bool,
+}
 
-    #[command(flatten)]
-    activate: ActivateCommonOpts,
+#[derive(Parser, Debug, Clone)]
+pub struct HeadOpts {
+    /// Remote to check (default: the remote from the config)
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
+
+    /// Print as JSON
+    #[arg(long)]
+    json: bool,
 }
 
-impl From<ActivateCommonOpts> for npcnix::ActivateOpts {
-    fn from(value: ActivateCommonOpts) -> Self {
-        npcnix::ActivateOpts {
-            extra_substituters: value.extra_substituters,
-            extra_trusted_public_keys: value.extra_trusted_public_keys,
-        }
-    }
+#[derive(Parser, Debug, Clone)]
+pub struct ListOpts {
+    /// Prefix to list (default: the remote from the config)
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
+
+    /// Print the archives as JSON
+    #[arg(long)]
+    json: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
-pub struct PackCommonOpts {
-    /// Source directory
+#[command(group(clap::ArgGroup::new("retention").required(true).multiple(true)))]
+pub struct PruneOpts {
+    /// Prefix to prune (required, to prevent accidental deletion)
     #[arg(long)]
-    src: PathBuf,
+    remote: Url,
 
-    /// Include this subdirectory (can be specified multiple times; default:
-    /// all)
+    /// Keep at least this many of the most recent archives
+    #[arg(long, group = "retention")]
+    keep: Option<usize>,
+
+    /// Delete the archives pushed longer ago than this (e.g. `30d`)
+    #[arg(long, group = "retention", value_parser = npcnix::misc::parse_duration)]
+    older_than: Option<std::time::Duration>,
+
+    /// Only show what would be deleted
     #[arg(long)]
-    include: Vec<OsString>,
+    dry_run: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
-pub struct PushOpts {
-    #[command(flatten)]
-    pack: PackCommonOpts,
+pub struct ListConfigurationsOpts {
+    /// Local Nix Flake directory to list (default: pull the remote)
+    #[arg(long)]
+    src: Option<PathBuf>,
+
+    /// Override the remote from config (ignored with `--src`)
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
 
-    /// To prevent accidental push, remote is required
+    /// Backend whose configurations to list (default: from the config)
     #[arg(long)]
-    remote: Url,
+    backend: Option<ActivationBackend>,
 }
 
 #[derive(Parser, Debug, Clone)]
-pub struct PackOpts {
-    #[command(flatten)]
-    pack: PackCommonOpts,
+pub struct CompletionsOpts {
+    shell: clap_complete::Shell,
 
-    /// Destination file
+    /// Write the script to this directory (named as the shell expects it),
+    /// instead of stdout
     #[arg(long)]
-    dst: PathBuf,
+    dir: Option<PathBuf>,
 }
 
-#[derive(Subcommand, Debug, Clone)]
-pub enum SetOpts {
-    Remote { url: Url },
-    Configuration { configuration: String },
+#[derive(Parser, Debug, Clone)]
+pub struct ManOpts {
+    /// Write the pages of the command and of all the subcommands (e.g.
+    /// `npcnix-pull.1`) to this directory, instead of only the one of the
+    /// command to stdout
+    #[arg(long)]
+    dir: Option<PathBuf>,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
-pub enum Once {
-    /// Finish on any success
-    #[default]
-    Any,
-    /// Finish on first activation of a new config
-    Activate,
+#[derive(Subcommand, Debug, Clone)]
+pub enum GenerateOpts {
+    /// A hardened systemd service unit running `npcnix follow`
+    SystemdUnit(GenerateUnitOpts),
+    /// A NixOS module running the daemon, with the current config as its base
+    /// settings
+    NixosModule(GenerateModuleOpts),
 }
 
-impl From<Once> for npcnix::Once {
-    fn from(value: Once) -> Self {
-        match value {
-            Once::Any => npcnix::Once::Any,
-            Once::Activate => npcnix::Once::Activate,
-        }
-    }
+#[derive(Parser, Debug, Clone)]
+pub struct GenerateUnitOpts {
+    /// Path of the npcnix binary to run (default: this one)
+    #[arg(long)]
+    npcnix: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug, Clone)]
-pub struct FollowOpts {
-    #[command(flatten)]
-    activate: ActivateCommonOpts,
+pub struct GenerateModuleOpts {
+    /// Store path of the npcnix package to run (default: the one of this
+    /// binary, if it's in the Nix store)
+    #[arg(long)]
+    package: Option<PathBuf>,
+}
 
-    /// Stop after first success
+#[derive(Parser, Debug, Clone)]
+pub struct DoctorOpts {
+    /// Don't check the remote and the flake in it
     #[arg(long)]
-    once: Option<Option<Once>>,
+    offline: bool,
 
-    /// Ignore etag and assume configuration changed
+    /// Print the report as JSON
     #[arg(long)]
-    ignore_etag: bool,
+    json: bool,
 }
 
-impl FollowOpts {
-    fn once(&self) -> Option<npcnix::Once> {
-        match self.once {
-            Some(Some(o)) => Some(o.into()),
-            Some(None) => Some(npcnix::Once::Any),
-            None => None,
-        }
-    }
-}
-pub fn tracing_init() -> anyhow::Result<()> {
-    let filter_layer = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .with_writer(io::stderr)
-        .with_filter(filter_layer);
+#[derive(Parser, Debug, Clone)]
+pub struct HistoryOpts {
+    /// Number of the most recent cycles to show
+    #[arg(short = 'n', long, default_value = "20")]
+    count: usize,
 
-    tracing_subscriber::registry().with(fmt_layer).init();
-    Ok(())
-}
+    /// Only show cycles that activated something, or failed
+    #[arg(long)]
+    changes: bool,
+
+    /// Print the entries as JSON lines
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct FleetStatusOpts {
+    /// Prefix the hosts report their status under (default: the one from
+    /// the config)
+    #[arg(long, env = "NPCNIX_STATUS_REPORT_PREFIX")]
+    prefix: Option<Url>,
+
+    /// Print the statuses as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct InitOpts {
+    /// Remote to use for the host (prompted for if not set otherwise)
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
+
+    /// Region to use for the remote access (typically s3 bucket)
+    #[arg(long, env = "NPCNIX_REMOTE_REGION")]
+    remote_region: Option<String>,
+
+    /// Configuration to use for the host (default: derived from the
+    /// hostname)
+    #[arg(long, env = "NPCNIX_CONFIGURATION")]
+    configuration: Option<String>,
+
+    /// Read the settings from a JSON or flat TOML document in the user data
+    /// (default: the copy kept by cloud-init)
+    #[arg(long, value_name = "PATH")]
+    from_user_data: Option<Option<PathBuf>>,
+
+    /// Read the settings from the EC2 instance tags (`npcnix:remote`,
+    /// `npcnix:configuration`, ...)
+    #[arg(long)]
+    from_imds: bool,
+
+    /// Never prompt for missing settings
+    #[arg(long, env = "NPCNIX_NON_INTERACTIVE", value_parser = clap::builder::BoolishValueParser::new())]
+    non_interactive: bool,
+
+    /// Pull and activate the configuration right away
+    #[arg(long)]
+    activate: bool,
+
+    #[command(flatten)]
+    activate_opts: ActivateCommonOpts,
+}
+
+/// Ask for a value on the terminal, `None` if the answer is empty
+fn prompt(question: &str, default: Option<&str>) -> anyhow::Result<Option<String>> {
+    let mut stderr = io::stderr();
+    match default {
+        Some(default) => write!(stderr, "{question} [{default}]: ")?,
+        None => write!(stderr, "{question}: ")?,
+    }
+    stderr.flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.map(ToOwned::to_owned)
+    } else {
+        Some(answer.to_owned())
+    })
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct WaitOpts {
+    /// Etag to wait for (default: the current one of the remote)
+    #[arg(long)]
+    etag: Option<String>,
+
+    /// Override the remote from config, to get the etag from
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
+
+    /// Number of hosts, or comma-separated list of hostnames, that need to
+    /// report the etag
+    #[arg(long)]
+    hosts: npcnix::report::RequiredHosts,
+
+    /// Fail after this long (e.g. `90s`, `15m`, `2h`)
+    #[arg(long, default_value = "30m", value_parser = npcnix::misc::parse_duration)]
+    timeout: std::time::Duration,
+
+    /// How often to check the statuses
+    #[arg(long, default_value = "15s", value_parser = npcnix::misc::parse_duration)]
+    interval: std::time::Duration,
+
+    /// Prefix the hosts report their status under (default: the one from
+    /// the config)
+    #[arg(long, env = "NPCNIX_STATUS_REPORT_PREFIX")]
+    prefix: Option<Url>,
+
+    /// Print the result as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct RepointOpts {
+    /// Content-addressed remote to repoint (required, to prevent accidents)
+    #[arg(long)]
+    remote: Url,
+
+    /// Hash of the archive to point to, as in its `by-hash` key
+    #[arg(long)]
+    sha256: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PromoteOpts {
+    /// Remote, without the channel (default: the one from the config)
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
+
+    /// Channel to promote
+    #[arg(long)]
+    from: String,
+
+    /// Channel to promote to
+    #[arg(long)]
+    to: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ApproveOpts {
+    /// Override the remote from config
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    remote: Option<Url>,
+
+    /// Etag to approve (default: the current one)
+    #[arg(long)]
+    etag: Option<String>,
+
+    /// Sign the approval with this SSH private key
+    #[arg(long, env = "NPCNIX_APPROVAL_SIGN_KEY")]
+    sign_key: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct RollbackOpts {
+    /// Generation to roll back to (default: the one activated by npcnix
+    /// before the current one)
+    #[arg(long)]
+    to: Option<u64>,
+
+    /// Let the daemon re-activate the current remote etag
+    #[arg(long)]
+    no_hold: bool,
+
+    /// List the generations activated by npcnix instead
+    #[arg(long)]
+    list: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ActivateCommonOpts {
+    #[arg(long, env = "NPCNIX_EXTRA_SUBSTITUTERS", value_delimiter = ',')]
+    extra_substituters: Vec<String>,
+
+    #[arg(long, env = "NPCNIX_EXTRA_TRUSTED_PUBLIC_KEYS", value_delimiter = ',')]
+    extra_trusted_public_keys: Vec<String>,
+
+    /// Override the activation mode from config
+    #[arg(long, env = "NPCNIX_ACTIVATION_MODE")]
+    mode: Option<ActivationMode>,
+
+    /// Override the activation backend from config
+    #[arg(long, env = "NPCNIX_ACTIVATION_BACKEND")]
+    backend: Option<ActivationBackend>,
+
+    /// Configuration (`[<flake>#]<name>`) or full attribute path (e.g.
+    /// `.#nixosConfigurations.{configuration}.config.specialisation.<name>.configuration`)
+    /// to activate, instead of `.#<configuration>` (`{configuration}` is
+    /// substituted)
+    #[arg(long, env = "NPCNIX_FLAKE_ATTR")]
+    flake_attr: Option<String>,
+
+    /// Build the configuration on this host (`nixos-rebuild --build-host`)
+    #[arg(long, env = "NPCNIX_BUILD_HOST")]
+    build_host: Option<String>,
+
+    /// Use `sudo` on the build host
+    #[arg(long, env = "NPCNIX_USE_REMOTE_SUDO", value_parser = clap::builder::BoolishValueParser::new())]
+    use_remote_sudo: bool,
+
+    /// Build the system closure first, then switch to it
+    #[arg(long, env = "NPCNIX_TWO_PHASE", value_parser = clap::builder::BoolishValueParser::new())]
+    two_phase: bool,
+
+    /// Kill the activation if it takes longer than this many seconds
+    #[arg(long, env = "NPCNIX_ACTIVATION_TIMEOUT_SECS")]
+    timeout_secs: Option<u64>,
+
+    /// Append the activation output to this file, instead of a new file in
+    /// the data dir logs
+    #[arg(long, env = "NPCNIX_ACTIVATION_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// How to gain root privileges when not running as root
+    #[arg(long, env = "NPCNIX_ESCALATION")]
+    escalation: Option<Escalation>,
+
+    /// Pass this argument to the rebuild command verbatim (can be specified
+    /// multiple times)
+    #[arg(long = "rebuild-arg", allow_hyphen_values = true)]
+    rebuild_args: Vec<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ActivateOpts {
+    #[arg(long, default_value = ".")]
+    /// Source directory
+    src: PathBuf,
+
+    #[arg(long, env = "NPCNIX_CONFIGURATION")]
+    /// Configuration to apply
+    configuration: Option<String>,
+
+    /// Only build the configuration and show the closure changes compared to
+    /// the current system
+    #[arg(long)]
+    diff_only: bool,
+
+    /// Activate this pre-built system closure instead of evaluating the flake
+    #[arg(long, conflicts_with = "diff_only")]
+    store_path: Option<PathBuf>,
+
+    /// Activate the update downloaded by the daemon in staged mode
+    #[arg(long, conflicts_with_all = ["diff_only", "store_path", "src", "configuration"])]
+    staged: bool,
+
+    #[command(flatten)]
+    activate: ActivateCommonOpts,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct InstallOpts {
+    #[arg(long, env = "NPCNIX_REMOTE")]
+    /// Remote to use for the host
+    remote: Url,
+
+    #[arg(long, env = "NPCNIX_REMOTE_REGION")]
+    /// Region to use for the remote access (typically s3 bucket)
+    remote_region: Option<String>,
+
+    #[arg(long, env = "NPCNIX_CONFIGURATION")]
+    /// Configuration to use for the host (default: derived from the
+    /// hostname)
+    configuration: Option<String>,
+
+    #[arg(long, env = "NPCNIX_INITIAL_CONFIGURATION")]
+    /// Configuration to activate (as an intermediate step)
+    initial_configuration: Option<String>,
+
+    #[command(flatten)]
+    activate: ActivateCommonOpts,
+}
+
+impl From<ActivateCommonOpts> for npcnix::ActivateOpts {
+    fn from(value: ActivateCommonOpts) -> Self {
+        npcnix::ActivateOpts {
+            extra_substituters: value.extra_substituters,
+            extra_trusted_public_keys: value.extra_trusted_public_keys,
+            mode: value.mode.map(Into::into),
+            backend: value.backend.map(Into::into),
+            build_host: value.build_host,
+            use_remote_sudo: value.use_remote_sudo,
+            two_phase: value.two_phase,
+            timeout: value.timeout_secs.map(std::time::Duration::from_secs),
+            log_file: value.log_file,
+            escalation: value.escalation.map(Into::into),
+            store_path: None,
+            enforce_activation_windows: false,
+            flake_attr: value.flake_attr,
+            extra_nixos_rebuild_args: value.rebuild_args,
+            cancel: None,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PackCommonOpts {
+    /// Source directory (`-` to read an already packed archive from stdin,
+    /// where supported)
+    #[arg(long)]
+    src: PathBuf,
+
+    /// Include this subdirectory (can be specified multiple times; default:
+    /// all)
+    #[arg(long)]
+    include: Vec<OsString>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PushOpts {
+    #[command(flatten)]
+    pack: PackCommonOpts,
+
+    #[command(flatten)]
+    push: PushCommonOpts,
+
+    /// To prevent accidental push, remote is required (unless using a
+    /// `--profile`)
+    #[arg(long)]
+    remote: Option<Url>,
+
+    /// Push to this release channel of the remote
+    #[arg(long)]
+    channel: Option<String>,
+
+    /// Only show what would be pushed, and what it would change in the
+    /// remote
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Don't ask for confirmation (only asked on a terminal, and not with
+    /// `--output json`)
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Exit with 7 if the remote already has the same content
+    #[arg(long)]
+    exit_code: bool,
+}
+
+impl PushOpts {
+    fn remote(&self, data_dir: &DataDir) -> anyhow::Result<Url> {
+        let remote = match self.remote {
+            Some(ref remote) => remote.clone(),
+            None if self.channel.is_none() => return profile_remote(data_dir),
+            None => {
+                profile_remote(data_dir)?;
+                data_dir.load_config()?.expanded_remote()?
+            }
+        };
+        match self.channel {
+            Some(ref channel) => npcnix::channel::channel_url(&remote, channel),
+            None => Ok(remote),
+        }
+    }
+}
+
+/// The remote of the selected profile, for the commands requiring an explicit
+/// remote
+fn profile_remote(data_dir: &DataDir) -> anyhow::Result<Url> {
+    if data_dir.profile().is_none() {
+        anyhow::bail!("`--remote` is required (unless using a `--profile`)");
+    }
+    Ok(data_dir.load_config()?.effective_remote()?)
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PushClosureOpts {
+    /// Store path of the NixOS system (`config.system.build.toplevel`)
+    #[arg(long)]
+    store_path: PathBuf,
+
+    #[command(flatten)]
+    push: PushCommonOpts,
+
+    /// To prevent accidental push, remote is required (unless using a
+    /// `--profile`)
+    #[arg(long)]
+    remote: Option<Url>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PushCommonOpts {
+    /// Encrypt the archive to this `age` recipient (can be specified
+    /// multiple times)
+    #[arg(long, env = "NPCNIX_ENCRYPT_RECIPIENT", value_delimiter = ',')]
+    encrypt_recipient: Vec<String>,
+
+    /// Upload to `<prefix>/by-hash/<sha256>.tar.zst` and make the remote a
+    /// pointer to it
+    #[arg(long, env = "NPCNIX_CONTENT_ADDRESSED", value_parser = clap::builder::BoolishValueParser::new())]
+    content_addressed: bool,
+
+    /// Upload to `<prefix>/<timestamp>-<git rev>.tar.zst` and make
+    /// `<prefix>/latest` a pointer to it (the remote must end with `/`)
+    #[arg(long, env = "NPCNIX_TIMESTAMPED", value_parser = clap::builder::BoolishValueParser::new(), conflicts_with = "content_addressed")]
+    timestamped: bool,
+
+    /// Push even if the git tree has uncommitted changes
+    #[arg(long)]
+    allow_dirty: bool,
+
+    /// Push even if `flake.lock` is not committed
+    #[arg(long)]
+    allow_uncommitted_flake_lock: bool,
+
+    /// Push even if some files look like secrets (private keys, `.env`
+    /// files, AWS credentials)
+    #[arg(long)]
+    allow_secrets: bool,
+
+    /// Run `nix flake check` before pushing
+    #[arg(long)]
+    flake_check: bool,
+
+    /// Evaluate the system of this configuration before pushing (can be
+    /// specified multiple times)
+    #[arg(long, value_name = "CONFIGURATION")]
+    evaluate: Vec<String>,
+
+    /// Push even if the remote already has the same content
+    #[arg(long)]
+    force: bool,
+
+    /// Override the multipart upload part size (in MiB)
+    #[arg(long, env = "NPCNIX_MULTIPART_PART_SIZE_MB")]
+    multipart_part_size_mb: Option<u64>,
+
+    /// Override the number of parts uploaded concurrently
+    #[arg(long, env = "NPCNIX_MULTIPART_PARALLELISM")]
+    multipart_parallelism: Option<usize>,
+}
+
+impl PushCommonOpts {
+    fn to_push_opts(&self, config: &npcnix::config::Config) -> npcnix::PushOpts {
+        let multipart = config.multipart_upload();
+        npcnix::PushOpts {
+            encrypt_recipients: self.encrypt_recipient.clone(),
+            content_addressed: self.content_addressed,
+            timestamped: self.timestamped,
+            checks: {
+                let checks = config.push_checks();
+                npcnix::push_checks::PushChecks {
+                    allow_dirty: self.allow_dirty || checks.allow_dirty,
+                    require_flake_lock: !self.allow_uncommitted_flake_lock
+                        && checks.require_flake_lock,
+                    allow_secrets: self.allow_secrets || checks.allow_secrets,
+                    flake_check: self.flake_check || checks.flake_check,
+                    evaluate: checks
+                        .evaluate
+                        .iter()
+                        .chain(&self.evaluate)
+                        .cloned()
+                        .collect(),
+                }
+            },
+            force: self.force,
+            retry: config.transfer_retry(),
+            multipart: npcnix::s3::MultipartOpts {
+                part_size_bytes: self
+                    .multipart_part_size_mb
+                    .map(|mb| mb.saturating_mul(1024 * 1024))
+                    .unwrap_or(multipart.part_size_bytes),
+                parallelism: self.multipart_parallelism.unwrap_or(multipart.parallelism),
+                ..multipart
+            },
+            progress: None,
+            cancel: None,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PackOpts {
+    #[command(flatten)]
+    pack: PackCommonOpts,
+
+    /// Leave out this path, relative to the source directory (can be
+    /// specified multiple times)
+    #[arg(long, value_name = "PATH")]
+    exclude: Vec<PathBuf>,
+
+    /// Pack the files in a stable order, with fixed modification times and
+    /// no build timestamp, so the same source always packs to the same
+    /// archive
+    #[arg(long)]
+    deterministic: bool,
+
+    /// `zstd` compression level (default: the `zstd` default)
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1..=22))]
+    compression_level: Option<i32>,
+
+    /// Destination file
+    #[arg(long)]
+    dst: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct UnpackOpts {
+    /// Packed Nix Flake file to unpack
+    #[arg(long)]
+    archive: PathBuf,
+
+    /// Destination directory
+    #[arg(long)]
+    dst: PathBuf,
+
+    /// Override the `age` identity file used to decrypt the archive
+    #[arg(long, env = "NPCNIX_DECRYPT_IDENTITY")]
+    decrypt_identity: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum SetOpts {
+    /// Remote to follow, with optional `{hostname}`, `{configuration}` and
+    /// `{env:VAR}` placeholders
+    Remote {
+        url: Url,
+    },
+    /// Without a configuration set, use `<prefix><hostname><suffix>`
+    HostnameConfiguration {
+        #[arg(long, default_value = "", allow_hyphen_values = true)]
+        prefix: String,
+
+        #[arg(long, default_value = "", allow_hyphen_values = true)]
+        suffix: String,
+    },
+    /// Follow this release channel of the remote (none: the remote itself)
+    Channel {
+        channel: Option<String>,
+    },
+    Configuration {
+        configuration: String,
+    },
+    /// `age` identity file to decrypt encrypted archives with
+    DecryptIdentity {
+        path: PathBuf,
+    },
+    /// What to do with the built configuration
+    ActivationMode {
+        mode: ActivationMode,
+    },
+    /// Tool used to build and activate the configuration
+    ActivationBackend {
+        backend: ActivationBackend,
+    },
+    /// Configuration (`[<flake>#]<name>`) or full attribute path to
+    /// activate, instead of `.#<configuration>`
+    FlakeAttr {
+        flake_attr: String,
+    },
+    /// Host to delegate building to (`nixos-rebuild --build-host`)
+    BuildHost {
+        host: String,
+    },
+    /// Use `sudo` on the build host
+    UseRemoteSudo {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+    },
+    /// Build the system closure first, then switch to it
+    TwoPhaseActivation {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+    },
+    /// Check the remote right after the daemon starts, instead of sleeping
+    /// first
+    CheckOnStart {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+    },
+    /// Shortest time between the daemon checks, right after a change
+    MinSleep {
+        secs: u64,
+    },
+    /// Longest time between the daemon checks
+    MaxSleep {
+        secs: u64,
+    },
+    /// Grow the time between the daemon checks from the min to the max over
+    /// this many hours since the last change
+    MaxSleepAfter {
+        hours: u64,
+    },
+    /// Delay the first check of the daemon by a random time up to this many
+    /// seconds (`0` to disable)
+    BootSplay {
+        secs: u64,
+    },
+    /// Delay activating a new remote etag by `hash(hostname) % secs`, to roll
+    /// out every push gradually over the fleet (`0` to disable)
+    RolloutSplay {
+        secs: u64,
+    },
+    /// Re-activate the last remote when the running system is not the one
+    /// activated by npcnix (e.g. after a manual switch)
+    Reconverge {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+    },
+    /// Only let the daemon download new remotes, activating them needs an
+    /// approval (`npcnix activate --staged` or `npcnix ctl approve`)
+    Staged {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+    },
+    /// Follow the settings overrides published as `settings.json` next to
+    /// the remote
+    RemoteSettings {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+    },
+    /// Kill the activation if it takes longer than this many seconds (`0`
+    /// to disable)
+    ActivationTimeout {
+        secs: u64,
+    },
+    /// Number of activation logs to keep in the data dir
+    ActivationLogsKeep {
+        count: usize,
+    },
+    /// Number of daemon cycles to keep in the history
+    HistoryKeep {
+        count: usize,
+    },
+    /// Garbage collection after successful activations
+    Gc {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+
+        /// Number of the newest system generations to keep
+        #[arg(long, default_value = "10")]
+        keep_generations: u32,
+
+        /// Only collect garbage if the Nix store has less free space than
+        /// this many MiB
+        #[arg(long)]
+        min_free_mb: Option<u64>,
+    },
+    /// Run `nix flake check` on the source before activating it
+    FlakeCheck {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+
+        /// Extra arguments of `nix flake check` (e.g. `--no-build`)
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        args: Vec<String>,
+    },
+    /// How the daemon retries failed activations
+    FailureBackoff {
+        /// Delay before the first retry, doubled on every next one
+        #[arg(long, default_value = "60")]
+        initial_backoff_secs: u64,
+
+        #[arg(long, default_value = "3600")]
+        max_backoff_secs: u64,
+
+        /// Quarantine an etag after this many failed activations
+        #[arg(long, default_value = "5")]
+        max_retries_per_etag: u32,
+    },
+    /// How to gain root privileges when not running as root
+    Escalation {
+        escalation: Option<Escalation>,
+    },
+    /// Publish metrics of every daemon cycle to CloudWatch
+    Cloudwatch {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
 
-fn main() -> anyhow::Result<()> {
-    tracing_init()?;
+        #[arg(long, default_value = "npcnix")]
+        namespace: String,
+
+        /// Region to publish to (by default the remote region)
+        #[arg(long)]
+        region: Option<String>,
+    },
+    /// Format of the logs
+    LogFormat {
+        format: LogFormat,
+    },
+    /// Webhooks to `POST` activation outcomes to, with `{hostname}`,
+    /// `{configuration}` and `{event}` placeholders (replaces existing ones;
+    /// none: disable; `secret:<NAME>`: a URL stored with `config secret set`
+    /// or as a systemd credential)
+    Webhooks {
+        urls: Vec<String>,
+
+        /// Event to notify about (can be specified multiple times; by
+        /// default all)
+        #[arg(long)]
+        event: Vec<NotifyEvent>,
+    },
+    /// Write Prometheus metrics to this file after every daemon cycle (for the
+    /// node exporter textfile collector; none: disable)
+    MetricsTextfile {
+        path: Option<PathBuf>,
+    },
+    /// Upload the host status to `<prefix>/<hostname>.json` after every daemon
+    /// cycle (none: disable)
+    StatusReportPrefix {
+        prefix: Option<Url>,
+    },
+    /// Only let the daemon switch configurations inside these windows, e.g.
+    /// `Sat..Sun 02:00-05:00` (UTC; none: any time)
+    ActivationWindows {
+        windows: Vec<npcnix::schedule::ActivationWindow>,
+    },
+    /// Never let the daemon switch configurations inside these windows, e.g.
+    /// `Mon..Fri 08:00-18:00` (UTC; none: disable)
+    QuietHours {
+        windows: Vec<npcnix::schedule::ActivationWindow>,
+    },
+    /// Only let the daemon activate remote etags approved with `npcnix
+    /// approve`
+    Approval {
+        #[arg(action = clap::ArgAction::Set)]
+        enable: bool,
+
+        /// Require approvals signed by a key from this `ssh-keygen` allowed
+        /// signers file
+        #[arg(long)]
+        allowed_signers: Option<PathBuf>,
+
+        /// Principal the approvals are signed as
+        #[arg(long, default_value = "npcnix")]
+        principal: String,
+    },
+    /// Limit the number of hosts activating at once, using slot objects
+    /// under this prefix shared by the hosts (none: disable)
+    Coordination {
+        prefix: Option<Url>,
+
+        /// Maximum number of hosts activating at once
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+        max_concurrent: u32,
+
+        /// Free the slots of hosts that didn't release them after this long
+        #[arg(long, default_value = "3600")]
+        lease_secs: u64,
+    },
+    /// Checks to run after activation (replaces existing ones)
+    HealthCheck {
+        /// Shell command that needs to succeed (can be specified multiple
+        /// times)
+        #[arg(long)]
+        command: Vec<String>,
+
+        /// URL that needs to respond with a 2xx status (can be specified
+        /// multiple times)
+        #[arg(long)]
+        http: Vec<Url>,
+
+        /// Timeout of every single check
+        #[arg(long, default_value = "60")]
+        timeout_secs: u64,
+
+        /// Don't roll back to the previous system when a check fails
+        #[arg(long)]
+        no_rollback: bool,
+    },
+    /// Arguments passed to `nixos-rebuild` verbatim (replaces existing ones)
+    ExtraNixosRebuildArgs {
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        args: Vec<String>,
+    },
+    /// Program to run instead of `nixos-rebuild`, with `{src}`,
+    /// `{configuration}`, `{flake_ref}` and `{mode}` placeholders (empty to
+    /// use `nixos-rebuild`)
+    ActivationCommand {
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ActivationMode {
+    /// Build, activate and make the boot default
+    Switch,
+    /// Build and make the boot default, but don't activate
+    Boot,
+    /// Build and activate, but don't make the boot default
+    Test,
+    /// Build and show what would be changed by the activation
+    DryActivate,
+}
+
+impl From<ActivationMode> for npcnix::ActivationMode {
+    fn from(value: ActivationMode) -> Self {
+        match value {
+            ActivationMode::Switch => npcnix::ActivationMode::Switch,
+            ActivationMode::Boot => npcnix::ActivationMode::Boot,
+            ActivationMode::Test => npcnix::ActivationMode::Test,
+            ActivationMode::DryActivate => npcnix::ActivationMode::DryActivate,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ActivationBackend {
+    /// `darwin-rebuild` on macOS, `nixos-rebuild` otherwise
+    Auto,
+    /// `nixos-rebuild`, activating `nixosConfigurations`
+    NixosRebuild,
+    /// `home-manager`, activating `homeConfigurations` of the current user
+    HomeManager,
+    /// `darwin-rebuild`, activating `darwinConfigurations`
+    DarwinRebuild,
+}
+
+impl From<ActivationBackend> for npcnix::ActivationBackend {
+    fn from(value: ActivationBackend) -> Self {
+        match value {
+            ActivationBackend::Auto => npcnix::ActivationBackend::Auto,
+            ActivationBackend::NixosRebuild => npcnix::ActivationBackend::NixosRebuild,
+            ActivationBackend::HomeManager => npcnix::ActivationBackend::HomeManager,
+            ActivationBackend::DarwinRebuild => npcnix::ActivationBackend::DarwinRebuild,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Escalation {
+    Sudo,
+    Doas,
+}
+
+impl From<Escalation> for npcnix::Escalation {
+    fn from(value: Escalation) -> Self {
+        match value {
+            Escalation::Sudo => npcnix::Escalation::Sudo,
+            Escalation::Doas => npcnix::Escalation::Doas,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
+pub enum Once {
+    /// Finish on any success
+    #[default]
+    Any,
+    /// Finish on first activation of a new config
+    Activate,
+    /// Finish after a single check/pull/activate cycle, exiting with 0 if
+    /// nothing changed, 8 if a new config was activated, 4 if the remote
+    /// is unreachable, 6 if the activation failed
+    Cycle,
+}
+
+impl From<Once> for npcnix::Once {
+    fn from(value: Once) -> Self {
+        match value {
+            Once::Any => npcnix::Once::Any,
+            Once::Activate => npcnix::Once::Activate,
+            Once::Cycle => npcnix::Once::Cycle,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct FollowOpts {
+    #[command(flatten)]
+    activate: ActivateCommonOpts,
+
+    /// Stop after first success
+    #[arg(long)]
+    once: Option<Option<Once>>,
+
+    /// Ignore etag and assume configuration changed
+    #[arg(long)]
+    ignore_etag: bool,
+
+    /// Re-activate the remote on the next cycle only, even if unchanged
+    #[arg(long)]
+    force_next: bool,
+
+    /// Listen for `npcnix ctl` commands on this socket
+    #[arg(long, env = "NPCNIX_CONTROL_SOCKET", default_value_os_t = npcnix::control::default_socket_path())]
+    control_socket: PathBuf,
+
+    /// Don't listen for `npcnix ctl` commands
+    #[arg(long, env = "NPCNIX_NO_CONTROL_SOCKET", value_parser = clap::builder::BoolishValueParser::new())]
+    no_control_socket: bool,
+
+    /// If no remote is set yet, initialize the config from the EC2 instance
+    /// tags (`npcnix:remote`, `npcnix:configuration`, ...)
+    #[arg(long, env = "NPCNIX_BOOTSTRAP_FROM_IMDS", value_parser = clap::builder::BoolishValueParser::new())]
+    bootstrap_from_imds: bool,
+}
+
+impl FollowOpts {
+    fn bootstrap(&self, data_dir: &DataDir) -> anyhow::Result<()> {
+        if self.bootstrap_from_imds {
+            npcnix::bootstrap::bootstrap_from_imds(data_dir)?;
+        }
+        Ok(())
+    }
+
+    fn request_force_next(&self, data_dir: &DataDir) -> anyhow::Result<()> {
+        if self.force_next {
+            data_dir.update_config(|config| Ok(config.with_force_next(true)))?;
+        }
+        Ok(())
+    }
+
+    fn once(&self) -> Option<npcnix::Once> {
+        match self.once {
+            Some(Some(o)) => Some(o.into()),
+            Some(None) => Some(npcnix::Once::Any),
+            None => None,
+        }
+    }
+}
+/// Modules logging for each subsystem usable as a target in `NPCNIX_LOG`
+const LOG_SUBSYSTEMS: &[(&str, &[&str])] = &[
+    (
+        "remote",
+        &[
+            "s3",
+            "pointer",
+            "versions",
+            "channel",
+            "remote_settings",
+            "retry",
+        ],
+    ),
+    ("archive", &["archive", "age", "meta", "diff"]),
+    (
+        "activate",
+        &["activation", "closure", "health", "hooks", "gc", "systemd"],
+    ),
+    (
+        "daemon",
+        &[
+            "engine",
+            "schedule",
+            "control",
+            "coordination",
+            "notify",
+            "report",
+        ],
+    ),
+];
+
+/// Replace the subsystem targets of the `NPCNIX_LOG` `directives` with the
+/// modules logging for them
+fn expand_log_directives(directives: &str) -> String {
+    let mut expanded = vec![];
+    for directive in directives.split(',').map(str::trim) {
+        let (target, level) = match directive.split_once('=') {
+            Some((target, level)) => (target, Some(level)),
+            None => (directive, None),
+        };
+        match LOG_SUBSYSTEMS.iter().find(|(name, _)| *name == target) {
+            Some((_, modules)) => {
+                for module in *modules {
+                    expanded.push(match level {
+                        Some(level) => format!("npcnix::{module}={level}"),
+                        None => format!("npcnix::{module}"),
+                    });
+                }
+            }
+            None => expanded.push(directive.to_owned()),
+        }
+    }
+    expanded.join(",")
+}
+
+/// Log to stderr at `level`, unless overridden by `NPCNIX_LOG` (or
+/// `RUST_LOG`)
+pub fn tracing_init(log_format: npcnix::config::LogFormat, level: &str) -> anyhow::Result<()> {
+    let directives = match std::env::var("NPCNIX_LOG") {
+        Ok(directives) => Some(expand_log_directives(&directives)),
+        Err(_) => std::env::var("RUST_LOG").ok(),
+    };
+    let filter_layer = match directives {
+        // the later directives win
+        Some(directives) => EnvFilter::try_new(format!("{level},{directives}"))
+            .with_context(|| format!("Invalid log filter: {directives}"))?,
+        None => EnvFilter::new(level),
+    };
+    let fmt_layer = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+    let fmt_layer = match log_format {
+        npcnix::config::LogFormat::Text => fmt_layer.boxed(),
+        npcnix::config::LogFormat::Json => fmt_layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(filter_layer))
+        .init();
+    Ok(())
+}
+
+/// Logs the daemon events (at debug level, the engine itself logs the
+/// important ones already)
+struct LogEvents;
+
+impl npcnix::engine::DaemonEvents for LogEvents {
+    fn on_check(&self, remote: &Url, etag: &str) {
+        debug!(event = "check", %remote, etag, "Checked the remote");
+    }
+
+    fn on_change_detected(&self, configuration: &str, etag: &str) {
+        debug!(
+            event = "change_detected",
+            configuration, etag, "Remote changed"
+        );
+    }
+
+    fn on_activation_start(&self, configuration: &str, etag: &str) {
+        debug!(
+            event = "activation_start",
+            configuration, etag, "Activating"
+        );
+    }
+
+    fn on_activation_finish(
+        &self,
+        configuration: &str,
+        etag: &str,
+        result: Result<&npcnix::ActivationResult, &anyhow::Error>,
+    ) {
+        debug!(
+            event = "activation_finish",
+            configuration,
+            etag,
+            success = result.is_ok(),
+            duration_secs = result.ok().map(|res| res.duration.as_secs_f64()),
+            system = result
+                .ok()
+                .and_then(|res| res.system.as_deref())
+                .map(|path| path.display().to_string()),
+            "Activation finished"
+        );
+    }
+
+    fn on_error(&self, error: &anyhow::Error) {
+        debug!(
+            event = "error",
+            error = format!("{error:#}"),
+            "Cycle failed"
+        );
+    }
+}
+
+/// A progress bar for the transfers, when attached to a terminal
+fn transfer_progress(message: &'static str) -> Option<npcnix::progress::ProgressFn> {
+    use std::io::IsTerminal as _;
+
+    if !io::stderr().is_terminal() {
+        return None;
+    }
+    let bar = indicatif::ProgressBar::hidden();
+    bar.set_style(
+        indicatif::ProgressStyle::with_template(
+            "{msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+        )
+        .expect("valid template")
+        .progress_chars("=> "),
+    );
+    bar.set_message(message);
+    bar.set_draw_target(indicatif::ProgressDrawTarget::stderr());
+    Some(npcnix::progress::ProgressFn::new(move |progress| {
+        if progress.transferred == 0 {
+            // (re)started, e.g. the archive after its pointer, or a retry
+            bar.reset();
+        }
+        match progress.total {
+            Some(total) => bar.set_length(total),
+            None => bar.unset_length(),
+        }
+        bar.set_position(progress.transferred);
+        if Some(progress.transferred) == progress.total {
+            bar.finish_and_clear();
+        }
+    }))
+}
+
+/// Exit with the conventional `128 + signal` exit code, if `npcnix::follow`
+/// was cancelled by a signal
+fn exit_if_signalled(cancel_signal: Option<i32>) {
+    if let Some(sig) = cancel_signal {
+        std::process::exit(128 + sig);
+    }
+}
+
+fn main() {
+    if let Err(e) = run(Opts::parse()) {
+        let _ = writeln!(io::stderr(), "Error: {e:?}");
+        std::process::exit(ExitCode::of(&e) as i32);
+    }
+}
+
+fn run(opts: Opts) -> anyhow::Result<()> {
+    tracing_init(opts.log_format(), opts.log_level())?;
     trace!("Staring npcnix");
-    let opts = Opts::parse();
 
     match opts.command {
-        Command::Pull(ref pull_opts) => npcnix::pull(
-            &opts
+        Command::Pull(ref pull_opts) => {
+            let remote = opts
                 .data_dir()
-                .get_current_remote_with_opt_override(pull_opts.remote.as_ref())?,
-            &pull_opts.dst,
-        )?,
-        Command::Push(ref push_opts) => npcnix::push(
-            &push_opts.pack.src,
-            &push_opts.clone().pack.include.into_iter().collect(),
-            &push_opts.remote,
-        )?,
-        Command::Pack(ref pack_opts) => npcnix::pack(
-            &pack_opts.pack.src,
-            &pack_opts.clone().pack.include.into_iter().collect(),
-            &pack_opts.dst,
-        )?,
-        Command::Config { ref command } => match command {
-            Some(ConfigOpts::Show) | None => {
-                let _ = write!(std::io::stdout(), "{}\n", opts.data_dir().load_config()?);
+                .get_current_remote_with_opt_override(pull_opts.remote.as_ref())?;
+            let lib_pull_opts = npcnix::PullOpts {
+                decrypt_identity: opts
+                    .data_dir()
+                    .get_current_decrypt_identity_with_opt_override(
+                        pull_opts.decrypt_identity.as_deref(),
+                    )?,
+                unpack_limits: opts.data_dir().load_config()?.unpack_limits(),
+                retry: opts.data_dir().load_config()?.transfer_retry(),
+                progress: transfer_progress("Downloading"),
+                cancel: None,
+            };
+            if pull_opts.dst.as_os_str() == "-" {
+                // stdout is the archive
+                npcnix::pull_raw(&remote, io::stdout().lock(), &lib_pull_opts)?;
+            } else {
+                let res = npcnix::pull_atomic(
+                    &remote,
+                    &pull_opts.dst,
+                    &lib_pull_opts,
+                    pull_opts.backup_path().as_deref(),
+                )?;
+                if opts.json(false) {
+                    print_json(&serde_json::json!({
+                        "remote": remote,
+                        "dst": pull_opts.dst,
+                        "bytes": res.bytes,
+                        "etag": res.etag,
+                    }))?;
+                }
             }
-            Some(ConfigOpts::Set { init, ref value }) => match value {
-                SetOpts::Remote { ref url } => opts.data_dir().store_config(
-                    &opts
-                        .data_dir()
-                        .load_config()?
-                        .with_remote_maybe_init(url, *init),
-                )?,
-                SetOpts::Configuration { ref configuration } => opts.data_dir().store_config(
+        }
+        Command::Push(ref push_opts) => {
+            use std::io::IsTerminal as _;
+
+            let config = opts.data_dir().load_config()?;
+            let lib_push_opts = npcnix::PushOpts {
+                progress: transfer_progress("Uploading"),
+                ..push_opts.push.to_push_opts(&config)
+            };
+            let remote = push_opts.remote(&opts.data_dir())?;
+            if push_opts.pack.src.as_os_str() == "-" {
+                if push_opts.dry_run {
+                    anyhow::bail!("Can't dry-run pushing an already packed archive");
+                }
+                let res = npcnix::push_raw(io::stdin().lock(), &remote, &lib_push_opts)?;
+                if opts.json(false) {
+                    print_push_result(&remote, &res)?;
+                }
+            } else {
+                let include = push_opts.pack.include.iter().cloned().collect();
+                let confirm = !push_opts.yes && !opts.json(false) && io::stdin().is_terminal();
+                if push_opts.dry_run || confirm {
+                    let plan = npcnix::plan_push(
+                        &push_opts.pack.src,
+                        &include,
+                        &remote,
+                        &lib_push_opts,
+                        &(&config).into(),
+                    )?;
+                    if opts.json(false) {
+                        print_json(&plan)?;
+                    } else {
+                        print_push_plan(&plan);
+                    }
+                    if !push_opts.dry_run
+                        && !prompt(&format!("Push to {}? [y/N]", plan.target), None)?
+                            .is_some_and(|answer| matches!(answer.as_str(), "y" | "Y" | "yes"))
+                    {
+                        anyhow::bail!("Push cancelled");
+                    }
+                }
+                if !push_opts.dry_run {
+                    let res = npcnix::push(&push_opts.pack.src, &include, &remote, &lib_push_opts)?;
+                    if opts.json(false) {
+                        print_push_result(&remote, &res)?;
+                    } else if res.unchanged {
+                        let _ = writeln!(
+                            io::stdout(),
+                            "{remote} already has this content (etag {}), nothing pushed",
+                            res.etag
+                        );
+                    }
+                    if res.unchanged && push_opts.exit_code {
+                        std::process::exit(ExitCode::NothingToDo as i32);
+                    }
+                }
+            }
+        }
+        Command::PushClosure(ref push_opts) => {
+            let remote = match push_opts.remote {
+                Some(ref remote) => remote.clone(),
+                None => profile_remote(&opts.data_dir())?,
+            };
+            let res = npcnix::push_closure(
+                &push_opts.store_path,
+                &remote,
+                &npcnix::PushOpts {
+                    progress: transfer_progress("Uploading"),
+                    ..push_opts.push.to_push_opts(&opts.data_dir().load_config()?)
+                },
+            )?;
+            if opts.json(false) {
+                print_push_result(&remote, &res)?;
+            }
+        }
+        Command::Inspect(ref inspect_opts) => {
+            let pull_opts = npcnix::PullOpts {
+                decrypt_identity: opts
+                    .data_dir()
+                    .get_current_decrypt_identity_with_opt_override(
+                        inspect_opts.decrypt_identity.as_deref(),
+                    )?,
+                unpack_limits: opts.data_dir().load_config()?.unpack_limits(),
+                retry: opts.data_dir().load_config()?.transfer_retry(),
+                progress: None,
+                cancel: None,
+            };
+            let meta = if let Some(ref archive) = inspect_opts.archive {
+                npcnix::inspect_archive(archive, &pull_opts)?
+            } else {
+                npcnix::inspect_remote(
                     &opts
                         .data_dir()
-                        .load_config()?
-                        .with_configuration_maybe_init(configuration, *init),
-                )?,
+                        .get_current_remote_with_opt_override(inspect_opts.remote.as_ref())?,
+                    &pull_opts,
+                )?
+            };
+            match meta {
+                Some(meta) => {
+                    let _ = writeln!(
+                        std::io::stdout(),
+                        "{}",
+                        serde_json::to_string_pretty(&meta)?
+                    );
+                }
+                None => anyhow::bail!("Archive does not contain any build metadata"),
+            }
+        }
+        Command::Pack(ref pack_opts) => {
+            let src = &pack_opts.pack.src;
+            let meta = npcnix::meta::ArchiveMeta::collect(src);
+            npcnix::pack_with(
+                src,
+                &npcnix::PackOptions {
+                    include: pack_opts.pack.include.iter().cloned().collect(),
+                    exclude: pack_opts.exclude.iter().cloned().collect(),
+                    compression_level: pack_opts.compression_level.unwrap_or(0),
+                    deterministic: pack_opts.deterministic,
+                    meta: Some(if pack_opts.deterministic {
+                        meta.without_volatile_fields()
+                    } else {
+                        meta
+                    }),
+                },
+                &pack_opts.dst,
+            )?;
+        }
+        Command::Unpack(ref unpack_opts) => {
+            let config = opts.data_dir().load_config()?;
+            let pull_opts = npcnix::PullOpts {
+                decrypt_identity: opts
+                    .data_dir()
+                    .get_current_decrypt_identity_with_opt_override(
+                        unpack_opts.decrypt_identity.as_deref(),
+                    )?,
+                unpack_limits: config.unpack_limits(),
+                retry: config.transfer_retry(),
+                progress: None,
+                cancel: None,
+            };
+            npcnix::unpack(&unpack_opts.archive, &unpack_opts.dst, &pull_opts)?;
+        }
+        Command::Config {
+            show_origin,
+            check,
+            ref command,
+        } => match command {
+            Some(ConfigOpts::Show { .. }) | None if check => {
+                opts.data_dir().load_config_unchecked()?.validate()?;
+                if opts.json(false) {
+                    print_json(&serde_json::json!({ "valid": true }))?;
+                } else {
+                    let _ = writeln!(std::io::stdout(), "Config is valid");
+                }
+            }
+            Some(ConfigOpts::Show { .. }) | None if show_origin => {
+                print_setting_origins(&opts.data_dir(), opts.json(false))?;
+            }
+            Some(ConfigOpts::Show { show_secrets: true }) => {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    opts.data_dir().load_config()?.to_string_with_secrets()
+                );
+            }
+            Some(ConfigOpts::Show { .. }) | None => {
+                let _ = writeln!(std::io::stdout(), "{}", opts.data_dir().load_config()?);
+            }
+            Some(ConfigOpts::Secret(ref secret_opts)) => {
+                let secrets_file = opts.data_dir().secrets_file_path();
+                match secret_opts {
+                    SecretOpts::List => {
+                        let names = npcnix::secrets::names(&secrets_file)?;
+                        if opts.json(false) {
+                            print_json(&names)?;
+                        } else {
+                            let mut stdout = std::io::stdout().lock();
+                            for name in names {
+                                writeln!(stdout, "{name}")?;
+                            }
+                        }
+                    }
+                    SecretOpts::Set { ref name } => {
+                        let mut value = String::new();
+                        io::stdin().read_to_string(&mut value)?;
+                        let value = value.trim_end_matches('\n');
+                        if value.is_empty() {
+                            anyhow::bail!("No secret given on stdin");
+                        }
+                        npcnix::secrets::store(&secrets_file, name, Some(value))?;
+                    }
+                    SecretOpts::Remove { ref name } => {
+                        npcnix::secrets::store(&secrets_file, name, None)?
+                    }
+                }
+            }
+            Some(ConfigOpts::Profile(ref profile_opts)) => {
+                // the profiles themselves are top-level settings
+                let data_dir = opts.data_dir().with_profile(None);
+                match profile_opts {
+                    ProfileOpts::List => {
+                        let config = data_dir.load_config()?;
+                        if opts.json(false) {
+                            print_json(&config.profile_names().collect::<Vec<_>>())?;
+                        } else {
+                            let mut stdout = std::io::stdout().lock();
+                            for name in config.profile_names() {
+                                writeln!(stdout, "{name}")?;
+                            }
+                        }
+                    }
+                    ProfileOpts::Add { ref name } => {
+                        data_dir.update_config(|config| Ok(config.with_profile(name)))?
+                    }
+                    ProfileOpts::Remove { ref name } => data_dir.update_config(|config| {
+                        if !config.profile_names().any(|profile| profile == name) {
+                            anyhow::bail!("Profile `{name}` not found");
+                        }
+                        Ok(config.without_profile(name))
+                    })?,
+                }
+            }
+            Some(ConfigOpts::Unset { setting }) => {
+                opts.data_dir().update_config(|config| {
+                    Ok(match setting {
+                        UnsetSetting::Remote => config.without_remote(),
+                        UnsetSetting::RemoteRegion => config.with_remote_region(None),
+                        UnsetSetting::Configuration => config.without_configuration(),
+                        UnsetSetting::Channel => config.with_channel(None),
+                        UnsetSetting::DecryptIdentity => config.with_decrypt_identity(None),
+                        UnsetSetting::FlakeAttr => config.with_flake_attr(None),
+                        UnsetSetting::BuildHost => config.with_build_host(None),
+                        UnsetSetting::ActivationTimeout => {
+                            config.with_activation_timeout_secs(None)
+                        }
+                        UnsetSetting::ActivationWindows => config.with_activation_windows(vec![]),
+                        UnsetSetting::QuietHours => config.with_quiet_hours(vec![]),
+                        UnsetSetting::Webhooks => config.with_webhooks(vec![]),
+                        UnsetSetting::MetricsTextfile => config.with_metrics_textfile(None),
+                        UnsetSetting::StatusReportPrefix => config.with_status_report_prefix(None),
+                        UnsetSetting::Approval => config.with_approval(None),
+                        UnsetSetting::Coordination => config.with_coordination(None),
+                    })
+                })?;
+            }
+            Some(ConfigOpts::Set { init, ref value }) => match value {
+                SetOpts::Remote { ref url } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_remote_maybe_init(url, *init)))?,
+                SetOpts::HostnameConfiguration {
+                    ref prefix,
+                    ref suffix,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(
+                        config.with_hostname_configuration(npcnix::config::HostnameConfiguration {
+                            prefix: prefix.clone(),
+                            suffix: suffix.clone(),
+                        }),
+                    )
+                })?,
+                SetOpts::Channel { ref channel } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_channel(channel.as_deref())))?,
+                SetOpts::Configuration { ref configuration } => {
+                    opts.data_dir().update_config(|config| {
+                        Ok(config.with_configuration_maybe_init(configuration, *init))
+                    })?
+                }
+                SetOpts::DecryptIdentity { ref path } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_decrypt_identity(Some(path))))?,
+                SetOpts::ActivationMode { mode } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_activation_mode((*mode).into())))?,
+                SetOpts::ActivationBackend { backend } => {
+                    opts.data_dir().update_config(|config| {
+                        Ok(config.with_activation_backend((*backend).into()))
+                    })?
+                }
+                SetOpts::FlakeAttr { ref flake_attr } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_flake_attr(Some(flake_attr))))?,
+                SetOpts::BuildHost { ref host } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_build_host(Some(host))))?,
+                SetOpts::UseRemoteSudo { enable } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_use_remote_sudo(*enable)))?,
+                SetOpts::TwoPhaseActivation { enable } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_two_phase_activation(*enable)))?,
+                SetOpts::MinSleep { secs } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_min_sleep_secs(*secs)?))?,
+                SetOpts::MaxSleep { secs } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_max_sleep_secs(*secs)?))?,
+                SetOpts::MaxSleepAfter { hours } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_max_sleep_after_hours(*hours)))?,
+                SetOpts::CheckOnStart { enable } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_check_on_start(*enable)))?,
+                SetOpts::BootSplay { secs } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_boot_splay_secs(*secs)))?,
+                SetOpts::RolloutSplay { secs } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_rollout_splay_secs(*secs)))?,
+                SetOpts::Reconverge { enable } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_reconverge(*enable)))?,
+                SetOpts::Staged { enable } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_staged(*enable)))?,
+                SetOpts::RemoteSettings { enable } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_remote_settings(*enable)))?,
+                SetOpts::ActivationTimeout { secs } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_activation_timeout_secs(Some(*secs).filter(|secs| *secs != 0)))
+                })?,
+                SetOpts::ActivationLogsKeep { count } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_activation_logs_keep(*count)))?,
+                SetOpts::HistoryKeep { count } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_history_keep(*count)))?,
+                SetOpts::Gc {
+                    enable,
+                    keep_generations,
+                    min_free_mb,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_gc(npcnix::gc::GcOpts {
+                        enabled: *enable,
+                        keep_generations: *keep_generations,
+                        min_free_bytes: min_free_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+                    }))
+                })?,
+                SetOpts::FlakeCheck { enable, ref args } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_flake_check(*enable, args.clone())))?,
+                SetOpts::FailureBackoff {
+                    initial_backoff_secs,
+                    max_backoff_secs,
+                    max_retries_per_etag,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(
+                        config.with_failure_backoff(npcnix::retry::FailureBackoffOpts {
+                            initial_backoff_secs: *initial_backoff_secs,
+                            max_backoff_secs: *max_backoff_secs,
+                            max_retries_per_etag: *max_retries_per_etag,
+                        }),
+                    )
+                })?,
+                SetOpts::Escalation { escalation } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_escalation(escalation.map(Into::into)))
+                })?,
+                SetOpts::Cloudwatch {
+                    enable,
+                    ref namespace,
+                    ref region,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_cloudwatch(npcnix::config::CloudWatchOpts {
+                        enabled: *enable,
+                        namespace: namespace.clone(),
+                        region: region.clone(),
+                    }))
+                })?,
+                SetOpts::Webhooks {
+                    ref urls,
+                    ref event,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_webhooks(
+                        urls.iter()
+                            .map(|url| {
+                                npcnix::notify::WebhookOpts::new(
+                                    url,
+                                    event.iter().copied().map(Into::into).collect(),
+                                )
+                            })
+                            .collect(),
+                    ))
+                })?,
+                SetOpts::LogFormat { format } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_log_format((*format).into())))?,
+                SetOpts::MetricsTextfile { path } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_metrics_textfile(path.clone())))?,
+                SetOpts::StatusReportPrefix { prefix } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_status_report_prefix(prefix.clone())))?,
+                SetOpts::QuietHours { windows } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_quiet_hours(windows.clone())))?,
+                SetOpts::ActivationWindows { windows } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_activation_windows(windows.clone())))?,
+                SetOpts::Approval {
+                    enable,
+                    ref allowed_signers,
+                    ref principal,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(
+                        config.with_approval(enable.then(|| npcnix::approval::ApprovalOpts {
+                            allowed_signers: allowed_signers.clone(),
+                            principal: principal.clone(),
+                        })),
+                    )
+                })?,
+                SetOpts::Coordination {
+                    ref prefix,
+                    max_concurrent,
+                    lease_secs,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_coordination(prefix.clone().map(|prefix| {
+                        npcnix::coordination::CoordinationOpts {
+                            prefix,
+                            max_concurrent: *max_concurrent,
+                            lease_secs: *lease_secs,
+                        }
+                    })))
+                })?,
+                SetOpts::HealthCheck {
+                    ref command,
+                    ref http,
+                    timeout_secs,
+                    no_rollback,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_health_check(npcnix::health::HealthCheckOpts {
+                        commands: command.clone(),
+                        http: http.clone(),
+                        timeout_secs: *timeout_secs,
+                        rollback: !no_rollback,
+                    }))
+                })?,
+                SetOpts::ExtraNixosRebuildArgs { ref args } => {
+                    opts.data_dir().update_config(|config| {
+                        Ok(config.with_extra_nixos_rebuild_args(args.clone()))
+                    })?
+                }
+                SetOpts::ActivationCommand { ref command } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_activation_command(command.clone())))?,
             },
         },
-        Command::Status => {
-            let status_string = opts.data_dir().load_config()?.status_string();
-            let _ = write!(std::io::stdout(), "{}\n", status_string);
+        Command::Status(ref status_opts) => {
+            let status = npcnix::status::Status::collect(
+                &opts.data_dir(),
+                (!status_opts.no_daemon_check).then_some(status_opts.socket.as_path()),
+            )?;
+            if opts.json(status_opts.json) {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    serde_json::to_string_pretty(&status)?
+                );
+            } else {
+                print_status(&status);
+            }
+        }
+        Command::Completions(ref completions_opts) => {
+            let script = completion_script(completions_opts.shell);
+            match completions_opts.dir {
+                Some(ref dir) => {
+                    let path = dir.join(clap_complete::Generator::file_name(
+                        &completions_opts.shell,
+                        "npcnix",
+                    ));
+                    std::fs::write(&path, script)
+                        .with_context(|| format!("Failed to write {}", path.display()))?;
+                }
+                None => {
+                    let _ = std::io::stdout().write_all(&script);
+                }
+            }
+        }
+        Command::Man(ref man_opts) => match man_opts.dir {
+            Some(ref dir) => write_man_pages(&Opts::command(), "npcnix", dir)?,
+            None => {
+                let _ = clap_mangen::Man::new(Opts::command()).render(&mut std::io::stdout());
+            }
+        },
+        Command::Generate { ref command } => {
+            let data_dir = opts.data_dir();
+            let config = data_dir.load_config()?;
+            // the unit runs from `/`
+            let data_dir_path = std::path::absolute(data_dir.path())?;
+            let generated = match command {
+                GenerateOpts::SystemdUnit(unit_opts) => {
+                    let npcnix = match unit_opts.npcnix {
+                        Some(ref npcnix) => npcnix.clone(),
+                        None => std::env::current_exe()?,
+                    };
+                    npcnix::generate::systemd_unit(&npcnix, &data_dir_path, &config)
+                }
+                GenerateOpts::NixosModule(module_opts) => {
+                    let package = match module_opts.package {
+                        Some(ref package) => Some(package.clone()),
+                        // `<package>/bin/npcnix`
+                        None => std::env::current_exe()?
+                            .ancestors()
+                            .nth(2)
+                            .filter(|package| package.starts_with("/nix/store"))
+                            .map(Path::to_owned),
+                    };
+                    npcnix::generate::nixos_module(package.as_deref(), &data_dir_path, &config)?
+                }
+            };
+            let _ = write!(std::io::stdout(), "{generated}");
+        }
+        Command::Diff(ref diff_opts) => {
+            let data_dir = opts.data_dir();
+            let remote =
+                data_dir.get_current_remote_with_opt_override(diff_opts.remote.as_ref())?;
+            let diff = npcnix::diff::Diff::compute(
+                &diff_opts.src,
+                &diff_opts.include.iter().cloned().collect(),
+                &remote,
+                &(&data_dir.load_config()?).into(),
+            )?;
+            if opts.json(false) {
+                let changes = diff
+                    .changes
+                    .iter()
+                    .map(|change| {
+                        let mut value = serde_json::to_value(change)?;
+                        if diff_opts.content {
+                            value["content"] = diff.content_diff(change)?.into();
+                        }
+                        Ok(value)
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                print_json(&serde_json::json!({
+                    "remote": remote,
+                    "etag": diff.etag,
+                    "changes": changes,
+                }))?;
+            } else {
+                print_diff(&diff, &remote, diff_opts.content)?;
+            }
+            if diff_opts.exit_code && !diff.is_empty() {
+                anyhow::bail!("{} files differ from {remote}", diff.changes.len());
+            }
+        }
+        Command::ListConfigurations(ref list_opts) => {
+            let data_dir = opts.data_dir();
+            let config = data_dir.load_config()?;
+            let backend = npcnix::activation::effective_backend(
+                &npcnix::ActivateOpts {
+                    backend: list_opts.backend.map(Into::into),
+                    ..Default::default()
+                },
+                &config,
+            );
+            let configurations = match list_opts.src {
+                Some(ref src) => npcnix::configurations::list(src, backend)?,
+                None => {
+                    let remote =
+                        data_dir.get_current_remote_with_opt_override(list_opts.remote.as_ref())?;
+                    fetch_configurations(&data_dir, &remote, backend, &config)?.configurations
+                }
+            };
+            if opts.json(false) {
+                print_json(&configurations)?;
+            } else {
+                let mut stdout = std::io::stdout().lock();
+                for configuration in configurations {
+                    let _ = writeln!(stdout, "{configuration}");
+                }
+            }
+        }
+        Command::CompleteConfigurations { ref words } => {
+            let mut stdout = std::io::stdout().lock();
+            for configuration in completion_configurations(&opts.data_dir(), words) {
+                let _ = writeln!(stdout, "{configuration}");
+            }
+        }
+        Command::Doctor(ref doctor_opts) => {
+            let report = npcnix::doctor::Report::run(&opts.data_dir(), doctor_opts.offline);
+            print_report(&report, opts.json(doctor_opts.json))?;
+        }
+        Command::Verify(ref verify_opts) => {
+            let data_dir = opts.data_dir();
+            let config = data_dir.load_config()?;
+            let remote =
+                data_dir.get_current_remote_with_opt_override(verify_opts.remote.as_ref())?;
+            let pull_opts = npcnix::PullOpts {
+                decrypt_identity: data_dir.get_current_decrypt_identity_with_opt_override(
+                    verify_opts.decrypt_identity.as_deref(),
+                )?,
+                progress: transfer_progress("Downloading"),
+                ..(&config).into()
+            };
+            let report = npcnix::verify::verify(
+                &remote,
+                &npcnix::verify::VerifyOpts {
+                    configuration: verify_opts.configuration.clone(),
+                    evaluate: verify_opts.evaluate,
+                },
+                &pull_opts,
+                &config,
+            );
+            print_report(&report, opts.json(verify_opts.json))?;
+        }
+        Command::GetEtag(ref head_opts) => {
+            let remote = opts
+                .data_dir()
+                .get_current_remote_with_opt_override(head_opts.remote.as_ref())?;
+            let etag = npcnix::get_etag(&remote, &opts.data_dir().load_config()?)?;
+            if opts.json(head_opts.json) {
+                print_json(&serde_json::json!({ "remote": remote, "etag": etag }))?;
+            } else {
+                let _ = writeln!(std::io::stdout(), "{etag}");
+            }
+        }
+        Command::Head(ref head_opts) => {
+            let remote = opts
+                .data_dir()
+                .get_current_remote_with_opt_override(head_opts.remote.as_ref())?;
+            let head = npcnix::head(&remote, &opts.data_dir().load_config()?)?;
+            let mut stdout = std::io::stdout().lock();
+            if opts.json(head_opts.json) {
+                let _ = writeln!(stdout, "{}", serde_json::to_string_pretty(&head)?);
+            } else {
+                let _ = writeln!(stdout, "Remote:        {remote}");
+                let _ = writeln!(stdout, "Size:          {}", format_size(head.size));
+                let _ = writeln!(stdout, "Etag:          {}", head.etag);
+                if let Some(last_modified) = head.last_modified {
+                    let _ = writeln!(
+                        stdout,
+                        "Last modified: {}",
+                        last_modified.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                    );
+                }
+                if let Some(ref version_id) = head.version_id {
+                    let _ = writeln!(stdout, "Version id:    {version_id}");
+                }
+            }
+        }
+        Command::List(ref list_opts) => {
+            let remote = opts
+                .data_dir()
+                .get_current_remote_with_opt_override(list_opts.remote.as_ref())?;
+            if !npcnix::versions::is_prefix(&remote) {
+                anyhow::bail!("{remote} is not a prefix, pass one ending with `/` with `--remote`");
+            }
+            let versions = npcnix::versions::list(&remote)?;
+            if opts.json(list_opts.json) {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    serde_json::to_string_pretty(&versions)?
+                );
+            } else {
+                print_versions(&versions);
+            }
+        }
+        Command::Prune(ref prune_opts) => {
+            let pruned = npcnix::versions::prune(
+                &prune_opts.remote,
+                &npcnix::versions::PruneOpts {
+                    keep: prune_opts.keep,
+                    older_than: prune_opts.older_than,
+                    dry_run: prune_opts.dry_run,
+                },
+            )?;
+            if opts.json(false) {
+                print_json(&pruned)?;
+            } else {
+                let mut stdout = std::io::stdout().lock();
+                if pruned.is_empty() {
+                    let _ = writeln!(stdout, "Nothing to prune under {}", prune_opts.remote);
+                }
+                for version in &pruned {
+                    let _ = writeln!(
+                        stdout,
+                        "{} {}",
+                        if prune_opts.dry_run {
+                            "Would delete"
+                        } else {
+                            "Deleted"
+                        },
+                        version.name
+                    );
+                }
+            }
+        }
+        Command::Activate(ref activate_opts) if activate_opts.diff_only => {
+            let data_dir = opts.data_dir();
+            let data_dir = data_dir.config_exist()?.then_some(&data_dir);
+            let configuration = match data_dir {
+                Some(data_dir) => data_dir.get_current_configuration_with_opt_override(
+                    activate_opts.configuration.as_deref(),
+                )?,
+                None => activate_opts
+                    .configuration
+                    .clone()
+                    .ok_or_else(|| anyhow::format_err!("Must pass configuration to activate"))?,
+            };
+            let diff = npcnix::preview_activation(
+                data_dir,
+                &activate_opts.src,
+                &configuration,
+                &activate_opts.clone().activate.into(),
+            )?;
+            if opts.json(false) {
+                print_json(&serde_json::json!({ "configuration": configuration, "diff": diff }))?;
+            } else {
+                let _ = write!(std::io::stdout(), "{diff}");
+            }
+        }
+        Command::Activate(ref activate_opts) if activate_opts.staged => {
+            npcnix::activate_staged(&opts.data_dir(), &activate_opts.clone().activate.into())?;
         }
         Command::Activate(ref activate_opts) => {
+            let lib_activate_opts = npcnix::ActivateOpts {
+                store_path: activate_opts.store_path.clone(),
+                ..activate_opts.clone().activate.into()
+            };
             if opts.data_dir().config_exist()? {
                 let configuration = opts
                     .data_dir()
@@ -291,7 +2617,7 @@ fn main() -> anyhow::Result<()> {
                     Some(&opts.data_dir()),
                     &activate_opts.src,
                     &configuration,
-                    &activate_opts.clone().activate.into(),
+                    &lib_activate_opts,
                 )?;
             } else {
                 npcnix::activate(
@@ -300,43 +2626,109 @@ fn main() -> anyhow::Result<()> {
                     activate_opts.configuration.as_deref().ok_or_else(|| {
                         anyhow::format_err!("Must pass configuration to activate")
                     })?,
-                    &activate_opts.clone().activate.into(),
+                    &lib_activate_opts,
                 )?;
             }
         }
+        Command::Follow(ref follow_opts) if follow_opts.once() == Some(npcnix::Once::Cycle) => {
+            follow_opts.bootstrap(&opts.data_dir())?;
+            follow_opts.request_force_next(&opts.data_dir())?;
+            let outcome =
+                npcnix::DaemonEngine::new(opts.data_dir(), follow_opts.clone().activate.into())
+                    .with_ignore_etag(follow_opts.ignore_etag)
+                    .with_events(LogEvents)
+                    .step()?;
+            std::process::exit(match outcome {
+                npcnix::CycleOutcome::Unchanged(_) => 0,
+                npcnix::CycleOutcome::Changed { .. } => ExitCode::Changed as i32,
+                npcnix::CycleOutcome::RemoteUnavailable(_) => ExitCode::RemoteUnavailable as i32,
+                npcnix::CycleOutcome::Failed(_) => ExitCode::ActivationFailed as i32,
+            });
+        }
         Command::Follow(ref follow_opts) => {
-            npcnix::follow(
+            follow_opts.bootstrap(&opts.data_dir())?;
+            follow_opts.request_force_next(&opts.data_dir())?;
+            exit_if_signalled(npcnix::follow(
                 &opts.data_dir(),
                 &follow_opts.clone().activate.into(),
                 None,
                 follow_opts.once(),
                 follow_opts.ignore_etag,
-            )?;
+                (!follow_opts.no_control_socket).then_some(follow_opts.control_socket.as_path()),
+                LogEvents,
+            )?);
         }
-        Command::Pause(PauseOpts { hours, minutes }) => {
-            let config = opts.data_dir().load_config()?;
-
-            let config = if let Some(minutes) = minutes {
-                config.with_paused_until(
-                    chrono::Utc::now()
-                        + chrono::Duration::seconds(TryFrom::try_from(minutes.saturating_mul(60))?),
-                )
-            } else if let Some(hours) = hours {
-                config.with_paused_until(
-                    chrono::Utc::now()
-                        + chrono::Duration::seconds(TryFrom::try_from(
-                            hours.saturating_mul(60 * 60),
-                        )?),
-                )
-            } else {
-                config.with_paused_indefinitely()
+        Command::Pause(ref pause_opts) => {
+            let until = pause_opts.until()?;
+            opts.data_dir().update_config(|config| {
+                Ok(match until {
+                    Some(until) => config.with_paused_until(until),
+                    None => config.with_paused_indefinitely(),
+                }
+                .with_pause_reason(pause_opts.reason.as_deref()))
+            })?;
+        }
+        Command::Ctl(ref ctl_opts) => {
+            let request = match ctl_opts.command {
+                CtlCommand::Status => npcnix::control::ControlRequest::Status,
+                CtlCommand::Trigger => npcnix::control::ControlRequest::Trigger,
+                CtlCommand::Pause(ref pause_opts) => npcnix::control::ControlRequest::Pause {
+                    until: pause_opts.until()?,
+                    reason: pause_opts.reason.clone(),
+                },
+                CtlCommand::Resume => npcnix::control::ControlRequest::Resume,
+                CtlCommand::Approve => npcnix::control::ControlRequest::Approve,
+                CtlCommand::Force => npcnix::control::ControlRequest::Force,
+                CtlCommand::Unquarantine => npcnix::control::ControlRequest::Unquarantine,
             };
-
-            opts.data_dir().store_config(&config)?;
+            let response = npcnix::control::send(&ctl_opts.socket, &request)?;
+            if !response.ok {
+                anyhow::bail!("{}", response.message);
+            }
+            if opts.json(false) {
+                print_json(&response)?;
+            } else {
+                let _ = writeln!(std::io::stdout(), "{}", response.message);
+            }
         }
         Command::Unpause => {
+            opts.data_dir()
+                .update_config(|config| Ok(config.with_unpaused()))?;
+        }
+        Command::Rollback(RollbackOpts { list: true, .. }) => {
             let config = opts.data_dir().load_config()?;
-            opts.data_dir().store_config(&config.with_unpaused())?;
+            if opts.json(false) {
+                print_json(&config.generations())?;
+            } else {
+                for generation in config.generations() {
+                    let _ = writeln!(
+                        std::io::stdout(),
+                        "{}\t{}\t{}\t{}",
+                        generation.number,
+                        generation
+                            .timestamp
+                            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                        generation.configuration,
+                        generation.etag.as_deref().unwrap_or("-"),
+                    );
+                }
+            }
+        }
+        Command::Rollback(RollbackOpts { to, no_hold, .. }) => {
+            let system = npcnix::rollback(&opts.data_dir(), to, no_hold, &Default::default())?;
+            if opts.json(false) {
+                print_json(&serde_json::json!({ "system": system }))?;
+            } else {
+                let _ = writeln!(std::io::stdout(), "{}", system.display());
+            }
+        }
+        Command::Unhold => {
+            opts.data_dir()
+                .update_config(|config| Ok(config.with_held_etag(None)))?;
+        }
+        Command::Unquarantine => {
+            opts.data_dir()
+                .update_config(|config| Ok(config.with_unquarantined()))?;
         }
         Command::Install(InstallOpts {
             ref remote,
@@ -345,24 +2737,834 @@ fn main() -> anyhow::Result<()> {
             ref initial_configuration,
             ref activate,
         }) => {
-            opts.data_dir().store_config(
-                &opts
-                    .data_dir()
-                    .load_config()?
+            opts.data_dir().update_config(|config| {
+                let config = config
                     .with_remote(remote)
-                    .with_remote_region(remote_region.as_deref())
-                    .with_configuration(configuration),
-            )?;
+                    .with_remote_region(remote_region.as_deref());
+                Ok(match configuration {
+                    Some(configuration) => config.with_configuration(configuration),
+                    None => config,
+                })
+            })?;
 
-            npcnix::follow(
+            exit_if_signalled(npcnix::follow(
                 &opts.data_dir(),
                 &activate.clone().into(),
                 initial_configuration.as_deref(),
                 Some(npcnix::Once::Any),
                 false,
+                None,
+                LogEvents,
+            )?);
+        }
+        Command::History(ref history_opts) => {
+            let mut entries = npcnix::history::read(&opts.data_dir().history_path())?;
+            if history_opts.changes {
+                entries.retain(|entry| entry.outcome != "unchanged");
+            }
+            let skip_count = entries.len().saturating_sub(history_opts.count);
+            let mut stdout = std::io::stdout().lock();
+            for entry in &entries[skip_count..] {
+                if opts.json(history_opts.json) {
+                    let _ = writeln!(stdout, "{}", serde_json::to_string(entry)?);
+                } else {
+                    let _ = writeln!(
+                        stdout,
+                        "{}\t{}\t{}\t{}\t{:.1}s\t{}\t{}",
+                        entry
+                            .timestamp
+                            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                        entry.outcome,
+                        entry.configuration.as_deref().unwrap_or("-"),
+                        entry.etag,
+                        entry.duration_secs,
+                        entry
+                            .log_file
+                            .as_deref()
+                            .map(|path| path.display().to_string())
+                            .unwrap_or_else(|| "-".into()),
+                        entry.error.as_deref().unwrap_or(""),
+                    );
+                }
+            }
+        }
+        Command::Approve(ref approve_opts) => {
+            let remote = opts
+                .data_dir()
+                .get_current_remote_with_opt_override(approve_opts.remote.as_ref())?;
+            let etag = match approve_opts.etag {
+                Some(ref etag) => etag.clone(),
+                None => npcnix::get_etag(&remote, &opts.data_dir().load_config()?)?,
+            };
+            npcnix::approval::approve(&remote, &etag, approve_opts.sign_key.as_deref())?;
+        }
+        Command::Init(ref init_opts) => {
+            use std::io::IsTerminal as _;
+
+            let user_data_settings = init_opts
+                .from_user_data
+                .as_ref()
+                .map(|user_data| {
+                    npcnix::bootstrap::BootstrapSettings::from_user_data(
+                        user_data.as_deref().unwrap_or(std::path::Path::new(
+                            npcnix::bootstrap::CLOUD_INIT_USER_DATA_PATH,
+                        )),
+                    )
+                })
+                .transpose()?;
+            let imds_settings = init_opts
+                .from_imds
+                .then(npcnix::bootstrap::BootstrapSettings::from_imds)
+                .transpose()?;
+            let mut remote = init_opts.remote.clone();
+            let mut configuration = init_opts.configuration.clone();
+            // the settings are applied again when storing, so the daemon's
+            // own updates made while prompting are kept
+            let apply = |config: npcnix::config::Config,
+                         remote: Option<&Url>,
+                         configuration: Option<&str>| {
+                let mut config = config;
+                for settings in [&user_data_settings, &imds_settings].into_iter().flatten() {
+                    config = settings.apply(config)?;
+                }
+                if let Some(remote) = remote {
+                    config = config.with_remote(remote);
+                }
+                if let Some(ref remote_region) = init_opts.remote_region {
+                    config = config.with_remote_region(Some(remote_region));
+                }
+                if let Some(configuration) = configuration {
+                    config = config.with_configuration(configuration);
+                }
+                anyhow::Ok(config)
+            };
+            let mut config = apply(
+                opts.data_dir().load_config()?,
+                remote.as_ref(),
+                configuration.as_deref(),
+            )?;
+
+            let interactive = !init_opts.non_interactive && io::stdin().is_terminal();
+            if interactive {
+                let current = config.remote().ok().map(Url::to_string);
+                if let Some(answer) = prompt("Remote", current.as_deref())? {
+                    let answer = answer.parse()?;
+                    config = config.with_remote(&answer);
+                    remote = Some(answer);
+                }
+                // offer (and check against) the configurations of the remote
+                let available = match config.effective_remote() {
+                    Ok(remote) => {
+                        let backend = npcnix::activation::effective_backend(
+                            &init_opts.activate_opts.clone().into(),
+                            &config,
+                        );
+                        match fetch_configurations(&opts.data_dir(), &remote, backend, &config) {
+                            Ok(listing) => listing.configurations,
+                            Err(e) => {
+                                warn!(error = %format!("{e:#}"), "Failed to list the configurations");
+                                vec![]
+                            }
+                        }
+                    }
+                    Err(_) => vec![],
+                };
+                if !available.is_empty() {
+                    let _ = writeln!(
+                        io::stderr(),
+                        "Available configurations: {}",
+                        available.join(", ")
+                    );
+                }
+                for attempt in 1.. {
+                    let current = config.configuration().ok().map(ToOwned::to_owned);
+                    let Some(answer) = prompt("Configuration", current.as_deref())? else {
+                        break;
+                    };
+                    if !available.is_empty() && !available.contains(&answer) {
+                        let message =
+                            format!("`{answer}` is not one of the configurations of the remote");
+                        if attempt == 3 {
+                            anyhow::bail!("{message}");
+                        }
+                        let _ = writeln!(io::stderr(), "{message}");
+                        continue;
+                    }
+                    config = config.with_configuration(&answer);
+                    configuration = Some(answer);
+                    break;
+                }
+            }
+            if config.remote().is_err() {
+                anyhow::bail!("Remote not set, use `--remote`");
+            }
+            let effective_remote = config.effective_remote()?;
+            let configuration_name = config.configuration()?;
+            let etag = npcnix::get_etag(&effective_remote, &config)
+                .with_context(|| format!("Failed to access the remote {effective_remote}"))?;
+            opts.data_dir().update_config(|current| {
+                apply(current, remote.as_ref(), configuration.as_deref())
+            })?;
+            let _ = writeln!(
+                io::stdout(),
+                "Initialized: remote {effective_remote} (etag {etag}), configuration \
+                 {configuration_name}"
+            );
+
+            if init_opts.activate {
+                exit_if_signalled(npcnix::follow(
+                    &opts.data_dir(),
+                    &init_opts.activate_opts.clone().into(),
+                    None,
+                    Some(npcnix::Once::Any),
+                    false,
+                    None,
+                    LogEvents,
+                )?);
+            }
+        }
+        Command::Wait(ref wait_opts) => {
+            let config = opts.data_dir().load_config()?;
+            let prefix = match wait_opts.prefix {
+                Some(ref prefix) => prefix.clone(),
+                None => config.status_report_prefix().cloned().ok_or_else(|| {
+                    anyhow::format_err!("Status report prefix not set, use `--prefix`")
+                })?,
+            };
+            let etag = match wait_opts.etag {
+                Some(ref etag) => etag.clone(),
+                None => npcnix::get_etag(
+                    &opts
+                        .data_dir()
+                        .get_current_remote_with_opt_override(wait_opts.remote.as_ref())?,
+                    &config,
+                )?,
+            };
+            let convergence = npcnix::report::wait_for_etag(
+                &prefix,
+                &etag,
+                &wait_opts.hosts,
+                wait_opts.timeout,
+                wait_opts.interval,
             )?;
+            if opts.json(wait_opts.json) {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    serde_json::to_string_pretty(&convergence)?
+                );
+            } else {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "Converged to etag {etag}: {}",
+                    convergence.converged.join(", ")
+                );
+            }
+        }
+        Command::Repoint(ref repoint_opts) => {
+            let push_opts = npcnix::PushOpts {
+                content_addressed: true,
+                retry: opts.data_dir().load_config()?.transfer_retry(),
+                ..Default::default()
+            };
+            let res = npcnix::repoint(&repoint_opts.remote, &repoint_opts.sha256, &push_opts)?;
+            if opts.json(false) {
+                print_push_result(&repoint_opts.remote, &res)?;
+            }
+        }
+        Command::Promote(ref promote_opts) => {
+            let remote = match promote_opts.remote {
+                Some(ref remote) => remote.clone(),
+                None => opts.data_dir().load_config()?.expanded_remote()?,
+            };
+            npcnix::channel::promote(&remote, &promote_opts.from, &promote_opts.to)?;
+        }
+        Command::FleetStatus(ref fleet_status_opts) => {
+            let prefix = match fleet_status_opts.prefix {
+                Some(ref prefix) => prefix.clone(),
+                None => opts
+                    .data_dir()
+                    .load_config()?
+                    .status_report_prefix()
+                    .cloned()
+                    .ok_or_else(|| {
+                        anyhow::format_err!("Status report prefix not set, use `--prefix`")
+                    })?,
+            };
+            let statuses = npcnix::report::fetch_all(&prefix)?;
+            if opts.json(fleet_status_opts.json) {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    serde_json::to_string_pretty(&statuses)?
+                );
+            } else {
+                print_fleet_status(&statuses);
+            }
+        }
+        Command::Ci {
+            command: CiOpts::Publish(ref publish_opts),
+        } => match npcnix::ci::publish(&publish_opts.clone().into()) {
+            Ok(report) => {
+                if opts.json(publish_opts.json) {
+                    let _ = writeln!(
+                        std::io::stdout(),
+                        "{}",
+                        serde_json::to_string_pretty(&report)?
+                    );
+                }
+            }
+            Err(e) => {
+                npcnix::ci::annotate_error(format!("{e:#}"));
+                return Err(e);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+fn format_age(age: chrono::Duration) -> String {
+    let secs = age.num_seconds().max(0);
+    match secs {
+        0..=119 => format!("{secs}s"),
+        120..=7199 => format!("{}m", secs / 60),
+        7200..=172_799 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn print_fleet_status(statuses: &[npcnix::report::HostStatus]) {
+    let now = chrono::Utc::now();
+    let rows: Vec<[String; 7]> = statuses
+        .iter()
+        .map(|status| {
+            [
+                status.hostname.clone(),
+                status.configuration.clone().unwrap_or_else(|| "-".into()),
+                status.etag.clone(),
+                status
+                    .generation
+                    .map(|generation| generation.to_string())
+                    .unwrap_or_else(|| "-".into()),
+                if status.paused {
+                    format!("{} (paused)", status.outcome)
+                } else {
+                    status.outcome.clone()
+                },
+                format_age(status.age(now)),
+                status
+                    .last_failure
+                    .as_ref()
+                    .map(|failure| {
+                        format!(
+                            "{} (etag {}): {}",
+                            failure
+                                .timestamp
+                                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                            failure.etag,
+                            failure.error
+                        )
+                    })
+                    .unwrap_or_else(|| "-".into()),
+            ]
+        })
+        .collect();
+    print_table(
+        [
+            "HOST",
+            "CONFIGURATION",
+            "ETAG",
+            "GENERATION",
+            "OUTCOME",
+            "AGE",
+            "LAST FAILURE",
+        ],
+        &rows,
+    );
+}
+
+fn print_versions(versions: &[npcnix::versions::ArchiveVersion]) {
+    let rows: Vec<[String; 5]> = versions
+        .iter()
+        .map(|version| {
+            let meta = version.meta.as_ref();
+            [
+                version.name.clone(),
+                version
+                    .last_modified
+                    .map(|last_modified| {
+                        last_modified.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                    })
+                    .unwrap_or_else(|| "-".into()),
+                format_size(version.size),
+                version.etag.clone(),
+                meta.and_then(|meta| meta.git_rev.as_ref())
+                    .map(|git_rev| {
+                        let short = &git_rev[..git_rev.len().min(12)];
+                        if meta.and_then(|meta| meta.git_dirty) == Some(true) {
+                            format!("{short}-dirty")
+                        } else {
+                            short.to_owned()
+                        }
+                    })
+                    .unwrap_or_else(|| "-".into()),
+            ]
+        })
+        .collect();
+    print_table(["NAME", "LAST MODIFIED", "SIZE", "ETAG", "GIT REV"], &rows);
+}
+
+fn print_push_plan(plan: &npcnix::PushPlan) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "Target:   {}", plan.target);
+    let _ = writeln!(
+        stdout,
+        "Archive:  {} ({} files)",
+        format_size(plan.archive_bytes),
+        plan.summary.files
+    );
+    for path in &plan.summary.excluded {
+        let _ = writeln!(stdout, "Excluded: {}", path.display());
+    }
+    match plan.changes {
+        None => {
+            let _ = writeln!(stdout, "Changes:  new remote");
+        }
+        Some(ref changes) if changes.is_empty() => {
+            let _ = writeln!(stdout, "Changes:  none, the remote has the same content");
+        }
+        Some(ref changes) => {
+            for change in changes {
+                let _ = writeln!(
+                    stdout,
+                    "Changes:  {} {}",
+                    diff_change_letter(change.change),
+                    change.path.display()
+                );
+            }
+        }
+    }
+}
+
+fn diff_change_letter(change: npcnix::diff::Change) -> char {
+    match change {
+        npcnix::diff::Change::Added => 'A',
+        npcnix::diff::Change::Removed => 'D',
+        npcnix::diff::Change::Modified => 'M',
+    }
+}
+
+/// Print `rows` under `header`, in aligned columns
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let mut print_row = |row: &[&str]| {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        let _ = writeln!(stdout, "{}", line.join("  ").trim_end());
+    };
+    print_row(&header);
+    for row in rows {
+        print_row(&row.each_ref().map(String::as_str));
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while 1024.0 <= size && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1}{}", UNITS[unit])
+}
+
+/// Settings overridden by environment variables of the commands using them
+const SETTING_ENV_VARS: &[(&str, &str)] = &[
+    ("remote", "NPCNIX_REMOTE"),
+    ("remote_region", "NPCNIX_REMOTE_REGION"),
+    ("configuration", "NPCNIX_CONFIGURATION"),
+    ("decrypt_identity", "NPCNIX_DECRYPT_IDENTITY"),
+    ("status_report_prefix", "NPCNIX_STATUS_REPORT_PREFIX"),
+    ("activation_mode", "NPCNIX_ACTIVATION_MODE"),
+    ("activation_backend", "NPCNIX_ACTIVATION_BACKEND"),
+    ("flake_attr", "NPCNIX_FLAKE_ATTR"),
+    ("build_host", "NPCNIX_BUILD_HOST"),
+    ("use_remote_sudo", "NPCNIX_USE_REMOTE_SUDO"),
+    ("two_phase_activation", "NPCNIX_TWO_PHASE"),
+    ("activation_timeout_secs", "NPCNIX_ACTIVATION_TIMEOUT_SECS"),
+    ("escalation", "NPCNIX_ESCALATION"),
+    ("log_format", "NPCNIX_LOG_FORMAT"),
+];
+
+/// Print the settings with where their value comes from: the defaults, the
+/// settings files, or the environment (command line flags override all of
+/// them)
+fn print_setting_origins(data_dir: &DataDir, json: bool) -> anyhow::Result<()> {
+    let serde_json::Value::Object(values) = serde_json::to_value(data_dir.load_config()?)? else {
+        unreachable!("config is serialized as an object");
+    };
+    let mut stdout = std::io::stdout().lock();
+    let mut settings = vec![];
+    for (key, origin) in data_dir.setting_origins()? {
+        let env = SETTING_ENV_VARS
+            .iter()
+            .find(|(setting, _)| *setting == key)
+            .and_then(|(_, var)| Some((*var, std::env::var(var).ok()?)));
+        let (mut value, origin) = match env {
+            Some((var, value)) => (
+                serde_json::Value::String(value),
+                npcnix::config::SettingOrigin::Env(var.to_owned()),
+            ),
+            None => (
+                values.get(&key).cloned().unwrap_or(serde_json::Value::Null),
+                origin,
+            ),
+        };
+        npcnix::secrets::redact_setting(&key, &mut value);
+        if json {
+            settings.push(serde_json::json!({
+                "key": key,
+                "value": value,
+                "origin": origin.to_string(),
+            }));
+        } else {
+            writeln!(stdout, "{key} = {value}  # {origin}")?;
+        }
+    }
+    if json {
+        writeln!(stdout, "{}", serde_json::to_string_pretty(&settings)?)?;
+    }
+    Ok(())
+}
+
+fn print_diff(diff: &npcnix::diff::Diff, remote: &Url, content: bool) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    if diff.is_empty() {
+        let _ = writeln!(stdout, "No changes to {remote} (etag {})", diff.etag);
+    }
+    for change in &diff.changes {
+        if content {
+            let _ = write!(stdout, "{}", diff.content_diff(change)?);
+        } else {
+            let _ = writeln!(
+                stdout,
+                "{} {}",
+                diff_change_letter(change.change),
+                change.path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_push_result(remote: &Url, res: &npcnix::PushResult) -> anyhow::Result<()> {
+    print_json(&serde_json::json!({
+        "remote": remote,
+        "bytes": res.bytes,
+        "etag": res.etag,
+        "unchanged": res.unchanged,
+    }))
+}
+
+fn print_json(value: &impl serde::Serialize) -> anyhow::Result<()> {
+    let _ = writeln!(
+        std::io::stdout(),
+        "{}",
+        serde_json::to_string_pretty(value)?
+    );
+    Ok(())
+}
+
+/// Print the checks of `report`, failing if any failed
+fn print_report(report: &npcnix::doctor::Report, json: bool) -> anyhow::Result<()> {
+    if json {
+        let _ = writeln!(
+            std::io::stdout(),
+            "{}",
+            serde_json::to_string_pretty(report)?
+        );
+    } else {
+        let mut stdout = std::io::stdout().lock();
+        for check in &report.checks {
+            let _ = writeln!(
+                stdout,
+                "{:<4}  {:<20}  {}",
+                check.status.as_str().to_uppercase(),
+                check.name,
+                check.message
+            );
+        }
+    }
+    let failures = report.failures();
+    if 0 < failures {
+        return Err(Exit::error(
+            ExitCode::VerificationFailed,
+            format!("{failures} of {} checks failed", report.checks.len()),
+        ));
+    }
+    Ok(())
+}
+
+/// Long flags taking a configuration name, in `command` and its subcommands
+fn configuration_flags(command: &clap::Command) -> BTreeSet<String> {
+    let mut flags: BTreeSet<_> = command
+        .get_arguments()
+        .filter(|arg| arg.get_id().as_str().ends_with("configuration"))
+        .filter_map(|arg| arg.get_long())
+        .map(|long| format!("--{long}"))
+        .collect();
+    for subcommand in command.get_subcommands() {
+        flags.extend(configuration_flags(subcommand));
+    }
+    flags
+}
+
+/// The completions generated by `clap_complete`, completing the
+/// configuration names dynamically (with `npcnix complete-configurations`)
+/// in bash, zsh and fish
+fn completion_script(shell: clap_complete::Shell) -> Vec<u8> {
+    let mut command = Opts::command();
+    let flags = configuration_flags(&command);
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut command, "npcnix", &mut script);
+    match shell {
+        clap_complete::Shell::Bash => {
+            script.extend_from_slice(
+                format!(
+                    r#"
+_npcnix_dynamic() {{
+    case "${{COMP_WORDS[COMP_CWORD-1]}}" in
+        {})
+            COMPREPLY=($(compgen -W "$(npcnix complete-configurations -- "${{COMP_WORDS[@]}}" 2>/dev/null)" -- "${{COMP_WORDS[COMP_CWORD]}}"))
+            return 0
+            ;;
+    esac
+    _npcnix "$@"
+}}
+
+complete -F _npcnix_dynamic -o bashdefault -o default npcnix
+"#,
+                    flags.iter().cloned().collect::<Vec<_>>().join("|")
+                )
+                .as_bytes(),
+            );
+        }
+        clap_complete::Shell::Zsh => {
+            let generated = String::from_utf8(script).expect("Completions are UTF-8");
+            let mut lines = generated.lines();
+            // `#compdef` has to stay the first line
+            let mut zsh = format!(
+                r#"{}
+
+_npcnix_configurations() {{
+    local -a configurations
+    configurations=(${{(f)"$(npcnix complete-configurations -- "${{words[@]}}" 2>/dev/null)"}})
+    _describe 'configuration' configurations
+}}
+"#,
+                lines.next().unwrap_or_default()
+            );
+            for line in lines {
+                let takes_configuration = flags
+                    .iter()
+                    .any(|flag| line.starts_with(&format!("'{flag}=[")));
+                match line.strip_suffix(": ' \\") {
+                    Some(line) if takes_configuration => {
+                        zsh.push_str(line);
+                        zsh.push_str(":_npcnix_configurations' \\");
+                    }
+                    _ => zsh.push_str(line),
+                }
+                zsh.push('\n');
+            }
+            return zsh.into_bytes();
+        }
+        clap_complete::Shell::Fish => {
+            script.push(b'\n');
+            for flag in &flags {
+                script.extend_from_slice(
+                    format!(
+                        "complete -c npcnix -l {} -f -a '(npcnix complete-configurations -- (commandline -opc) 2>/dev/null)'\n",
+                        flag.trim_start_matches("--")
+                    )
+                    .as_bytes(),
+                );
+            }
+        }
+        _ => {}
+    }
+    script
+}
+
+/// Configuration names worth completing in the command line `words`
+///
+/// The ones of the flake in `--src` (completing `activate`), otherwise the
+/// current one, the ones activated before and the ones of the remote last
+/// listed by `list-configurations`. Never pulls the remote, completions have
+/// to be quick.
+fn completion_configurations(data_dir: &DataDir, words: &[String]) -> BTreeSet<String> {
+    let config = data_dir.load_config().unwrap_or_default();
+    let backend = npcnix::activation::effective_backend(&Default::default(), &config);
+    let src = words
+        .iter()
+        .position(|word| word == "--src")
+        .and_then(|i| words.get(i + 1).map(PathBuf::from))
+        .or_else(|| {
+            words
+                .iter()
+                .find_map(|word| word.strip_prefix("--src=").map(PathBuf::from))
+        })
+        .or_else(|| {
+            // the default `--src` of `activate`
+            words
+                .iter()
+                .any(|word| word == "activate")
+                .then(|| PathBuf::from("."))
+                .filter(|src| src.join("flake.nix").exists())
+        });
+    if let Some(src) = src {
+        if let Ok(configurations) = npcnix::configurations::list(&src, backend) {
+            return configurations.into_iter().collect();
         }
     }
 
+    let mut configurations = BTreeSet::new();
+    if let Ok(configuration) = config.configuration() {
+        configurations.insert(configuration.to_owned());
+    }
+    if let Ok(entries) = npcnix::history::read(&data_dir.history_path()) {
+        configurations.extend(entries.into_iter().filter_map(|entry| entry.configuration));
+    }
+    if let Ok(Some(listing)) =
+        npcnix::configurations::Listing::load_cached(&data_dir.configurations_cache_path())
+    {
+        configurations.extend(listing.configurations);
+    }
+    configurations
+}
+
+/// List the configurations of `remote`, caching them for the completions
+fn fetch_configurations(
+    data_dir: &DataDir,
+    remote: &Url,
+    backend: npcnix::ActivationBackend,
+    config: &npcnix::config::Config,
+) -> anyhow::Result<npcnix::configurations::Listing> {
+    let listing = npcnix::configurations::Listing::fetch(remote, backend, &config.into())?;
+    // e.g. not running as the owner of the data dir
+    if let Err(e) = listing.store_cached(&data_dir.configurations_cache_path()) {
+        debug!(error = %e, "Failed to cache the configurations");
+    }
+    Ok(listing)
+}
+
+/// Write the man page of `command` (named `name`), and of its subcommands
+fn write_man_pages(command: &clap::Command, name: &str, dir: &Path) -> anyhow::Result<()> {
+    let path = dir.join(format!("{name}.1"));
+    let mut file = std::fs::File::create(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    clap_mangen::Man::new(command.clone().name(name.to_owned())).render(&mut file)?;
+    for subcommand in command.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        write_man_pages(
+            subcommand,
+            &format!("{name}-{}", subcommand.get_name()),
+            dir,
+        )?;
+    }
     Ok(())
 }
+
+fn print_status(status: &npcnix::status::Status) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", status.state);
+    let _ = writeln!(
+        stdout,
+        "configuration: {}",
+        status.configuration.as_deref().unwrap_or("-")
+    );
+    let _ = writeln!(
+        stdout,
+        "remote: {}",
+        status
+            .remote
+            .as_ref()
+            .map(Url::to_string)
+            .unwrap_or_else(|| "-".into())
+    );
+    if let Some(ref channel) = status.channel {
+        let _ = writeln!(stdout, "channel: {channel}");
+    }
+    let _ = writeln!(
+        stdout,
+        "last reconfiguration: {} ({} etag {})",
+        status
+            .last_reconfiguration
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        status.last_configuration,
+        status.last_etag
+    );
+    if let (Some(last_check), Some(age)) = (status.last_check, status.last_check_age_secs) {
+        let _ = writeln!(
+            stdout,
+            "last check: {} ({} ago)",
+            last_check.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            format_age(chrono::Duration::seconds(age))
+        );
+    }
+    if let Some(ref daemon) = status.daemon {
+        if daemon.running {
+            let _ = writeln!(stdout, "daemon: running ({})", daemon.message);
+        } else {
+            let _ = writeln!(stdout, "daemon: not reachable ({})", daemon.message);
+        }
+    }
+    if let Some(ref failure) = status.last_failure {
+        let _ = writeln!(
+            stdout,
+            "last failure: {} (etag {}, {} time(s)): {}",
+            failure
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            failure.etag,
+            failure.count,
+            failure.error,
+        );
+        if status.quarantined {
+            let _ = writeln!(
+                stdout,
+                "quarantined: etag {} of {}",
+                failure.etag, failure.configuration
+            );
+        }
+    }
+    if let Some(ref etag) = status.held_etag {
+        let _ = writeln!(stdout, "held etag: {etag}");
+    }
+    if let Some(ref log) = status.last_activation_log {
+        let _ = writeln!(stdout, "last activation log: {}", log.display());
+    }
+    if let Some(ref system) = status.expected_system {
+        let _ = writeln!(stdout, "expected system: {}", system.display());
+    }
+    if status.staged_mode {
+        let _ = writeln!(stdout, "staged mode: on");
+    }
+    if let Some(ref pending) = status.pending_update {
+        let _ = writeln!(stdout, "pending update: {pending}");
+    }
+}
@@ -0,0 +1,178 @@
+//! Initializing the config of fresh hosts (e.g. launched from stock images)
+//!
+//! From the EC2 instance tags, or a small document in the user data: either
+//! JSON, or TOML (optionally in an `[npcnix]` table), e.g.
+//!
+//! ```toml
+//! remote = "s3://bucket/nixos.tar.zst"
+//! configuration = "web"
+//! staged = true
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tracing::{debug, info};
+use url::Url;
+
+use crate::activation::ActivationMode;
+use crate::config::Config;
+use crate::data_dir::DataDir;
+#[cfg(feature = "http")]
+use crate::imds::Imds;
+
+/// Settings a fresh host can be bootstrapped with
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct BootstrapSettings {
+    pub remote: Option<Url>,
+    pub remote_region: Option<String>,
+    pub configuration: Option<String>,
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub decrypt_identity: Option<PathBuf>,
+    #[serde(default)]
+    pub activation_mode: Option<ActivationMode>,
+    #[serde(default)]
+    pub staged: Option<bool>,
+    #[serde(default)]
+    pub check_on_start: Option<bool>,
+    #[serde(default)]
+    pub boot_splay_secs: Option<u64>,
+    #[serde(default)]
+    pub rollout_splay_secs: Option<u64>,
+    #[serde(default)]
+    pub min_sleep_secs: Option<u64>,
+    #[serde(default)]
+    pub max_sleep_secs: Option<u64>,
+    #[serde(default)]
+    pub max_sleep_after_hours: Option<u64>,
+}
+
+/// Where cloud-init keeps the user data of the instance
+pub const CLOUD_INIT_USER_DATA_PATH: &str = "/var/lib/cloud/instance/user-data.txt";
+
+impl BootstrapSettings {
+    /// Read the `npcnix:remote`, `npcnix:remote-region`,
+    /// `npcnix:configuration` and `npcnix:channel` instance tags, defaulting
+    /// the region to the one of the instance
+    #[cfg(feature = "http")]
+    pub fn from_imds() -> anyhow::Result<Self> {
+        let imds = Imds::connect()?;
+        let remote = imds
+            .instance_tag("npcnix:remote")?
+            .map(|remote| Url::parse(&remote))
+            .transpose()?;
+        let remote_region = match imds.instance_tag("npcnix:remote-region")? {
+            Some(region) => Some(region),
+            None => imds.region()?,
+        };
+        Ok(Self {
+            remote,
+            remote_region,
+            configuration: imds.instance_tag("npcnix:configuration")?,
+            channel: imds.instance_tag("npcnix:channel")?,
+            ..Self::default()
+        })
+    }
+
+    #[cfg(not(feature = "http"))]
+    pub fn from_imds() -> anyhow::Result<Self> {
+        Err(crate::error::feature_disabled(
+            "Reading the instance tags",
+            "http",
+        ))
+    }
+
+    pub fn from_user_data(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the user data from {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid user data in {}", path.display()))
+    }
+
+    /// Parse a JSON or TOML document, with the settings at the top
+    /// level or under `npcnix`
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut value = if content.trim_start().starts_with('{') {
+            serde_json::from_str(content)?
+        } else {
+            serde_json::Value::Object(crate::toml::parse(content)?)
+        };
+        if let Some(settings) = value.get_mut("npcnix") {
+            value = settings.take();
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Set the settings given in `self`, leaving the other ones as they are
+    pub fn apply(&self, config: Config) -> anyhow::Result<Config> {
+        let mut config = config;
+        if let Some(ref remote) = self.remote {
+            config = config.with_remote(remote);
+        }
+        if let Some(ref remote_region) = self.remote_region {
+            config = config.with_remote_region(Some(remote_region));
+        }
+        if let Some(ref configuration) = self.configuration {
+            config = config.with_configuration(configuration);
+        }
+        if let Some(ref channel) = self.channel {
+            config = config.with_channel(Some(channel));
+        }
+        if let Some(ref decrypt_identity) = self.decrypt_identity {
+            config = config.with_decrypt_identity(Some(decrypt_identity));
+        }
+        if let Some(activation_mode) = self.activation_mode {
+            config = config.with_activation_mode(activation_mode);
+        }
+        if let Some(staged) = self.staged {
+            config = config.with_staged(staged);
+        }
+        if let Some(check_on_start) = self.check_on_start {
+            config = config.with_check_on_start(check_on_start);
+        }
+        if let Some(boot_splay_secs) = self.boot_splay_secs {
+            config = config.with_boot_splay_secs(boot_splay_secs);
+        }
+        if let Some(rollout_splay_secs) = self.rollout_splay_secs {
+            config = config.with_rollout_splay_secs(rollout_splay_secs);
+        }
+        if self.min_sleep_secs.is_some() || self.max_sleep_secs.is_some() {
+            let min_sleep_secs = self.min_sleep_secs.unwrap_or(config.min_sleep_secs());
+            let max_sleep_secs = self.max_sleep_secs.unwrap_or(config.max_sleep_secs());
+            config = config.with_sleep_secs(min_sleep_secs, max_sleep_secs)?;
+        }
+        if let Some(max_sleep_after_hours) = self.max_sleep_after_hours {
+            config = config.with_max_sleep_after_hours(max_sleep_after_hours);
+        }
+        Ok(config)
+    }
+}
+
+/// On the first run (no remote set yet), initialize the config from the
+/// instance tags
+pub fn bootstrap_from_imds(data_dir: &DataDir) -> anyhow::Result<()> {
+    let config = data_dir.load_config()?;
+    if config.remote().is_ok() {
+        debug!("Remote already set, not bootstrapping");
+        return Ok(());
+    }
+    let settings = BootstrapSettings::from_imds()?;
+    let Some(ref remote) = settings.remote else {
+        anyhow::bail!("Remote not set, and there's no `npcnix:remote` instance tag");
+    };
+    info!(
+        %remote,
+        configuration = settings.configuration,
+        "Bootstrapping from the instance tags"
+    );
+    data_dir.update_config(|config| {
+        if config.remote().is_ok() {
+            debug!("Remote set in the meantime, not bootstrapping");
+            return Ok(config);
+        }
+        settings.apply(config)
+    })
+}
@@ -0,0 +1,114 @@
+//! Cancellation of long-running operations (transfers, activations, the
+//! daemon cycles)
+//!
+//! Operations given a [`CancellationToken`] (e.g. [`crate::PullOpts::cancel`])
+//! check it between their steps, and kill the commands they run once it's
+//! cancelled. Cancelled operations fail with [`Cancelled`] (or
+//! [`crate::NpcnixError::Cancelled`] at the API boundary), cleaning up their
+//! temporary files and directories on the way out.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{thread, time};
+
+/// How often the commands check for cancellation
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// The operation was cancelled, see [`CancellationToken`]
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Cancelled")]
+pub struct Cancelled;
+
+/// Cancels the operations it's passed to, from any thread
+///
+/// Clones share the state. Can also be made from an existing
+/// `Arc<AtomicBool>`, cancelled by setting it to `true`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// `Err` if cancelled
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled);
+        }
+        Ok(())
+    }
+}
+
+impl From<Arc<AtomicBool>> for CancellationToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        Self(flag)
+    }
+}
+
+/// `Err` if `cancel` is set and cancelled
+pub(crate) fn check(cancel: Option<&CancellationToken>) -> Result<(), Cancelled> {
+    cancel.map_or(Ok(()), CancellationToken::check)
+}
+
+/// Is `e` (caused by) a cancellation
+pub fn is_cancelled(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| e.is::<Cancelled>())
+}
+
+/// Run `f` (waiting for the child process `pid`), killing the child (and its
+/// whole process group, if it leads one) once `cancel` is cancelled
+pub(crate) fn kill_on_cancel<T>(
+    pid: u32,
+    process_group: bool,
+    cancel: Option<&CancellationToken>,
+    f: impl FnOnce() -> T,
+) -> Result<T, Cancelled> {
+    let Some(cancel) = cancel else {
+        return Ok(f());
+    };
+    let pid = libc::pid_t::try_from(pid).expect("pid fits pid_t");
+    let done = (Mutex::new(false), Condvar::new());
+    let killed = AtomicBool::new(false);
+    let res = thread::scope(|scope| {
+        scope.spawn(|| {
+            let (done, condvar) = &done;
+            let mut done = done.lock().expect("Locking failed");
+            while !*done {
+                if cancel.is_cancelled() {
+                    // SAFETY: `kill` and `killpg` have no memory safety requirements
+                    unsafe {
+                        if process_group {
+                            libc::killpg(pid, libc::SIGKILL);
+                        } else {
+                            libc::kill(pid, libc::SIGKILL);
+                        }
+                    }
+                    killed.store(true, Ordering::SeqCst);
+                    return;
+                }
+                done = condvar
+                    .wait_timeout(done, POLL_INTERVAL)
+                    .expect("Locking failed")
+                    .0;
+            }
+        });
+        let res = f();
+        let (done, condvar) = &done;
+        *done.lock().expect("Locking failed") = true;
+        condvar.notify_all();
+        res
+    });
+    if killed.load(Ordering::SeqCst) {
+        return Err(Cancelled);
+    }
+    Ok(res)
+}
@@ -0,0 +1,42 @@
+//! Release channels (e.g. `canary` and `stable`)
+//!
+//! The channel is a directory right before the file name of the remote key:
+//! hosts subscribed to `canary` with the remote `s3://bucket/dir/nixos.tar.zst`
+//! follow `s3://bucket/dir/canary/nixos.tar.zst`. Promoting copies the
+//! archive from one channel to another.
+
+use anyhow::{bail, format_err};
+use tracing::info;
+use url::Url;
+
+use crate::ci;
+
+/// The remote of `channel`, based on the channel-less `remote`
+pub fn channel_url(remote: &Url, channel: &str) -> anyhow::Result<Url> {
+    if channel.is_empty() || channel.contains('/') {
+        bail!("Invalid channel name: {channel:?}");
+    }
+    let mut url = remote.clone();
+    let file_name = remote
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|file_name| !file_name.is_empty())
+        .ok_or_else(|| format_err!("Remote has no file name: {remote}"))?;
+    url.path_segments_mut()
+        .map_err(|_| format_err!("Invalid remote: {remote}"))?
+        .pop()
+        .push(channel)
+        .push(file_name);
+    Ok(url)
+}
+
+/// Server-side copy the archive of the `from` channel to the `to` channel
+pub fn promote(remote: &Url, from: &str, to: &str) -> anyhow::Result<()> {
+    if from == to {
+        bail!("Can't promote a channel to itself");
+    }
+    let from_url = channel_url(remote, from)?;
+    let to_url = channel_url(remote, to)?;
+    info!(from = %from_url, to = %to_url, "Promoting");
+    ci::promote(&from_url, &to_url)
+}
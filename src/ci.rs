@@ -0,0 +1,319 @@
+//! Helpers for publishing from CI environments (GitHub Actions in
+//! particular)
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+use crate::activation;
+use crate::config::Config;
+use crate::{aws_cli_path, nix_path, CommandExt};
+
+/// Is the current process running inside GitHub Actions
+pub fn is_github_actions() -> bool {
+    std::env::var_os("GITHUB_ACTIONS").is_some_and(|v| v == "true")
+}
+
+/// Emit a GitHub Actions `error` annotation (no-op outside of GitHub Actions)
+pub fn annotate_error(msg: impl fmt::Display) {
+    if is_github_actions() {
+        println!("::error::{}", escape_annotation(&msg.to_string()));
+    }
+}
+
+/// Emit a GitHub Actions `notice` annotation (no-op outside of GitHub
+/// Actions)
+pub fn annotate_notice(msg: impl fmt::Display) {
+    if is_github_actions() {
+        println!("::notice::{}", escape_annotation(&msg.to_string()));
+    }
+}
+
+fn escape_annotation(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[cfg(feature = "http")]
+#[derive(Deserialize)]
+struct OidcTokenResponse {
+    value: String,
+}
+
+/// Request an OIDC token from the GitHub Actions runtime
+///
+/// Requires `id-token: write` permission in the workflow.
+#[cfg(feature = "http")]
+pub fn github_oidc_token(audience: &str) -> anyhow::Result<String> {
+    let request_url = std::env::var("ACTIONS_ID_TOKEN_REQUEST_URL")
+        .context("ACTIONS_ID_TOKEN_REQUEST_URL not set; missing `id-token: write` permission?")?;
+    let request_token = std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN")
+        .context("ACTIONS_ID_TOKEN_REQUEST_TOKEN not set")?;
+
+    let mut url = Url::parse(&request_url).context("Invalid ACTIONS_ID_TOKEN_REQUEST_URL")?;
+    url.query_pairs_mut().append_pair("audience", audience);
+
+    let resp = ureq::get(url.as_str())
+        .set("Authorization", &format!("Bearer {request_token}"))
+        .call()
+        .context("Failed to request OIDC token")?;
+
+    let resp: OidcTokenResponse = serde_json::from_reader(resp.into_reader())?;
+    Ok(resp.value)
+}
+
+#[cfg(not(feature = "http"))]
+pub fn github_oidc_token(_audience: &str) -> anyhow::Result<String> {
+    Err(crate::error::feature_disabled(
+        "Requesting OIDC tokens",
+        "http",
+    ))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AssumeRoleResponse {
+    credentials: AwsCredentials,
+}
+
+/// Temporary AWS credentials
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+}
+
+impl fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AwsCredentials {
+    /// Export the credentials so any `aws` cli called later picks them up
+    pub fn export_to_env(&self) {
+        std::env::set_var("AWS_ACCESS_KEY_ID", &self.access_key_id);
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", &self.secret_access_key);
+        std::env::set_var("AWS_SESSION_TOKEN", &self.session_token);
+    }
+}
+
+/// Exchange a web identity (OIDC) token for temporary AWS credentials
+pub fn assume_role_with_web_identity(
+    role_arn: &str,
+    session_name: &str,
+    token: &str,
+    region: Option<&str>,
+) -> anyhow::Result<AwsCredentials> {
+    // in a (private) file, as the command line is visible to all the users
+    let mut token_file = tempfile::NamedTempFile::new()?;
+    token_file.write_all(token.as_bytes())?;
+    token_file.flush()?;
+
+    let mut cmd = process::Command::new(aws_cli_path());
+    cmd.args([
+        "sts",
+        "assume-role-with-web-identity",
+        "--role-arn",
+        role_arn,
+        "--role-session-name",
+        session_name,
+    ])
+    .arg("--web-identity-token")
+    .arg(format!("file://{}", token_file.path().display()))
+    .args(["--output", "json"]);
+    if let Some(region) = region {
+        cmd.args(["--region", region]);
+    }
+    let output = cmd.log_debug().output().context("`aws` cli failed")?;
+
+    if !output.status.success() {
+        bail!(
+            "aws sts assume-role-with-web-identity returned code={:?} stderr={}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr),
+        )
+    }
+
+    let resp: AssumeRoleResponse = serde_json::from_slice(&output.stdout)?;
+    Ok(resp.credentials)
+}
+
+/// Run `nix flake check` in the `src` directory
+pub fn flake_check(src: &Path) -> anyhow::Result<()> {
+    activation::flake_check(src, &[], &Default::default())
+}
+
+/// Build the system closure of a NixOS `configuration` and return its store
+/// path
+pub fn build_configuration(src: &Path, configuration: &str) -> anyhow::Result<PathBuf> {
+    activation::build_system(
+        src,
+        &activation::flake_target(configuration, None),
+        &Default::default(),
+    )
+}
+
+/// Sign the store paths (recursively) with a Nix signing key
+pub fn sign_store_paths(paths: &[PathBuf], key_file: &Path) -> anyhow::Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let status = process::Command::new(nix_path())
+        .args(["store", "sign", "--recursive", "--key-file"])
+        .arg(key_file)
+        .args(paths)
+        .log_debug()
+        .status()
+        .context("Calling `nix` failed")?;
+    if !status.success() {
+        bail!("nix store sign returned exit code={:?}", status.code());
+    }
+    Ok(())
+}
+
+/// Copy the store paths (recursively, along with their signatures) to the
+/// binary `cache` (`nix copy --to`), e.g. `s3://bucket?region=eu-west-1`
+pub fn copy_store_paths(paths: &[PathBuf], cache: &str) -> anyhow::Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let status = process::Command::new(nix_path())
+        .args(["copy", "--to", cache])
+        .args(paths)
+        .log_debug()
+        .status()
+        .context("Calling `nix` failed")?;
+    if !status.success() {
+        bail!("nix copy returned exit code={:?}", status.code());
+    }
+    Ok(())
+}
+
+/// Copy an already published archive from one remote to another
+pub fn promote(from: &Url, to: &Url) -> anyhow::Result<()> {
+    match (from.scheme(), to.scheme()) {
+        ("s3", "s3") => crate::s3::copy(from, to),
+        (from, to) => bail!("Promotion not supported: {from} -> {to}"),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PublishOpts {
+    pub src: PathBuf,
+    pub include: HashSet<OsString>,
+    pub remote: Url,
+    pub remote_region: Option<String>,
+    /// Role to assume using GitHub Actions OIDC web identity
+    pub role_arn: Option<String>,
+    pub role_session_name: String,
+    pub oidc_audience: String,
+    /// Run `nix flake check` before anything else
+    pub check: bool,
+    /// Configurations to build before pushing
+    pub build: Vec<String>,
+    /// Sign built closures with this key (requires a `cache`)
+    pub sign_key_file: Option<PathBuf>,
+    /// Copy built closures to this binary cache, so the hosts can
+    /// substitute them
+    pub cache: Option<String>,
+    /// After a successful push, copy the archive to this remote
+    pub promote_to: Option<Url>,
+    pub push: crate::PushOpts,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PublishedConfiguration {
+    pub configuration: String,
+    pub store_path: PathBuf,
+}
+
+/// Result of a [`publish`] call (used for JSON output)
+#[derive(Serialize, Debug, Clone)]
+pub struct PublishReport {
+    pub remote: Url,
+    pub etag: String,
+    pub built: Vec<PublishedConfiguration>,
+    pub signed: bool,
+    /// Binary cache the built closures were copied to
+    pub cached_to: Option<String>,
+    pub promoted_to: Option<Url>,
+}
+
+/// Assume role, check, build, sign, cache, push and promote in one go
+pub fn publish(opts: &PublishOpts) -> anyhow::Result<PublishReport> {
+    if opts.sign_key_file.is_some() && opts.cache.is_none() {
+        bail!("Signing the built closures is pointless without a binary cache to copy them to");
+    }
+    if let Some(role_arn) = opts.role_arn.as_deref() {
+        info!(role_arn, "Assuming AWS role using OIDC web identity");
+        let token = github_oidc_token(&opts.oidc_audience)?;
+        assume_role_with_web_identity(
+            role_arn,
+            &opts.role_session_name,
+            &token,
+            opts.remote_region.as_deref(),
+        )?
+        .export_to_env();
+    }
+
+    if opts.check {
+        info!(src = %opts.src.display(), "Checking flake");
+        flake_check(&opts.src)?;
+    }
+
+    let mut built = vec![];
+    for configuration in &opts.build {
+        info!(configuration, "Building configuration");
+        let store_path = build_configuration(&opts.src, configuration)?;
+        built.push(PublishedConfiguration {
+            configuration: configuration.clone(),
+            store_path,
+        });
+    }
+
+    let store_paths: Vec<_> = built.iter().map(|b| b.store_path.clone()).collect();
+    if let Some(key_file) = opts.sign_key_file.as_deref() {
+        info!("Signing built closures");
+        sign_store_paths(&store_paths, key_file)?;
+    }
+    if let Some(cache) = opts.cache.as_deref() {
+        info!(cache, "Copying built closures to the binary cache");
+        copy_store_paths(&store_paths, cache)?;
+    }
+
+    crate::push(&opts.src, &opts.include, &opts.remote, &opts.push)?;
+    let etag = crate::get_etag(
+        &opts.remote,
+        &Config::default().with_remote_region(opts.remote_region.as_deref()),
+    )?;
+    annotate_notice(format!("Published {} (etag: {etag})", opts.remote));
+
+    if let Some(promote_to) = opts.promote_to.as_ref() {
+        info!(from = %opts.remote, to = %promote_to, "Promoting");
+        promote(&opts.remote, promote_to)?;
+        annotate_notice(format!("Promoted {} to {promote_to}", opts.remote));
+    }
+
+    Ok(PublishReport {
+        remote: opts.remote.clone(),
+        etag,
+        built,
+        signed: opts.sign_key_file.is_some(),
+        cached_to: opts.cache.clone(),
+        promoted_to: opts.promote_to.clone(),
+    })
+}
@@ -0,0 +1,51 @@
+//! Pre-built system closure references (`.npcnix-closure.json`)
+//!
+//! An archive containing a closure reference activates the referenced system
+//! directly, without evaluating any flake. The closure must be available in
+//! the local store or one of the configured substituters.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+pub const CLOSURE_FILE_NAME: &str = ".npcnix-closure.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ClosureRef {
+    /// Store path of the NixOS system (`config.system.build.toplevel`)
+    pub store_path: PathBuf,
+}
+
+impl ClosureRef {
+    pub fn new(store_path: &Path) -> anyhow::Result<Self> {
+        if !store_path.starts_with("/nix/store") {
+            bail!("Not a Nix store path: {}", store_path.display());
+        }
+        Ok(Self {
+            store_path: store_path.to_owned(),
+        })
+    }
+
+    /// Load the closure reference from an unpacked archive in `dir`, if
+    /// present
+    pub fn load_from(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let path = dir.join(CLOSURE_FILE_NAME);
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+        let closure: Self = serde_json::from_reader(fs::File::open(&path)?)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        Ok(Some(Self::new(&closure.store_path)?))
+    }
+
+    pub fn write_to(&self, dir: &Path) -> anyhow::Result<()> {
+        fs::write(
+            dir.join(CLOSURE_FILE_NAME),
+            serde_json::to_vec_pretty(self)?,
+        )?;
+        Ok(())
+    }
+}
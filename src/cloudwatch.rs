@@ -0,0 +1,87 @@
+//! Per-cycle daemon metrics published to CloudWatch, using the `aws` cli
+
+use std::io::Write;
+use std::process;
+use std::time;
+
+use anyhow::{bail, Context};
+use serde_json::json;
+
+use crate::config::{CloudWatchOpts, Config};
+use crate::{aws_cli_path, misc, CommandExt, CycleOutcome};
+
+/// Publish the metrics of a daemon cycle that took `duration`
+///
+/// Metrics have `Configuration` and `Host` dimensions. `DriftAgeSeconds` is
+/// the time since the host last converged: zero, unless activating the
+/// current remote failed.
+pub fn publish(
+    opts: &CloudWatchOpts,
+    config: &Config,
+    outcome: &CycleOutcome,
+    duration: time::Duration,
+) -> anyhow::Result<()> {
+    let dimensions = json!([
+        {"Name": "Configuration", "Value": config.configuration().unwrap_or("unknown")},
+        {"Name": "Host", "Value": misc::hostname().unwrap_or_else(|| "unknown".into())},
+    ]);
+    let drift_age = match outcome.error() {
+        Some(_) => (chrono::Utc::now() - config.last_reconfiguration())
+            .to_std()
+            .unwrap_or_default(),
+        None => time::Duration::ZERO,
+    };
+    let mut metric_data = vec![
+        json!({
+            "MetricName": "CycleSuccess",
+            "Dimensions": dimensions,
+            "Value": u8::from(outcome.error().is_none()),
+            "Unit": "Count",
+        }),
+        json!({
+            "MetricName": "Activated",
+            "Dimensions": dimensions,
+            "Value": u8::from(matches!(outcome, CycleOutcome::Changed { .. })),
+            "Unit": "Count",
+        }),
+        json!({
+            "MetricName": "DriftAgeSeconds",
+            "Dimensions": dimensions,
+            "Value": drift_age.as_secs(),
+            "Unit": "Seconds",
+        }),
+    ];
+    if !matches!(outcome, CycleOutcome::Unchanged(_)) {
+        metric_data.push(json!({
+            "MetricName": "ActivationDurationSeconds",
+            "Dimensions": dimensions,
+            "Value": duration.as_secs_f64(),
+            "Unit": "Seconds",
+        }));
+    }
+
+    let mut metric_data_file = tempfile::NamedTempFile::new()?;
+    serde_json::to_writer(&mut metric_data_file, &metric_data)?;
+    metric_data_file.flush()?;
+
+    let mut cmd = process::Command::new(aws_cli_path());
+    cmd.args([
+        "cloudwatch",
+        "put-metric-data",
+        "--namespace",
+        &opts.namespace,
+    ])
+    .arg("--metric-data")
+    .arg(format!("file://{}", metric_data_file.path().display()));
+    if let Some(region) = opts.region.as_deref().or(config.region_opt()) {
+        cmd.args(["--region", region]);
+    }
+    let status = cmd.log_debug().status().context("`aws` cli failed")?;
+    if !status.success() {
+        bail!(
+            "aws cloudwatch put-metric-data returned exit code={:?}",
+            status.code()
+        );
+    }
+    Ok(())
+}
@@ -1,12 +1,32 @@
-use std::path::Path;
-use std::{cmp, fmt, thread};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::{cmp, fmt, fs, thread};
 
-use anyhow::format_err;
+use anyhow::{bail, format_err, Context};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, warn};
 use url::Url;
 
+use crate::activation::{ActivationBackend, ActivationMode, Escalation, Generation};
+use crate::approval::ApprovalOpts;
+use crate::archive::UnpackLimits;
+use crate::channel;
+use crate::coordination::CoordinationOpts;
+use crate::error::NpcnixError;
+use crate::gc::GcOpts;
+use crate::health::HealthCheckOpts;
+use crate::misc;
+use crate::notify::WebhookOpts;
+use crate::push_checks::PushChecks;
+use crate::remote_settings::RemoteSettings;
+use crate::retry::FailureBackoffOpts;
+use crate::retry::RetryOpts;
+use crate::s3::MultipartOpts;
+use crate::schedule::ActivationWindow;
+use crate::secrets;
+
 fn default_min_sleep_secs() -> u64 {
     5
 }
@@ -15,6 +35,105 @@ fn default_max_sleep_secs() -> u64 {
     120
 }
 
+const MAX_RECORDED_GENERATIONS: usize = 50;
+
+/// A failed activation of a remote etag
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ActivationFailure {
+    pub configuration: String,
+    pub etag: String,
+    /// Number of consecutive failures
+    pub count: u32,
+    pub error: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl ActivationFailure {
+    pub fn is_for(&self, configuration: &str, etag: &str) -> bool {
+        self.configuration == configuration && self.etag == etag
+    }
+}
+
+/// A remote etag first seen by the daemon, for the staggered rollout
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct SeenRemote {
+    pub configuration: String,
+    pub etag: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl SeenRemote {
+    pub fn is_for(&self, configuration: &str, etag: &str) -> bool {
+        self.configuration == configuration && self.etag == etag
+    }
+}
+
+/// A remote downloaded in staged mode, waiting to be activated
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PendingUpdate {
+    pub configuration: String,
+    pub etag: String,
+    /// When it was staged
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// The daemon can activate it
+    #[serde(default)]
+    pub approved: bool,
+}
+
+impl PendingUpdate {
+    pub fn is_for(&self, configuration: &str, etag: &str) -> bool {
+        self.configuration == configuration && self.etag == etag
+    }
+}
+
+impl fmt::Display for PendingUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (etag {}, staged {}, {})",
+            self.configuration,
+            self.etag,
+            self.timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            if self.approved {
+                "approved"
+            } else {
+                "waiting for approval"
+            }
+        )
+    }
+}
+
+/// Format of the logs of npcnix itself
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+fn default_check_on_start() -> bool {
+    true
+}
+
+fn default_reconverge() -> bool {
+    true
+}
+
+fn default_activation_logs_keep() -> usize {
+    20
+}
+
+fn default_history_keep() -> usize {
+    1000
+}
+
 fn default_max_sleep_after_hours() -> u64 {
     24
 }
@@ -41,33 +160,347 @@ impl ConfigPaused {
         }
     }
 
-    fn is_expired(self) -> bool {
+    fn is_expired(self, now: chrono::DateTime<Utc>) -> bool {
         match self {
             ConfigPaused::Indefinitely => false,
-            ConfigPaused::Until { until } => until <= Utc::now(),
+            ConfigPaused::Until { until } => until <= now,
+        }
+    }
+}
+
+fn default_namespace() -> String {
+    "npcnix".into()
+}
+
+/// Publishing of the daemon metrics to CloudWatch (see `npcnix::cloudwatch`)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct CloudWatchOpts {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    /// Region to publish to (by default the remote region)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+impl Default for CloudWatchOpts {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            namespace: default_namespace(),
+            region: None,
+        }
+    }
+}
+
+/// Configuration used when none is set: `<prefix><hostname><suffix>`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct HostnameConfiguration {
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub suffix: String,
+}
+
+impl HostnameConfiguration {
+    pub fn is_default(&self) -> bool {
+        self.prefix.is_empty() && self.suffix.is_empty()
+    }
+
+    pub fn configuration(&self, hostname: &str) -> String {
+        format!("{}{hostname}{}", self.prefix, self.suffix)
+    }
+}
+
+/// Where the value of a setting comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingOrigin {
+    /// Built-in default
+    Default,
+    File(PathBuf),
+    Profile(String),
+    /// Environment variable, overriding the setting where it's used
+    Env(String),
+}
+
+impl fmt::Display for SettingOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingOrigin::Default => f.write_str("default"),
+            SettingOrigin::File(path) => write!(f, "{}", path.display()),
+            SettingOrigin::Profile(profile) => write!(f, "profile {profile}"),
+            SettingOrigin::Env(var) => write!(f, "env {var}"),
         }
     }
 }
 
-/// Persistent config (`/var/lib/npcnix/config.json`)
+/// Where [`Config`] is loaded from, see [`Config::load_from`]
+#[derive(Debug, Clone)]
+pub struct ConfigSources {
+    /// Settings managed with `npcnix config`
+    pub settings: PathBuf,
+    /// TOML base settings, in order of precedence
+    pub base: Vec<PathBuf>,
+    /// Runtime state of the daemon
+    pub state: PathBuf,
+    /// Profile overriding the top-level settings
+    pub profile: Option<String>,
+    /// Secrets referred to by the settings (see [`crate::secrets`])
+    pub secrets: PathBuf,
+}
+
+/// Fields of [`Config`] owned by the daemon, stored in `state.json` apart from
+/// the settings provided by the operator (`config.json`)
+pub const STATE_FIELDS: &[&str] = &[
+    "last_reconfiguration",
+    "last_etag",
+    "last_configuration",
+    "last_activation_log",
+    "generations",
+    "last_failure",
+    "held_etag",
+    "prebuilt_etag",
+    "pending_update",
+    "last_check",
+    "first_seen",
+    "force_next",
+    "expected_system",
+    "paused",
+    "pause_reason",
+    "remote_overrides",
+];
+
+/// Persistent config: settings in `/var/lib/npcnix/config.json`, and state in
+/// `/var/lib/npcnix/state.json`
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct Config {
     remote: Option<Url>,
     remote_region: Option<String>,
+    /// Release channel to follow (see [`crate::channel`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channel: Option<String>,
     configuration: Option<String>,
+    /// Derive the configuration from the hostname if it's not set
+    #[serde(default, skip_serializing_if = "HostnameConfiguration::is_default")]
+    hostname_configuration: HostnameConfiguration,
+    #[serde(skip)]
+    hostname_configuration_cache: OnceLock<Option<String>>,
+    #[serde(default)]
     last_reconfiguration: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
     last_etag: String,
+    /// Output of the most recent activation (successful or not)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_activation_log: Option<PathBuf>,
+    /// System generations activated by npcnix, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    generations: Vec<Generation>,
+    /// Most recent failed activation, cleared on success
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_failure: Option<ActivationFailure>,
+    /// Don't activate this remote etag (e.g. after rolling back from it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    held_etag: Option<String>,
+    /// Remote etag already pre-built outside of the activation windows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prebuilt_etag: Option<String>,
+    /// Remote downloaded into the staging directory in staged mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_update: Option<PendingUpdate>,
+    /// Last time the daemon successfully checked the remote
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_check: Option<chrono::DateTime<chrono::Utc>>,
+    /// When the daemon first saw the newest remote etag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    first_seen: Option<SeenRemote>,
+    /// Activate the remote on the next daemon cycle, even if unchanged
+    #[serde(default)]
+    force_next: bool,
+    /// System store path running after the last activation by npcnix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expected_system: Option<PathBuf>,
+    #[serde(default)]
     last_configuration: String,
+    /// Shortest time between the daemon checks, right after a change
     #[serde(default = "default_min_sleep_secs")]
     min_sleep_secs: u64,
+    /// Longest time between the daemon checks
     #[serde(default = "default_max_sleep_secs")]
     max_sleep_secs: u64,
+    /// The time between the checks grows from the min to the max over this
+    /// long since the last change
     #[serde(default = "default_max_sleep_after_hours")]
     max_sleep_after_hours: u64,
+    /// Check the remote right after the daemon starts, instead of sleeping
+    /// first
+    #[serde(default = "default_check_on_start")]
+    check_on_start: bool,
+    /// Delay the first check by a random time up to this, to spread the
+    /// load of a fleet booting at once
+    #[serde(default)]
+    boot_splay_secs: u64,
+    /// Delay activating a newly seen remote etag by `hash(hostname) %` this,
+    /// so a push rolls out gradually over the fleet
+    #[serde(default)]
+    rollout_splay_secs: u64,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     paused: Option<ConfigPaused>,
+    /// Why the daemon is paused (e.g. a change freeze)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pause_reason: Option<String>,
+
+    /// `age` identity file used to decrypt encrypted archives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decrypt_identity: Option<PathBuf>,
+
+    #[serde(default)]
+    unpack_limits: UnpackLimits,
+
+    #[serde(default)]
+    transfer_retry: RetryOpts,
+
+    #[serde(default)]
+    multipart_upload: MultipartOpts,
+
+    /// Checks run before pushing from this machine
+    #[serde(default)]
+    push_checks: PushChecks,
+
+    #[serde(default)]
+    failure_backoff: FailureBackoffOpts,
+
+    #[serde(default)]
+    log_format: LogFormat,
+
+    #[serde(default)]
+    activation_mode: ActivationMode,
+
+    #[serde(default)]
+    activation_backend: ActivationBackend,
+
+    /// Override the configuration to activate (see
+    /// [`crate::activation::flake_target`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    flake_attr: Option<String>,
+
+    /// Delegate building to this host (`nixos-rebuild --build-host`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    build_host: Option<String>,
+
+    #[serde(default)]
+    use_remote_sudo: bool,
+
+    /// Build the system closure with `nix build` first, and only then switch
+    /// to it, instead of calling `nixos-rebuild`
+    #[serde(default)]
+    two_phase_activation: bool,
+
+    /// The daemon only downloads new remotes into the staging directory,
+    /// activating them needs an approval
+    #[serde(default)]
+    staged: bool,
+
+    /// Follow the overrides published next to the remote (see
+    /// [`crate::remote_settings`])
+    #[serde(default)]
+    remote_settings: bool,
+
+    /// Last overrides fetched from the remote
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_overrides: Option<RemoteSettings>,
+
+    #[serde(default)]
+    cloudwatch: CloudWatchOpts,
+
+    /// Notify these webhooks about activation outcomes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    webhooks: Vec<WebhookOpts>,
+    #[serde(skip)]
+    secrets_file: Option<PathBuf>,
+
+    /// Write Prometheus metrics to this file after every daemon cycle (for the
+    /// node exporter textfile collector)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metrics_textfile: Option<PathBuf>,
+
+    /// Upload the host status to `<prefix>/<hostname>.json` after every
+    /// daemon cycle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status_report_prefix: Option<Url>,
+
+    /// Only activate remote etags approved in the bucket (see
+    /// [`crate::approval`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    approval: Option<ApprovalOpts>,
+
+    /// Limit the number of hosts activating at once (see
+    /// [`crate::coordination`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    coordination: Option<CoordinationOpts>,
+
+    /// The daemon only switches to new configurations inside these windows
+    /// (if any)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    activation_windows: Vec<ActivationWindow>,
+
+    /// The daemon never switches configurations inside these windows (but
+    /// still checks and pre-builds them)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    quiet_hours: Vec<ActivationWindow>,
+
+    /// Kill the activation if it takes longer than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    activation_timeout_secs: Option<u64>,
+
+    /// Number of activation logs to keep in the data dir
+    #[serde(default = "default_activation_logs_keep")]
+    activation_logs_keep: usize,
+
+    /// Number of daemon cycles to keep in the history
+    #[serde(default = "default_history_keep")]
+    history_keep: usize,
+
+    /// Re-activate the last remote if the running system is not the one
+    /// activated by npcnix (e.g. after a manual switch)
+    #[serde(default = "default_reconverge")]
+    reconverge: bool,
+
+    /// Run `nix flake check` on the source before activating it
+    #[serde(default)]
+    verify_flake_check: bool,
+
+    /// Extra arguments of `nix flake check` (e.g. `--no-build`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    flake_check_args: Vec<String>,
+
+    /// How to gain root privileges when not running as root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    escalation: Option<Escalation>,
+
+    #[serde(default)]
+    health_check: HealthCheckOpts,
+
+    #[serde(default)]
+    gc: GcOpts,
+
+    /// Passed to `nixos-rebuild` verbatim (e.g. `--impure`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_nixos_rebuild_args: Vec<String>,
+
+    /// Program (and arguments) to run instead of `nixos-rebuild` (see
+    /// [`crate::activation::expand_command_template`])
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    activation_command: Vec<String>,
+
+    /// Named sets of settings overriding the top-level ones, selected with
+    /// `--profile`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    profiles: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
 }
 
 impl Default for Config {
@@ -75,25 +508,310 @@ impl Default for Config {
         Self {
             remote: None,
             remote_region: None,
+            channel: None,
             configuration: None,
+            hostname_configuration: HostnameConfiguration::default(),
+            hostname_configuration_cache: OnceLock::new(),
             last_reconfiguration: chrono::Utc::now(),
             last_etag: "".into(),
+            last_activation_log: None,
+            generations: vec![],
+            last_failure: None,
+            held_etag: None,
+            prebuilt_etag: None,
+            pending_update: None,
+            last_check: None,
+            first_seen: None,
+            force_next: false,
+            expected_system: None,
             last_configuration: "".into(),
             min_sleep_secs: default_min_sleep_secs(),
             max_sleep_secs: default_max_sleep_secs(),
             max_sleep_after_hours: default_max_sleep_after_hours(),
+            check_on_start: default_check_on_start(),
+            boot_splay_secs: 0,
+            rollout_splay_secs: 0,
             paused: None,
+            pause_reason: None,
+            decrypt_identity: None,
+            unpack_limits: UnpackLimits::default(),
+            transfer_retry: RetryOpts::default(),
+            multipart_upload: MultipartOpts::default(),
+            push_checks: PushChecks::default(),
+            failure_backoff: FailureBackoffOpts::default(),
+            log_format: LogFormat::default(),
+            activation_mode: ActivationMode::default(),
+            activation_backend: ActivationBackend::default(),
+            flake_attr: None,
+            build_host: None,
+            use_remote_sudo: false,
+            two_phase_activation: false,
+            staged: false,
+            remote_settings: false,
+            remote_overrides: None,
+            cloudwatch: CloudWatchOpts::default(),
+            webhooks: vec![],
+            secrets_file: None,
+            metrics_textfile: None,
+            status_report_prefix: None,
+            approval: None,
+            coordination: None,
+            activation_windows: vec![],
+            quiet_hours: vec![],
+            activation_timeout_secs: None,
+            activation_logs_keep: default_activation_logs_keep(),
+            history_keep: default_history_keep(),
+            reconverge: default_reconverge(),
+            verify_flake_check: false,
+            flake_check_args: vec![],
+            escalation: None,
+            health_check: HealthCheckOpts::default(),
+            gc: GcOpts::default(),
+            extra_nixos_rebuild_args: vec![],
+            activation_command: vec![],
+            profiles: BTreeMap::new(),
         }
     }
 }
 
 impl Config {
-    pub fn load(path: &Path) -> anyhow::Result<Self> {
+    pub fn load(path: &Path) -> Result<Self, NpcnixError> {
         Ok(serde_json::from_reader::<_, Self>(std::fs::File::open(path)?)?.expire_paused())
     }
 
-    pub fn store(&self, path: &Path) -> anyhow::Result<()> {
-        crate::misc::store_json_pretty_to_file(path, &self.clone().expire_paused())
+    pub fn store(&self, path: &Path) -> Result<(), NpcnixError> {
+        Ok(crate::misc::store_json_pretty_to_file(
+            path,
+            &self.clone().expire_paused(),
+        )?)
+    }
+
+    /// The settings as layered: the TOML base files, overridden by the
+    /// settings file, where a `null` resets a setting to its default
+    fn merged_settings(
+        sources: &ConfigSources,
+    ) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+        let mut merged = serde_json::Map::new();
+        for (_, layer) in read_base_layers(&sources.base)? {
+            merged.extend(layer);
+        }
+        for (key, value) in read_settings_layer(&sources.settings, &sources.state)? {
+            if value.is_null() {
+                merged.remove(&key);
+            } else {
+                merged.insert(key, value);
+            }
+        }
+        Ok(merged)
+    }
+
+    fn profile_overrides(
+        merged: &serde_json::Map<String, serde_json::Value>,
+        profile: &str,
+    ) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+        let overrides = merged
+            .get("profiles")
+            .and_then(|profiles| profiles.get(profile))
+            .and_then(serde_json::Value::as_object)
+            .cloned()
+            .ok_or_else(|| format_err!("Profile `{profile}` not found"))?;
+        if let Some(key) = overrides
+            .keys()
+            .find(|key| *key == "profiles" || STATE_FIELDS.contains(&key.as_str()))
+        {
+            bail!("`{key}` can't be set in a profile");
+        }
+        Ok(overrides)
+    }
+
+    /// The settings, without the state (see [`STATE_FIELDS`])
+    pub fn settings(&self) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+        let mut settings = self.serialized_settings()?;
+        settings.retain(|key, _| !STATE_FIELDS.contains(&key.as_str()));
+        Ok(settings)
+    }
+
+    fn serialized_settings(&self) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+        let serde_json::Value::Object(settings) =
+            serde_json::to_value(self.clone().expire_paused())?
+        else {
+            unreachable!("config is serialized as an object");
+        };
+        Ok(settings)
+    }
+
+    /// Load the settings and the state
+    ///
+    /// The settings are layered, each layer overriding the previous ones:
+    /// the built-in defaults, the TOML base files (e.g. provided by the
+    /// system image), the settings file and the selected profile. Without a
+    /// state file (e.g. before the split), the state is read from the settings
+    /// file.
+    pub fn load_from(sources: &ConfigSources) -> Result<Self, NpcnixError> {
+        let config = Self::load_unchecked(sources)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like [`Self::load_from`], without validating the config (e.g. to fix
+    /// it)
+    pub fn load_unchecked(sources: &ConfigSources) -> Result<Self, NpcnixError> {
+        let mut merged = Self::merged_settings(sources)?;
+        if let Some(ref profile) = sources.profile {
+            for (key, value) in Self::profile_overrides(&merged, profile)? {
+                if value.is_null() {
+                    merged.remove(&key);
+                } else {
+                    merged.insert(key, value);
+                }
+            }
+        }
+        let state_path = &sources.state;
+        if state_path.exists() {
+            match read_json_object(state_path) {
+                Ok(state) => merged.extend(state),
+                Err(e) => {
+                    // the state is only the daemon's bookkeeping: better start
+                    // over than refuse to run until it's repaired by hand
+                    let corrupt_path = state_path.with_extension("json.corrupt");
+                    warn!(
+                        error = %e,
+                        path = %state_path.display(),
+                        moved_to = %corrupt_path.display(),
+                        "Corrupted state, discarding it"
+                    );
+                    fs::rename(state_path, &corrupt_path)
+                        .with_context(|| format!("Failed to move away {}", state_path.display()))?;
+                }
+            }
+        }
+        let config = serde_json::from_value::<Self>(serde_json::Value::Object(merged))?;
+        Ok(Self {
+            secrets_file: Some(sources.secrets.clone()),
+            ..config
+        }
+        .expire_paused())
+    }
+
+    /// Store the state, and the settings only if they changed (so the
+    /// settings file can be read-only)
+    ///
+    /// Settings with the value given by the base files are left out, so
+    /// changing them there takes effect. With a profile selected, the changed
+    /// settings are stored in the profile.
+    pub fn store_to(&self, sources: &ConfigSources) -> Result<(), NpcnixError> {
+        let mut settings = self.serialized_settings()?;
+        let state: serde_json::Map<_, _> = STATE_FIELDS
+            .iter()
+            .filter_map(|key| settings.remove_entry(*key))
+            .collect();
+
+        if let Some(ref profile) = sources.profile {
+            let unprofiled = serde_json::from_value::<Self>(serde_json::Value::Object(
+                Self::merged_settings(sources)?,
+            ))?
+            .serialized_settings()?;
+            let mut overrides = Self::profile_overrides(&settings, profile)?;
+            let keys: std::collections::BTreeSet<_> = settings
+                .keys()
+                .chain(unprofiled.keys())
+                .filter(|key| *key != "profiles" && !STATE_FIELDS.contains(&key.as_str()))
+                .cloned()
+                .collect();
+            for key in keys {
+                let value = settings.get(&key).cloned();
+                let unprofiled_value = unprofiled.get(&key).cloned();
+                if overrides.contains_key(&key) || value != unprofiled_value {
+                    overrides.insert(key.clone(), value.unwrap_or(serde_json::Value::Null));
+                }
+                match unprofiled_value {
+                    Some(unprofiled_value) => settings.insert(key, unprofiled_value),
+                    None => settings.remove(&key),
+                };
+            }
+            if let Some(profiles) = settings
+                .get_mut("profiles")
+                .and_then(serde_json::Value::as_object_mut)
+            {
+                profiles.insert(profile.clone(), serde_json::Value::Object(overrides));
+            }
+        }
+
+        let base: serde_json::Map<_, _> = read_base_layers(&sources.base)?
+            .into_iter()
+            .flat_map(|(_, layer)| layer)
+            .collect();
+        if !base.is_empty() {
+            // as serialized, to compare with `settings`
+            let normalized =
+                serde_json::from_value::<Self>(serde_json::Value::Object(base.clone()))
+                    .context("Invalid base settings")?
+                    .serialized_settings()?;
+            for key in base.keys() {
+                match (settings.get(key), normalized.get(key)) {
+                    (Some(value), Some(base_value)) if value == base_value => {
+                        settings.remove(key);
+                    }
+                    // reset to the default (not serialized)
+                    (None, Some(_)) => {
+                        settings.insert(key.clone(), serde_json::Value::Null);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        crate::misc::store_json_pretty_to_file(&sources.state, &state)
+            .with_context(|| format!("Failed to store {}", sources.state.display()))?;
+
+        let settings_path = &sources.settings;
+        let current = if settings_path.exists() {
+            let mut current = read_json_object(settings_path)?;
+            current.retain(|key, _| !STATE_FIELDS.contains(&key.as_str()));
+            Some(current)
+        } else {
+            None
+        };
+        if current.as_ref() != Some(&settings) {
+            crate::misc::store_json_pretty_to_file(settings_path, &settings)
+                .with_context(|| format!("Failed to store {}", settings_path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Where the value of each setting comes from, see [`Self::load_from`]
+    pub fn setting_origins(
+        sources: &ConfigSources,
+    ) -> Result<BTreeMap<String, SettingOrigin>, NpcnixError> {
+        let mut origins: BTreeMap<_, _> = Self::load_from(sources)?
+            .serialized_settings()?
+            .into_iter()
+            .filter(|(key, _)| !STATE_FIELDS.contains(&key.as_str()))
+            .map(|(key, _)| (key, SettingOrigin::Default))
+            .collect();
+        for (path, layer) in read_base_layers(&sources.base)? {
+            for (key, _) in layer {
+                origins.insert(key, SettingOrigin::File(path.clone()));
+            }
+        }
+        for (key, value) in read_settings_layer(&sources.settings, &sources.state)? {
+            if value.is_null() {
+                origins.insert(key, SettingOrigin::Default);
+            } else {
+                origins.insert(key, SettingOrigin::File(sources.settings.clone()));
+            }
+        }
+        if let Some(ref profile) = sources.profile {
+            for (key, value) in Self::profile_overrides(&Self::merged_settings(sources)?, profile)?
+            {
+                if value.is_null() {
+                    origins.insert(key, SettingOrigin::Default);
+                } else {
+                    origins.insert(key, SettingOrigin::Profile(profile.clone()));
+                }
+            }
+        }
+        Ok(origins)
     }
 
     pub fn expire_paused(self) -> Self {
@@ -102,6 +820,7 @@ impl Config {
         } else {
             Self {
                 paused: None,
+                pause_reason: None,
                 ..self
             }
         }
@@ -124,6 +843,32 @@ impl Config {
         }
     }
 
+    pub fn with_hostname_configuration(
+        self,
+        hostname_configuration: HostnameConfiguration,
+    ) -> Self {
+        Self {
+            hostname_configuration,
+            hostname_configuration_cache: OnceLock::new(),
+            ..self
+        }
+    }
+
+    pub fn without_configuration(self) -> Self {
+        Self {
+            configuration: None,
+            hostname_configuration_cache: OnceLock::new(),
+            ..self
+        }
+    }
+
+    pub fn without_remote(self) -> Self {
+        Self {
+            remote: None,
+            ..self
+        }
+    }
+
     pub fn with_remote(self, remote: &Url) -> Self {
         Self {
             remote: Some(remote.clone()),
@@ -131,6 +876,13 @@ impl Config {
         }
     }
 
+    pub fn with_channel(self, channel: Option<&str>) -> Self {
+        Self {
+            channel: channel.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
     pub fn with_remote_region(self, remote_region: Option<&str>) -> Self {
         Self {
             remote_region: remote_region.map(ToString::to_string),
@@ -138,120 +890,1010 @@ impl Config {
         }
     }
 
-    pub fn with_paused_until(self, until: chrono::DateTime<chrono::Utc>) -> Self {
-        let until = ConfigPaused::Until { until };
+    pub fn with_decrypt_identity(self, decrypt_identity: Option<&Path>) -> Self {
         Self {
-            paused: Some(
-                self.paused
-                    .map(|current| current.combine(until))
-                    .unwrap_or(until),
-            ),
+            decrypt_identity: decrypt_identity.map(ToOwned::to_owned),
             ..self
         }
     }
 
-    pub fn with_paused_indefinitely(self) -> Self {
+    pub fn with_log_format(self, log_format: LogFormat) -> Self {
+        Self { log_format, ..self }
+    }
+
+    pub fn with_activation_mode(self, activation_mode: ActivationMode) -> Self {
         Self {
-            paused: Some(ConfigPaused::Indefinitely),
+            activation_mode,
             ..self
         }
     }
 
-    pub fn with_unpaused(self) -> Self {
+    pub fn with_activation_backend(self, activation_backend: ActivationBackend) -> Self {
         Self {
-            paused: None,
+            activation_backend,
             ..self
         }
     }
 
-    pub fn is_paused(&self) -> bool {
-        self.paused
-            .map(|paused| !paused.is_expired())
-            .unwrap_or(false)
+    pub fn with_flake_attr(self, flake_attr: Option<&str>) -> Self {
+        Self {
+            flake_attr: flake_attr.map(ToString::to_string),
+            ..self
+        }
     }
 
-    pub fn status_string(&self) -> String {
-        match self.paused {
-            Some(paused) if !paused.is_expired() => match paused {
-                ConfigPaused::Indefinitely => "paused (indefinitely)".to_string(),
-                ConfigPaused::Until { until } => {
-                    let duration = until.signed_duration_since(Utc::now());
-                    format!(
-                        "paused (until {}; <={}h)",
-                        until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
-                        duration.num_hours() + 1
-                    )
-                }
-            },
-            _ => "active".to_string(),
+    pub fn with_build_host(self, build_host: Option<&str>) -> Self {
+        Self {
+            build_host: build_host.map(ToString::to_string),
+            ..self
         }
     }
 
-    /// Like [`Self:with_remote`] but if `init` is `true` will not overwrite the
-    /// existing value
-    pub fn with_remote_maybe_init(self, remote: &Url, init: bool) -> Self {
-        if !init || self.remote.is_none() {
-            self.with_remote(remote)
-        } else {
-            self
+    pub fn with_use_remote_sudo(self, use_remote_sudo: bool) -> Self {
+        Self {
+            use_remote_sudo,
+            ..self
         }
     }
 
-    pub fn with_updated_last_reconfiguration(self, configuration: &str, etag: &str) -> Self {
+    pub fn with_two_phase_activation(self, two_phase_activation: bool) -> Self {
         Self {
-            last_configuration: configuration.to_owned(),
-            last_etag: etag.to_owned(),
-            last_reconfiguration: chrono::Utc::now(),
+            two_phase_activation,
             ..self
         }
     }
 
-    pub fn remote(&self) -> anyhow::Result<&Url> {
-        self.remote
-            .as_ref()
-            .ok_or_else(|| format_err!("Remote not set"))
+    pub fn with_quiet_hours(self, quiet_hours: Vec<ActivationWindow>) -> Self {
+        Self {
+            quiet_hours,
+            ..self
+        }
     }
 
-    pub fn region_opt(&self) -> Option<&str> {
-        self.remote_region.as_deref()
+    pub fn with_activation_windows(self, activation_windows: Vec<ActivationWindow>) -> Self {
+        Self {
+            activation_windows,
+            ..self
+        }
     }
 
-    pub fn configuration(&self) -> anyhow::Result<&str> {
-        self.configuration
-            .as_deref()
-            .ok_or_else(|| format_err!("configuration not set"))
+    pub fn with_cloudwatch(self, cloudwatch: CloudWatchOpts) -> Self {
+        Self { cloudwatch, ..self }
     }
 
-    pub fn cur_rng_sleep_time(&self) -> chrono::Duration {
-        use rand::Rng;
+    pub fn with_webhooks(self, webhooks: Vec<WebhookOpts>) -> Self {
+        Self { webhooks, ..self }
+    }
 
-        let since_last_update = cmp::max(
-            chrono::Duration::seconds(1),
-            chrono::Utc::now() - self.last_reconfiguration,
-        );
+    pub fn with_metrics_textfile(self, metrics_textfile: Option<PathBuf>) -> Self {
+        Self {
+            metrics_textfile,
+            ..self
+        }
+    }
 
-        let duration_ratio = (since_last_update.num_seconds() as f32
-            / self.max_sleep_after_hours.saturating_mul(60 * 60) as f32)
-            .clamp(0f32, 1f32);
-        assert!(0f32 <= duration_ratio);
+    pub fn with_approval(self, approval: Option<ApprovalOpts>) -> Self {
+        Self { approval, ..self }
+    }
 
-        let avg_duration_secs = (self.min_sleep_secs as f32
-            + duration_ratio * self.max_sleep_secs.saturating_sub(self.min_sleep_secs) as f32)
-            .clamp(0.01, 60f32 * 60f32);
-        let rnd_time =
-            rand::thread_rng().gen_range(avg_duration_secs * 0.5..=avg_duration_secs * 1.5);
-        assert!(0f32 < rnd_time);
+    pub fn with_coordination(self, coordination: Option<CoordinationOpts>) -> Self {
+        Self {
+            coordination,
+            ..self
+        }
+    }
 
-        chrono::Duration::seconds(cmp::max(self.min_sleep_secs as i64, rnd_time as i64))
+    pub fn with_status_report_prefix(self, status_report_prefix: Option<Url>) -> Self {
+        Self {
+            status_report_prefix,
+            ..self
+        }
     }
 
-    pub fn rng_sleep(&self) {
-        let duration = self.cur_rng_sleep_time();
-        debug!(duration = %duration, "Sleeping");
-        thread::sleep(duration.to_std().expect("Can't be negative"));
+    pub fn with_staged(self, staged: bool) -> Self {
+        Self { staged, ..self }
     }
 
-    pub fn last_configuration(&self) -> &str {
+    pub fn with_remote_settings(self, remote_settings: bool) -> Self {
+        Self {
+            remote_settings,
+            ..self
+        }
+    }
+
+    pub fn with_remote_overrides(self, remote_overrides: Option<RemoteSettings>) -> Self {
+        Self {
+            remote_overrides,
+            ..self
+        }
+    }
+
+    pub fn with_pending_update(self, pending_update: Option<PendingUpdate>) -> Self {
+        Self {
+            pending_update,
+            ..self
+        }
+    }
+
+    pub fn with_prebuilt_etag(self, prebuilt_etag: Option<&str>) -> Self {
+        Self {
+            prebuilt_etag: prebuilt_etag.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    pub fn with_activation_timeout_secs(self, activation_timeout_secs: Option<u64>) -> Self {
+        Self {
+            activation_timeout_secs,
+            ..self
+        }
+    }
+
+    pub fn with_history_keep(self, history_keep: usize) -> Self {
+        Self {
+            history_keep,
+            ..self
+        }
+    }
+
+    pub fn with_activation_logs_keep(self, activation_logs_keep: usize) -> Self {
+        Self {
+            activation_logs_keep,
+            ..self
+        }
+    }
+
+    pub fn with_expected_system(self, expected_system: Option<&Path>) -> Self {
+        Self {
+            expected_system: expected_system.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    pub fn with_last_check(self, last_check: chrono::DateTime<chrono::Utc>) -> Self {
+        Self {
+            last_check: Some(last_check),
+            ..self
+        }
+    }
+
+    /// Set the range of the time between the daemon checks
+    pub fn with_sleep_secs(
+        self,
+        min_sleep_secs: u64,
+        max_sleep_secs: u64,
+    ) -> Result<Self, NpcnixError> {
+        if max_sleep_secs < min_sleep_secs {
+            return Err(NpcnixError::ConfigInvalid(vec![format!(
+                "The min sleep ({min_sleep_secs}s) can't be longer than the max sleep \
+                 ({max_sleep_secs}s)"
+            )]));
+        }
+        if max_sleep_secs == 0 {
+            return Err(NpcnixError::ConfigInvalid(vec![
+                "The max sleep can't be 0".into()
+            ]));
+        }
+        Ok(Self {
+            min_sleep_secs,
+            max_sleep_secs,
+            ..self
+        })
+    }
+
+    /// Like [`Self::with_sleep_secs`], keeping the max sleep set locally
+    pub fn with_min_sleep_secs(self, min_sleep_secs: u64) -> Result<Self, NpcnixError> {
+        let max_sleep_secs = self.max_sleep_secs;
+        self.with_sleep_secs(min_sleep_secs, max_sleep_secs)
+    }
+
+    /// Like [`Self::with_sleep_secs`], keeping the min sleep set locally
+    pub fn with_max_sleep_secs(self, max_sleep_secs: u64) -> Result<Self, NpcnixError> {
+        let min_sleep_secs = self.min_sleep_secs;
+        self.with_sleep_secs(min_sleep_secs, max_sleep_secs)
+    }
+
+    pub fn with_max_sleep_after_hours(self, max_sleep_after_hours: u64) -> Self {
+        Self {
+            max_sleep_after_hours,
+            ..self
+        }
+    }
+
+    pub fn with_check_on_start(self, check_on_start: bool) -> Self {
+        Self {
+            check_on_start,
+            ..self
+        }
+    }
+
+    pub fn with_rollout_splay_secs(self, rollout_splay_secs: u64) -> Self {
+        Self {
+            rollout_splay_secs,
+            ..self
+        }
+    }
+
+    /// Record `now` as the time `etag` was first seen, unless it already is
+    pub fn with_seen_remote(
+        self,
+        configuration: &str,
+        etag: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Self {
+        if self
+            .first_seen
+            .as_ref()
+            .is_some_and(|seen| seen.is_for(configuration, etag))
+        {
+            return self;
+        }
+        Self {
+            first_seen: Some(SeenRemote {
+                configuration: configuration.to_owned(),
+                etag: etag.to_owned(),
+                timestamp: now,
+            }),
+            ..self
+        }
+    }
+
+    pub fn with_boot_splay_secs(self, boot_splay_secs: u64) -> Self {
+        Self {
+            boot_splay_secs,
+            ..self
+        }
+    }
+
+    pub fn with_force_next(self, force_next: bool) -> Self {
+        Self { force_next, ..self }
+    }
+
+    pub fn with_reconverge(self, reconverge: bool) -> Self {
+        Self { reconverge, ..self }
+    }
+
+    pub fn with_last_activation_log(self, last_activation_log: Option<&Path>) -> Self {
+        Self {
+            last_activation_log: last_activation_log.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    pub fn with_recorded_generation(mut self, generation: Generation) -> Self {
+        if self.generations.last().map(|last| last.number) != Some(generation.number) {
+            self.generations.push(generation);
+        }
+        let remove_count = self
+            .generations
+            .len()
+            .saturating_sub(MAX_RECORDED_GENERATIONS);
+        self.generations.drain(..remove_count);
+        self
+    }
+
+    /// Record a failed activation, counting consecutive failures of the same
+    /// etag and configuration
+    pub fn with_activation_failure(self, configuration: &str, etag: &str, error: &str) -> Self {
+        let count = match self.last_failure {
+            Some(ref failure) if failure.is_for(configuration, etag) => failure.count + 1,
+            _ => 1,
+        };
+        Self {
+            last_failure: Some(ActivationFailure {
+                configuration: configuration.to_owned(),
+                etag: etag.to_owned(),
+                count,
+                error: error.to_owned(),
+                timestamp: chrono::Utc::now(),
+            }),
+            ..self
+        }
+    }
+
+    /// Forget the failed activations, so the quarantined etag is retried
+    pub fn with_unquarantined(self) -> Self {
+        Self {
+            last_failure: None,
+            ..self
+        }
+    }
+
+    pub fn with_failure_backoff(self, failure_backoff: FailureBackoffOpts) -> Self {
+        Self {
+            failure_backoff,
+            ..self
+        }
+    }
+
+    pub fn with_held_etag(self, held_etag: Option<&str>) -> Self {
+        Self {
+            held_etag: held_etag.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    pub fn with_gc(self, gc: GcOpts) -> Self {
+        Self { gc, ..self }
+    }
+
+    pub fn with_flake_check(self, verify_flake_check: bool, flake_check_args: Vec<String>) -> Self {
+        Self {
+            verify_flake_check,
+            flake_check_args,
+            ..self
+        }
+    }
+
+    pub fn with_escalation(self, escalation: Option<Escalation>) -> Self {
+        Self { escalation, ..self }
+    }
+
+    pub fn with_health_check(self, health_check: HealthCheckOpts) -> Self {
+        Self {
+            health_check,
+            ..self
+        }
+    }
+
+    pub fn with_extra_nixos_rebuild_args(self, extra_nixos_rebuild_args: Vec<String>) -> Self {
+        Self {
+            extra_nixos_rebuild_args,
+            ..self
+        }
+    }
+
+    pub fn with_activation_command(self, activation_command: Vec<String>) -> Self {
+        Self {
+            activation_command,
+            ..self
+        }
+    }
+
+    /// Add an empty profile, if not there yet
+    pub fn with_profile(self, name: &str) -> Self {
+        let mut profiles = self.profiles;
+        profiles.entry(name.to_owned()).or_default();
+        Self { profiles, ..self }
+    }
+
+    pub fn without_profile(self, name: &str) -> Self {
+        let mut profiles = self.profiles;
+        profiles.remove(name);
+        Self { profiles, ..self }
+    }
+
+    pub fn with_paused_until(self, until: chrono::DateTime<chrono::Utc>) -> Self {
+        let until = ConfigPaused::Until { until };
+        Self {
+            paused: Some(
+                self.paused
+                    .map(|current| current.combine(until))
+                    .unwrap_or(until),
+            ),
+            ..self
+        }
+    }
+
+    pub fn with_paused_indefinitely(self) -> Self {
+        Self {
+            paused: Some(ConfigPaused::Indefinitely),
+            ..self
+        }
+    }
+
+    pub fn with_unpaused(self) -> Self {
+        Self {
+            paused: None,
+            pause_reason: None,
+            ..self
+        }
+    }
+
+    /// Replace the reason of the pause, if any
+    pub fn with_pause_reason(self, pause_reason: Option<&str>) -> Self {
+        Self {
+            pause_reason: pause_reason.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    pub fn pause_reason(&self) -> Option<&str> {
+        self.pause_reason.as_deref()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.is_paused_at(Utc::now())
+    }
+
+    pub fn is_paused_at(&self, now: chrono::DateTime<Utc>) -> bool {
+        self.paused
+            .map(|paused| !paused.is_expired(now))
+            .unwrap_or(false)
+    }
+
+    pub fn status_string(&self) -> String {
+        let status = match self.paused {
+            Some(paused) if !paused.is_expired(Utc::now()) => match paused {
+                ConfigPaused::Indefinitely => "paused (indefinitely)".to_string(),
+                ConfigPaused::Until { until } => {
+                    let duration = until.signed_duration_since(Utc::now());
+                    format!(
+                        "paused (until {}; <={}h)",
+                        until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                        duration.num_hours() + 1
+                    )
+                }
+            },
+            _ => return "active".to_string(),
+        };
+        match &self.pause_reason {
+            Some(reason) => format!("{status}: {reason}"),
+            None => status,
+        }
+    }
+
+    /// Like [`Self:with_remote`] but if `init` is `true` will not overwrite the
+    /// existing value
+    pub fn with_remote_maybe_init(self, remote: &Url, init: bool) -> Self {
+        if !init || self.remote.is_none() {
+            self.with_remote(remote)
+        } else {
+            self
+        }
+    }
+
+    pub fn with_updated_last_reconfiguration(self, configuration: &str, etag: &str) -> Self {
+        Self {
+            last_configuration: configuration.to_owned(),
+            last_etag: etag.to_owned(),
+            last_reconfiguration: chrono::Utc::now(),
+            last_failure: None,
+            pending_update: self
+                .pending_update
+                .filter(|pending| !pending.is_for(configuration, etag)),
+            ..self
+        }
+    }
+
+    pub fn remote(&self) -> Result<&Url, NpcnixError> {
+        self.remote
+            .as_ref()
+            .ok_or_else(|| NpcnixError::NotConfigured("Remote not set".into()))
+    }
+
+    pub fn channel(&self) -> Option<&str> {
+        self.channel.as_deref()
+    }
+
+    /// The remote with the placeholders expanded, in the subscribed channel
+    /// (if any)
+    pub fn effective_remote(&self) -> Result<Url, NpcnixError> {
+        let remote = self.expanded_remote()?;
+        match self.channel.as_deref() {
+            Some(channel) => Ok(channel::channel_url(&remote, channel)?),
+            None => Ok(remote),
+        }
+    }
+
+    /// The remote with the `{hostname}`, `{configuration}` and `{env:VAR}`
+    /// placeholders expanded
+    pub fn expanded_remote(&self) -> Result<Url, NpcnixError> {
+        let remote = self.remote()?;
+        // `{` and `}` get percent-encoded in the path
+        let template = remote.as_str().replace("%7B", "{").replace("%7D", "}");
+        if !template.contains('{') {
+            return Ok(remote.clone());
+        }
+        let expanded = misc::expand_placeholders(&template, |name| match name {
+            "hostname" => misc::hostname().ok_or_else(|| format_err!("Failed to get the hostname")),
+            "configuration" => Ok(self.configuration()?.to_owned()),
+            _ => match name.strip_prefix("env:") {
+                Some(var) => std::env::var(var)
+                    .map_err(|_| format_err!("Environment variable {var} not set")),
+                None => Err(format_err!("Unknown placeholder in the remote: {{{name}}}")),
+            },
+        })?;
+        Url::parse(&expanded).map_err(|e| {
+            NpcnixError::ConfigInvalid(vec![format!("Invalid remote {expanded}: {e}")])
+        })
+    }
+
+    /// Everything wrong with the settings, as actionable messages
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(ref remote) = self.remote {
+            if remote.scheme() != "s3" {
+                problems.push(format!(
+                    "Unsupported remote {remote}: only `s3://` is supported, fix it with \
+                     `npcnix config set remote`"
+                ));
+            } else if remote.host_str().unwrap_or_default().is_empty()
+                || remote.path().trim_matches('/').is_empty()
+            {
+                problems.push(format!(
+                    "Invalid remote {remote}: expected `s3://<bucket>/<key>`, fix it with \
+                     `npcnix config set remote`"
+                ));
+            }
+        }
+        if let Some(ref configuration) = self.configuration {
+            if let Some(problem) = configuration_name_problem(configuration) {
+                problems.push(format!(
+                    "{problem}, fix it with `npcnix config set configuration`"
+                ));
+            }
+        }
+        if let Some(ref channel) = self.channel {
+            if channel.is_empty() || channel.contains('/') {
+                problems.push(format!(
+                    "Invalid channel `{channel}`: must be a non-empty name without `/`, fix it \
+                     with `npcnix config set channel`"
+                ));
+            }
+        }
+        if self.max_sleep_secs < self.min_sleep_secs {
+            problems.push(format!(
+                "`min_sleep_secs` ({}) is greater than `max_sleep_secs` ({}), fix them with \
+                 `npcnix config set min-sleep` / `max-sleep`",
+                self.min_sleep_secs, self.max_sleep_secs
+            ));
+        }
+        if self.max_sleep_secs == 0 {
+            problems
+                .push("`max_sleep_secs` is 0, fix it with `npcnix config set max-sleep`".into());
+        }
+        for (name, prefix) in [
+            ("status_report_prefix", self.status_report_prefix.as_ref()),
+            (
+                "coordination.prefix",
+                self.coordination
+                    .as_ref()
+                    .map(|coordination| &coordination.prefix),
+            ),
+        ] {
+            if let Some(prefix) = prefix {
+                if prefix.scheme() != "s3" {
+                    problems.push(format!(
+                        "Unsupported `{name}` {prefix}: only `s3://` is supported"
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Fail with all the [`Self::problems`], if any
+    pub fn validate(&self) -> Result<(), NpcnixError> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(NpcnixError::ConfigInvalid(problems))
+    }
+
+    pub fn region_opt(&self) -> Option<&str> {
+        self.remote_region.as_deref()
+    }
+
+    pub fn decrypt_identity(&self) -> Option<&Path> {
+        self.decrypt_identity.as_deref()
+    }
+
+    pub fn unpack_limits(&self) -> UnpackLimits {
+        self.unpack_limits
+    }
+
+    pub fn transfer_retry(&self) -> RetryOpts {
+        self.transfer_retry
+    }
+
+    pub fn multipart_upload(&self) -> MultipartOpts {
+        self.multipart_upload
+    }
+
+    pub fn push_checks(&self) -> &PushChecks {
+        &self.push_checks
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format
+    }
+
+    pub fn activation_mode(&self) -> ActivationMode {
+        self.overrides()
+            .and_then(|overrides| overrides.activation_mode)
+            .unwrap_or(self.activation_mode)
+    }
+
+    pub fn activation_backend(&self) -> ActivationBackend {
+        self.activation_backend
+    }
+
+    pub fn flake_attr(&self) -> Option<&str> {
+        self.flake_attr.as_deref()
+    }
+
+    pub fn build_host(&self) -> Option<&str> {
+        self.build_host.as_deref()
+    }
+
+    pub fn use_remote_sudo(&self) -> bool {
+        self.use_remote_sudo
+    }
+
+    pub fn two_phase_activation(&self) -> bool {
+        self.two_phase_activation
+    }
+
+    pub fn cloudwatch(&self) -> &CloudWatchOpts {
+        &self.cloudwatch
+    }
+
+    pub fn webhooks(&self) -> &[WebhookOpts] {
+        &self.webhooks
+    }
+
+    /// Where to resolve the `secret:` references of the settings from, if
+    /// loaded from a data dir
+    pub fn secrets_file(&self) -> Option<&Path> {
+        self.secrets_file.as_deref()
+    }
+
+    /// The config as JSON, without redacting the sensitive settings (unlike
+    /// the `Display` impl)
+    pub fn to_string_with_secrets(&self) -> String {
+        serde_json::to_string_pretty(self).expect("config is serializable")
+    }
+
+    pub fn metrics_textfile(&self) -> Option<&Path> {
+        self.metrics_textfile.as_deref()
+    }
+
+    pub fn approval(&self) -> Option<&ApprovalOpts> {
+        self.approval.as_ref()
+    }
+
+    pub fn coordination(&self) -> Option<&CoordinationOpts> {
+        self.coordination.as_ref()
+    }
+
+    pub fn status_report_prefix(&self) -> Option<&Url> {
+        self.status_report_prefix.as_ref()
+    }
+
+    pub fn remote_settings(&self) -> bool {
+        self.remote_settings
+    }
+
+    pub fn remote_overrides(&self) -> Option<&RemoteSettings> {
+        self.remote_overrides.as_ref()
+    }
+
+    /// The remote overrides, if followed
+    fn overrides(&self) -> Option<&RemoteSettings> {
+        self.remote_overrides
+            .as_ref()
+            .filter(|_| self.remote_settings)
+    }
+
+    pub fn activation_windows(&self) -> &[ActivationWindow] {
+        self.overrides()
+            .and_then(|overrides| overrides.activation_windows.as_deref())
+            .unwrap_or(&self.activation_windows)
+    }
+
+    pub fn quiet_hours(&self) -> &[ActivationWindow] {
+        self.overrides()
+            .and_then(|overrides| overrides.quiet_hours.as_deref())
+            .unwrap_or(&self.quiet_hours)
+    }
+
+    /// Can the daemon switch configurations at `time`: inside of the
+    /// activation windows, and outside of the quiet hours
+    pub fn is_in_activation_window(&self, time: chrono::DateTime<Utc>) -> bool {
+        let activation_windows = self.activation_windows();
+        (activation_windows.is_empty()
+            || activation_windows
+                .iter()
+                .any(|window| window.contains(time)))
+            && !self
+                .quiet_hours()
+                .iter()
+                .any(|window| window.contains(time))
+    }
+
+    pub fn staged(&self) -> bool {
+        self.overrides()
+            .and_then(|overrides| overrides.staged)
+            .unwrap_or(self.staged)
+    }
+
+    pub fn min_sleep_secs(&self) -> u64 {
+        let min_sleep_secs = self
+            .overrides()
+            .and_then(|overrides| overrides.min_sleep_secs)
+            .unwrap_or(self.min_sleep_secs);
+        // with only one bound overridden
+        cmp::min(min_sleep_secs, self.max_sleep_secs())
+    }
+
+    pub fn max_sleep_secs(&self) -> u64 {
+        self.overrides()
+            .and_then(|overrides| overrides.max_sleep_secs)
+            .unwrap_or(self.max_sleep_secs)
+    }
+
+    pub fn max_sleep_after_hours(&self) -> u64 {
+        self.overrides()
+            .and_then(|overrides| overrides.max_sleep_after_hours)
+            .unwrap_or(self.max_sleep_after_hours)
+    }
+
+    pub fn rollout_splay_secs(&self) -> u64 {
+        self.overrides()
+            .and_then(|overrides| overrides.rollout_splay_secs)
+            .unwrap_or(self.rollout_splay_secs)
+    }
+
+    pub fn first_seen(&self) -> Option<&SeenRemote> {
+        self.first_seen.as_ref()
+    }
+
+    /// Delay of this host in the staggered rollout, the same for every etag
+    pub fn rollout_delay(&self) -> std::time::Duration {
+        let rollout_splay_secs = self.rollout_splay_secs();
+        if rollout_splay_secs == 0 {
+            return std::time::Duration::ZERO;
+        }
+        let hostname = misc::hostname().unwrap_or_default();
+        std::time::Duration::from_secs(misc::stable_hash(hostname.as_bytes()) % rollout_splay_secs)
+    }
+
+    /// When this host may activate `etag` in the staggered rollout, if it was
+    /// already seen
+    pub fn rollout_time(&self, configuration: &str, etag: &str) -> Option<chrono::DateTime<Utc>> {
+        let seen = self
+            .first_seen
+            .as_ref()
+            .filter(|seen| seen.is_for(configuration, etag))?;
+        Some(seen.timestamp + chrono::Duration::from_std(self.rollout_delay()).ok()?)
+    }
+
+    pub fn pending_update(&self) -> Option<&PendingUpdate> {
+        self.pending_update.as_ref()
+    }
+
+    pub fn prebuilt_etag(&self) -> Option<&str> {
+        self.prebuilt_etag.as_deref()
+    }
+
+    pub fn activation_timeout(&self) -> Option<std::time::Duration> {
+        self.activation_timeout_secs
+            .map(std::time::Duration::from_secs)
+    }
+
+    pub fn activation_logs_keep(&self) -> usize {
+        self.activation_logs_keep
+    }
+
+    pub fn history_keep(&self) -> usize {
+        self.history_keep
+    }
+
+    pub fn last_activation_log(&self) -> Option<&Path> {
+        self.last_activation_log.as_deref()
+    }
+
+    pub fn expected_system(&self) -> Option<&Path> {
+        self.expected_system.as_deref()
+    }
+
+    pub fn last_check(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.last_check
+    }
+
+    pub fn force_next(&self) -> bool {
+        self.force_next
+    }
+
+    pub fn reconverge(&self) -> bool {
+        self.reconverge
+    }
+
+    /// The running system, if it's not the one activated by npcnix last
+    pub fn drifted_system(&self, running_system: Option<&Path>) -> Option<PathBuf> {
+        if !self.reconverge {
+            return None;
+        }
+        let expected = self.expected_system.as_deref()?;
+        let running = running_system?;
+        (running != expected).then(|| running.to_owned())
+    }
+
+    pub fn generations(&self) -> &[Generation] {
+        &self.generations
+    }
+
+    /// The recorded generation activated before `current` one
+    pub fn previous_generation(&self, current: Option<u64>) -> Option<&Generation> {
+        let current = current?;
+        self.generations
+            .iter()
+            .rev()
+            .find(|generation| generation.number < current)
+    }
+
+    pub fn last_reconfiguration(&self) -> chrono::DateTime<Utc> {
+        self.last_reconfiguration
+    }
+
+    pub fn last_failure(&self) -> Option<&ActivationFailure> {
+        self.last_failure.as_ref()
+    }
+
+    pub fn failure_backoff(&self) -> FailureBackoffOpts {
+        self.failure_backoff
+    }
+
+    /// The last failed activation, if it failed too many times and its etag
+    /// is quarantined: not retried until the remote changes, or operator
+    /// clears it
+    pub fn quarantined(&self) -> Option<&ActivationFailure> {
+        self.last_failure
+            .as_ref()
+            .filter(|failure| self.failure_backoff.max_retries_per_etag <= failure.count)
+    }
+
+    pub fn is_quarantined(&self, configuration: &str, etag: &str) -> bool {
+        self.quarantined()
+            .is_some_and(|failure| failure.is_for(configuration, etag))
+    }
+
+    /// How long to sleep after the last failed activation: backing off
+    /// while it's retried, and as long as possible once its etag is
+    /// quarantined
+    pub fn failure_backoff_time(&self) -> Option<std::time::Duration> {
+        let failure = self.last_failure.as_ref()?;
+        Some(if self.quarantined().is_some() {
+            std::time::Duration::from_secs(self.failure_backoff.max_backoff_secs)
+        } else {
+            self.failure_backoff.backoff(failure.count)
+        })
+    }
+
+    pub fn held_etag(&self) -> Option<&str> {
+        self.held_etag.as_deref()
+    }
+
+    pub fn gc(&self) -> GcOpts {
+        self.gc
+    }
+
+    /// Extra arguments of `nix flake check`, if it's enabled
+    pub fn flake_check(&self) -> Option<&[String]> {
+        self.verify_flake_check
+            .then_some(self.flake_check_args.as_slice())
+    }
+
+    pub fn escalation(&self) -> Option<Escalation> {
+        self.escalation
+    }
+
+    pub fn health_check(&self) -> &HealthCheckOpts {
+        &self.health_check
+    }
+
+    pub fn extra_nixos_rebuild_args(&self) -> &[String] {
+        &self.extra_nixos_rebuild_args
+    }
+
+    pub fn profile_names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    /// Custom activation command, if set
+    pub fn activation_command(&self) -> Option<&[String]> {
+        if self.activation_command.is_empty() {
+            None
+        } else {
+            Some(&self.activation_command)
+        }
+    }
+
+    /// The configuration set, or the one derived from the hostname
+    pub fn configuration(&self) -> Result<&str, NpcnixError> {
+        if let Some(configuration) = self.configuration.as_deref() {
+            return Ok(configuration);
+        }
+        self.hostname_configuration_cache
+            .get_or_init(|| {
+                misc::hostname()
+                    .map(|hostname| self.hostname_configuration.configuration(&hostname))
+            })
+            .as_deref()
+            .ok_or_else(|| {
+                NpcnixError::NotConfigured(
+                    "configuration not set, and the hostname is unknown".into(),
+                )
+            })
+    }
+
+    pub fn hostname_configuration(&self) -> &HostnameConfiguration {
+        &self.hostname_configuration
+    }
+
+    pub fn cur_rng_sleep_time(&self) -> chrono::Duration {
+        self.cur_rng_sleep_time_at(Utc::now(), &mut rand::thread_rng())
+    }
+
+    pub fn cur_rng_sleep_time_at(
+        &self,
+        now: chrono::DateTime<Utc>,
+        rng: &mut impl rand::Rng,
+    ) -> chrono::Duration {
+        let since_last_update = cmp::max(
+            chrono::Duration::seconds(1),
+            now - self.last_reconfiguration,
+        );
+
+        let min_sleep_secs = self.min_sleep_secs();
+        let duration_ratio = (since_last_update.num_seconds() as f32
+            / self.max_sleep_after_hours().saturating_mul(60 * 60) as f32)
+            .clamp(0f32, 1f32);
+        assert!(0f32 <= duration_ratio);
+
+        let avg_duration_secs = (min_sleep_secs as f32
+            + duration_ratio * self.max_sleep_secs().saturating_sub(min_sleep_secs) as f32)
+            .clamp(0.01, 60f32 * 60f32);
+        let rnd_time = rng.gen_range(avg_duration_secs * 0.5..=avg_duration_secs * 1.5);
+        assert!(0f32 < rnd_time);
+
+        chrono::Duration::seconds(cmp::max(min_sleep_secs as i64, rnd_time as i64))
+    }
+
+    pub fn rng_sleep(&self) {
+        thread::sleep(self.next_sleep_time());
+    }
+
+    /// How long the daemon should wait before the first cycle
+    pub fn start_delay(&self) -> std::time::Duration {
+        self.start_delay_at(Utc::now(), &mut rand::thread_rng())
+    }
+
+    pub fn start_delay_at(
+        &self,
+        now: chrono::DateTime<Utc>,
+        rng: &mut impl rand::Rng,
+    ) -> std::time::Duration {
+        let splay = std::time::Duration::from_secs(rng.gen_range(0..=self.boot_splay_secs));
+        if self.check_on_start {
+            splay
+        } else {
+            splay + self.next_sleep_time_at(now, rng)
+        }
+    }
+
+    /// How long the daemon should sleep before the next cycle
+    pub fn next_sleep_time(&self) -> std::time::Duration {
+        self.next_sleep_time_at(Utc::now(), &mut rand::thread_rng())
+    }
+
+    pub fn next_sleep_time_at(
+        &self,
+        now: chrono::DateTime<Utc>,
+        rng: &mut impl rand::Rng,
+    ) -> std::time::Duration {
+        if let Some(duration) = self.failure_backoff_time() {
+            debug!(duration_secs = duration.as_secs(), "Sleeping after failure");
+            return duration;
+        }
+        let duration = self.cur_rng_sleep_time_at(now, rng);
+        debug!(duration = %duration, "Sleeping");
+        duration.to_std().expect("Can't be negative")
+    }
+
+    pub fn last_configuration(&self) -> &str {
         &self.last_configuration
     }
 
@@ -260,8 +1902,117 @@ impl Config {
     }
 }
 
+/// Configuration names are used as flake attributes
+/// (`nixosConfigurations.<name>`)
+pub fn check_configuration_name(name: &str) -> Result<(), NpcnixError> {
+    match configuration_name_problem(name) {
+        Some(problem) => Err(NpcnixError::ConfigInvalid(vec![problem])),
+        None => Ok(()),
+    }
+}
+
+fn configuration_name_problem(name: &str) -> Option<String> {
+    (name.is_empty()
+        || name.starts_with(['-', '.'])
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)))
+    .then(|| {
+        format!(
+            "Invalid configuration name `{name}`: only letters, digits, `-`, `_` and `.` are \
+             allowed"
+        )
+    })
+}
+
+/// Settings from a TOML file, which can't contain state
+fn read_toml_settings(path: &Path) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let settings = crate::toml::parse(&content)
+        .with_context(|| format!("Invalid TOML in {}", path.display()))?;
+    if let Some(key) = settings
+        .keys()
+        .find(|key| STATE_FIELDS.contains(&key.as_str()))
+    {
+        bail!(
+            "`{key}` is not a setting, it can't be set in {}",
+            path.display()
+        );
+    }
+    Ok(settings)
+}
+
+fn read_base_layers(
+    paths: &[PathBuf],
+) -> anyhow::Result<Vec<(PathBuf, serde_json::Map<String, serde_json::Value>)>> {
+    paths
+        .iter()
+        .filter(|path| path.exists())
+        .map(|path| Ok((path.clone(), read_toml_settings(path)?)))
+        .collect()
+}
+
+/// The settings of `settings_path`, skipping the state if it's already in
+/// `state_path`
+fn read_settings_layer(
+    settings_path: &Path,
+    state_path: &Path,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    if !settings_path.exists() {
+        return Ok(Default::default());
+    }
+    let mut settings = read_json_object(settings_path)?;
+    if state_path.exists() {
+        settings.retain(|key, _| !STATE_FIELDS.contains(&key.as_str()));
+    }
+    Ok(settings)
+}
+
+fn read_json_object(path: &Path) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    match serde_json::from_reader(std::fs::File::open(path)?)? {
+        serde_json::Value::Object(map) => Ok(map),
+        _ => bail!("Not a JSON object: {}", path.display()),
+    }
+}
+
+/// With the sensitive settings redacted, see [`crate::secrets`]
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&serde_json::to_string_pretty(self).map_err(|_e| fmt::Error)?)
+        let serde_json::Value::Object(mut value) =
+            serde_json::to_value(self).map_err(|_e| fmt::Error)?
+        else {
+            unreachable!("config is serialized as an object");
+        };
+        secrets::redact_settings(&mut value);
+        f.write_str(&serde_json::to_string_pretty(&value).map_err(|_e| fmt::Error)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Config;
+    use crate::retry::FailureBackoffOpts;
+
+    #[test]
+    fn backs_off_as_long_as_possible_once_quarantined() {
+        let opts = FailureBackoffOpts::default();
+        let mut config = Config::default().with_failure_backoff(opts);
+        assert_eq!(config.failure_backoff_time(), None);
+        for failures in 1..opts.max_retries_per_etag {
+            config = config.with_activation_failure("host", "etag", "failed");
+            assert!(!config.is_quarantined("host", "etag"));
+            assert_eq!(config.failure_backoff_time(), Some(opts.backoff(failures)));
+        }
+        for _ in 0..2 {
+            config = config.with_activation_failure("host", "etag", "failed");
+            assert!(config.is_quarantined("host", "etag"));
+            assert_eq!(
+                config.failure_backoff_time(),
+                Some(Duration::from_secs(opts.max_backoff_secs))
+            );
+        }
     }
 }
@@ -0,0 +1,124 @@
+//! Configurations available in a flake (`npcnix list-configurations`)
+//!
+//! Listing the configurations of the remote needs pulling it, so the result
+//! is cached in the data dir, for the (offline) shell completions.
+
+use std::fs;
+use std::path::Path;
+use std::process::{self, Stdio};
+
+use anyhow::{bail, format_err, Context};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use url::Url;
+
+use crate::activation::ActivationBackend;
+use crate::error::NpcnixError;
+use crate::{nix_path, CommandExt, PullOpts};
+
+/// Names of the configurations activated by `backend` (e.g. the
+/// `nixosConfigurations`) in the flake in `src`
+pub fn list(src: &Path, backend: ActivationBackend) -> anyhow::Result<Vec<String>> {
+    let output_name = backend.flake_output();
+    let show = nix_json(src, &["flake", "show", "--json"])?;
+    let Some(output) = show.get(output_name) else {
+        return Ok(vec![]);
+    };
+    // `nix flake show` doesn't enumerate the outputs it doesn't know (e.g.
+    // `homeConfigurations`), evaluate their names instead
+    if output.get("type").is_some_and(serde_json::Value::is_string) {
+        debug!(
+            output = output_name,
+            "Unknown flake output, evaluating its names"
+        );
+        let installable = format!(".#{output_name}");
+        let names = nix_json(
+            src,
+            &[
+                "eval",
+                "--json",
+                &installable,
+                "--apply",
+                "builtins.attrNames",
+            ],
+        )?;
+        return Ok(serde_json::from_value(names)?);
+    }
+    let Some(output) = output.as_object() else {
+        bail!("Unexpected `nix flake show` output for {output_name}");
+    };
+    // already sorted by nix
+    Ok(output.keys().cloned().collect())
+}
+
+fn nix_json(src: &Path, args: &[&str]) -> anyhow::Result<serde_json::Value> {
+    let output = process::Command::new(nix_path())
+        .args(args)
+        .current_dir(src)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .log_debug()
+        .output()
+        .context("Calling `nix` failed")?;
+    if !output.status.success() {
+        bail!(
+            "nix {} returned exit code={:?}",
+            args.first().copied().unwrap_or_default(),
+            output.status.code()
+        );
+    }
+    serde_json::from_slice(&output.stdout).context("Failed to parse the `nix` output")
+}
+
+/// The configurations of the remote, as of an etag
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Listing {
+    pub remote: Url,
+    pub etag: String,
+    pub configurations: Vec<String>,
+}
+
+impl Listing {
+    /// Pull `remote` and list its configurations
+    pub fn fetch(
+        remote: &Url,
+        backend: ActivationBackend,
+        pull_opts: &PullOpts,
+    ) -> Result<Self, NpcnixError> {
+        let dir = tempfile::tempdir()?;
+        let res = crate::pull(remote, dir.path(), pull_opts)?;
+        if crate::closure::ClosureRef::load_from(dir.path())?.is_some() {
+            return Err(
+                format_err!("{remote} is a pre-built system, without configurations").into(),
+            );
+        }
+        Ok(Self {
+            remote: remote.clone(),
+            etag: res.etag,
+            configurations: list(dir.path(), backend)?,
+        })
+    }
+
+    /// The cached listing, if there's any
+    pub fn load_cached(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+        Ok(Some(
+            serde_json::from_reader(fs::File::open(path)?)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+        ))
+    }
+
+    pub fn store_cached(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = tempfile::NamedTempFile::new_in(
+            path.parent()
+                .ok_or_else(|| format_err!("Invalid path: {}", path.display()))?,
+        )?;
+        serde_json::to_writer_pretty(tmp.as_file(), self)?;
+        tmp.persist(path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Local control socket of the daemon
+//!
+//! The protocol is one JSON [`ControlRequest`] per connection, answered with
+//! one JSON [`ControlResponse`], both terminated with a newline.
+
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::{fs, thread, time};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::data_dir::DataDir;
+use crate::misc::DaemonControl;
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/npcnix.sock";
+
+/// [`DEFAULT_SOCKET_PATH`] as root, `$XDG_RUNTIME_DIR/npcnix.sock` otherwise
+/// (user mode)
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(runtime_dir) if !crate::misc::is_root() && runtime_dir.is_absolute() => {
+            runtime_dir.join("npcnix.sock")
+        }
+        _ => PathBuf::from(DEFAULT_SOCKET_PATH),
+    }
+}
+
+const RESPONSE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    /// Check the remote right away
+    Trigger,
+    /// Pause until the given time, or indefinitely
+    Pause {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<chrono::DateTime<chrono::Utc>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    Resume,
+    /// Activate the update staged in staged mode
+    Approve,
+    /// Re-activate the remote right away, even if unchanged
+    Force,
+    /// Retry activating the quarantined etag
+    Unquarantine,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ControlResponse {
+    pub ok: bool,
+    pub message: String,
+}
+
+/// Listen on the control socket at `path` in a background thread
+///
+/// Fails if another daemon is already listening on it. A stale socket file
+/// is replaced.
+pub fn serve(path: &Path, data_dir: DataDir, control: DaemonControl) -> anyhow::Result<()> {
+    if UnixStream::connect(path).is_ok() {
+        bail!("Control socket {} is already in use", path.display());
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind the control socket {}", path.display()))?;
+    // Controlling the daemon is as privileged as the daemon itself
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!(path = %path.display(), "Listening on the control socket");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| handle_connection(stream, &data_dir, &control));
+            if let Err(e) = res {
+                warn!(error = %e, "Control socket connection failed");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection(
+    stream: UnixStream,
+    data_dir: &DataDir,
+    control: &DaemonControl,
+) -> anyhow::Result<()> {
+    let mut line = String::new();
+    io::BufReader::new(&stream).read_line(&mut line)?;
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => {
+            debug!(?request, "Control request");
+            handle_request(request, data_dir, control).unwrap_or_else(|e| ControlResponse {
+                ok: false,
+                message: format!("{e:#}"),
+            })
+        }
+        Err(e) => ControlResponse {
+            ok: false,
+            message: format!("Invalid request: {e}"),
+        },
+    };
+    let mut stream = &stream;
+    serde_json::to_writer(stream, &response)?;
+    stream.write_all(b"\n")?;
+    Ok(())
+}
+
+fn handle_request(
+    request: ControlRequest,
+    data_dir: &DataDir,
+    control: &DaemonControl,
+) -> anyhow::Result<ControlResponse> {
+    let message = match request {
+        ControlRequest::Status => {
+            let config = data_dir.load_config()?;
+            match config.pending_update() {
+                Some(pending) => format!("{}; pending update: {pending}", config.status_string()),
+                None => config.status_string(),
+            }
+        }
+        ControlRequest::Trigger => {
+            control.wake_up();
+            "Checking the remote".into()
+        }
+        ControlRequest::Pause { until, reason } => {
+            data_dir.update_config(|config| {
+                Ok(match until {
+                    Some(until) => config.with_paused_until(until),
+                    None => config.with_paused_indefinitely(),
+                }
+                .with_pause_reason(reason.as_deref()))
+            })?;
+            data_dir.load_config()?.status_string()
+        }
+        ControlRequest::Resume => {
+            data_dir.update_config(|config| Ok(config.with_unpaused()))?;
+            control.wake_up();
+            data_dir.load_config()?.status_string()
+        }
+        ControlRequest::Force => {
+            data_dir.update_config(|config| Ok(config.with_force_next(true)))?;
+            control.wake_up();
+            "Re-activating the remote".into()
+        }
+        ControlRequest::Unquarantine => {
+            data_dir.update_config(|config| Ok(config.with_unquarantined()))?;
+            control.wake_up();
+            "Unquarantined".into()
+        }
+        ControlRequest::Approve => {
+            let pending = crate::approve_staged(data_dir)?;
+            control.wake_up();
+            format!("Approved {pending}")
+        }
+    };
+    Ok(ControlResponse { ok: true, message })
+}
+
+/// Send a `request` to the daemon listening on the control socket at `path`
+pub fn send(path: &Path, request: &ControlRequest) -> anyhow::Result<ControlResponse> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to the daemon at {}", path.display()))?;
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    serde_json::to_writer(&stream, request)?;
+    stream.write_all(b"\n")?;
+    let mut line = String::new();
+    io::BufReader::new(&stream).read_line(&mut line)?;
+    serde_json::from_str(&line).context("Invalid response from the daemon")
+}
@@ -0,0 +1,124 @@
+//! Fleet-wide rollout coordination, limiting concurrent activations
+//!
+//! Hosts take one of `max_concurrent` slots (`<prefix>/slot-<n>.json`, with
+//! S3 conditional writes) before activating, and release it afterwards.
+//! Slots expire after `lease_secs`, so a host dying mid-activation can't
+//! block the rollout forever.
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::misc;
+use crate::s3::{self, PutCondition};
+
+fn default_max_concurrent() -> u32 {
+    1
+}
+
+fn default_lease_secs() -> u64 {
+    60 * 60
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct CoordinationOpts {
+    /// Prefix of the slot objects, shared by the coordinated hosts
+    pub prefix: Url,
+    /// Maximum number of hosts activating at once
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: u32,
+    /// Slots not released after this long are free again
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+}
+
+impl CoordinationOpts {
+    fn slot_url(&self, slot: u32) -> anyhow::Result<Url> {
+        let mut prefix = self.prefix.clone();
+        if !prefix.path().ends_with('/') {
+            prefix.set_path(&format!("{}/", prefix.path()));
+        }
+        Ok(prefix.join(&format!("slot-{slot}.json"))?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+struct LeaseInfo {
+    hostname: String,
+    acquired: chrono::DateTime<chrono::Utc>,
+    expires: chrono::DateTime<chrono::Utc>,
+}
+
+/// A taken slot, to be released after activating
+#[derive(Debug)]
+pub struct Lease {
+    url: Url,
+    hostname: String,
+}
+
+/// Take a free slot, `None` if all of them are taken
+pub fn acquire(opts: &CoordinationOpts) -> anyhow::Result<Option<Lease>> {
+    if opts.prefix.scheme() != "s3" {
+        bail!("Protocol not supported: {}", opts.prefix.scheme());
+    }
+    let hostname = misc::hostname().unwrap_or_else(|| "unknown".into());
+    let now = chrono::Utc::now();
+    let info = LeaseInfo {
+        hostname: hostname.clone(),
+        acquired: now,
+        expires: now + chrono::Duration::seconds(opts.lease_secs as i64),
+    };
+    let bytes = serde_json::to_vec(&info)?;
+
+    for slot in 0..opts.max_concurrent {
+        let url = opts.slot_url(slot)?;
+        if s3::put_conditional(&bytes, &url, PutCondition::Absent)? {
+            info!(%url, "Took the rollout slot");
+            return Ok(Some(Lease { url, hostname }));
+        }
+        let Some((current, etag)) = s3::get_object(&url)? else {
+            // released in the meantime, try again on the next cycle
+            continue;
+        };
+        let takeover = match serde_json::from_slice::<LeaseInfo>(&current) {
+            Ok(current) if current.hostname == hostname => true,
+            Ok(current) if current.expires < now => {
+                warn!(%url, holder = current.hostname, "Taking over an expired rollout slot");
+                true
+            }
+            Ok(current) => {
+                debug!(%url, holder = current.hostname, "Rollout slot taken");
+                false
+            }
+            Err(e) => {
+                warn!(%url, error = %e, "Taking over an invalid rollout slot");
+                true
+            }
+        };
+        if takeover && s3::put_conditional(&bytes, &url, PutCondition::Etag(&etag))? {
+            info!(%url, "Took the rollout slot");
+            return Ok(Some(Lease { url, hostname }));
+        }
+    }
+    Ok(None)
+}
+
+impl Lease {
+    /// Free the slot, unless it was taken over in the meantime
+    pub fn release(self) -> anyhow::Result<()> {
+        let Some((current, _)) = s3::get_object(&self.url)? else {
+            return Ok(());
+        };
+        match serde_json::from_slice::<LeaseInfo>(&current) {
+            Ok(current) if current.hostname == self.hostname => {
+                s3::delete(&self.url)?;
+                debug!(url = %self.url, "Released the rollout slot");
+            }
+            _ => warn!(url = %self.url, "Rollout slot was taken over, not releasing"),
+        }
+        Ok(())
+    }
+}
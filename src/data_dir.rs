@@ -4,20 +4,115 @@ use std::path::{Path, PathBuf};
 use anyhow::Context;
 use url::Url;
 
-use crate::config;
+use crate::activation::Generation;
+use crate::error::NpcnixError;
+use crate::{config, misc};
+
+/// Data dir of the system daemon
+pub const SYSTEM_DATA_DIR: &str = "/var/lib/npcnix";
+
+/// Base settings of the system daemon
+pub const SYSTEM_SETTINGS_FILE: &str = "/etc/npcnix/config.toml";
+
+/// [`SYSTEM_DATA_DIR`] as root, `$XDG_STATE_HOME/npcnix` otherwise (user
+/// mode)
+pub fn default_data_dir() -> PathBuf {
+    user_mode_dir("XDG_STATE_HOME", ".local/state").unwrap_or_else(|| SYSTEM_DATA_DIR.into())
+}
+
+/// [`SYSTEM_SETTINGS_FILE`] as root, `$XDG_CONFIG_HOME/npcnix/config.toml`
+/// otherwise (user mode)
+pub fn default_settings_file() -> PathBuf {
+    user_mode_dir("XDG_CONFIG_HOME", ".config")
+        .map(|dir| dir.join("config.toml"))
+        .unwrap_or_else(|| SYSTEM_SETTINGS_FILE.into())
+}
+
+fn user_mode_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    if misc::is_root() {
+        return None;
+    }
+    Some(misc::xdg_dir(var, fallback)?.join("npcnix"))
+}
 
 #[derive(Debug, Clone)]
 pub struct DataDir {
     path: PathBuf,
+    /// Base settings, overridden by the ones of `config.json` (see
+    /// [`config::Config::load_from`])
+    settings_file: Option<PathBuf>,
+    /// Profile of settings to use (see [`config::ConfigSources::profile`])
+    profile: Option<String>,
 }
 
 impl DataDir {
     pub fn new(path: &Path) -> Self {
         Self {
             path: path.to_owned(),
+            settings_file: None,
+            profile: None,
         }
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn with_settings_file(self, settings_file: Option<&Path>) -> Self {
+        Self {
+            settings_file: settings_file.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    pub fn with_profile(self, profile: Option<&str>) -> Self {
+        Self {
+            profile: profile.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The settings file and the `config.d/*.toml` drop-ins next to it, in
+    /// order of precedence (the last one wins)
+    pub fn base_settings_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let Some(ref settings_file) = self.settings_file else {
+            return Ok(vec![]);
+        };
+        let mut files = vec![];
+        if settings_file.try_exists()? {
+            files.push(settings_file.clone());
+        }
+        let drop_in_dir = settings_file
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("config.d");
+        if drop_in_dir.is_dir() {
+            let mut drop_ins = vec![];
+            for entry in fs::read_dir(&drop_in_dir)
+                .with_context(|| format!("Failed to read {}", drop_in_dir.display()))?
+            {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "toml") {
+                    drop_ins.push(path);
+                }
+            }
+            drop_ins.sort();
+            files.extend(drop_ins);
+        }
+        Ok(files)
+    }
+
+    /// Where the value of each setting comes from
+    pub fn setting_origins(
+        &self,
+    ) -> anyhow::Result<std::collections::BTreeMap<String, config::SettingOrigin>> {
+        Ok(config::Config::setting_origins(&self.config_sources()?)?)
+    }
+
     pub fn activate_lock(&self) -> anyhow::Result<Option<fd_lock::RwLock<fs::File>>> {
         if self.config_exist()? {
             Ok(Some(fd_lock::RwLock::new(fs::File::create(
@@ -36,7 +131,7 @@ impl DataDir {
         remote
             .cloned()
             .ok_or(())
-            .or_else(|_| -> anyhow::Result<Url> { Ok(self.load_config()?.remote()?.clone()) })
+            .or_else(|_| -> anyhow::Result<Url> { Ok(self.load_config()?.effective_remote()?) })
     }
 
     /// Load currently configured `configuration` if not overridden
@@ -52,40 +147,215 @@ impl DataDir {
             })
     }
 
+    /// Load currently configured `decrypt_identity` if not overridden
+    pub fn get_current_decrypt_identity_with_opt_override(
+        &self,
+        decrypt_identity: Option<&Path>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        match decrypt_identity {
+            Some(decrypt_identity) => Ok(Some(decrypt_identity.to_owned())),
+            None => Ok(self
+                .load_config()?
+                .decrypt_identity()
+                .map(ToOwned::to_owned)),
+        }
+    }
+
+    /// Directory of the activation hooks (see [`crate::hooks`])
+    pub fn hooks_dir(&self) -> PathBuf {
+        self.path.join("hooks")
+    }
+
+    /// Directory of the activation logs (see [`crate::logs`])
+    pub fn logs_dir(&self) -> PathBuf {
+        self.path.join("logs")
+    }
+
+    /// Where the daemon downloads remotes to in staged mode
+    pub fn staging_dir(&self) -> PathBuf {
+        self.path.join("staged")
+    }
+
+    /// Last listing of the configurations of the remote (see
+    /// [`crate::configurations`])
+    pub fn configurations_cache_path(&self) -> PathBuf {
+        self.path.join("configurations.json")
+    }
+
+    /// History of the daemon cycles (see [`crate::history`])
+    pub fn history_path(&self) -> PathBuf {
+        self.path.join("history.jsonl")
+    }
+
     fn config_file_path(&self) -> PathBuf {
         self.path.join("config.json")
     }
 
+    /// Secrets referred to by the settings, see [`crate::secrets`]
+    pub fn secrets_file_path(&self) -> PathBuf {
+        self.path.join("secrets.json")
+    }
+
+    /// Runtime state of the daemon, apart from the settings
+    fn state_file_path(&self) -> PathBuf {
+        self.path.join("state.json")
+    }
+
+    fn config_sources(&self) -> anyhow::Result<config::ConfigSources> {
+        Ok(config::ConfigSources {
+            settings: self.config_file_path(),
+            base: self.base_settings_files()?,
+            state: self.state_file_path(),
+            profile: self.profile.clone(),
+            secrets: self.secrets_file_path(),
+        })
+    }
+
     pub fn config_exist(&self) -> anyhow::Result<bool> {
-        Ok(self.config_file_path().try_exists()?)
+        Ok(self.config_file_path().try_exists()? || !self.base_settings_files()?.is_empty())
+    }
+
+    /// Advisory lock around reading and writing the config, so concurrent
+    /// read-modify-write cycles don't lose updates
+    fn config_lock(&self) -> anyhow::Result<fd_lock::RwLock<fs::File>> {
+        fs::create_dir_all(&self.path)
+            .with_context(|| format!("Failed to create data directory: {}", self.path.display()))?;
+        let path = self.path.join("config.lock");
+        Ok(fd_lock::RwLock::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?,
+        ))
+    }
+
+    fn with_config_lock<T>(&self, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let mut lock = self.config_lock()?;
+        let _guard = lock.write().context("Failed to lock the config")?;
+        f()
     }
 
     pub fn load_config(&self) -> anyhow::Result<config::Config> {
-        let config_path = self.config_file_path();
-        if config_path.exists() {
-            config::Config::load(&self.config_file_path()).context("Failed to load config")
+        if !self.config_exist()? {
+            return Ok(Default::default());
+        }
+        // e.g. `npcnix status` run by a user that can't write the data dir
+        let Ok(lock) = self.config_lock() else {
+            return self.load_config_unlocked();
+        };
+        let _guard = lock.read().context("Failed to lock the config")?;
+        self.load_config_unlocked()
+    }
+
+    fn load_config_unlocked(&self) -> anyhow::Result<config::Config> {
+        let config = self.load_config_unchecked_unlocked()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load the config, even if it's invalid (see
+    /// [`config::Config::problems`])
+    pub fn load_config_unchecked(&self) -> anyhow::Result<config::Config> {
+        self.with_config_lock(|| self.load_config_unchecked_unlocked())
+    }
+
+    fn load_config_unchecked_unlocked(&self) -> anyhow::Result<config::Config> {
+        if self.config_exist()? {
+            config::Config::load_unchecked(&self.config_sources()?).context("Failed to load config")
         } else {
             Ok(Default::default())
         }
     }
 
     pub fn store_config(&self, config: &config::Config) -> anyhow::Result<()> {
+        config.validate()?;
+        self.with_config_lock(|| self.store_config_unlocked(config))
+    }
+
+    /// Load the config, modify it with `f` and store it, holding the config
+    /// lock all along
+    ///
+    /// Fails if `f` makes the config invalid, but an invalid config can be
+    /// fixed one setting at a time.
+    pub fn update_config(
+        &self,
+        f: impl FnOnce(config::Config) -> anyhow::Result<config::Config>,
+    ) -> anyhow::Result<()> {
+        self.with_config_lock(|| {
+            let config = self.load_config_unchecked_unlocked()?;
+            let problems = config.problems();
+            let config = f(config)?;
+            let new_problems: Vec<_> = config
+                .problems()
+                .into_iter()
+                .filter(|problem| !problems.contains(problem))
+                .collect();
+            if !new_problems.is_empty() {
+                return Err(NpcnixError::ConfigInvalid(new_problems).into());
+            }
+            self.store_config_unlocked(&config)
+        })
+    }
+
+    fn store_config_unlocked(&self, config: &config::Config) -> anyhow::Result<()> {
         fs::create_dir_all(&self.path)
             .with_context(|| format!("Failed to create data directory: {}", self.path.display()))?;
         config
-            .store(&self.config_file_path())
+            .store_to(&self.config_sources()?)
             .context("Failed to store config")
     }
 
+    pub fn update_last_activation_log(&self, path: &Path) -> anyhow::Result<()> {
+        self.update_config(|config| Ok(config.with_last_activation_log(Some(path))))
+    }
+
+    pub fn update_last_check(&self) -> anyhow::Result<()> {
+        self.update_config(|config| Ok(config.with_last_check(chrono::Utc::now())))
+    }
+
+    /// Record `now` as when a remote etag was first seen (unless it already
+    /// was), returning the updated config
+    pub fn record_seen_remote(
+        &self,
+        configuration: &str,
+        etag: &str,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<config::Config> {
+        self.with_config_lock(|| {
+            let config =
+                self.load_config_unchecked_unlocked()?
+                    .with_seen_remote(configuration, etag, now);
+            self.store_config_unlocked(&config)?;
+            Ok(config)
+        })
+    }
+
+    pub fn update_expected_system(&self, system: Option<&Path>) -> anyhow::Result<()> {
+        self.update_config(|config| Ok(config.with_expected_system(system)))
+    }
+
+    pub fn record_generation(&self, generation: Generation) -> anyhow::Result<()> {
+        self.update_config(|config| Ok(config.with_recorded_generation(generation)))
+    }
+
+    pub fn record_activation_failure(
+        &self,
+        configuration: &str,
+        etag: &str,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        self.update_config(|config| Ok(config.with_activation_failure(configuration, etag, error)))
+    }
+
     pub fn update_last_reconfiguration(
         &self,
         configuration: &str,
         etag: &str,
     ) -> anyhow::Result<()> {
-        self.store_config(
-            &self
-                .load_config()?
-                .with_updated_last_reconfiguration(configuration, etag),
-        )
+        self.update_config(|config| {
+            Ok(config.with_updated_last_reconfiguration(configuration, etag))
+        })
     }
 }
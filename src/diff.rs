@@ -0,0 +1,183 @@
+//! Differences between a local Nix Flake and the archive of a remote, as
+//! shown by `npcnix diff`
+//!
+//! The local side is packed (and unpacked again) just as a push would, so
+//! only what a push would change shows up.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use url::Url;
+
+use crate::archive::{self, PackOptions, UnpackOptions};
+use crate::error::NpcnixError;
+use crate::meta::META_FILE_NAME;
+use crate::{CommandExt, PullOpts};
+
+pub fn diff_path() -> OsString {
+    std::env::var_os("NPCNIX_DIFF").unwrap_or_else(|| OsString::from("diff"))
+}
+
+/// How a file differs, from the remote to the local flake
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// Only in the local flake
+    Added,
+    /// Only in the remote
+    Removed,
+    Modified,
+}
+
+impl Change {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Modified => "modified",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub change: Change,
+}
+
+/// The differences, with both sides still unpacked (until dropped) to show
+/// the contents
+#[derive(Debug)]
+pub struct Diff {
+    /// Etag of the remote compared
+    pub etag: String,
+    pub changes: Vec<FileChange>,
+    local: tempfile::TempDir,
+    remote: tempfile::TempDir,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    File(Vec<u8>, bool),
+    Symlink(PathBuf),
+}
+
+impl Diff {
+    /// Compare the flake in `src` (limited to `include`, as in
+    /// [`crate::push`]) with `remote`
+    pub fn compute(
+        src: &Path,
+        include: &HashSet<OsString>,
+        remote: &Url,
+        pull_opts: &PullOpts,
+    ) -> Result<Self, NpcnixError> {
+        crate::verify_flake_src(src)?;
+        let local = tempfile::tempdir()?;
+        let mut packed = vec![];
+        let pack_opts = PackOptions {
+            include: include.clone(),
+            ..Default::default()
+        };
+        archive::pack_archive_from(src, &pack_opts, &mut packed)
+            .with_context(|| format!("Failed to pack {}", src.display()))?;
+        archive::unpack_archive_to(packed.as_slice(), local.path(), &UnpackOptions::default())?;
+
+        let remote_dir = tempfile::tempdir()?;
+        let etag = crate::pull(remote, remote_dir.path(), pull_opts)?.etag;
+
+        let local_entries = entries(local.path())?;
+        let remote_entries = entries(remote_dir.path())?;
+        let mut changes = vec![];
+        for (path, entry) in &remote_entries {
+            match local_entries.get(path) {
+                None => changes.push((path, Change::Removed)),
+                Some(local_entry) if local_entry != entry => {
+                    changes.push((path, Change::Modified));
+                }
+                Some(_) => {}
+            }
+        }
+        for path in local_entries.keys() {
+            if !remote_entries.contains_key(path) {
+                changes.push((path, Change::Added));
+            }
+        }
+        changes.sort_by(|a, b| a.0.cmp(b.0));
+        Ok(Self {
+            etag,
+            changes: changes
+                .into_iter()
+                .map(|(path, change)| FileChange {
+                    path: path.clone(),
+                    change,
+                })
+                .collect(),
+            local,
+            remote: remote_dir,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Unified diff of the contents of `change`, using `diff`
+    pub fn content_diff(&self, change: &FileChange) -> anyhow::Result<String> {
+        let side = |dir: &Path, present: bool| {
+            if present {
+                dir.join(&change.path)
+            } else {
+                PathBuf::from("/dev/null")
+            }
+        };
+        let output = process::Command::new(diff_path())
+            .args(["-u", "--no-dereference"])
+            .arg(format!("--label=a/{}", change.path.display()))
+            .arg(format!("--label=b/{}", change.path.display()))
+            .arg(side(self.remote.path(), change.change != Change::Added))
+            .arg(side(self.local.path(), change.change != Change::Removed))
+            .log_debug()
+            .output()
+            .context("Calling `diff` failed")?;
+        // 1 means "different"
+        if !matches!(output.status.code(), Some(0 | 1)) {
+            bail!("diff returned exit code={:?}", output.status.code());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Files and symlinks under `root`, by their relative paths
+fn entries(root: &Path) -> anyhow::Result<BTreeMap<PathBuf, Entry>> {
+    let mut entries = BTreeMap::new();
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for dir_entry in
+            fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            let path = dir_entry?.path();
+            let rel_path = path.strip_prefix(root)?.to_owned();
+            if rel_path == Path::new(META_FILE_NAME) {
+                // differs on every push
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if metadata.is_symlink() {
+                entries.insert(rel_path, Entry::Symlink(fs::read_link(&path)?));
+            } else {
+                let executable = metadata.permissions().mode() & 0o111 != 0;
+                entries.insert(rel_path, Entry::File(fs::read(&path)?, executable));
+            }
+        }
+    }
+    Ok(entries)
+}
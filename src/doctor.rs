@@ -0,0 +1,293 @@
+//! Diagnostics of the environment npcnix runs in, as shown by `npcnix doctor`
+//!
+//! Checks the things a fresh host usually gets wrong: missing binaries,
+//! missing credentials, data dir permissions, and a configuration that's not
+//! in the archive.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+
+use serde::Serialize;
+use url::Url;
+
+use crate::activation::{self, ActivateOpts};
+use crate::closure::ClosureRef;
+use crate::config::Config;
+use crate::data_dir::DataDir;
+use crate::{aws_cli_path, misc, nix_path, s3, CommandExt, PullOpts};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but likely to cause trouble
+    Warn,
+    Fail,
+    /// Not checked, as a check it depends on failed
+    Skip,
+}
+
+impl CheckStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "fail",
+            CheckStatus::Skip => "skip",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Run all the checks, for the config in `data_dir`
+    ///
+    /// Checking the remote and the flake needs the network (and downloads the
+    /// archive), unless `offline`.
+    pub fn run(data_dir: &DataDir, offline: bool) -> Self {
+        let mut report = Self::default();
+
+        let (status, message) = check_data_dir(data_dir.path());
+        report.push("data-dir", status, message);
+        report.check_secrets_file(&data_dir.secrets_file_path());
+        let config = report.check_config(data_dir);
+
+        if !s3::is_native() {
+            report.check_program("aws", aws_cli_path());
+        }
+        report.check_program("nix", nix_path());
+        let backend = activation::effective_backend(&ActivateOpts::default(), &config);
+        match config.activation_command() {
+            Some(command) => report.check_program("activation-command", &command[0]),
+            None => report.check_program(&backend.to_string(), backend.program()),
+        }
+        if let Some(escalation) = config.escalation().filter(|_| !misc::is_root()) {
+            report.check_program("escalation", escalation.program());
+        }
+
+        let configuration = match config.configuration() {
+            Ok(configuration) => {
+                report.push("configuration", CheckStatus::Pass, configuration);
+                Some(configuration.to_owned())
+            }
+            Err(e) => {
+                report.push("configuration", CheckStatus::Fail, e.to_string());
+                None
+            }
+        };
+
+        let remote = match config.effective_remote() {
+            Ok(remote) => remote,
+            Err(e) => {
+                report.push("remote", CheckStatus::Fail, e.to_string());
+                report.push("flake", CheckStatus::Skip, "No remote");
+                return report;
+            }
+        };
+        if offline {
+            report.push("remote", CheckStatus::Skip, format!("{remote} (offline)"));
+            report.push("flake", CheckStatus::Skip, "Offline");
+            return report;
+        }
+        match crate::get_etag(&remote, &config) {
+            Ok(etag) => report.push(
+                "remote",
+                CheckStatus::Pass,
+                format!("{remote} is readable (etag {etag})"),
+            ),
+            Err(e) => {
+                report.push(
+                    "remote",
+                    CheckStatus::Fail,
+                    format!("{:#} (missing credentials?)", anyhow::Error::from(e)),
+                );
+                report.push("flake", CheckStatus::Skip, "Remote not readable");
+                return report;
+            }
+        }
+        match configuration {
+            Some(configuration) => {
+                let (status, message) = check_flake(&remote, &configuration, &config);
+                report.push("flake", status, message);
+            }
+            None => report.push("flake", CheckStatus::Skip, "No configuration"),
+        }
+        report
+    }
+
+    /// Number of failed checks
+    pub fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Fail)
+            .count()
+    }
+
+    pub(crate) fn push(&mut self, name: &str, status: CheckStatus, message: impl Into<String>) {
+        self.checks.push(Check {
+            name: name.to_owned(),
+            status,
+            message: message.into(),
+        });
+    }
+
+    fn check_config(&mut self, data_dir: &DataDir) -> Config {
+        match data_dir.config_exist() {
+            Ok(true) => {}
+            Ok(false) => {
+                self.push(
+                    "config",
+                    CheckStatus::Fail,
+                    "Not initialized (see `npcnix init`)",
+                );
+                return Config::default();
+            }
+            Err(e) => {
+                self.push("config", CheckStatus::Fail, format!("{e:#}"));
+                return Config::default();
+            }
+        }
+        match data_dir.load_config() {
+            Ok(config) => {
+                self.push("config", CheckStatus::Pass, "Valid");
+                config
+            }
+            Err(e) => {
+                self.push("config", CheckStatus::Fail, format!("{e:#}"));
+                // check the rest anyway
+                data_dir.load_config_unchecked().unwrap_or_default()
+            }
+        }
+    }
+
+    fn check_secrets_file(&mut self, path: &Path) {
+        let Ok(metadata) = fs::metadata(path) else {
+            return;
+        };
+        if metadata.permissions().mode() & 0o077 != 0 {
+            self.push(
+                "secrets",
+                CheckStatus::Warn,
+                format!("{} is accessible by other users", path.display()),
+            );
+        } else {
+            self.push("secrets", CheckStatus::Pass, path.display().to_string());
+        }
+    }
+
+    fn check_program(&mut self, name: &str, program: impl AsRef<OsStr>) {
+        let program = program.as_ref();
+        match find_program(program) {
+            Some(path) => self.push(name, CheckStatus::Pass, path.display().to_string()),
+            None => self.push(
+                name,
+                CheckStatus::Fail,
+                format!("`{}` not found", program.to_string_lossy()),
+            ),
+        }
+    }
+}
+
+fn check_data_dir(path: &Path) -> (CheckStatus, String) {
+    match fs::metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => (
+            CheckStatus::Fail,
+            format!("{} is not a directory", path.display()),
+        ),
+        Ok(_) => match tempfile::tempfile_in(path) {
+            Ok(_) => (CheckStatus::Pass, format!("{} is writable", path.display())),
+            Err(e) => (
+                CheckStatus::Fail,
+                format!("{} is not writable: {e}", path.display()),
+            ),
+        },
+        Err(e) => (
+            CheckStatus::Fail,
+            format!("{} is not accessible: {e}", path.display()),
+        ),
+    }
+}
+
+/// Pull `remote` and check that the flake attribute of `configuration` exists
+fn check_flake(remote: &Url, configuration: &str, config: &Config) -> (CheckStatus, String) {
+    let dir = match tempfile::tempdir() {
+        Ok(dir) => dir,
+        Err(e) => return (CheckStatus::Fail, format!("{e:#}")),
+    };
+    if let Err(e) = crate::pull(remote, dir.path(), &PullOpts::from(config)) {
+        return (
+            CheckStatus::Fail,
+            format!("Failed to pull: {:#}", anyhow::Error::from(e)),
+        );
+    }
+    match ClosureRef::load_from(dir.path()) {
+        Ok(Some(closure)) => {
+            return (
+                CheckStatus::Pass,
+                format!("Pre-built system {}", closure.store_path.display()),
+            )
+        }
+        Ok(None) => {}
+        Err(e) => return (CheckStatus::Fail, format!("{e:#}")),
+    }
+
+    let backend = activation::effective_backend(&ActivateOpts::default(), config);
+    let installable = activation::flake_target(configuration, config.flake_attr())
+        .configuration_installable(backend);
+    let output = process::Command::new(nix_path())
+        // only check that the attribute exists, without evaluating it
+        .args(["eval", "--json", &installable, "--apply", "_: true"])
+        .current_dir(dir.path())
+        .stdin(Stdio::null())
+        .log_debug()
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            (CheckStatus::Pass, format!("`{installable}` exists"))
+        }
+        Ok(output) => (
+            CheckStatus::Fail,
+            format!(
+                "`{installable}` not found: {}",
+                String::from_utf8_lossy(&output.stderr)
+                    .lines()
+                    .rfind(|line| !line.trim().is_empty())
+                    .unwrap_or("nix eval failed")
+                    .trim()
+            ),
+        ),
+        Err(e) => (CheckStatus::Skip, format!("Calling `nix` failed: {e}")),
+    }
+}
+
+/// `program` (looked up in `$PATH` unless it's a path) if it's executable
+fn find_program(program: &OsStr) -> Option<PathBuf> {
+    let program = Path::new(program);
+    if program.components().count() > 1 {
+        return is_executable(program).then(|| program.to_owned());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
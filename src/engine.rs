@@ -0,0 +1,1120 @@
+//! The daemon cycle as a state machine, for embedding and testing
+//!
+//! [`DaemonEngine::step`] performs a single check/pull/activate cycle and
+//! returns a [`CycleOutcome`]; the caller decides when to run the next one
+//! ([`DaemonEngine::next_sleep_time`]). The time, the randomness, the
+//! transfers of the archive and the activation are all behind traits, so
+//! they can be replaced, e.g. by deterministic fakes.
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use tracing::{debug, error, info, info_span, warn};
+use url::Url;
+
+use crate::activation::{self, ActivateOpts, ActivationResult};
+use crate::cancel::{self, CancellationToken};
+use crate::closure::ClosureRef;
+use crate::config::{Config, PendingUpdate};
+use crate::data_dir::DataDir;
+use crate::error::NpcnixError;
+use crate::notify::{self, NotifyEvent};
+use crate::{
+    approval, coordination, history, misc, remote_settings, report, systemd, PullOpts, PullResult,
+};
+#[cfg(feature = "metrics")]
+use crate::{cloudwatch, metrics};
+
+/// Source of the current time
+pub trait Clock: Send {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Where the archives are checked and pulled from
+pub trait Remote: Send {
+    fn get_etag(&self, remote: &Url, config: &Config) -> Result<String, NpcnixError>;
+
+    /// Pull the archive from `remote` and unpack to `dst`
+    fn pull(
+        &self,
+        remote: &Url,
+        dst: &Path,
+        pull_opts: &PullOpts,
+    ) -> Result<PullResult, NpcnixError>;
+}
+
+/// The remotes in S3, through the `aws` cli
+#[derive(Debug, Clone, Copy, Default)]
+pub struct S3Remote;
+
+impl Remote for S3Remote {
+    fn get_etag(&self, remote: &Url, config: &Config) -> Result<String, NpcnixError> {
+        crate::get_etag(remote, config)
+    }
+
+    fn pull(
+        &self,
+        remote: &Url,
+        dst: &Path,
+        pull_opts: &PullOpts,
+    ) -> Result<PullResult, NpcnixError> {
+        crate::pull(remote, dst, pull_opts)
+    }
+}
+
+/// Applies an unpacked remote to the system
+pub trait Activator: Send {
+    /// Activate `configuration` of the flake in `src` (or the pre-built
+    /// `activate_opts.store_path`)
+    fn activate(
+        &self,
+        src: &Path,
+        configuration: &str,
+        etag: Option<&str>,
+        data_dir: Option<&DataDir>,
+        activate_opts: &ActivateOpts,
+        config: &Config,
+    ) -> anyhow::Result<ActivationResult>;
+
+    /// Whether [`Self::prebuild`] does anything with these settings
+    fn supports_prebuild(&self, _activate_opts: &ActivateOpts, _config: &Config) -> bool {
+        false
+    }
+
+    /// Build (but don't activate) `configuration`, so the activation later
+    /// is quick
+    fn prebuild(
+        &self,
+        _src: &Path,
+        _configuration: &str,
+        _activate_opts: &ActivateOpts,
+        _config: &Config,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// The built-in activator selected by the settings of each activation, see
+/// [`activation::activator_for`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NixActivator;
+
+impl Activator for NixActivator {
+    fn activate(
+        &self,
+        src: &Path,
+        configuration: &str,
+        etag: Option<&str>,
+        data_dir: Option<&DataDir>,
+        activate_opts: &ActivateOpts,
+        config: &Config,
+    ) -> anyhow::Result<ActivationResult> {
+        activation::activator_for(activate_opts, config).activate(
+            src,
+            configuration,
+            etag,
+            data_dir,
+            activate_opts,
+            config,
+        )
+    }
+
+    fn supports_prebuild(&self, activate_opts: &ActivateOpts, config: &Config) -> bool {
+        activation::activator_for(activate_opts, config).supports_prebuild(activate_opts, config)
+    }
+
+    fn prebuild(
+        &self,
+        src: &Path,
+        configuration: &str,
+        activate_opts: &ActivateOpts,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        activation::activator_for(activate_opts, config).prebuild(
+            src,
+            configuration,
+            activate_opts,
+            config,
+        )
+    }
+}
+
+/// Callbacks on the progress of the daemon cycles, e.g. for metrics or
+/// notifications
+///
+/// All of them do nothing by default.
+pub trait DaemonEvents: Send {
+    /// The etag of `remote` was checked
+    fn on_check(&self, _remote: &Url, _etag: &str) {}
+
+    /// The remote differs from the last activated one (it may still not get
+    /// activated, see [`UnchangedReason`])
+    fn on_change_detected(&self, _configuration: &str, _etag: &str) {}
+
+    fn on_activation_start(&self, _configuration: &str, _etag: &str) {}
+
+    fn on_activation_finish(
+        &self,
+        _configuration: &str,
+        _etag: &str,
+        _result: Result<&ActivationResult, &anyhow::Error>,
+    ) {
+    }
+
+    /// The cycle failed, see [`CycleOutcome::error`]
+    fn on_error(&self, _error: &anyhow::Error) {}
+}
+
+/// No callbacks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoEvents;
+
+impl DaemonEvents for NoEvents {}
+
+/// Why a cycle didn't activate anything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnchangedReason {
+    /// The last activated remote is still current
+    UpToDate,
+    Paused,
+    /// The remote etag is held (e.g. after a rollback)
+    Held,
+    /// The remote etag failed to activate too many times
+    Quarantined,
+    /// The remote etag is not approved (see [`crate::approval`])
+    NotApproved,
+    /// This host's turn in the staggered rollout is at `until`
+    WaitingForRollout {
+        until: DateTime<Utc>,
+    },
+    /// Staged mode: the update is staged, waiting for approval
+    Staged,
+    /// Outside of the activation windows or in quiet hours
+    OutsideActivationWindow,
+    /// All the rollout slots (see [`crate::coordination`]) are taken
+    NoRolloutSlot,
+}
+
+impl UnchangedReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnchangedReason::UpToDate => "up_to_date",
+            UnchangedReason::Paused => "paused",
+            UnchangedReason::Held => "held",
+            UnchangedReason::Quarantined => "quarantined",
+            UnchangedReason::NotApproved => "not_approved",
+            UnchangedReason::WaitingForRollout { .. } => "waiting_for_rollout",
+            UnchangedReason::Staged => "staged",
+            UnchangedReason::OutsideActivationWindow => "outside_activation_window",
+            UnchangedReason::NoRolloutSlot => "no_rollout_slot",
+        }
+    }
+}
+
+/// Outcome of a single daemon cycle
+#[derive(Debug)]
+pub enum CycleOutcome {
+    /// A new configuration was activated
+    Changed { configuration: String, etag: String },
+    /// Nothing to do
+    Unchanged(UnchangedReason),
+    /// Checking or pulling the remote failed (already logged), likely to go
+    /// away by itself
+    RemoteUnavailable(anyhow::Error),
+    /// Activation failed (already logged and recorded)
+    Failed(anyhow::Error),
+}
+
+impl CycleOutcome {
+    /// `changed`, `unchanged` or `failed`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CycleOutcome::Changed { .. } => "changed",
+            CycleOutcome::Unchanged(_) => "unchanged",
+            CycleOutcome::RemoteUnavailable(_) | CycleOutcome::Failed(_) => "failed",
+        }
+    }
+
+    pub fn error(&self) -> Option<&anyhow::Error> {
+        match self {
+            CycleOutcome::RemoteUnavailable(e) | CycleOutcome::Failed(e) => Some(e),
+            CycleOutcome::Changed { .. } | CycleOutcome::Unchanged(_) => None,
+        }
+    }
+}
+
+/// The daemon convergence logic, following the remote of a data dir
+pub struct DaemonEngine {
+    data_dir: DataDir,
+    activate_opts: ActivateOpts,
+    override_configuration: Option<String>,
+    ignore_etag: bool,
+    clock: Box<dyn Clock>,
+    rng: Box<dyn RngCore + Send>,
+    remote: Box<dyn Remote>,
+    activator: Box<dyn Activator>,
+    events: Box<dyn DaemonEvents>,
+    cancel: Option<CancellationToken>,
+}
+
+impl DaemonEngine {
+    pub fn new(data_dir: DataDir, activate_opts: ActivateOpts) -> Self {
+        Self {
+            data_dir,
+            activate_opts,
+            override_configuration: None,
+            ignore_etag: false,
+            clock: Box::new(SystemClock),
+            rng: Box::new(StdRng::from_entropy()),
+            remote: Box::new(S3Remote),
+            activator: Box::new(NixActivator),
+            events: Box::new(NoEvents),
+            cancel: None,
+        }
+    }
+
+    /// Activate this configuration instead of the configured one
+    pub fn with_override_configuration(self, configuration: Option<String>) -> Self {
+        Self {
+            override_configuration: configuration,
+            ..self
+        }
+    }
+
+    /// Activate even if the remote didn't change (only the first cycle)
+    pub fn with_ignore_etag(self, ignore_etag: bool) -> Self {
+        Self {
+            ignore_etag,
+            ..self
+        }
+    }
+
+    pub fn with_clock(self, clock: impl Clock + 'static) -> Self {
+        Self {
+            clock: Box::new(clock),
+            ..self
+        }
+    }
+
+    pub fn with_rng(self, rng: impl RngCore + Send + 'static) -> Self {
+        Self {
+            rng: Box::new(rng),
+            ..self
+        }
+    }
+
+    pub fn with_remote(self, remote: impl Remote + 'static) -> Self {
+        Self {
+            remote: Box::new(remote),
+            ..self
+        }
+    }
+
+    pub fn with_activator(self, activator: impl Activator + 'static) -> Self {
+        Self {
+            activator: Box::new(activator),
+            ..self
+        }
+    }
+
+    pub fn with_events(self, events: impl DaemonEvents + 'static) -> Self {
+        Self {
+            events: Box::new(events),
+            ..self
+        }
+    }
+
+    /// Abort the cycles (killing the running pull or activation) once
+    /// `cancel` is cancelled
+    ///
+    /// A cancelled [`Self::step`] returns [`NpcnixError::Cancelled`], and
+    /// isn't recorded as a failure.
+    pub fn with_cancellation(self, cancel: CancellationToken) -> Self {
+        Self {
+            activate_opts: ActivateOpts {
+                cancel: Some(cancel.clone()),
+                ..self.activate_opts
+            },
+            cancel: Some(cancel),
+            ..self
+        }
+    }
+
+    pub fn data_dir(&self) -> &DataDir {
+        &self.data_dir
+    }
+
+    /// How long to wait before the first cycle
+    pub fn start_delay(&mut self) -> Result<std::time::Duration, NpcnixError> {
+        let config = self.data_dir.load_config()?;
+        Ok(config.start_delay_at(self.clock.now(), &mut self.rng))
+    }
+
+    /// How long to wait before the next cycle
+    pub fn next_sleep_time(&mut self) -> Result<std::time::Duration, NpcnixError> {
+        // reload the config, just in case it changed in the meantime
+        let config = self.data_dir.load_config()?;
+        Ok(config.next_sleep_time_at(self.clock.now(), &mut self.rng))
+    }
+
+    /// Perform a single check/pull/activate cycle
+    ///
+    /// Failures to check, pull or activate the remote are logged, recorded in
+    /// the data dir and returned as outcomes; other errors (e.g. loading the
+    /// config) are returned as `Err`.
+    pub fn step(&mut self) -> Result<CycleOutcome, NpcnixError> {
+        cancel::check(self.cancel.as_ref())?;
+        let start = std::time::Instant::now();
+        let data_dir = self.data_dir.clone();
+        let outcome = crate::with_activate_lock(Some(&data_dir), || self.step_locked())?;
+        if let Some(e) = outcome.error() {
+            self.events.on_error(e);
+        }
+        // only the first cycle
+        self.ignore_etag = false;
+
+        let duration = start.elapsed();
+        info!(
+            outcome = outcome.as_str(),
+            reason = match outcome {
+                CycleOutcome::Unchanged(ref reason) => Some(reason.as_str()),
+                _ => None,
+            },
+            duration_secs = duration.as_secs_f64(),
+            "Cycle finished"
+        );
+        #[cfg(feature = "metrics")]
+        metrics::record_cycle(&outcome, duration);
+        let config = self.data_dir.load_config()?;
+        if let Err(e) = history::append(
+            &self.data_dir.history_path(),
+            &history::HistoryEntry::new(&config, &outcome, duration),
+            config.history_keep(),
+        ) {
+            warn!(error = %e, "Failed to record the cycle in the history");
+        }
+        if let Some(path) = config.metrics_textfile() {
+            #[cfg(feature = "metrics")]
+            if let Err(e) = metrics::write_textfile(path, &config) {
+                warn!(error = %e, path = %path.display(), "Failed to write metrics");
+            }
+            #[cfg(not(feature = "metrics"))]
+            warn!(
+                error = %crate::error::feature_disabled("The metrics textfile", "metrics"),
+                path = %path.display(),
+                "Failed to write metrics"
+            );
+        }
+        if config.cloudwatch().enabled {
+            #[cfg(feature = "metrics")]
+            if let Err(e) = cloudwatch::publish(config.cloudwatch(), &config, &outcome, duration) {
+                warn!(error = %e, "Failed to publish CloudWatch metrics");
+            }
+            #[cfg(not(feature = "metrics"))]
+            warn!(
+                error = %crate::error::feature_disabled("Publishing to CloudWatch", "metrics"),
+                "Failed to publish CloudWatch metrics"
+            );
+        }
+        if let Some(prefix) = config.status_report_prefix() {
+            if let Err(e) = report::upload(prefix, &report::HostStatus::collect(&config, &outcome))
+            {
+                warn!(error = %e, "Failed to report the host status");
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Fetch the settings overrides published next to the remote, keeping
+    /// the previous ones if that fails
+    fn refresh_remote_settings(&self, config: Config) -> anyhow::Result<Config> {
+        let overrides = match config
+            .effective_remote()
+            .and_then(|remote| Ok(remote_settings::fetch(&remote)?))
+        {
+            Ok(overrides) => overrides,
+            Err(e) => {
+                warn!(error = %e, "Failed to fetch the remote settings, keeping the previous ones");
+                return Ok(config);
+            }
+        };
+        if serde_json::to_value(&overrides)? == serde_json::to_value(config.remote_overrides())? {
+            return Ok(config);
+        }
+        info!(
+            overrides = %serde_json::to_string(&overrides)?,
+            "Remote settings changed"
+        );
+        self.data_dir
+            .update_config(|config| Ok(config.with_remote_overrides(overrides)))?;
+        self.data_dir.load_config()
+    }
+
+    fn step_locked(&mut self) -> Result<CycleOutcome, NpcnixError> {
+        let data_dir = &self.data_dir;
+        // Note: we load every time, in case settings changed
+        let config = data_dir.load_config()?;
+        let config = if config.remote_settings() {
+            self.refresh_remote_settings(config)?
+        } else {
+            config
+        };
+
+        if config.is_paused_at(self.clock.now()) {
+            // keep polling, so it's visible what would be activated
+            match config.effective_remote().and_then(|remote| {
+                let etag = self.remote.get_etag(&remote, &config)?;
+                self.events.on_check(&remote, &etag);
+                Ok(etag)
+            }) {
+                Ok(etag) if etag != config.last_etag() => {
+                    data_dir.update_last_check()?;
+                    info!(
+                        etag,
+                        reason = config.pause_reason(),
+                        "Paused, not activating the changed remote"
+                    )
+                }
+                Ok(_) => {
+                    data_dir.update_last_check()?;
+                    info!(reason = config.pause_reason(), "Paused")
+                }
+                Err(e) => warn!(error = %e, "Paused, and failed to check the remote"),
+            }
+            systemd::status(&config.status_string());
+            return Ok(CycleOutcome::Unchanged(UnchangedReason::Paused));
+        }
+        systemd::status("Checking remote");
+        if config.force_next() {
+            info!("Forced re-activation requested");
+            data_dir.update_config(|config| Ok(config.with_force_next(false)))?;
+        }
+        let ignore_etag = self.ignore_etag || config.force_next();
+        match self.try_activate(&config, ignore_etag) {
+            Ok(CycleOutcome::Changed {
+                configuration,
+                etag,
+            }) => {
+                self.data_dir
+                    .update_last_reconfiguration(&configuration, &etag)?;
+                info!(etag, "Successfully activated new configuration");
+                systemd::status(&format!("Activated {configuration} (etag {etag})"));
+                Ok(CycleOutcome::Changed {
+                    configuration,
+                    etag,
+                })
+            }
+            Ok(CycleOutcome::Unchanged(UnchangedReason::UpToDate)) => {
+                info!("Remote not changed");
+                systemd::status(&format!("Up to date (etag {})", config.last_etag()));
+                Ok(CycleOutcome::Unchanged(UnchangedReason::UpToDate))
+            }
+            Ok(outcome) => Ok(outcome),
+            Err(NpcnixError::Cancelled) => {
+                warn!("Cycle cancelled");
+                Err(NpcnixError::Cancelled)
+            }
+            // e.g. a network outage: not the fault of the remote, no point in
+            // backing off
+            Err(e) if e.is_transient() => {
+                let e = anyhow::Error::from(e);
+                warn!(error = format!("{e:#}"), "Failed to check the remote");
+                systemd::status(&format!("Remote unavailable: {e:#}"));
+                Ok(CycleOutcome::RemoteUnavailable(e))
+            }
+            Err(e) => {
+                let e = anyhow::Error::from(e);
+                error!(
+                    error = format!("{e:#}"),
+                    "Failed to activate new configuration"
+                );
+                systemd::status(&format!("Activation failed: {e:#}"));
+                Ok(CycleOutcome::Failed(e))
+            }
+        }
+    }
+
+    /// Check the remote and activate it if needed (and allowed), returning
+    /// either [`CycleOutcome::Changed`] or [`CycleOutcome::Unchanged`]
+    fn try_activate(
+        &mut self,
+        config: &Config,
+        ignore_etag: bool,
+    ) -> Result<CycleOutcome, NpcnixError> {
+        let data_dir = &self.data_dir;
+        let configuration = self
+            .override_configuration
+            .as_deref()
+            .map(Ok)
+            .unwrap_or_else(|| config.configuration())?;
+        let unchanged = |reason| Ok(CycleOutcome::Unchanged(reason));
+
+        let remote = config.effective_remote()?;
+        let etag = info_span!("check", phase = "check", configuration)
+            .in_scope(|| self.remote.get_etag(&remote, config))?;
+        data_dir.update_last_check()?;
+        self.events.on_check(&remote, &etag);
+
+        if !ignore_etag
+            && config.last_configuration() == configuration
+            && config.last_etag() == etag
+        {
+            let Some(running) = config.drifted_system(activation::running_system().as_deref())
+            else {
+                return unchanged(UnchangedReason::UpToDate);
+            };
+            warn!(
+                etag,
+                expected = config.expected_system().map(|path| path.display().to_string()),
+                running = %running.display(),
+                "Running system differs from the last activated one, re-activating"
+            );
+        }
+        self.events.on_change_detected(configuration, &etag);
+        if config.held_etag() == Some(etag.as_str()) {
+            info!(etag, "Remote etag is held, not activating");
+            return unchanged(UnchangedReason::Held);
+        }
+        if !ignore_etag && config.is_quarantined(configuration, &etag) {
+            info!(etag, "Remote etag is quarantined, not activating");
+            return unchanged(UnchangedReason::Quarantined);
+        }
+        if let Some(approval_opts) = config.approval() {
+            if !approval::is_approved(&config.effective_remote()?, &etag, approval_opts)? {
+                info!(etag, "Remote etag is not approved, not activating");
+                return unchanged(UnchangedReason::NotApproved);
+            }
+        }
+        if !ignore_etag
+            && config.rollout_splay_secs() != 0
+            && (config.last_configuration() != configuration || config.last_etag() != etag)
+        {
+            let rollout_time = data_dir
+                .record_seen_remote(configuration, &etag, self.clock.now())?
+                .rollout_time(configuration, &etag);
+            if let Some(until) = rollout_time.filter(|time| self.clock.now() < *time) {
+                info!(
+                    etag,
+                    rollout_time = %until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    "Waiting for this host's turn in the staggered rollout, not activating yet"
+                );
+                return unchanged(UnchangedReason::WaitingForRollout { until });
+            }
+        }
+        let staged = config.staged();
+        if staged && !self.stage_update(config, configuration, &etag)? {
+            return unchanged(UnchangedReason::Staged);
+        }
+        if !config.is_in_activation_window(self.clock.now()) {
+            info!(
+                etag,
+                "Outside of the activation windows or in quiet hours, not activating"
+            );
+            if config.prebuilt_etag() != Some(etag.as_str()) {
+                // a failed build counts as a failed activation, so it's
+                // backed off and quarantined rather than rebuilt every cycle
+                self.pull_and_prebuild(config, configuration, &etag)
+                    .inspect_err(|e| {
+                        if is_transient(e) || cancel::is_cancelled(e) {
+                            return;
+                        }
+                        if let Err(e) = record_activation_failure(data_dir, configuration, &etag, e)
+                        {
+                            warn!(error = %e, "Failed to record pre-build failure");
+                        }
+                    })?;
+                data_dir.update_config(|config| Ok(config.with_prebuilt_etag(Some(&etag))))?;
+            }
+            return unchanged(UnchangedReason::OutsideActivationWindow);
+        }
+        let lease = match config.coordination() {
+            Some(coordination_opts) => match coordination::acquire(coordination_opts)? {
+                Some(lease) => Some(lease),
+                None => {
+                    info!(etag, "All the rollout slots are taken, not activating yet");
+                    return unchanged(UnchangedReason::NoRolloutSlot);
+                }
+            },
+            None => None,
+        };
+
+        self.events.on_activation_start(configuration, &etag);
+        let res = if staged {
+            crate::activate_unpacked(
+                &*self.activator,
+                config,
+                Some(data_dir),
+                &self.activate_opts,
+                configuration,
+                &etag,
+                &data_dir.staging_dir(),
+            )
+        } else {
+            self.pull_and_activate(config, configuration, &etag)
+        };
+        if let Some(lease) = lease {
+            if let Err(e) = lease.release() {
+                warn!(error = %e, "Failed to release the rollout slot");
+            }
+        }
+        self.events
+            .on_activation_finish(configuration, &etag, res.as_ref());
+        if res.as_ref().is_err_and(cancel::is_cancelled) {
+            return Err(NpcnixError::Cancelled);
+        }
+        res.inspect_err(|e| {
+            // e.g. the network failing while pulling: not the fault of the
+            // etag, so not counted towards its quarantine
+            if is_transient(e) {
+                return;
+            }
+            notify::notify(config, NotifyEvent::Failure, configuration, &etag, Some(e));
+            if let Err(e) = record_activation_failure(data_dir, configuration, &etag, e) {
+                warn!(error = %e, "Failed to record activation failure");
+            }
+        })?;
+        notify::notify(config, NotifyEvent::Success, configuration, &etag, None);
+
+        Ok(CycleOutcome::Changed {
+            configuration: configuration.to_owned(),
+            etag,
+        })
+    }
+
+    fn pull_opts(&self, config: &Config) -> PullOpts {
+        PullOpts {
+            cancel: self.cancel.clone(),
+            ..config.into()
+        }
+    }
+
+    /// Pull and build (but don't activate) the remote, so the activation
+    /// inside the activation window is quick
+    fn pull_and_prebuild(
+        &self,
+        config: &Config,
+        configuration: &str,
+        etag: &str,
+    ) -> anyhow::Result<()> {
+        if !self
+            .activator
+            .supports_prebuild(&self.activate_opts, config)
+        {
+            debug!("Pre-building not supported");
+            return Ok(());
+        }
+        let tmp_dir = tempfile::TempDir::new()?;
+        self.remote.pull(
+            &config.effective_remote()?,
+            tmp_dir.path(),
+            &self.pull_opts(config),
+        )?;
+        info!(etag, "Pre-building the new configuration");
+        self.activator
+            .prebuild(tmp_dir.path(), configuration, &self.activate_opts, config)
+    }
+
+    fn pull_and_activate(
+        &self,
+        config: &Config,
+        configuration: &str,
+        etag: &str,
+    ) -> anyhow::Result<ActivationResult> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        info_span!("pull", phase = "pull", etag).in_scope(|| {
+            self.remote.pull(
+                &config.effective_remote()?,
+                tmp_dir.path(),
+                &self.pull_opts(config),
+            )
+        })?;
+        crate::activate_unpacked(
+            &*self.activator,
+            config,
+            Some(&self.data_dir),
+            &self.activate_opts,
+            configuration,
+            etag,
+            tmp_dir.path(),
+        )
+    }
+
+    /// Pull the remote into the staging directory, unless already there
+    ///
+    /// Returns whether the staged update was approved for activation.
+    fn stage_update(
+        &self,
+        config: &Config,
+        configuration: &str,
+        etag: &str,
+    ) -> anyhow::Result<bool> {
+        match config.pending_update() {
+            Some(pending) if pending.is_for(configuration, etag) && pending.approved => {
+                return Ok(true)
+            }
+            Some(pending) if pending.is_for(configuration, etag) => {
+                info!(etag, "Update staged, waiting for approval");
+                return Ok(false);
+            }
+            _ => {}
+        }
+
+        let staging_dir = self.data_dir.staging_dir();
+        info_span!("pull", phase = "pull", etag).in_scope(|| {
+            let remote = config.effective_remote()?;
+            misc::replace_dir_with(&staging_dir, None, |tmp_dst| {
+                self.remote
+                    .pull(&remote, tmp_dst, &self.pull_opts(config))?;
+                Ok(())
+            })
+        })?;
+        if ClosureRef::load_from(&staging_dir)?.is_none() {
+            crate::verify_flake_src(&staging_dir)?;
+        }
+        self.data_dir.update_config(|config| {
+            Ok(config.with_pending_update(Some(PendingUpdate {
+                configuration: configuration.to_owned(),
+                etag: etag.to_owned(),
+                timestamp: self.clock.now(),
+                approved: false,
+            })))
+        })?;
+        info!(etag, staging_dir = %staging_dir.display(), "Staged new remote, waiting for approval");
+        Ok(false)
+    }
+}
+
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<NpcnixError>()
+        .is_some_and(NpcnixError::is_transient)
+}
+
+/// Record a failed activation, quarantining the etag after too many of them
+fn record_activation_failure(
+    data_dir: &DataDir,
+    configuration: &str,
+    etag: &str,
+    error: &anyhow::Error,
+) -> anyhow::Result<()> {
+    data_dir.record_activation_failure(configuration, etag, &format!("{error:#}"))?;
+    let config = data_dir.load_config()?;
+    if let Some(failure) = config
+        .quarantined()
+        .filter(|failure| failure.is_for(configuration, etag))
+    {
+        error!(
+            etag,
+            configuration,
+            failures = failure.count,
+            quarantined = true,
+            "Quarantined the remote etag after too many failed activations, not retrying until \
+             the remote changes or it's unquarantined"
+        );
+        notify::notify(
+            &config,
+            NotifyEvent::Quarantine,
+            configuration,
+            etag,
+            Some(error),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use url::Url;
+
+    use super::{Clock, CycleOutcome, DaemonEngine, UnchangedReason};
+    use crate::data_dir::DataDir;
+    use crate::retry::FailureBackoffOpts;
+    use crate::test_util::{self, FakeActivator, FixedClock, FsRemote};
+
+    struct Fixture {
+        _tmp: tempfile::TempDir,
+        _data_tmp: tempfile::TempDir,
+        url: Url,
+        remote: FsRemote,
+        data_dir: DataDir,
+        activator: FakeActivator,
+        clock: FixedClock,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let tmp = tempfile::tempdir().unwrap();
+            let remote = FsRemote::new(tmp.path());
+            let url = Url::parse("s3://bucket/host").unwrap();
+            let (data_tmp, data_dir) = test_util::data_dir(&url, "host").unwrap();
+            Self {
+                _tmp: tmp,
+                _data_tmp: data_tmp,
+                url,
+                remote,
+                data_dir,
+                activator: FakeActivator::new(),
+                clock: FixedClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()),
+            }
+        }
+
+        /// Publish a new version of the flake, returning its etag
+        fn publish(&self, marker: &str) -> String {
+            let src = test_util::flake_dir(&["host"]).unwrap();
+            std::fs::write(src.path().join("marker"), marker).unwrap();
+            self.remote.publish(&self.url, src.path()).unwrap()
+        }
+
+        fn engine(&self) -> DaemonEngine {
+            DaemonEngine::new(self.data_dir.clone(), Default::default())
+                .with_remote(self.remote.clone())
+                .with_activator(self.activator.clone())
+                .with_clock(self.clock.clone())
+        }
+
+        fn activated_etags(&self) -> Vec<Option<String>> {
+            self.activator
+                .activations()
+                .into_iter()
+                .map(|activation| activation.etag)
+                .collect()
+        }
+    }
+
+    fn assert_changed(outcome: CycleOutcome, etag: &str) {
+        match outcome {
+            CycleOutcome::Changed {
+                configuration,
+                etag: changed,
+            } => {
+                assert_eq!(configuration, "host");
+                assert_eq!(changed, etag);
+            }
+            outcome => panic!("Expected a change, got {outcome:?}"),
+        }
+    }
+
+    fn assert_unchanged(outcome: CycleOutcome, reason: UnchangedReason) {
+        match outcome {
+            CycleOutcome::Unchanged(unchanged) => assert_eq!(unchanged, reason),
+            outcome => panic!("Expected no change ({reason:?}), got {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn activates_new_etags_only() {
+        let fixture = Fixture::new();
+        let mut engine = fixture.engine();
+        let v1 = fixture.publish("v1");
+        assert_changed(engine.step().unwrap(), &v1);
+        assert_eq!(
+            fixture.data_dir.load_config().unwrap().last_etag(),
+            v1.as_str()
+        );
+
+        assert_unchanged(engine.step().unwrap(), UnchangedReason::UpToDate);
+        assert_eq!(fixture.activated_etags(), [Some(v1.clone())]);
+
+        let v2 = fixture.publish("v2");
+        assert_ne!(v1, v2);
+        assert_changed(engine.step().unwrap(), &v2);
+        assert_eq!(fixture.activated_etags(), [Some(v1), Some(v2)]);
+    }
+
+    #[test]
+    fn backs_off_then_quarantines_failed_activations() {
+        let fixture = Fixture::new();
+        let opts = FailureBackoffOpts::default();
+        let mut engine = fixture.engine();
+        let etag = fixture.publish("v1");
+        fixture
+            .activator
+            .set_failure(Some("switch-to-configuration failed"));
+        for failures in 1..=opts.max_retries_per_etag {
+            assert!(matches!(engine.step().unwrap(), CycleOutcome::Failed(_)));
+            assert_eq!(fixture.activator.activations().len(), failures as usize);
+            let config = fixture.data_dir.load_config().unwrap();
+            assert_eq!(config.last_failure().unwrap().count, failures);
+            if failures < opts.max_retries_per_etag {
+                assert!(!config.is_quarantined("host", &etag));
+                assert_eq!(engine.next_sleep_time().unwrap(), opts.backoff(failures));
+            }
+        }
+        assert!(fixture
+            .data_dir
+            .load_config()
+            .unwrap()
+            .is_quarantined("host", &etag));
+        assert_eq!(
+            engine.next_sleep_time().unwrap(),
+            Duration::from_secs(opts.max_backoff_secs)
+        );
+
+        // not retried, even once activating would succeed
+        fixture.activator.set_failure(None);
+        assert_unchanged(engine.step().unwrap(), UnchangedReason::Quarantined);
+        assert_eq!(
+            fixture.activator.activations().len(),
+            opts.max_retries_per_etag as usize
+        );
+
+        // until the remote changes
+        let fixed = fixture.publish("v2");
+        assert_changed(engine.step().unwrap(), &fixed);
+        assert!(fixture
+            .data_dir
+            .load_config()
+            .unwrap()
+            .last_failure()
+            .is_none());
+    }
+
+    #[test]
+    fn skips_held_etags() {
+        let fixture = Fixture::new();
+        let mut engine = fixture.engine();
+        let v1 = fixture.publish("v1");
+        fixture
+            .data_dir
+            .update_config(|config| Ok(config.with_held_etag(Some(&v1))))
+            .unwrap();
+        assert_unchanged(engine.step().unwrap(), UnchangedReason::Held);
+        assert!(fixture.activator.activations().is_empty());
+
+        let v2 = fixture.publish("v2");
+        assert_changed(engine.step().unwrap(), &v2);
+        assert_eq!(fixture.activated_etags(), [Some(v2)]);
+    }
+
+    #[test]
+    fn defers_activations_outside_the_activation_windows() {
+        let fixture = Fixture::new();
+        let mut engine = fixture.engine();
+        let etag = fixture.publish("v1");
+        fixture
+            .data_dir
+            .update_config(
+                |config| Ok(config.with_activation_windows(vec!["02:00-04:00".parse()?])),
+            )
+            .unwrap();
+        assert_unchanged(
+            engine.step().unwrap(),
+            UnchangedReason::OutsideActivationWindow,
+        );
+        assert!(fixture.activator.activations().is_empty());
+
+        fixture.clock.advance(chrono::Duration::hours(15));
+        assert_changed(engine.step().unwrap(), &etag);
+        assert_eq!(fixture.activated_etags(), [Some(etag)]);
+        assert_eq!(fixture.activator.prebuilds(), ["host"]);
+    }
+
+    #[test]
+    fn backs_off_then_quarantines_failed_prebuilds() {
+        let fixture = Fixture::new();
+        let opts = FailureBackoffOpts::default();
+        let mut engine = fixture.engine();
+        let etag = fixture.publish("v1");
+        fixture
+            .data_dir
+            .update_config(
+                |config| Ok(config.with_activation_windows(vec!["02:00-04:00".parse()?])),
+            )
+            .unwrap();
+        fixture.activator.set_prebuild_failure(Some("build failed"));
+        for failures in 1..=opts.max_retries_per_etag {
+            assert!(matches!(engine.step().unwrap(), CycleOutcome::Failed(_)));
+            assert_eq!(fixture.activator.prebuilds().len(), failures as usize);
+            let config = fixture.data_dir.load_config().unwrap();
+            assert_eq!(config.last_failure().unwrap().count, failures);
+            assert_eq!(config.prebuilt_etag(), None);
+            if failures < opts.max_retries_per_etag {
+                assert_eq!(engine.next_sleep_time().unwrap(), opts.backoff(failures));
+            }
+        }
+        assert_unchanged(engine.step().unwrap(), UnchangedReason::Quarantined);
+        assert_eq!(
+            fixture.activator.prebuilds().len(),
+            opts.max_retries_per_etag as usize
+        );
+        assert!(fixture.activator.activations().is_empty());
+        assert!(fixture
+            .data_dir
+            .load_config()
+            .unwrap()
+            .is_quarantined("host", &etag));
+    }
+
+    #[test]
+    fn waits_for_the_rollout_delay_on_the_engine_clock() {
+        let fixture = Fixture::new();
+        let mut engine = fixture.engine();
+        let etag = fixture.publish("v1");
+        fixture
+            .data_dir
+            .update_config(|config| Ok(config.with_rollout_splay_secs(24 * 3600)))
+            .unwrap();
+        let config = fixture.data_dir.load_config().unwrap();
+        let delay = chrono::Duration::from_std(config.rollout_delay()).unwrap();
+        let seen = fixture.clock.now();
+        if !delay.is_zero() {
+            assert_unchanged(
+                engine.step().unwrap(),
+                UnchangedReason::WaitingForRollout {
+                    until: seen + delay,
+                },
+            );
+            let config = fixture.data_dir.load_config().unwrap();
+            assert_eq!(config.first_seen().unwrap().timestamp, seen);
+
+            fixture.clock.advance(delay - chrono::Duration::seconds(1));
+            assert!(matches!(
+                engine.step().unwrap(),
+                CycleOutcome::Unchanged(UnchangedReason::WaitingForRollout { .. })
+            ));
+            assert!(fixture.activator.activations().is_empty());
+        }
+
+        fixture.clock.set(seen + delay);
+        assert_changed(engine.step().unwrap(), &etag);
+    }
+
+    #[test]
+    fn remote_failures_while_pulling_dont_quarantine() {
+        let fixture = Fixture::new();
+        let etag = fixture.publish("v1");
+        let max_retries = FailureBackoffOpts::default().max_retries_per_etag;
+        fixture.remote.set_pull_failure(Some("connection reset"));
+        let mut engine = fixture.engine();
+        for _ in 0..=max_retries {
+            assert!(matches!(
+                engine.step().unwrap(),
+                CycleOutcome::RemoteUnavailable(_)
+            ));
+        }
+        let config = fixture.data_dir.load_config().unwrap();
+        assert!(config.last_failure().is_none());
+        assert!(!config.is_quarantined("host", &etag));
+        assert!(fixture.activator.activations().is_empty());
+
+        fixture.remote.set_pull_failure(None);
+        assert!(matches!(
+            engine.step().unwrap(),
+            CycleOutcome::Changed { etag: ref changed, .. } if *changed == etag
+        ));
+    }
+}
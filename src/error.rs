@@ -0,0 +1,149 @@
+//! Errors of the library API
+//!
+//! The public functions of the crate return [`NpcnixError`], so callers (and
+//! the daemon itself) can tell e.g. a temporarily unreachable remote from a
+//! failed activation. Internally errors are still `anyhow::Error`s, which can
+//! carry an `NpcnixError` through to the API boundary.
+
+use url::Url;
+
+use crate::activation::CommandFailed;
+use crate::cancel;
+
+#[derive(thiserror::Error, Debug)]
+pub enum NpcnixError {
+    /// Transferring from or to the remote failed (e.g. network or permission
+    /// issue)
+    #[error("Remote {remote} unavailable")]
+    RemoteUnavailable { remote: Url, source: anyhow::Error },
+    /// Checking the etag of the remote failed
+    #[error("Failed to check the etag of {remote}")]
+    EtagFetch { remote: Url, source: anyhow::Error },
+    /// The archive is invalid (e.g. hash mismatch, unpack limits exceeded)
+    #[error("Invalid archive")]
+    Unpack(#[source] anyhow::Error),
+    /// Building or switching to the configuration failed
+    #[error("Failed to activate {configuration}")]
+    ActivationFailed {
+        configuration: String,
+        source: anyhow::Error,
+    },
+    /// The settings are invalid, see [`crate::config::Config::problems`]
+    #[error("Invalid config:\n  - {}", .0.join("\n  - "))]
+    ConfigInvalid(Vec<String>),
+    /// A setting required for the operation is missing
+    #[error("{0}")]
+    NotConfigured(String),
+    /// Cancelled through a [`cancel::CancellationToken`]
+    #[error("Cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl NpcnixError {
+    /// Remote errors which are likely to go away by themselves, so worth
+    /// retrying later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            NpcnixError::RemoteUnavailable { .. } | NpcnixError::EtagFetch { .. }
+        )
+    }
+
+    /// Exit code of the failed activation command, if that's what failed
+    pub fn exit_code(&self) -> Option<i32> {
+        let NpcnixError::ActivationFailed { source, .. } = self else {
+            return None;
+        };
+        source
+            .chain()
+            .find_map(|e| e.downcast_ref::<CommandFailed>())
+            .and_then(|e| e.code)
+    }
+
+    pub(crate) fn remote_unavailable(remote: &Url) -> impl FnOnce(anyhow::Error) -> Self + '_ {
+        |source| {
+            if cancel::is_cancelled(&source) {
+                return NpcnixError::Cancelled;
+            }
+            NpcnixError::RemoteUnavailable {
+                remote: remote.clone(),
+                source,
+            }
+        }
+    }
+
+    pub(crate) fn etag_fetch(remote: &Url) -> impl FnOnce(anyhow::Error) -> Self + '_ {
+        |source| NpcnixError::EtagFetch {
+            remote: remote.clone(),
+            source,
+        }
+    }
+
+    pub(crate) fn activation_failed(
+        configuration: &str,
+    ) -> impl FnOnce(anyhow::Error) -> Self + '_ {
+        |source| {
+            if cancel::is_cancelled(&source) {
+                return NpcnixError::Cancelled;
+            }
+            NpcnixError::ActivationFailed {
+                configuration: configuration.to_owned(),
+                source,
+            }
+        }
+    }
+}
+
+/// Unwraps an `NpcnixError` passed through `anyhow` (unless some context was
+/// added since), cancellations are [`NpcnixError::Cancelled`], anything else
+/// is [`NpcnixError::Other`]
+impl From<anyhow::Error> for NpcnixError {
+    fn from(e: anyhow::Error) -> Self {
+        if cancel::is_cancelled(&e) {
+            return NpcnixError::Cancelled;
+        }
+        if !e.chain().next().is_some_and(|e| e.is::<NpcnixError>()) {
+            return NpcnixError::Other(e);
+        }
+        match e.downcast() {
+            Ok(e) => e,
+            Err(e) => NpcnixError::Other(e),
+        }
+    }
+}
+
+/// The error of the subsystems left out of the build
+#[cfg_attr(
+    all(
+        feature = "http",
+        feature = "metrics",
+        feature = "notifications",
+        feature = "s3-cli"
+    ),
+    allow(dead_code)
+)]
+pub(crate) fn feature_disabled(what: &str, feature: &str) -> anyhow::Error {
+    anyhow::format_err!("{what} needs npcnix built with the `{feature}` feature")
+}
+
+macro_rules! impl_from {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for NpcnixError {
+                fn from(e: $ty) -> Self {
+                    NpcnixError::Other(e.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(std::io::Error, serde_json::Error, url::ParseError);
+
+impl From<cancel::Cancelled> for NpcnixError {
+    fn from(_: cancel::Cancelled) -> Self {
+        NpcnixError::Cancelled
+    }
+}
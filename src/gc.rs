@@ -0,0 +1,104 @@
+//! Garbage collection of old system generations and the Nix store
+
+use std::ffi::{CString, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::{io, process};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::activation::{privileged_command, Escalation, SYSTEM_PROFILE};
+use crate::{nix_env_path, CommandExt};
+
+const NIX_STORE: &str = "/nix/store";
+
+pub fn nix_collect_garbage_path() -> OsString {
+    std::env::var_os("NPCNIX_NIX_COLLECT_GARBAGE")
+        .unwrap_or_else(|| OsString::from("nix-collect-garbage"))
+}
+
+fn default_keep_generations() -> u32 {
+    10
+}
+
+/// Settings of the garbage collection after successful activations
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub struct GcOpts {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Number of the newest system generations to keep
+    #[serde(default = "default_keep_generations")]
+    pub keep_generations: u32,
+    /// Only collect garbage if the Nix store has less free space than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_bytes: Option<u64>,
+}
+
+impl Default for GcOpts {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            keep_generations: default_keep_generations(),
+            min_free_bytes: None,
+        }
+    }
+}
+
+fn free_bytes(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string, and `stat` is only read on success
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn run(cmd: &mut process::Command, what: &str) -> anyhow::Result<()> {
+    let status = cmd
+        .log_debug()
+        .status()
+        .with_context(|| format!("Calling `{what}` failed"))?;
+    if !status.success() {
+        bail!("{what} returned exit code={:?}", status.code());
+    }
+    Ok(())
+}
+
+/// Delete old system generations and collect garbage, if enabled
+pub fn collect_garbage(opts: &GcOpts, escalation: Option<Escalation>) -> anyhow::Result<()> {
+    if !opts.enabled {
+        return Ok(());
+    }
+    if let Some(min_free_bytes) = opts.min_free_bytes {
+        let free_bytes = free_bytes(Path::new(NIX_STORE))
+            .with_context(|| format!("Failed to check free space of {NIX_STORE}"))?;
+        if min_free_bytes <= free_bytes {
+            debug!(free_bytes, min_free_bytes, "Enough free space, skipping GC");
+            return Ok(());
+        }
+    }
+
+    info!(
+        keep_generations = opts.keep_generations,
+        "Deleting old generations and collecting garbage"
+    );
+    run(
+        privileged_command(nix_env_path(), escalation).args([
+            "--profile",
+            SYSTEM_PROFILE,
+            "--delete-generations",
+            &format!("+{}", opts.keep_generations.max(1)),
+        ]),
+        "nix-env",
+    )?;
+    run(
+        &mut privileged_command(nix_collect_garbage_path(), escalation),
+        "nix-collect-garbage",
+    )
+}
@@ -0,0 +1,204 @@
+//! Deployment snippets for the daemon, as printed by `npcnix generate`
+//!
+//! For hosts not importing the NixOS module of the flake: a hardened systemd
+//! unit running `npcnix follow`, and a standalone NixOS module with the same
+//! service and the settings of the current config.
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time;
+
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::secrets;
+
+/// Used as the activation timeout if none is set, to size the watchdog
+const DEFAULT_CYCLE_TIMEOUT: time::Duration = time::Duration::from_secs(2 * 60 * 60);
+
+/// Time for a cycle to check and pull the remote, on top of activating it
+const TRANSFER_TIMEOUT: time::Duration = time::Duration::from_secs(15 * 60);
+
+/// Sandboxing of the service, as far as switching the system allows: it
+/// must run as root, and writes `/nix`, `/etc`, the boot loader and
+/// `/usr/bin/env`
+const HARDENING: &[(&str, &str)] = &[
+    ("DynamicUser", "no"),
+    ("ProtectSystem", "true"),
+    ("ReadWritePaths", "-/boot -/efi -/usr/bin"),
+    ("PrivateTmp", "true"),
+    ("ProtectClock", "true"),
+    ("ProtectKernelLogs", "true"),
+    ("LockPersonality", "true"),
+    ("RestrictRealtime", "true"),
+];
+
+/// The daemon only pings the watchdog between cycles, so it must allow for
+/// the longest activation
+fn watchdog_secs(config: &Config) -> u64 {
+    (config.activation_timeout().unwrap_or(DEFAULT_CYCLE_TIMEOUT) + TRANSFER_TIMEOUT).as_secs()
+}
+
+fn description(config: &Config) -> String {
+    match config.remote() {
+        Ok(remote) => format!("npcnix, activating the NixOS configuration from {remote}"),
+        Err(_) => "npcnix, activating the NixOS configuration from the remote".into(),
+    }
+}
+
+/// Arguments of the daemon
+///
+/// It exits after activating a new configuration, to get restarted running
+/// the npcnix of that configuration.
+fn follow_args(data_dir: &Path) -> String {
+    format!("--data-dir {} follow --once=activate", data_dir.display())
+}
+
+/// A systemd service unit running the `npcnix` binary at `npcnix`, for the
+/// config in `data_dir`
+pub fn systemd_unit(npcnix: &Path, data_dir: &Path, config: &Config) -> String {
+    let mut hardening = String::new();
+    for (key, value) in HARDENING {
+        let _ = writeln!(hardening, "{key}={value}");
+    }
+    format!(
+        r#"# Generated by `npcnix generate systemd-unit`
+[Unit]
+Description={description}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart={npcnix} {follow_args}
+# checks the remote right away
+ExecReload=kill -HUP $MAINPID
+# `nixos-rebuild`, `nix` and `aws` are looked up in `$PATH`
+Environment=PATH=/run/current-system/sw/bin:/usr/local/bin:/usr/bin:/bin
+# let npcnix finish the current activation on stop, instead of killing it
+KillMode=mixed
+Restart=always
+RestartSec=15
+WatchdogSec={watchdog_secs}
+{hardening}
+[Install]
+WantedBy=multi-user.target
+"#,
+        description = description(config),
+        npcnix = npcnix.display(),
+        follow_args = follow_args(data_dir),
+        watchdog_secs = watchdog_secs(config),
+    )
+}
+
+/// A NixOS module running npcnix from `package` (a store path, `None`: left
+/// for the user to fill in) for the config in `data_dir`, with the settings
+/// of `config` as the base settings
+///
+/// Sensitive settings that are not `secret:<name>` references are redacted,
+/// as the module ends up in the world-readable Nix store.
+pub fn nixos_module(
+    package: Option<&Path>,
+    data_dir: &Path,
+    config: &Config,
+) -> anyhow::Result<String> {
+    let mut settings = config.settings()?;
+    secrets::redact_settings(&mut settings);
+    let mut settings = Value::Object(settings);
+    // TOML has no null
+    remove_nulls(&mut settings);
+    let mut settings_json = String::new();
+    for line in serde_json::to_string_pretty(&settings)?.lines() {
+        let _ = writeln!(settings_json, "    {}", nix_indented_string_line(line));
+    }
+    let mut hardening = String::new();
+    for (key, value) in HARDENING {
+        let _ = writeln!(hardening, "      {key} = \"{}\";", nix_string_body(value));
+    }
+    let package = match package {
+        Some(package) => format!(
+            "builtins.storePath \"{}\"",
+            nix_string_body(&package.display().to_string())
+        ),
+        None => "throw \"Set the npcnix package in this module\"".into(),
+    };
+
+    Ok(format!(
+        r#"# Generated by `npcnix generate nixos-module`, to import in the
+# configuration of the host
+{{ config, lib, pkgs, ... }}:
+
+let
+  npcnix = {package};
+in
+{{
+  environment.systemPackages = [ npcnix ];
+
+  # base settings, overridden by the ones in {data_dir}
+  environment.etc."npcnix/config.toml".source = (pkgs.formats.toml {{ }}).generate "npcnix-config.toml" (builtins.fromJSON ''
+{settings_json}  '');
+
+  systemd.services.npcnix = {{
+    description = "{description}";
+    wantedBy = [ "multi-user.target" ];
+    wants = [ "network-online.target" ];
+    after = [ "network-online.target" ];
+    # don't kill the daemon currently running `nixos-rebuild`
+    stopIfChanged = false;
+    reloadIfChanged = false;
+    restartIfChanged = false;
+    serviceConfig = {{
+      Type = "notify";
+      ExecStart = "${{npcnix}}/bin/npcnix {follow_args}";
+      # checks the remote right away
+      ExecReload = "${{pkgs.coreutils}}/bin/kill -HUP $MAINPID";
+      # let npcnix finish the current activation on stop, instead of killing it
+      KillMode = "mixed";
+      Restart = "always";
+      RestartSec = 15;
+      WatchdogSec = {watchdog_secs};
+{hardening}    }};
+  }};
+}}
+"#,
+        data_dir = data_dir.display(),
+        description = nix_string_body(&description(config)),
+        follow_args = nix_string_body(&follow_args(data_dir)),
+        watchdog_secs = watchdog_secs(config),
+    ))
+}
+
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(remove_nulls);
+        }
+        Value::Array(array) => {
+            array.retain(|value| !value.is_null());
+            array.iter_mut().for_each(remove_nulls);
+        }
+        _ => {}
+    }
+}
+
+/// `s` escaped for a double-quoted Nix string
+fn nix_string_body(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `line` escaped for a Nix indented (`''`) string
+fn nix_indented_string_line(line: &str) -> String {
+    line.replace("''", "'''").replace("${", "''${")
+}
@@ -0,0 +1,105 @@
+//! Post-activation health checks
+
+use std::os::unix::process::CommandExt as _;
+use std::{process, time};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::misc::wait_timeout;
+use crate::CommandExt;
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+fn default_rollback() -> bool {
+    true
+}
+
+/// Checks run after a successful activation
+///
+/// With no checks configured, activation is considered healthy.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct HealthCheckOpts {
+    /// Shell commands (`sh -c`) that need to exit successfully
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<String>,
+    /// URLs that need to respond with a 2xx status
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub http: Vec<Url>,
+    /// Timeout of every single check
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Roll back to the previous system if any check fails
+    #[serde(default = "default_rollback")]
+    pub rollback: bool,
+}
+
+impl Default for HealthCheckOpts {
+    fn default() -> Self {
+        Self {
+            commands: vec![],
+            http: vec![],
+            timeout_secs: default_timeout_secs(),
+            rollback: default_rollback(),
+        }
+    }
+}
+
+impl HealthCheckOpts {
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty() && self.http.is_empty()
+    }
+
+    fn timeout(&self) -> time::Duration {
+        time::Duration::from_secs(self.timeout_secs)
+    }
+
+    /// Run all the checks, failing on the first unhealthy one
+    pub fn run(&self) -> anyhow::Result<()> {
+        for command in &self.commands {
+            self.run_command(command)
+                .with_context(|| format!("Health check `{command}` failed"))?;
+        }
+        for url in &self.http {
+            self.run_http(url)
+                .with_context(|| format!("Health check of {url} failed"))?;
+        }
+        if !self.is_empty() {
+            info!("Health checks passed");
+        }
+        Ok(())
+    }
+
+    fn run_command(&self, command: &str) -> anyhow::Result<()> {
+        debug!(command, "Running health check");
+        let mut child = process::Command::new("sh")
+            .args(["-c", command])
+            .process_group(0)
+            .log_debug()
+            .spawn()
+            .context("Failed to spawn `sh`")?;
+        match wait_timeout(&mut child, self.timeout())? {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => bail!("returned exit code={:?}", status.code()),
+            None => bail!("timed out after {}s", self.timeout_secs),
+        }
+    }
+
+    #[cfg(feature = "http")]
+    fn run_http(&self, url: &Url) -> anyhow::Result<()> {
+        debug!(%url, "Running health check");
+        // non-2xx statuses are returned as errors by `ureq`
+        ureq::get(url.as_str()).timeout(self.timeout()).call()?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "http"))]
+    fn run_http(&self, _url: &Url) -> anyhow::Result<()> {
+        Err(crate::error::feature_disabled("HTTP health checks", "http"))
+    }
+}
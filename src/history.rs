@@ -0,0 +1,115 @@
+//! History of the daemon cycles (`<data_dir>/history.jsonl`)
+//!
+//! One JSON object per line, oldest first, bounded to the configured number
+//! of entries.
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::{misc, CycleOutcome};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct HistoryEntry {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub configuration: Option<String>,
+    /// Etag activated (or attempted), or the current one if unchanged
+    pub etag: String,
+    pub outcome: String,
+    pub duration_secs: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+}
+
+impl HistoryEntry {
+    /// Describe a finished daemon cycle, with `config` loaded after it
+    pub fn new(config: &Config, outcome: &CycleOutcome, duration: std::time::Duration) -> Self {
+        let (configuration, etag, error) = match outcome {
+            CycleOutcome::Changed {
+                configuration,
+                etag,
+            } => (Some(configuration.clone()), etag.clone(), None),
+            CycleOutcome::Unchanged(_) => (
+                config.configuration().ok().map(ToOwned::to_owned),
+                config.last_etag().to_owned(),
+                None,
+            ),
+            CycleOutcome::Failed(e) | CycleOutcome::RemoteUnavailable(e) => {
+                match config.last_failure() {
+                    Some(failure) => (
+                        Some(failure.configuration.clone()),
+                        failure.etag.clone(),
+                        Some(format!("{e:#}")),
+                    ),
+                    None => (
+                        config.configuration().ok().map(ToOwned::to_owned),
+                        config.last_etag().to_owned(),
+                        Some(format!("{e:#}")),
+                    ),
+                }
+            }
+        };
+        let log_file = match outcome {
+            CycleOutcome::Unchanged(_) => None,
+            _ => config.last_activation_log().map(ToOwned::to_owned),
+        };
+        Self {
+            timestamp: chrono::Utc::now(),
+            configuration,
+            etag,
+            outcome: outcome.as_str().to_owned(),
+            duration_secs: duration.as_secs_f64(),
+            error,
+            log_file,
+        }
+    }
+}
+
+/// Read all the entries; unparsable lines are skipped
+pub fn read(path: &Path) -> anyhow::Result<Vec<HistoryEntry>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to open {}", path.display()));
+        }
+    };
+    let mut entries = vec![];
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!(error = %e, path = %path.display(), "Invalid history entry"),
+        }
+    }
+    Ok(entries)
+}
+
+/// Append an `entry`, dropping the oldest ones beyond `keep`
+pub fn append(path: &Path, entry: &HistoryEntry, keep: usize) -> anyhow::Result<()> {
+    let mut entries = read(path)?;
+    entries.push(entry.clone());
+    let remove_count = entries.len().saturating_sub(keep);
+    entries.drain(..remove_count);
+
+    misc::store_to_file_with(path, |f| -> anyhow::Result<()> {
+        for entry in &entries {
+            serde_json::to_writer(&mut *f, entry)?;
+            f.write_all(b"\n")?;
+        }
+        Ok(())
+    })??;
+    Ok(())
+}
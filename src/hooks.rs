@@ -0,0 +1,119 @@
+//! User hook scripts run around activation
+//!
+//! Hooks are executables in `<data_dir>/hooks/<hook>.d/`, run in the
+//! lexicographic order of their file names.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io, process};
+
+use anyhow::{bail, Context};
+use tracing::{debug, warn};
+
+use crate::activation::ActivationMode;
+use crate::CommandExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    /// Before activation; a failing hook aborts the activation
+    PreActivate,
+    /// After activation, whether it succeeded or not
+    PostActivate,
+    /// After a failed activation
+    OnFailure,
+}
+
+impl Hook {
+    fn dir_name(self) -> &'static str {
+        match self {
+            Hook::PreActivate => "pre-activate.d",
+            Hook::PostActivate => "post-activate.d",
+            Hook::OnFailure => "on-failure.d",
+        }
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.dir_name().trim_end_matches(".d"))
+    }
+}
+
+/// Details of the activation passed to hooks as `NPCNIX_*` env variables
+#[derive(Debug, Clone, Copy)]
+pub struct HookEnv<'a> {
+    pub configuration: &'a str,
+    pub src: &'a Path,
+    pub etag: Option<&'a str>,
+    pub mode: ActivationMode,
+    /// Error message of a failed activation
+    pub error: Option<&'a str>,
+}
+
+fn list_hooks(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut hooks = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+            hooks.push(path);
+        } else {
+            debug!(path = %path.display(), "Skipping non-executable hook");
+        }
+    }
+    hooks.sort();
+    Ok(hooks)
+}
+
+/// Run all executables of `hook` in `hooks_dir`, failing on the first failure
+pub fn run_hooks(hooks_dir: &Path, hook: Hook, env: &HookEnv) -> anyhow::Result<()> {
+    let dir = hooks_dir.join(hook.dir_name());
+    let hooks =
+        list_hooks(&dir).with_context(|| format!("Failed to list hooks in {}", dir.display()))?;
+
+    for path in hooks {
+        debug!(%hook, path = %path.display(), "Running hook");
+        let mut cmd = process::Command::new(&path);
+        if hook != Hook::PreActivate {
+            cmd.env(
+                "NPCNIX_OUTCOME",
+                if env.error.is_some() {
+                    "failure"
+                } else {
+                    "success"
+                },
+            );
+        }
+        let status = cmd
+            .current_dir(env.src)
+            .env("NPCNIX_HOOK", hook.to_string())
+            .env("NPCNIX_CONFIGURATION", env.configuration)
+            .env("NPCNIX_SRC", env.src)
+            .env("NPCNIX_ETAG", env.etag.unwrap_or_default())
+            .env("NPCNIX_MODE", env.mode.to_string())
+            .env("NPCNIX_ERROR", env.error.unwrap_or_default())
+            .log_debug()
+            .status()
+            .with_context(|| format!("Failed to run hook {}", path.display()))?;
+        if !status.success() {
+            bail!(
+                "Hook {} returned exit code={:?}",
+                path.display(),
+                status.code()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Like [`run_hooks`], but only log failures
+pub fn run_hooks_logged(hooks_dir: &Path, hook: Hook, env: &HookEnv) {
+    if let Err(e) = run_hooks(hooks_dir, hook, env) {
+        warn!(error = %e, %hook, "Hook failed");
+    }
+}
@@ -0,0 +1,59 @@
+//! EC2 instance metadata service (IMDSv2)
+
+use std::time;
+
+use anyhow::Context;
+use tracing::debug;
+
+const DEFAULT_ENDPOINT: &str = "http://169.254.169.254";
+const TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+fn endpoint() -> String {
+    std::env::var("NPCNIX_IMDS_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_owned())
+}
+
+/// An IMDSv2 session
+pub struct Imds {
+    endpoint: String,
+    token: String,
+}
+
+impl Imds {
+    pub fn connect() -> anyhow::Result<Self> {
+        let endpoint = endpoint();
+        let token = ureq::put(&format!("{endpoint}/latest/api/token"))
+            .timeout(TIMEOUT)
+            .set("X-aws-ec2-metadata-token-ttl-seconds", "300")
+            .call()
+            .context("Failed to get an IMDSv2 token")?
+            .into_string()?;
+        Ok(Self { endpoint, token })
+    }
+
+    /// Metadata at `path` (under `/latest/meta-data/`), `None` if not found
+    pub fn get(&self, path: &str) -> anyhow::Result<Option<String>> {
+        let url = format!("{}/latest/meta-data/{path}", self.endpoint);
+        match ureq::get(&url)
+            .timeout(TIMEOUT)
+            .set("X-aws-ec2-metadata-token", &self.token)
+            .call()
+        {
+            Ok(response) => Ok(Some(response.into_string()?.trim().to_owned())),
+            Err(ureq::Error::Status(404, _)) => {
+                debug!(path, "Instance metadata not found");
+                Ok(None)
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to get instance metadata {path}")),
+        }
+    }
+
+    /// Value of the instance tag `key` (needs the instance metadata tags
+    /// enabled)
+    pub fn instance_tag(&self, key: &str) -> anyhow::Result<Option<String>> {
+        self.get(&format!("tags/instance/{key}"))
+    }
+
+    pub fn region(&self) -> anyhow::Result<Option<String>> {
+        self.get("placement/region")
+    }
+}
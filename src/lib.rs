@@ -3,26 +3,92 @@
 use std::collections::HashSet;
 use std::ffi::OsString;
 use std::fs;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
 use std::ops::ControlFlow;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
 
+pub use activation::{
+    ActivateOpts, ActivationBackend, ActivationMode, ActivationResult, Escalation,
+};
 use anyhow::{bail, format_err, Context};
-use config::Config;
+pub use archive::{pack_archive_from, unpack_archive_to, PackOptions, PackSummary, UnpackOptions};
+use archive::{ArchiveHeader, UnpackLimits};
+use cancel::CancellationToken;
+use closure::ClosureRef;
+use config::{Config, PendingUpdate};
 use data_dir::DataDir;
-use serde::Deserialize;
-use signal_hook::consts::TERM_SIGNALS;
-use signal_hook::flag;
-use tracing::{debug, error, info, trace, warn};
+use engine::{Activator, DaemonEvents, NixActivator};
+pub use engine::{CycleOutcome, DaemonEngine};
+pub use error::NpcnixError;
+use meta::ArchiveMeta;
+use misc::DaemonControl;
+use notify::NotifyEvent;
+use pointer::Pointer;
+use progress::ProgressFn;
+use push_checks::PushChecks;
+use retry::RetryOpts;
+use s3::MultipartOpts;
+use serde::Serialize;
+use signal_hook::consts::{SIGHUP, TERM_SIGNALS};
+use signal_hook::iterator::Signals;
+use tracing::{debug, info, info_span, warn};
 use url::Url;
 
+pub mod activation;
+pub mod age;
+pub mod approval;
+pub mod archive;
+pub mod bootstrap;
+pub mod cancel;
+pub mod channel;
+pub mod ci;
+pub mod closure;
+#[cfg(feature = "metrics")]
+pub mod cloudwatch;
 pub mod config;
+pub mod configurations;
+pub mod control;
+pub mod coordination;
 pub mod data_dir;
+pub mod diff;
+pub mod doctor;
+pub mod engine;
+pub mod error;
+pub mod gc;
+pub mod generate;
+pub mod health;
+pub mod history;
+pub mod hooks;
+#[cfg(feature = "http")]
+pub mod imds;
+pub mod logs;
+pub mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod misc;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod notify;
+#[cfg(feature = "cli")]
 pub mod opts;
+pub mod pointer;
+pub mod progress;
+pub mod push_checks;
+pub mod remote_settings;
+pub mod report;
+pub mod retry;
+pub mod s3;
+pub mod schedule;
+pub mod secret_scan;
+pub mod secrets;
+pub mod status;
+pub mod systemd;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod toml;
+pub mod verify;
+pub mod versions;
 
 pub trait CommandExt {
     fn log_debug(&mut self) -> &mut Self;
@@ -51,60 +117,664 @@ pub fn nixos_rebuild_path() -> OsString {
     std::env::var_os("NPCNIX_NIXOS_REBUILD").unwrap_or_else(|| OsString::from("nixos-rebuild"))
 }
 
+pub fn home_manager_path() -> OsString {
+    std::env::var_os("NPCNIX_HOME_MANAGER").unwrap_or_else(|| OsString::from("home-manager"))
+}
+
+pub fn darwin_rebuild_path() -> OsString {
+    std::env::var_os("NPCNIX_DARWIN_REBUILD").unwrap_or_else(|| OsString::from("darwin-rebuild"))
+}
+
+pub fn nix_env_path() -> OsString {
+    std::env::var_os("NPCNIX_NIX_ENV").unwrap_or_else(|| OsString::from("nix-env"))
+}
+
+pub fn git_path() -> OsString {
+    std::env::var_os("NPCNIX_GIT").unwrap_or_else(|| OsString::from("git"))
+}
+
+pub fn nix_path() -> OsString {
+    std::env::var_os("NPCNIX_NIX").unwrap_or_else(|| OsString::from("nix"))
+}
+
+pub fn nix_store_path() -> OsString {
+    std::env::var_os("NPCNIX_NIX_STORE").unwrap_or_else(|| OsString::from("nix-store"))
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Once {
     Any,
     Activate,
+    /// Finish after a single cycle, whatever the outcome
+    Cycle,
 }
 
-pub fn pull(remote: &Url, dst: &Path) -> anyhow::Result<()> {
-    let scheme = remote.scheme();
-    let (reader, mut child) = match scheme {
-        "s3" => pull_s3(remote)?,
-        _ => anyhow::bail!("Protocol not supported: {scheme}"),
+#[derive(Debug, Clone, Default)]
+pub struct PullOpts {
+    /// If set, the archive is decrypted with `age` first
+    pub decrypt_identity: Option<PathBuf>,
+    pub unpack_limits: UnpackLimits,
+    pub retry: RetryOpts,
+    /// Notified as the downloads progress
+    pub progress: Option<ProgressFn>,
+    pub cancel: Option<CancellationToken>,
+}
+
+impl From<&Config> for PullOpts {
+    fn from(config: &Config) -> Self {
+        Self {
+            decrypt_identity: config.decrypt_identity().map(ToOwned::to_owned),
+            unpack_limits: config.unpack_limits(),
+            retry: config.transfer_retry(),
+            progress: None,
+            cancel: None,
+        }
+    }
+}
+
+/// What [`pull`] downloaded
+#[derive(Debug, Clone)]
+pub struct PullResult {
+    /// Including the [`Pointer`], if any
+    pub bytes: u64,
+    /// Etag of the `remote` (so of the pointer, if any), as checked right
+    /// before downloading it
+    pub etag: String,
+}
+
+/// Pull the archive from `remote` and unpack to `dst`
+///
+/// If `remote` is a [`Pointer`] (content-addressed layout), the archive it
+/// points to is downloaded and verified instead.
+pub fn pull(remote: &Url, dst: &Path, pull_opts: &PullOpts) -> Result<PullResult, NpcnixError> {
+    let (file, res) = download_archive(remote, pull_opts)?;
+    cancel::check(pull_opts.cancel.as_ref())?;
+    unpack_from(io::BufReader::new(file), dst, pull_opts).map_err(NpcnixError::Unpack)?;
+    Ok(res)
+}
+
+/// Like [`pull`] but writes the (raw, still packed) archive to `writer`
+pub fn pull_raw(
+    remote: &Url,
+    mut writer: impl Write,
+    pull_opts: &PullOpts,
+) -> Result<PullResult, NpcnixError> {
+    let (mut file, res) = download_archive(remote, pull_opts)?;
+    io::copy(&mut file, &mut writer)?;
+    writer.flush()?;
+    Ok(res)
+}
+
+/// Download the archive from `remote`, following a [`Pointer`] if needed
+fn download_archive(
+    remote: &Url,
+    pull_opts: &PullOpts,
+) -> Result<(fs::File, PullResult), NpcnixError> {
+    let (mut file, mut res) = download(remote, pull_opts)?;
+    let Some(pointer) = read_pointer(&mut file)? else {
+        return Ok((file, res));
     };
+    let (mut file, pointed) = download(&pointer.target, pull_opts)?;
+    verify_pointed(&mut file, &pointer)?;
+    res.bytes += pointed.bytes;
+    Ok((file, res))
+}
+
+/// The [`Pointer`] in the downloaded `file`, if it's one (rewound otherwise)
+fn read_pointer(file: &mut fs::File) -> Result<Option<Pointer>, NpcnixError> {
+    let mut reader = io::BufReader::new(&mut *file);
+    if !Pointer::is_pointer(reader.fill_buf()?) {
+        drop(reader);
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(None);
+    }
 
-    unpack_archive_to(reader, dst)?;
-    child.wait()?;
+    let pointer = Pointer::read_from(reader).map_err(NpcnixError::Unpack)?;
+    debug!(target = %pointer.target, sha256 = pointer.sha256, "Following pointer");
+    Ok(Some(pointer))
+}
 
+/// Check the archive downloaded from `pointer`'s target, and rewind it
+fn verify_pointed(file: &mut fs::File, pointer: &Pointer) -> Result<(), NpcnixError> {
+    let sha256 = pointer::sha256_reader(&mut *file)?;
+    if sha256 != pointer.sha256 {
+        return Err(NpcnixError::Unpack(format_err!(
+            "Archive hash mismatch: target={} expected={} actual={sha256}",
+            pointer.target,
+            pointer.sha256
+        )));
+    }
+    file.seek(SeekFrom::Start(0))?;
     Ok(())
 }
 
-pub fn push(src: &Path, include: &HashSet<OsString>, remote: &url::Url) -> anyhow::Result<()> {
+/// Like [`pull`] but unpacks into a temporary sibling of `dst` and renames it
+/// into place only on success
+///
+/// If `backup` is set, the previous content of `dst` is moved there.
+pub fn pull_atomic(
+    remote: &Url,
+    dst: &Path,
+    pull_opts: &PullOpts,
+    backup: Option<&Path>,
+) -> Result<PullResult, NpcnixError> {
+    let mut res = None;
+    misc::replace_dir_with(dst, backup, |tmp_dst| {
+        res = Some(pull(remote, tmp_dst, pull_opts)?);
+        Ok(())
+    })?;
+    Ok(res.expect("set on success"))
+}
+
+/// Download `remote` into an (unnamed) temporary file
+///
+/// A retry resumes what the failed attempt got, unless the object changed in
+/// the meantime.
+fn download(remote: &Url, pull_opts: &PullOpts) -> Result<(fs::File, PullResult), NpcnixError> {
+    check_scheme(remote)?;
+    let cancel = pull_opts.cancel.as_ref();
+    let mut file = tempfile::tempfile()?;
+    // the etag of the object (partially) in `file`
+    let mut downloading: Option<String> = None;
+    let res = retry::with_retry(&pull_opts.retry, "download", || {
+        cancel::check(cancel)?;
+        let head = s3::head(remote)?;
+        let progress = pull_opts
+            .progress
+            .as_ref()
+            .map(|progress| (progress, Some(head.size)));
+        let resume = downloading.replace(head.etag.clone()) == Some(head.etag.clone());
+        let offset = file.seek(SeekFrom::End(0))?;
+        if resume && offset == head.size {
+            debug!(bytes = offset, "Already downloaded");
+        } else if resume && 0 < offset && offset < head.size {
+            debug!(offset, "Resuming the download");
+            s3::resume_download_to(remote, &file, &head.etag, progress, cancel)?;
+        } else {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            match progress {
+                Some((progress, total)) => {
+                    s3::download_to_with_progress(remote, &file, total, progress, cancel)?
+                }
+                None => s3::download_to(remote, &file, cancel)?,
+            }
+        }
+        let bytes = file.seek(SeekFrom::End(0))?;
+        Ok(PullResult {
+            bytes,
+            etag: head.etag,
+        })
+    })
+    .map_err(NpcnixError::remote_unavailable(remote))?;
+    #[cfg(feature = "metrics")]
+    metrics::record_downloaded_bytes(res.bytes);
+    file.seek(SeekFrom::Start(0))?;
+    Ok((file, res))
+}
+
+fn unpack_from(
+    mut reader: impl BufRead + Send + 'static,
+    dst: &Path,
+    pull_opts: &PullOpts,
+) -> anyhow::Result<()> {
+    let header = ArchiveHeader::read_from(&mut reader)?;
+    let decrypt_identity = match header {
+        Some(header) => {
+            debug!(
+                version = header.version,
+                encrypted = header.encrypted,
+                "Archive header"
+            );
+            if header.encrypted && pull_opts.decrypt_identity.is_none() {
+                bail!("Archive is encrypted, but no decrypt identity was configured");
+            }
+            pull_opts
+                .decrypt_identity
+                .as_deref()
+                .filter(|_| header.encrypted)
+        }
+        // legacy archive: no way to tell, so go by the settings
+        None => pull_opts.decrypt_identity.as_deref(),
+    };
+
+    let unpack_opts = UnpackOptions {
+        limits: pull_opts.unpack_limits,
+    };
+    if let Some(identity) = decrypt_identity {
+        let (reader, age_child) = age::spawn_decrypt(identity, reader)?;
+        unpack_archive_to(reader, dst, &unpack_opts)?;
+        age_child.wait()?;
+    } else {
+        unpack_archive_to(reader, dst, &unpack_opts)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PushOpts {
+    /// If not empty, the archive is encrypted with `age` to all of them
+    pub encrypt_recipients: Vec<String>,
+    /// Upload using the content-addressed layout (see [`pointer`]), packing
+    /// deterministically (encrypted archives still differ on every push)
+    pub content_addressed: bool,
+    /// Upload a new archive under the (prefix) remote for every push, and
+    /// point `latest` to it (see [`versions`])
+    pub timestamped: bool,
+    /// Run before packing the flake
+    pub checks: PushChecks,
+    /// Upload even if the remote already has the same content
+    pub force: bool,
+    pub retry: RetryOpts,
+    pub multipart: MultipartOpts,
+    /// Notified as the uploads progress
+    pub progress: Option<ProgressFn>,
+    pub cancel: Option<CancellationToken>,
+}
+
+/// What [`push`] uploaded
+#[derive(Debug, Clone)]
+pub struct PushResult {
+    /// Including the [`Pointer`], if any (and not the archive if it was
+    /// already uploaded)
+    pub bytes: u64,
+    /// Etag of the `remote` after the upload
+    pub etag: String,
+    /// Nothing was uploaded, as the remote already had the same content
+    pub unchanged: bool,
+}
+
+/// Pack `src` and upload to `remote`
+pub fn push(
+    src: &Path,
+    include: &HashSet<OsString>,
+    remote: &url::Url,
+    push_opts: &PushOpts,
+) -> Result<PushResult, NpcnixError> {
+    check_scheme(remote)?;
+    let (tmp_file, meta, _) = pack_for_push(src, include, push_opts)?;
+    cancel::check(push_opts.cancel.as_ref())?;
+    if !push_opts.force {
+        if let Some(etag) = unchanged_etag(&tmp_file, remote, &meta, push_opts)
+            .map_err(NpcnixError::remote_unavailable(remote))?
+        {
+            info!(%remote, %etag, "Remote already has the same content, not pushing");
+            return Ok(PushResult::unchanged(etag));
+        }
+    }
+    upload_archive(tmp_file, remote, Some(&meta), push_opts)
+        .map_err(NpcnixError::remote_unavailable(remote))
+}
+
+impl PushResult {
+    fn unchanged(etag: String) -> Self {
+        Self {
+            bytes: 0,
+            etag,
+            unchanged: true,
+        }
+    }
+}
+
+/// Etag of `remote`, if it already has the content of the packed `tmp_file`
+/// (described by `meta`)
+///
+/// Content-addressed remotes are compared by the hash of the archive, the
+/// others by the [`ArchiveMeta::content_sha256`] of their metadata sidecar
+/// (as long as the archive didn't change since it was uploaded).
+fn unchanged_etag(
+    tmp_file: &tempfile::NamedTempFile,
+    remote: &Url,
+    meta: &ArchiveMeta,
+    push_opts: &PushOpts,
+) -> anyhow::Result<Option<String>> {
+    if !push_opts.timestamped && !push_opts.content_addressed {
+        if !s3::exists(remote)? {
+            return Ok(None);
+        }
+        let etag = s3::head(remote)?.etag;
+        return Ok(sidecar_matches(remote, &etag, meta)?.then_some(etag));
+    }
+
+    // the remote is a pointer, small enough to download
+    let current = if push_opts.timestamped {
+        versions::latest_url(remote)?
+    } else {
+        remote.clone()
+    };
+    let Some((bytes, etag)) = s3::get_object(&current)? else {
+        return Ok(None);
+    };
+    let Ok(pointer) = Pointer::read_from(bytes.as_slice()) else {
+        return Ok(None);
+    };
+    let unchanged = if push_opts.content_addressed {
+        pointer.sha256 == pointer::sha256_reader(tmp_file.reopen()?)?
+    } else {
+        let target_etag = s3::head(&pointer.target)?.etag;
+        sidecar_matches(&pointer.target, &target_etag, meta)?
+    };
+    Ok(unchanged.then(|| etag.trim_matches('"').to_owned()))
+}
+
+/// Does the sidecar of the archive at `remote` (with `etag`) describe the
+/// same content as `meta`
+fn sidecar_matches(remote: &Url, etag: &str, meta: &ArchiveMeta) -> anyhow::Result<bool> {
+    Ok(meta.content_sha256.is_some()
+        && meta::fetch_sidecar(remote)?.is_some_and(|sidecar| {
+            sidecar.is_current(etag) && sidecar.meta.content_sha256 == meta.content_sha256
+        }))
+}
+
+/// What [`push`] would do, see [`plan_push`]
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PushPlan {
+    /// Where the archive would be uploaded
+    pub target: Url,
+    pub archive_bytes: u64,
+    #[serde(flatten)]
+    pub summary: PackSummary,
+    /// Changes to the files of the remote, `None` if it doesn't exist yet
+    pub changes: Option<Vec<diff::FileChange>>,
+}
+
+impl PushPlan {
+    /// Would pushing change what the hosts get
+    pub fn changes_anything(&self) -> bool {
+        !matches!(self.changes.as_deref(), Some([]))
+    }
+}
+
+/// Pack `src` like [`push`] would, and compare it with the current content
+/// of `remote`, without uploading anything
+pub fn plan_push(
+    src: &Path,
+    include: &HashSet<OsString>,
+    remote: &Url,
+    push_opts: &PushOpts,
+    pull_opts: &PullOpts,
+) -> Result<PushPlan, NpcnixError> {
+    check_scheme(remote)?;
+    let (tmp_file, meta, summary) = pack_for_push(src, include, push_opts)?;
+    let archive_bytes = tmp_file.as_file().metadata()?.len();
+    let (target, current) = if push_opts.timestamped {
+        (
+            versions::timestamped_url(remote, Some(&meta), push_opts)?,
+            versions::latest_url(remote)?,
+        )
+    } else if push_opts.content_addressed {
+        let sha256 = pointer::sha256_reader(tmp_file.reopen()?)?;
+        (Pointer::new(remote, &sha256)?.target, remote.clone())
+    } else {
+        (remote.clone(), remote.clone())
+    };
+    let changes = if s3::exists(&current).map_err(NpcnixError::remote_unavailable(&current))? {
+        Some(diff::Diff::compute(src, include, &current, pull_opts)?.changes)
+    } else {
+        None
+    };
+    Ok(PushPlan {
+        target,
+        archive_bytes,
+        summary,
+        changes,
+    })
+}
+
+/// Pack `src` into a temporary file, as configured by `push_opts`, along with
+/// the metadata packed
+fn pack_for_push(
+    src: &Path,
+    include: &HashSet<OsString>,
+    push_opts: &PushOpts,
+) -> anyhow::Result<(tempfile::NamedTempFile, ArchiveMeta, PackSummary)> {
     verify_flake_src(src)?;
-    let scheme = remote.scheme();
-    let (mut writer, mut child) = match scheme {
-        "s3" => push_s3(remote)?,
-        _ => anyhow::bail!("Protocol not supported: {scheme}"),
+    push_opts.checks.run(src, include)?;
+
+    let mut meta = ArchiveMeta::collect(src);
+    meta.content_sha256 = Some(
+        archive::content_sha256(src, include)
+            .with_context(|| format!("Failed to pack {}", src.display()))?,
+    );
+    let meta = if push_opts.content_addressed {
+        meta.without_volatile_fields()
+    } else {
+        meta
     };
 
-    pack_archive_from(src, include, &mut writer).context("Failed to pack the src archive")?;
-    writer.flush()?;
-    drop(writer);
+    let tmp_file = tempfile::NamedTempFile::new()?;
+    let summary = pack_to(
+        src,
+        include,
+        meta.clone(),
+        push_opts,
+        tmp_file.as_file().try_clone()?,
+    )?;
+    Ok((tmp_file, meta, summary))
+}
 
-    child.wait()?;
+/// Upload an archive referencing the pre-built system closure at
+/// `store_path` to `remote`
+///
+/// Hosts following the `remote` activate the closure directly, without
+/// evaluating anything. The closure itself must be pushed to a cache the
+/// hosts substitute from.
+pub fn push_closure(
+    store_path: &Path,
+    remote: &Url,
+    push_opts: &PushOpts,
+) -> Result<PushResult, NpcnixError> {
+    check_scheme(remote)?;
 
-    Ok(())
+    let src = tempfile::TempDir::new()?;
+    ClosureRef::new(store_path)?.write_to(src.path())?;
+    let meta = ArchiveMeta::collect(src.path());
+    let meta = if push_opts.content_addressed {
+        meta.without_volatile_fields()
+    } else {
+        meta
+    };
+
+    let tmp_file = tempfile::NamedTempFile::new()?;
+    pack_to(
+        src.path(),
+        &HashSet::new(),
+        meta.clone(),
+        push_opts,
+        tmp_file.as_file().try_clone()?,
+    )?;
+
+    upload_archive(tmp_file, remote, Some(&meta), push_opts)
+        .map_err(NpcnixError::remote_unavailable(remote))
 }
 
-pub fn get_etag(remote: &Url, config: &Config) -> anyhow::Result<String> {
+/// Like [`push`] but uploads an already packed archive read from `reader`
+pub fn push_raw(
+    mut reader: impl Read,
+    remote: &Url,
+    push_opts: &PushOpts,
+) -> Result<PushResult, NpcnixError> {
+    if !push_opts.encrypt_recipients.is_empty() {
+        return Err(format_err!("Can't encrypt an already packed archive").into());
+    }
+    check_scheme(remote)?;
+
+    let mut tmp_file = tempfile::NamedTempFile::new()?;
+    io::copy(&mut reader, &mut tmp_file)?;
+    tmp_file.flush()?;
+
+    tmp_file.seek(SeekFrom::Start(0))?;
+    ArchiveHeader::read_from(&mut io::BufReader::new(tmp_file.as_file()))
+        .map_err(NpcnixError::Unpack)?;
+
+    upload_archive(tmp_file, remote, None, push_opts)
+        .map_err(NpcnixError::remote_unavailable(remote))
+}
+
+fn check_scheme(remote: &Url) -> Result<(), NpcnixError> {
     let scheme = remote.scheme();
-    Ok(match scheme {
-        "s3" => get_etag_s3(remote, config.region_opt())?,
-        _ => anyhow::bail!("Protocol not supported: {scheme}"),
+    if scheme != "s3" {
+        return Err(format_err!("Protocol not supported: {scheme}").into());
+    }
+    Ok(())
+}
+
+/// Upload the packed `tmp_file` to `remote`, along with the sidecar of its
+/// `meta`
+fn upload_archive(
+    mut tmp_file: tempfile::NamedTempFile,
+    remote: &Url,
+    meta: Option<&ArchiveMeta>,
+    push_opts: &PushOpts,
+) -> anyhow::Result<PushResult> {
+    if push_opts.timestamped {
+        return versions::upload_timestamped(tmp_file, remote, meta, push_opts);
+    }
+    let archive_bytes = tmp_file.as_file().metadata()?.len();
+    let bytes = if !push_opts.content_addressed {
+        upload_file(tmp_file.path(), remote, push_opts)?;
+        archive_bytes
+    } else {
+        tmp_file.seek(SeekFrom::Start(0))?;
+        let sha256 = pointer::sha256_reader(&mut tmp_file)?;
+        let pointer = Pointer::new(remote, &sha256)?;
+
+        cancel::check(push_opts.cancel.as_ref())?;
+        let archive_bytes = if s3::exists(&pointer.target)? {
+            info!(target = %pointer.target, "Archive already uploaded");
+            0
+        } else {
+            upload_file(tmp_file.path(), &pointer.target, push_opts)?;
+            archive_bytes
+        };
+
+        archive_bytes + upload_pointer(&pointer, remote, push_opts)?
+    };
+    let head = retry::with_retry(&push_opts.retry, "check upload", || s3::head(remote))?;
+    if let Some(meta) = meta {
+        meta::upload_sidecar(remote, meta, &head.etag, &push_opts.retry);
+    }
+    Ok(PushResult {
+        bytes,
+        etag: head.etag,
+        unchanged: false,
     })
 }
 
-#[derive(Debug, Clone)]
-pub struct ActivateOpts {
-    pub extra_substituters: Vec<String>,
-    pub extra_trusted_public_keys: Vec<String>,
+/// Upload `pointer` as the `remote`, returning its size
+fn upload_pointer(pointer: &Pointer, remote: &Url, push_opts: &PushOpts) -> anyhow::Result<u64> {
+    let pointer_bytes = serde_json::to_vec_pretty(pointer)?;
+    retry::with_retry(&push_opts.retry, "upload pointer", || {
+        s3::upload_bytes(&pointer_bytes, remote)
+    })?;
+    Ok(pointer_bytes.len() as u64)
+}
+
+/// Point the content-addressed `remote` back at its already uploaded archive
+/// with `sha256` (e.g. to roll the hosts back to a previous push)
+pub fn repoint(
+    remote: &Url,
+    sha256: &str,
+    push_opts: &PushOpts,
+) -> Result<PushResult, NpcnixError> {
+    check_scheme(remote)?;
+    if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format_err!("Invalid sha256: {sha256}").into());
+    }
+    let pointer = Pointer::new(remote, &sha256.to_ascii_lowercase())?;
+    if !s3::exists(&pointer.target).map_err(NpcnixError::remote_unavailable(remote))? {
+        return Err(format_err!("No archive uploaded at {}", pointer.target).into());
+    }
+    cancel::check(push_opts.cancel.as_ref())?;
+    let bytes = upload_pointer(&pointer, remote, push_opts)
+        .map_err(NpcnixError::remote_unavailable(remote))?;
+    let head = retry::with_retry(&push_opts.retry, "check upload", || s3::head(remote))
+        .map_err(NpcnixError::remote_unavailable(remote))?;
+    info!(%remote, target = %pointer.target, etag = head.etag, "Repointed the remote");
+    Ok(PushResult {
+        bytes,
+        etag: head.etag,
+        unchanged: false,
+    })
+}
+
+fn upload_file(path: &Path, remote: &Url, push_opts: &PushOpts) -> anyhow::Result<()> {
+    if push_opts.multipart.threshold_bytes <= fs::metadata(path)?.len() {
+        s3::upload_file_multipart(
+            path,
+            remote,
+            &push_opts.multipart,
+            &push_opts.retry,
+            push_opts.progress.as_ref(),
+            push_opts.cancel.as_ref(),
+        )
+    } else {
+        let cancel = push_opts.cancel.as_ref();
+        retry::with_retry(&push_opts.retry, "upload", || match &push_opts.progress {
+            Some(progress) => s3::upload_file_with_progress(path, remote, progress, cancel),
+            None => s3::upload_file(path, remote, cancel),
+        })
+    }
 }
 
-pub fn with_activate_lock<T>(
+/// Pack `src` to `output` for pushing, encrypting if
+/// `push_opts.encrypt_recipients` is not empty
+///
+/// Content-addressed archives are packed deterministically, so the same
+/// content always hashes to the same `by-hash` object.
+fn pack_to(
+    src: &Path,
+    include: &HashSet<OsString>,
+    meta: ArchiveMeta,
+    push_opts: &PushOpts,
+    mut output: impl Write + Into<Stdio>,
+) -> anyhow::Result<PackSummary> {
+    let encrypt_recipients = &push_opts.encrypt_recipients;
+    ArchiveHeader::new(!encrypt_recipients.is_empty()).write_to(&mut output)?;
+    output.flush()?;
+
+    let pack_opts = PackOptions {
+        include: include.clone(),
+        deterministic: push_opts.content_addressed,
+        meta: Some(meta),
+        ..Default::default()
+    };
+    if encrypt_recipients.is_empty() {
+        let mut writer = io::BufWriter::new(output);
+        let summary = pack_archive_from(src, &pack_opts, &mut writer)
+            .context("Failed to pack the src archive")?;
+        writer.flush()?;
+        Ok(summary)
+    } else {
+        let (mut writer, age_child) = age::spawn_encrypt(encrypt_recipients, output)?;
+        let summary = pack_archive_from(src, &pack_opts, &mut writer)
+            .context("Failed to pack the src archive")?;
+        writer.flush()?;
+        drop(writer);
+        age_child.wait()?;
+        Ok(summary)
+    }
+}
+
+pub fn get_etag(remote: &Url, config: &Config) -> Result<String, NpcnixError> {
+    check_scheme(remote)?;
+    s3::get_etag(remote, config.region_opt()).map_err(NpcnixError::etag_fetch(remote))
+}
+
+/// Like [`get_etag`], along with the rest of the object metadata
+pub fn head(remote: &Url, config: &Config) -> Result<s3::ObjectHead, NpcnixError> {
+    check_scheme(remote)?;
+    s3::head_in_region(remote, config.region_opt()).map_err(NpcnixError::etag_fetch(remote))
+}
+
+pub fn with_activate_lock<T, E>(
     data_dir: Option<&DataDir>,
-    f: impl FnOnce() -> anyhow::Result<T>,
-) -> anyhow::Result<T> {
+    f: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E>
+where
+    E: From<anyhow::Error>,
+{
     let mut lock = data_dir
         .map(|data_dir| data_dir.activate_lock())
         .transpose()?
@@ -124,11 +794,15 @@ pub fn with_activate_lock<T>(
         }
         Err(e) => {
             if e.kind() != io::ErrorKind::WouldBlock {
-                return Err(e)?;
+                Err(anyhow::Error::from(e))?;
             }
 
             warn!("Waiting for another instance to finish");
-            lock2.as_mut().map(|lock| lock.write()).transpose()?
+            lock2
+                .as_mut()
+                .map(|lock| lock.write())
+                .transpose()
+                .map_err(anyhow::Error::from)?
         }
     };
 
@@ -140,52 +814,93 @@ pub fn activate(
     src: &Path,
     configuration: &str,
     activate_opts: &ActivateOpts,
-) -> Result<(), anyhow::Error> {
+) -> Result<ActivationResult, NpcnixError> {
     with_activate_lock(data_dir, || {
         // Note: we load every time, in case settings changed
-        activate_inner(src, configuration, activate_opts)?;
-        data_dir
-            .map(|data_dir| data_dir.update_last_reconfiguration(configuration, ""))
-            .transpose()
-    })?;
-    Ok(())
+        let config = data_dir
+            .map(|data_dir| data_dir.load_config())
+            .transpose()?
+            .unwrap_or_default();
+        let res = NixActivator
+            .activate(src, configuration, None, data_dir, activate_opts, &config)
+            .map_err(NpcnixError::activation_failed(configuration))?;
+        if let Some(data_dir) = data_dir {
+            data_dir.update_last_reconfiguration(configuration, "")?;
+        }
+        Ok(res)
+    })
+}
+
+/// Roll the system back to a previous generation and activate it
+///
+/// Without an explicit `generation`, the one activated by npcnix before the
+/// current one is used, falling back to the previous generation of the
+/// system profile. Unless `no_hold`, the daemon will not re-activate the
+/// last activated remote etag afterwards.
+pub fn rollback(
+    data_dir: &DataDir,
+    generation: Option<u64>,
+    no_hold: bool,
+    activate_opts: &ActivateOpts,
+) -> Result<PathBuf, NpcnixError> {
+    with_activate_lock(Some(data_dir), || {
+        let config = data_dir.load_config()?;
+        let activate_opts = &ActivateOpts {
+            escalation: activate_opts.escalation.or(config.escalation()),
+            ..activate_opts.clone()
+        };
+        let generation = generation.or_else(|| {
+            config
+                .previous_generation(activation::current_generation_number())
+                .map(|generation| generation.number)
+        });
+        let system = activation::rollback_to(generation, activate_opts).map_err(
+            NpcnixError::activation_failed(config.configuration().unwrap_or("unknown")),
+        )?;
+        data_dir.update_expected_system(activation::running_system().as_deref())?;
+        notify::notify(
+            &config,
+            NotifyEvent::Rollback,
+            config.configuration().unwrap_or("unknown"),
+            config.last_etag(),
+            None,
+        );
+        if !no_hold && !config.last_etag().is_empty() {
+            info!(etag = config.last_etag(), "Holding the rolled back etag");
+            let etag = config.last_etag();
+            data_dir.update_config(|config| Ok(config.with_held_etag(Some(etag))))?;
+        }
+        Ok(system)
+    })
 }
 
-fn activate_inner(
+/// Build the `configuration` and describe how it differs from the current
+/// system, without activating it
+pub fn preview_activation(
+    data_dir: Option<&DataDir>,
     src: &Path,
     configuration: &str,
     activate_opts: &ActivateOpts,
-) -> Result<(), anyhow::Error> {
-    verify_flake_src(src)?;
-    info!(
-        configuration,
-        src = %src.display(),
-        "Activating configuration"
-    );
-    let mut cmd = process::Command::new(nixos_rebuild_path());
-    cmd.args(["switch", "-L"]);
-
-    for subscriber in &activate_opts.extra_substituters {
-        cmd.args(["--option", "extra-substituters", subscriber]);
-    }
-    for key in &activate_opts.extra_trusted_public_keys {
-        cmd.args(["--option", "extra-trusted-public-keys", key]);
-    }
-
-    cmd.args(["--flake", &format!(".#{configuration}")])
-        .current_dir(src);
+) -> Result<String, NpcnixError> {
+    let config = data_dir
+        .map(|data_dir| data_dir.load_config())
+        .transpose()?
+        .unwrap_or_default();
+    activation::preview_inner(src, configuration, activate_opts, &config)
+        .map_err(NpcnixError::activation_failed(configuration))
+}
 
-    let status = cmd
-        .log_debug()
-        .status()
-        .context("Calling `nixos-rebuild` failed")?;
-    if !status.success() {
-        bail!("nixos-rebuild returned exit code={:?}", status.code());
-    }
-    Ok(())
+pub fn pack(src: &Path, include: &HashSet<OsString>, dst: &Path) -> Result<(), NpcnixError> {
+    let pack_opts = PackOptions {
+        include: include.clone(),
+        meta: Some(ArchiveMeta::collect(src)),
+        ..Default::default()
+    };
+    pack_with(src, &pack_opts, dst)
 }
 
-pub fn pack(src: &Path, include: &HashSet<OsString>, dst: &Path) -> anyhow::Result<()> {
+/// Like [`pack`], as configured by `pack_opts`
+pub fn pack_with(src: &Path, pack_opts: &PackOptions, dst: &Path) -> Result<(), NpcnixError> {
     verify_flake_src(src)?;
 
     let tmp_dst = dst.with_extension("tmp");
@@ -197,7 +912,8 @@ pub fn pack(src: &Path, include: &HashSet<OsString>, dst: &Path) -> anyhow::Resu
         .with_context(|| format!("Could not create temporary file: {}", tmp_dst.display()))?;
     let mut writer = io::BufWriter::new(&file);
 
-    pack_archive_from(src, include, &mut writer)
+    ArchiveHeader::new(false).write_to(&mut writer)?;
+    pack_archive_from(src, pack_opts, &mut writer)
         .with_context(|| format!("Failed to pack the src archive: {}", src.display()))?;
     writer.flush()?;
     drop(writer);
@@ -213,7 +929,34 @@ pub fn pack(src: &Path, include: &HashSet<OsString>, dst: &Path) -> anyhow::Resu
     Ok(())
 }
 
-fn verify_flake_src(src: &Path) -> anyhow::Result<()> {
+/// Unpack a local archive file (e.g. created with [`pack`]) to `dst`
+pub fn unpack(archive: &Path, dst: &Path, pull_opts: &PullOpts) -> Result<(), NpcnixError> {
+    let file = fs::File::open(archive)
+        .with_context(|| format!("Could not open archive: {}", archive.display()))?;
+    unpack_from(io::BufReader::new(file), dst, pull_opts).map_err(NpcnixError::Unpack)
+}
+
+/// Read the build metadata of the archive at `remote`
+pub fn inspect_remote(
+    remote: &Url,
+    pull_opts: &PullOpts,
+) -> Result<Option<ArchiveMeta>, NpcnixError> {
+    let tmp_dir = tempfile::TempDir::new()?;
+    pull(remote, tmp_dir.path(), pull_opts)?;
+    ArchiveMeta::load_from(tmp_dir.path()).map_err(NpcnixError::Unpack)
+}
+
+/// Read the build metadata of a local archive file
+pub fn inspect_archive(
+    archive: &Path,
+    pull_opts: &PullOpts,
+) -> Result<Option<ArchiveMeta>, NpcnixError> {
+    let tmp_dir = tempfile::TempDir::new()?;
+    unpack(archive, tmp_dir.path(), pull_opts)?;
+    ArchiveMeta::load_from(tmp_dir.path()).map_err(NpcnixError::Unpack)
+}
+
+pub(crate) fn verify_flake_src(src: &Path) -> anyhow::Result<()> {
     if !src.join("flake.nix").exists() {
         anyhow::bail!(
             "Flake source directory {} does not contain flake.nix file",
@@ -223,248 +966,274 @@ fn verify_flake_src(src: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
-#[derive(Deserialize)]
-struct EtagResponse {
-    #[serde(rename = "ETag")]
-    etag: String,
-}
-
-fn get_etag_s3(remote: &Url, region: Option<&str>) -> anyhow::Result<String> {
-    let output = process::Command::new(aws_cli_path())
-        .args(
-            [
-                "s3api",
-                "get-object-attributes",
-                "--bucket",
-                remote
-                    .host_str()
-                    .ok_or_else(|| format_err!("Invalid URL"))?,
-                "--key",
-                remote
-                    .path()
-                    .split_once('/')
-                    .ok_or_else(|| format_err!("Path doesn't start with a /"))?
-                    .1,
-                "--object-attributes",
-                "ETag",
-            ]
-            .into_iter()
-            .chain(if let Some(region) = region {
-                vec!["--region", region]
-            } else {
-                vec![]
-            }),
-        )
-        .log_debug()
-        .output()
-        .context("`aws` cli failed")?;
-
-    if !output.status.success() {
-        bail!(
-            "aws s3api get-object-attributes returned code={:?} stdout={} stderr={}",
-            output.status.code(),
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr),
-        )
+/// Run the daemon cycles until `once` is satisfied or the termination
+/// signals, see [`handle_signals`]
+///
+/// Returns the signal which cancelled the current cycle, if any, leaving it
+/// to the caller to exit with the conventional `128 + signal` exit code.
+pub fn follow(
+    data_dir: &DataDir,
+    activate_opts: &ActivateOpts,
+    override_configuration: Option<&str>,
+    once: Option<Once>,
+    ignore_etag: bool,
+    control_socket: Option<&Path>,
+    events: impl DaemonEvents + 'static,
+) -> Result<Option<i32>, NpcnixError> {
+    let control = handle_signals()?;
+    if let Some(control_socket) = control_socket {
+        if let Err(e) = control::serve(control_socket, data_dir.clone(), control.clone()) {
+            warn!(error = %e, "Failed to set up the control socket");
+        }
     }
-    let resp: EtagResponse = serde_json::from_slice(&output.stdout)?;
-
-    Ok(resp.etag)
-}
-
-fn pull_s3(remote: &Url) -> anyhow::Result<(impl Read, process::Child)> {
-    // by default this has 60s read & connect timeouts, so should not just
-    // hang, so no need for extra timeouts, I guess
-    let mut child = process::Command::new(aws_cli_path())
-        .args(["s3", "cp", remote.as_str(), "-"])
-        .stdout(Stdio::piped())
-        .log_debug()
-        .spawn()
-        .context("`aws` cli failed")?;
-
-    let stdout = child.stdout.take().unwrap();
-
-    Ok((stdout, child))
-}
-
-fn push_s3(remote: &Url) -> anyhow::Result<(impl Write, process::Child)> {
-    let mut child = process::Command::new(aws_cli_path())
-        .args(["s3", "cp", "-", remote.as_str()])
-        .stdin(Stdio::piped())
-        .log_debug()
-        .spawn()
-        .context("`aws` cli failed")?;
-
-    let stdin = child.stdin.take().unwrap();
-
-    Ok((stdin, child))
-}
 
-fn unpack_archive_to(reader: impl Read, dst: &Path) -> io::Result<()> {
-    fs::create_dir_all(dst)?;
+    let mut engine = DaemonEngine::new(data_dir.clone(), activate_opts.clone())
+        .with_override_configuration(override_configuration.map(ToOwned::to_owned))
+        .with_ignore_etag(ignore_etag)
+        .with_events(events)
+        .with_cancellation(control.cancellation());
 
-    let decoder = zstd::stream::Decoder::new(reader)?;
-    let mut archive = tar::Archive::new(decoder);
-    archive.unpack(dst)?;
+    systemd::notify("READY=1");
+    let start_delay = engine.start_delay()?;
+    if !start_delay.is_zero() {
+        info!(
+            delay_secs = start_delay.as_secs(),
+            "Delaying the first check"
+        );
+        systemd::status("Waiting for the first check");
+        systemd::sleep(start_delay, &control);
+    }
+    while !control.is_shutdown_requested() {
+        systemd::watchdog_ping();
+        match follow_inner(&mut engine, once) {
+            Ok(ControlFlow::Break(())) | Err(NpcnixError::Cancelled) => break,
+            Ok(ControlFlow::Continue(())) => {}
+            Err(e) => return Err(e),
+        }
 
-    Ok(())
+        systemd::sleep(engine.next_sleep_time()?, &control);
+    }
+    systemd::notify("STOPPING=1");
+    Ok(control.cancel_signal())
 }
 
-fn pack_archive_from(
-    src: &Path,
-    include: &HashSet<OsString>,
-    writer: impl Write,
-) -> io::Result<()> {
-    let encoder = zstd::stream::Encoder::new(writer, 0)?;
-    let mut builder = tar::Builder::new(encoder);
-    let paths = fs::read_dir(src)?;
-    for path in paths {
-        let entry = path?;
-        let path = entry.path();
-        let file_name = path
-            .file_name()
-            .expect("read_dir must return only items with valid file_name");
-        let metadata = path.symlink_metadata()?;
-        trace!(
-            src = %path.display(),
-            "Considering path for archive inclusion"
-        );
-        if metadata.is_dir() {
-            if include.is_empty() || include.contains(file_name) {
-                trace!(src = %path.display(), "Packing directory");
-                builder.append_dir_all(file_name, &path)?;
-            } else {
-                debug!(
-                    src = %path.display(),
-                    "Ignoring directory with no 'include'"
-                );
-            }
-        } else if metadata.is_symlink() {
-            let path_target = path.read_link()?;
-            if !path_target.is_absolute() {
-                trace!(src = %path.display(),
-                    target = %path_target.display(),
-                     "Packing relative symlink");
-                builder.append_path_with_name(&path, file_name)?;
-            } else {
-                warn!(
-                    src = %path.display(),
-                    "Ignoring absolute symlink"
+/// Handle the daemon signals
+///
+/// On the first termination signal a graceful shutdown is requested: the
+/// current cycle (e.g. a running `nixos-rebuild`) is allowed to finish and
+/// the daemon exits normally. The second signal cancels the current cycle
+/// (killing the running commands, cleaning up the temporary files) and
+/// [`follow`] returns it. The third one exits immediately.
+///
+/// `SIGHUP` cuts the current sleep short, to check the remote right away.
+fn handle_signals() -> anyhow::Result<DaemonControl> {
+    let control = DaemonControl::default();
+    let mut signals = Signals::new(TERM_SIGNALS.iter().chain([&SIGHUP]))?;
+    std::thread::spawn({
+        let control = control.clone();
+        move || {
+            for sig in signals.forever() {
+                if sig == SIGHUP {
+                    info!("SIGHUP, checking the remote now");
+                    control.wake_up();
+                    continue;
+                }
+                if control.cancel_signal().is_some() {
+                    warn!(sig, "Third termination signal, exiting immediately");
+                    misc::kill_running_children(libc::SIGTERM);
+                    process::exit(128 + sig);
+                }
+                if control.is_shutdown_requested() {
+                    warn!(
+                        sig,
+                        "Second termination signal, cancelling the current cycle"
+                    );
+                    control.cancel_by_signal(sig);
+                    continue;
+                }
+                info!(
+                    sig,
+                    "Termination signal, shutting down after the current cycle"
                 );
+                systemd::notify("STOPPING=1");
+                control.request_shutdown();
             }
-        } else if metadata.is_file() {
-            trace!(src = %path.display(), "Packing file");
-            builder.append_path_with_name(&path, file_name)?;
-        } else {
-            warn!(src = %path.display(), "Ignoring unknown file type");
         }
-    }
-    builder.into_inner()?.finish()?;
+    });
+    Ok(control)
+}
 
-    Ok(())
+fn follow_inner(
+    engine: &mut DaemonEngine,
+    once: Option<Once>,
+) -> Result<ControlFlow<(), ()>, NpcnixError> {
+    let outcome = engine.step()?;
+    match (once, outcome) {
+        (Some(Once::Cycle), _) => {
+            debug!("Exiting after a single cycle due to `once` option");
+            Ok(ControlFlow::Break(()))
+        }
+        (Some(Once::Any), CycleOutcome::Changed { .. } | CycleOutcome::Unchanged(_))
+        | (Some(Once::Activate), CycleOutcome::Changed { .. }) => {
+            debug!("Exiting after success due to `once` option");
+            Ok(ControlFlow::Break(()))
+        }
+        _ => Ok(ControlFlow::Continue(())),
+    }
 }
 
-pub fn follow(
+/// Perform a single check/pull/activate cycle of the daemon, see
+/// [`DaemonEngine::step`]
+pub fn daemon_step(
     data_dir: &DataDir,
     activate_opts: &ActivateOpts,
     override_configuration: Option<&str>,
-    once: Option<Once>,
     ignore_etag: bool,
-) -> anyhow::Result<()> {
-    let shutdown_requested = Arc::new(AtomicBool::new(false));
-    let shutdown_on_signal = Arc::new(AtomicBool::new(false));
-
-    for sig in TERM_SIGNALS {
-        // On first signal, mark shutdown as requested
-        flag::register(*sig, Arc::clone(&shutdown_requested))?;
-        // Also make the second signal shutdown immediately
-        flag::register(*sig, Arc::clone(&shutdown_on_signal))?;
+) -> Result<CycleOutcome, NpcnixError> {
+    DaemonEngine::new(data_dir.clone(), activate_opts.clone())
+        .with_override_configuration(override_configuration.map(ToOwned::to_owned))
+        .with_ignore_etag(ignore_etag)
+        .step()
+}
 
-        // If shutdown_on_signal was already set, shutdown immediately on signal
-        flag::register_conditional_shutdown(*sig, 1, Arc::clone(&shutdown_on_signal))?;
+/// Activate a remote already unpacked into `src`
+fn activate_unpacked(
+    activator: &dyn Activator,
+    config: &Config,
+    data_dir: Option<&DataDir>,
+    activate_opts: &ActivateOpts,
+    configuration: &str,
+    etag: &str,
+    src: &Path,
+) -> anyhow::Result<ActivationResult> {
+    let _span = info_span!("activate", phase = "activate", configuration, etag).entered();
+    match ArchiveMeta::load_from(src) {
+        Ok(Some(meta)) => info!(
+            etag,
+            git_rev = meta.git_rev.as_deref().unwrap_or("unknown"),
+            git_dirty = meta.git_dirty.unwrap_or_default(),
+            user = meta.user.as_deref().unwrap_or("unknown"),
+            built_at = meta
+                .timestamp
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_else(|| "unknown".into()),
+            "New remote archive"
+        ),
+        Ok(None) => info!(etag, "New remote archive (no metadata)"),
+        Err(e) => warn!(error = %e, "Failed to load archive metadata"),
     }
-
-    while !shutdown_requested.load(Ordering::SeqCst) {
-        if let ControlFlow::Break(()) = follow_inner(
+    let activate_opts = &match ClosureRef::load_from(src)? {
+        Some(closure) => {
+            info!(
+                etag,
+                store_path = %closure.store_path.display(),
+                "Remote references a pre-built closure"
+            );
+            ActivateOpts {
+                store_path: Some(closure.store_path),
+                enforce_activation_windows: true,
+                ..activate_opts.clone()
+            }
+        }
+        None => ActivateOpts {
+            enforce_activation_windows: true,
+            ..activate_opts.clone()
+        },
+    };
+    activator
+        .activate(
+            src,
+            configuration,
+            Some(etag),
             data_dir,
             activate_opts,
-            override_configuration,
-            once,
-            ignore_etag,
-        )? {
-            break;
-        }
-
-        // reload the config, just in case it changed in the meantime
-        let config = data_dir.load_config()?;
-        // During sleep, shutdown immediately on any signal
-        shutdown_on_signal.store(true, Ordering::SeqCst);
-        config.rng_sleep();
-        shutdown_on_signal.store(false, Ordering::SeqCst);
-    }
-    Ok(())
+            config,
+        )
+        .map_err(NpcnixError::activation_failed(configuration))
+        .map_err(Into::into)
 }
 
-fn follow_inner(
+/// Activate the update staged by the daemon in staged mode
+///
+/// Returns the activated configuration and etag.
+pub fn activate_staged(
     data_dir: &DataDir,
     activate_opts: &ActivateOpts,
-    override_configuration: Option<&str>,
-    once: Option<Once>,
-    ignore_etag: bool,
-) -> Result<ControlFlow<(), ()>, anyhow::Error> {
+) -> Result<(String, String), NpcnixError> {
     with_activate_lock(Some(data_dir), || {
-        // Note: we load every time, in case settings changed
         let config = data_dir.load_config()?;
-
-        if config.is_paused() {
-            info!("Paused");
-        } else {
-            match follow_inner_try(&config, activate_opts, override_configuration, ignore_etag) {
-                Ok(res) => {
-                    match res {
-                        Some((ref configuration, ref etag)) => {
-                            data_dir.update_last_reconfiguration(configuration, etag)?;
-                            info!(etag, "Successfully activated new configuration");
-                        }
-                        None => {
-                            info!("Remote not changed");
-                        }
-                    }
-                    match (once, res.is_some()) {
-                        (None, _) => {}
-                        (Some(Once::Activate), false) => {}
-                        (Some(Once::Any), _) | (Some(Once::Activate), true) => {
-                            debug!("Exiting after success due to `once` option");
-                            return Ok(ControlFlow::Break(()));
-                        }
-                    }
-                }
-                Err(e) => error!(error = %e, "Failed to activate new configuration"),
-            }
-        }
-        Ok(ControlFlow::Continue(()))
+        let pending = config
+            .pending_update()
+            .cloned()
+            .ok_or_else(|| NpcnixError::NotConfigured("No update staged".into()))?;
+        activate_unpacked(
+            &NixActivator,
+            &config,
+            Some(data_dir),
+            activate_opts,
+            &pending.configuration,
+            &pending.etag,
+            &data_dir.staging_dir(),
+        )?;
+        data_dir.update_last_reconfiguration(&pending.configuration, &pending.etag)?;
+        info!(etag = pending.etag, "Activated the staged update");
+        Ok((pending.configuration, pending.etag))
     })
 }
 
-pub fn follow_inner_try(
-    config: &Config,
-    activate_opts: &ActivateOpts,
-    override_configuration: Option<&str>,
-    ignore_etag: bool,
-) -> anyhow::Result<Option<(String, String)>> {
-    let configuration = override_configuration
-        .map(Ok)
-        .unwrap_or_else(|| config.configuration())?;
+/// Let the daemon activate the staged update
+pub fn approve_staged(data_dir: &DataDir) -> Result<PendingUpdate, NpcnixError> {
+    let mut approved = None;
+    data_dir.update_config(|config| {
+        let pending = PendingUpdate {
+            approved: true,
+            ..config
+                .pending_update()
+                .cloned()
+                .ok_or_else(|| NpcnixError::NotConfigured("No update staged".into()))?
+        };
+        approved = Some(pending.clone());
+        Ok(config.with_pending_update(Some(pending)))
+    })?;
+    Ok(approved.expect("set when updated"))
+}
 
-    let etag = self::get_etag(config.remote()?, config)?;
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
 
-    if !ignore_etag && config.last_configuration() == configuration && config.last_etag() == etag {
-        return Ok(None);
-    }
+    use url::Url;
 
-    let tmp_dir = tempfile::TempDir::new()?;
-    self::pull(config.remote()?, tmp_dir.path())?;
-    self::activate_inner(tmp_dir.path(), configuration, activate_opts)?;
+    use super::{pack_for_push, PushOpts};
+    use crate::pointer::{self, Pointer};
+    use crate::test_util;
 
-    Ok(Some((configuration.to_string(), etag)))
+    #[test]
+    fn content_addressed_pushes_of_the_same_content_share_the_archive() {
+        let src = test_util::flake_dir(&["host"]).unwrap();
+        let remote = Url::parse("s3://bucket/host").unwrap();
+        let push_opts = PushOpts {
+            content_addressed: true,
+            ..Default::default()
+        };
+        let target = || {
+            let (tmp_file, ..) = pack_for_push(src.path(), &HashSet::new(), &push_opts).unwrap();
+            let sha256 = pointer::sha256_reader(tmp_file.reopen().unwrap()).unwrap();
+            Pointer::new(&remote, &sha256).unwrap().target
+        };
+        let first = target();
+        // e.g. a fresh checkout of the same commit
+        fs::File::options()
+            .append(true)
+            .open(src.path().join("flake.nix"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(target(), first);
+
+        fs::write(src.path().join("extra.nix"), "{}").unwrap();
+        assert_ne!(target(), first);
+    }
 }
@@ -0,0 +1,49 @@
+//! Persisted output of activations (`<data_dir>/logs/`)
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tracing::{debug, warn};
+
+const LOG_EXTENSION: &str = "log";
+
+/// Create a new (empty) log file for an activation of `etag`
+pub fn create(logs_dir: &Path, etag: Option<&str>) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(logs_dir)
+        .with_context(|| format!("Failed to create logs directory: {}", logs_dir.display()))?;
+
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+    // etags are typically quoted, and could contain anything really
+    let etag: String = etag
+        .unwrap_or("manual")
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    let path = logs_dir.join(format!("{timestamp}-{etag}.{LOG_EXTENSION}"));
+    fs::File::create(&path)
+        .with_context(|| format!("Failed to create log file: {}", path.display()))?;
+    Ok(path)
+}
+
+/// Remove all but the `keep` newest log files
+pub fn rotate(logs_dir: &Path, keep: usize) -> anyhow::Result<()> {
+    let mut logs = vec![];
+    for entry in fs::read_dir(logs_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == LOG_EXTENSION) {
+            logs.push(path);
+        }
+    }
+    // file names start with a timestamp
+    logs.sort();
+
+    let remove_count = logs.len().saturating_sub(keep);
+    for path in &logs[..remove_count] {
+        debug!(path = %path.display(), "Removing old activation log");
+        if let Err(e) = fs::remove_file(path) {
+            warn!(error = %e, path = %path.display(), "Failed to remove old activation log");
+        }
+    }
+    Ok(())
+}
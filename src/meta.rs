@@ -0,0 +1,174 @@
+//! Build metadata embedded in the archives (`.npcnix-meta.json`)
+//!
+//! Pushes also upload it as a sidecar object next to the archive
+//! (`<remote>.meta.json`), so listing the archives, or telling whether a
+//! push would change anything, doesn't need downloading them.
+
+use std::path::Path;
+#[cfg(feature = "git")]
+use std::process;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "git")]
+use tracing::debug;
+use tracing::warn;
+use url::Url;
+
+use crate::retry::{self, RetryOpts};
+use crate::s3;
+#[cfg(feature = "git")]
+use crate::{git_path, CommandExt};
+
+pub const META_FILE_NAME: &str = ".npcnix-meta.json";
+pub const SIDECAR_SUFFIX: &str = ".meta.json";
+
+/// Provenance of an archive
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ArchiveMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_rev: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_dirty: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    /// Hash of the packed content alone (see
+    /// [`crate::archive::content_sha256`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_sha256: Option<String>,
+    pub npcnix_version: String,
+}
+
+/// Metadata sidecar of an archive
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Sidecar {
+    /// Of the archive when the sidecar was uploaded, to detect archives
+    /// overwritten since without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(flatten)]
+    pub meta: ArchiveMeta,
+}
+
+impl Sidecar {
+    /// Is this still the sidecar of the archive, now with `etag`
+    pub fn is_current(&self, etag: &str) -> bool {
+        self.etag.as_deref().map(|own| own.trim_matches('"')) == Some(etag.trim_matches('"'))
+    }
+}
+
+impl ArchiveMeta {
+    /// Collect metadata about the flake in `src` and the current environment
+    pub fn collect(src: &Path) -> Self {
+        let git_rev = git_output(src, &["rev-parse", "HEAD"]);
+        let git_dirty = git_rev.as_ref().and_then(|_| {
+            git_output(src, &["status", "--porcelain"]).map(|status| !status.is_empty())
+        });
+
+        Self {
+            git_rev,
+            git_dirty,
+            user: std::env::var("USER")
+                .or_else(|_| std::env::var("LOGNAME"))
+                .ok(),
+            timestamp: Some(chrono::Utc::now()),
+            content_sha256: None,
+            npcnix_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Drop fields that would make archives of the same content differ
+    ///
+    /// Used in the content-addressed layout, to keep archive hashes stable.
+    pub fn without_volatile_fields(self) -> Self {
+        Self {
+            user: None,
+            timestamp: None,
+            ..self
+        }
+    }
+
+    /// Load metadata from an unpacked archive in `dir`, if present
+    pub fn load_from(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let path = dir.join(META_FILE_NAME);
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+        Ok(Some(
+            serde_json::from_reader(std::fs::File::open(&path)?)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+        ))
+    }
+}
+
+/// Location of the metadata sidecar of the archive at `remote`
+pub fn sidecar_url(remote: &Url) -> Url {
+    let mut url = remote.clone();
+    url.set_path(&format!("{}{SIDECAR_SUFFIX}", remote.path()));
+    url
+}
+
+/// Is `url` a metadata sidecar (and not an archive)
+pub fn is_sidecar(url: &Url) -> bool {
+    url.path().ends_with(SIDECAR_SUFFIX)
+}
+
+/// Download the metadata sidecar of the archive at `remote`, if there's any
+pub fn fetch_sidecar(remote: &Url) -> anyhow::Result<Option<Sidecar>> {
+    let url = sidecar_url(remote);
+    let Some((bytes, _)) = s3::get_object(&url)? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        serde_json::from_slice(&bytes).with_context(|| format!("Failed to parse {url}"))?,
+    ))
+}
+
+/// Upload `meta` as the sidecar of the archive at `remote`, just uploaded
+/// with `etag`
+///
+/// The hosts don't need it, so failing is only a warning.
+pub(crate) fn upload_sidecar(remote: &Url, meta: &ArchiveMeta, etag: &str, retry_opts: &RetryOpts) {
+    let url = sidecar_url(remote);
+    let sidecar = Sidecar {
+        etag: Some(etag.to_owned()),
+        meta: meta.clone(),
+    };
+    let res = serde_json::to_vec_pretty(&sidecar)
+        .map_err(anyhow::Error::from)
+        .and_then(|bytes| {
+            retry::with_retry(retry_opts, "upload metadata", || {
+                s3::upload_bytes(&bytes, &url)
+            })
+        });
+    if let Err(e) = res {
+        warn!(error = %e, %url, "Failed to upload the metadata sidecar");
+    }
+}
+
+#[cfg(feature = "git")]
+pub(crate) fn git_output(src: &Path, args: &[&str]) -> Option<String> {
+    let output = process::Command::new(git_path())
+        .args(args)
+        .current_dir(src)
+        .stderr(process::Stdio::null())
+        .log_debug()
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        debug!(src = %src.display(), "Not a git repository");
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(not(feature = "git"))]
+pub(crate) fn git_output(_src: &Path, _args: &[&str]) -> Option<String> {
+    None
+}
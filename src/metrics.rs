@@ -0,0 +1,145 @@
+//! Prometheus metrics, written in the textfile collector format
+//!
+//! Point `metrics_textfile` at e.g. `/var/lib/prometheus-node-exporter/npcnix.prom`
+//! and the daemon rewrites it after every cycle.
+
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time;
+
+use crate::config::Config;
+use crate::CycleOutcome;
+
+static DOWNLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+static ACTIVATIONS: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+struct LastCycle {
+    timestamp: chrono::DateTime<chrono::Utc>,
+    /// How long the last activation (successful or not) took
+    activation_duration: Option<time::Duration>,
+}
+
+static LAST_CYCLE: Mutex<Option<LastCycle>> = Mutex::new(None);
+
+pub fn record_downloaded_bytes(bytes: u64) {
+    DOWNLOADED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Record the outcome of a daemon cycle that took `duration`
+pub fn record_cycle(outcome: &CycleOutcome, duration: time::Duration) {
+    let mut last_cycle = LAST_CYCLE.lock().expect("Locking failed");
+    let activation_duration = match outcome {
+        CycleOutcome::Changed { .. } => {
+            ACTIVATIONS.fetch_add(1, Ordering::Relaxed);
+            Some(duration)
+        }
+        CycleOutcome::Failed(_) | CycleOutcome::RemoteUnavailable(_) => {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+            Some(duration)
+        }
+        CycleOutcome::Unchanged(_) => last_cycle.and_then(|last| last.activation_duration),
+    };
+    *last_cycle = Some(LastCycle {
+        timestamp: chrono::Utc::now(),
+        activation_duration,
+    });
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP npcnix_{name} {help}");
+    let _ = writeln!(out, "# TYPE npcnix_{name} {kind}");
+    let _ = writeln!(out, "npcnix_{name} {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the metrics of the daemon using `config`
+pub fn render(config: &Config) -> String {
+    let mut out = String::new();
+    let last_cycle = *LAST_CYCLE.lock().expect("Locking failed");
+
+    let _ = writeln!(
+        out,
+        "# HELP npcnix_info Currently activated remote etag and configuration\n\
+         # TYPE npcnix_info gauge\n\
+         npcnix_info{{configuration=\"{}\",etag=\"{}\",version=\"{}\"}} 1",
+        escape_label(config.last_configuration()),
+        escape_label(config.last_etag()),
+        env!("CARGO_PKG_VERSION"),
+    );
+    if let Some(last_cycle) = last_cycle {
+        metric(
+            &mut out,
+            "last_check_timestamp_seconds",
+            "gauge",
+            "Time of the last check of the remote",
+            last_cycle.timestamp.timestamp(),
+        );
+        if let Some(duration) = last_cycle.activation_duration {
+            metric(
+                &mut out,
+                "activation_duration_seconds",
+                "gauge",
+                "Duration of the last activation",
+                duration.as_secs_f64(),
+            );
+        }
+    }
+    metric(
+        &mut out,
+        "last_success_timestamp_seconds",
+        "gauge",
+        "Time of the last successful activation",
+        config.last_reconfiguration().timestamp(),
+    );
+    metric(
+        &mut out,
+        "activations_total",
+        "counter",
+        "Successful activations since the daemon started",
+        ACTIVATIONS.load(Ordering::Relaxed),
+    );
+    metric(
+        &mut out,
+        "activation_failures_total",
+        "counter",
+        "Failed activations since the daemon started",
+        FAILURES.load(Ordering::Relaxed),
+    );
+    metric(
+        &mut out,
+        "consecutive_failures",
+        "gauge",
+        "Failed activations of the current remote etag",
+        config.last_failure().map(|f| f.count).unwrap_or_default(),
+    );
+    metric(
+        &mut out,
+        "downloaded_bytes_total",
+        "counter",
+        "Bytes downloaded from the remote since the daemon started",
+        DOWNLOADED_BYTES.load(Ordering::Relaxed),
+    );
+    metric(
+        &mut out,
+        "paused",
+        "gauge",
+        "Whether the daemon is paused",
+        u8::from(config.is_paused()),
+    );
+    out
+}
+
+/// Atomically write the metrics to the `path` textfile
+pub fn write_textfile(path: &Path, config: &Config) -> std::io::Result<()> {
+    crate::misc::store_str_to_file(path, &render(config))
+}
@@ -1,8 +1,15 @@
+use std::ffi::CStr;
 use std::io::{self, Write};
-use std::path::Path;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{process, thread, time};
 
+use anyhow::Context;
 use serde::Serialize;
 
+use crate::cancel::CancellationToken;
+
 pub fn store_json_pretty_to_file<T>(path: &Path, val: &T) -> anyhow::Result<()>
 where
     T: Serialize,
@@ -21,15 +28,313 @@ pub fn store_to_file_with<E, F>(path: &Path, f: F) -> io::Result<Result<(), E>>
 where
     F: Fn(&mut dyn io::Write) -> Result<(), E>,
 {
-    std::fs::create_dir_all(path.parent().expect("Not a root path"))?;
-    let tmp_path = path.with_extension("tmp");
-    let mut file = std::fs::File::create(&tmp_path)?;
+    store_to_file_with_mode(path, 0o666, f)
+}
+
+/// Like [`store_to_file_with`], creating the file with permissions `mode`
+/// (minus the umask), e.g. `0o600` for private files
+pub fn store_to_file_with_mode<E, F>(path: &Path, mode: u32, f: F) -> io::Result<Result<(), E>>
+where
+    F: Fn(&mut dyn io::Write) -> Result<(), E>,
+{
+    let parent = path.parent().expect("Not a root path");
+    std::fs::create_dir_all(parent)?;
+    // unique per process, so concurrent writers don't clobber each other's
+    // temporary file
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name().expect("Not a root path").to_string_lossy(),
+        std::process::id()
+    ));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp_path)?;
     if let Err(e) = f(&mut file) {
+        drop(file);
+        let _ = std::fs::remove_file(&tmp_path);
         return Ok(Err(e));
     }
     file.flush()?;
     file.sync_data()?;
     drop(file);
     std::fs::rename(tmp_path, path)?;
+    // make the rename itself durable
+    if let Ok(dir) = std::fs::File::open(if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    }) {
+        let _ = dir.sync_all();
+    }
     Ok(Ok(()))
 }
+
+/// Replace directory `dst` with the content produced by `f`
+///
+/// `f` is given a temporary sibling directory of `dst` to fill, which is then
+/// renamed into place, so a failure never leaves `dst` half-written. If
+/// `backup` is set, the previous content of `dst` is moved there instead of
+/// being deleted.
+pub fn replace_dir_with<F>(dst: &Path, backup: Option<&Path>, f: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&Path) -> anyhow::Result<()>,
+{
+    let parent = match dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(parent)?;
+    let file_name = dst
+        .file_name()
+        .ok_or_else(|| anyhow::format_err!("Invalid destination: {}", dst.display()))?;
+
+    let tmp_dir = tempfile::Builder::new()
+        .prefix(&format!(".{}.tmp", file_name.to_string_lossy()))
+        .tempdir_in(parent)?;
+    f(tmp_dir.path())?;
+
+    if dst.try_exists()? {
+        let old_dir = tempfile::Builder::new()
+            .prefix(&format!(".{}.old", file_name.to_string_lossy()))
+            .tempdir_in(parent)?;
+        let old_path = old_dir.path().join("old");
+        std::fs::rename(dst, &old_path)
+            .with_context(|| format!("Could not move away the old content of {}", dst.display()))?;
+        if let Err(e) = std::fs::rename(tmp_dir.path(), dst) {
+            // try to put the old content back
+            let _ = std::fs::rename(&old_path, dst);
+            return Err(e).with_context(|| format!("Could not rename into {}", dst.display()));
+        }
+        if let Some(backup) = backup {
+            if backup.try_exists()? {
+                std::fs::remove_dir_all(backup).with_context(|| {
+                    format!("Could not remove previous backup {}", backup.display())
+                })?;
+            }
+            std::fs::rename(&old_path, backup)
+                .with_context(|| format!("Could not create backup {}", backup.display()))?;
+        }
+        // `old_dir` will clean up the old content (if not backed up) on drop
+    } else {
+        std::fs::rename(tmp_dir.path(), dst)
+            .with_context(|| format!("Could not rename into {}", dst.display()))?;
+    }
+    // `tmp_dir` was renamed, so its cleanup on drop is a no-op
+    Ok(())
+}
+
+/// Wait for `child` to exit, killing its process group if it doesn't within
+/// `timeout`
+///
+/// The `child` has to be spawned in its own process group (see
+/// [`std::os::unix::process::CommandExt::process_group`]), so that its
+/// subprocesses get killed too. Returns `None` if the child had to be killed.
+pub fn wait_timeout(
+    child: &mut process::Child,
+    timeout: time::Duration,
+) -> io::Result<Option<process::ExitStatus>> {
+    let start = time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if timeout <= start.elapsed() {
+            let pgid = libc::pid_t::try_from(child.id()).expect("pid fits pid_t");
+            // SAFETY: `killpg` has no memory safety requirements
+            if unsafe { libc::killpg(pgid, libc::SIGKILL) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+#[derive(Default)]
+struct DaemonControlState {
+    shutdown: bool,
+    wakeup: bool,
+    cancel: CancellationToken,
+    /// The signal that cancelled the daemon, if any
+    cancel_signal: Option<libc::c_int>,
+}
+
+/// Lets other threads (e.g. signal handlers) request the daemon to shut down
+/// (possibly cancelling the current cycle) or to wake up from its sleep early
+#[derive(Clone, Default)]
+pub struct DaemonControl(Arc<(Mutex<DaemonControlState>, Condvar)>);
+
+impl DaemonControl {
+    fn update(&self, f: impl FnOnce(&mut DaemonControlState)) {
+        let (state, condvar) = &*self.0;
+        f(&mut state.lock().expect("Locking failed"));
+        condvar.notify_all();
+    }
+
+    pub fn request_shutdown(&self) {
+        self.update(|state| state.shutdown = true);
+    }
+
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.0 .0.lock().expect("Locking failed").shutdown
+    }
+
+    /// Shut down without waiting for the current cycle to finish, see
+    /// [`Self::cancellation`]
+    pub fn cancel(&self) {
+        self.update(|state| {
+            state.shutdown = true;
+            state.cancel.cancel();
+        });
+    }
+
+    /// [`Self::cancel`], because of the signal `sig`
+    pub fn cancel_by_signal(&self, sig: libc::c_int) {
+        self.update(|state| state.cancel_signal = Some(sig));
+        self.cancel();
+    }
+
+    pub fn cancel_signal(&self) -> Option<libc::c_int> {
+        self.0 .0.lock().expect("Locking failed").cancel_signal
+    }
+
+    /// Cancelled by [`Self::cancel`]
+    pub fn cancellation(&self) -> CancellationToken {
+        self.0 .0.lock().expect("Locking failed").cancel.clone()
+    }
+
+    /// Make the current (or next) [`Self::sleep`] return immediately
+    pub fn wake_up(&self) {
+        self.update(|state| state.wakeup = true);
+    }
+
+    /// Sleep for `duration`, returning early (`true`) on shutdown request or
+    /// wake up
+    pub fn sleep(&self, duration: time::Duration) -> bool {
+        let (state, condvar) = &*self.0;
+        let (mut state, _) = condvar
+            .wait_timeout_while(state.lock().expect("Locking failed"), duration, |state| {
+                !state.shutdown && !state.wakeup
+            })
+            .expect("Locking failed");
+        let interrupted = state.shutdown || state.wakeup;
+        state.wakeup = false;
+        interrupted
+    }
+}
+
+/// Running child processes, killed by [`kill_running_children`]
+static RUNNING_CHILDREN: Mutex<Vec<(libc::pid_t, bool)>> = Mutex::new(vec![]);
+
+/// Keeps a child process registered as running, until dropped
+pub struct RunningChild(libc::pid_t);
+
+impl RunningChild {
+    /// Register a `child` (and its whole process group, if it leads one)
+    pub fn register(child: &process::Child, process_group: bool) -> Self {
+        let pid = libc::pid_t::try_from(child.id()).expect("pid fits pid_t");
+        RUNNING_CHILDREN
+            .lock()
+            .expect("Locking failed")
+            .push((pid, process_group));
+        Self(pid)
+    }
+}
+
+impl Drop for RunningChild {
+    fn drop(&mut self) {
+        RUNNING_CHILDREN
+            .lock()
+            .expect("Locking failed")
+            .retain(|(pid, _)| *pid != self.0);
+    }
+}
+
+/// Send `sig` to all registered running children
+pub fn kill_running_children(sig: libc::c_int) {
+    for (pid, process_group) in RUNNING_CHILDREN.lock().expect("Locking failed").iter() {
+        // SAFETY: `kill` and `killpg` have no memory safety requirements
+        unsafe {
+            if *process_group {
+                libc::killpg(*pid, sig);
+            } else {
+                libc::kill(*pid, sig);
+            }
+        }
+    }
+}
+
+/// Parse a duration like `90s`, `15m`, `2h` or `1d` (plain numbers are
+/// seconds)
+pub fn parse_duration(s: &str) -> anyhow::Result<time::Duration> {
+    let s = s.trim();
+    let (number, unit_secs) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration: {s:?}"))?;
+    Ok(time::Duration::from_secs(number.saturating_mul(unit_secs)))
+}
+
+/// Replace every `{name}` in `template` with `lookup(name)`
+pub fn expand_placeholders(
+    template: &str,
+    mut lookup: impl FnMut(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow::format_err!("Unclosed placeholder in {template:?}"))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&lookup(&rest[start + 1..start + end])?);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// FNV-1a, stable across builds and platforms (unlike `DefaultHasher`)
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn is_root() -> bool {
+    // SAFETY: `geteuid` is always successful and has no side effects
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// `$<var>`, or `$HOME/<fallback>` if not set, as in the XDG base directory
+/// specification
+pub fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    match std::env::var_os(var).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => Some(dir),
+        _ => Some(PathBuf::from(std::env::var_os("HOME")?).join(fallback)),
+    }
+}
+
+/// Host name of the machine
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is valid for writes of its length
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    CStr::from_bytes_until_nul(&buf)
+        .ok()?
+        .to_str()
+        .ok()
+        .map(ToOwned::to_owned)
+}
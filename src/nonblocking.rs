@@ -0,0 +1,245 @@
+//! Async variants of the transfers and of the daemon loop (`async` feature)
+//!
+//! The downloads run the `aws` cli as tokio child processes, so many of them
+//! can be in flight at once without tying up threads. With the native client
+//! (see [`s3::is_native`]) they, like the pushes, share the blocking
+//! implementation instead, on tokio's blocking thread pool like the rest of
+//! the CPU-bound work (packing, unpacking, hashing, activating). Either way,
+//! dropping a future (e.g. on a `tokio::time::timeout`) kills the processes
+//! or cancels the requests it started.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::future::Future;
+use std::path::Path;
+use std::{fs, io, process};
+
+use anyhow::Context;
+use url::Url;
+
+use crate::cancel::CancellationToken;
+use crate::config::Config;
+use crate::data_dir::DataDir;
+use crate::error::NpcnixError;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::retry;
+use crate::{s3, systemd, ActivateOpts, CommandExt, PullOpts, PullResult, PushOpts, PushResult};
+
+/// How often [`blocking_cancellable`] checks for cancellation
+const CANCEL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Run `f` on the blocking thread pool
+async fn blocking<T, E>(f: impl FnOnce() -> Result<T, E> + Send + 'static) -> Result<T, NpcnixError>
+where
+    T: Send + 'static,
+    E: Into<NpcnixError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| NpcnixError::Other(e.into()))?
+        .map_err(Into::into)
+}
+
+/// Cancels the token when dropped
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Like [`blocking`], passing `f` a token cancelled once `cancel` is, or
+/// once the returned future is dropped
+async fn blocking_cancellable<T>(
+    cancel: Option<CancellationToken>,
+    f: impl FnOnce(CancellationToken) -> Result<T, NpcnixError> + Send + 'static,
+) -> Result<T, NpcnixError>
+where
+    T: Send + 'static,
+{
+    let token = CancellationToken::new();
+    let guard = CancelOnDrop(token.clone());
+    let task = blocking(move || f(token));
+    tokio::pin!(task);
+    loop {
+        tokio::select! {
+            res = &mut task => return res,
+            () = tokio::time::sleep(CANCEL_POLL_INTERVAL), if cancel.is_some() => {
+                if cancel.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                    guard.0.cancel();
+                }
+            }
+        }
+    }
+}
+
+fn tokio_command(mut command: process::Command) -> tokio::process::Command {
+    command.log_debug();
+    let mut command = tokio::process::Command::from(command);
+    command.kill_on_drop(true);
+    command
+}
+
+async fn output(command: process::Command) -> anyhow::Result<process::Output> {
+    tokio_command(command)
+        .output()
+        .await
+        .context("`aws` cli failed")
+}
+
+/// Like [`crate::get_etag`]
+pub async fn get_etag(remote: &Url, config: &Config) -> Result<String, NpcnixError> {
+    if s3::is_native() {
+        let (remote, config) = (remote.clone(), config.clone());
+        return blocking(move || crate::get_etag(&remote, &config)).await;
+    }
+    crate::check_scheme(remote)?;
+    async {
+        let output = output(s3::get_etag_command(remote, config.region_opt())?).await?;
+        s3::parse_etag_output(&output)
+    }
+    .await
+    .map_err(NpcnixError::etag_fetch(remote))
+}
+
+/// Like [`crate::pull`]
+pub async fn pull(
+    remote: &Url,
+    dst: &Path,
+    pull_opts: &PullOpts,
+) -> Result<PullResult, NpcnixError> {
+    let (mut file, mut res) = download(remote, pull_opts).await?;
+    if let Some(pointer) = crate::read_pointer(&mut file)? {
+        let (mut pointed, pointed_res) = download(&pointer.target, pull_opts).await?;
+        res.bytes += pointed_res.bytes;
+        file = blocking(move || {
+            crate::verify_pointed(&mut pointed, &pointer)?;
+            Ok::<_, NpcnixError>(pointed)
+        })
+        .await?;
+    }
+
+    let dst = dst.to_owned();
+    let pull_opts = pull_opts.clone();
+    blocking(move || {
+        crate::unpack_from(io::BufReader::new(file), &dst, &pull_opts).map_err(NpcnixError::Unpack)
+    })
+    .await?;
+    Ok(res)
+}
+
+async fn download(
+    remote: &Url,
+    pull_opts: &PullOpts,
+) -> Result<(fs::File, PullResult), NpcnixError> {
+    if s3::is_native() {
+        let (remote, pull_opts) = (remote.clone(), pull_opts.clone());
+        return blocking_cancellable(pull_opts.cancel.clone(), move |cancel| {
+            crate::download(
+                &remote,
+                &PullOpts {
+                    cancel: Some(cancel),
+                    ..pull_opts
+                },
+            )
+        })
+        .await;
+    }
+    crate::check_scheme(remote)?;
+    retry::with_retry_async(&pull_opts.retry, "download", || async {
+        let head = s3::parse_head_output(&output(s3::head_command(remote)?).await?)?;
+        let file = tempfile::tempfile()?;
+        s3::check_output(
+            &output(s3::download_command(remote, &file)?).await?,
+            "aws s3 cp",
+        )?;
+        let bytes = file.metadata()?.len();
+        #[cfg(feature = "metrics")]
+        metrics::record_downloaded_bytes(bytes);
+        Ok((
+            file,
+            PullResult {
+                bytes,
+                etag: head.etag,
+            },
+        ))
+    })
+    .await
+    .map_err(NpcnixError::remote_unavailable(remote))
+}
+
+/// Like [`crate::push`]
+///
+/// Runs the blocking implementation on the blocking thread pool, killing
+/// its commands once `push_opts.cancel` is cancelled or the future dropped.
+pub async fn push(
+    src: &Path,
+    include: &HashSet<OsString>,
+    remote: &Url,
+    push_opts: &PushOpts,
+) -> Result<PushResult, NpcnixError> {
+    let (src, include, remote) = (src.to_owned(), include.clone(), remote.clone());
+    let push_opts = push_opts.clone();
+    blocking_cancellable(push_opts.cancel.clone(), move |cancel| {
+        crate::push(
+            &src,
+            &include,
+            &remote,
+            &PushOpts {
+                cancel: Some(cancel),
+                ..push_opts
+            },
+        )
+    })
+    .await
+}
+
+/// Like [`crate::follow`], until `shutdown` completes
+///
+/// Each cycle runs on the blocking thread pool and is allowed to finish, only
+/// the sleeps between them are cut short by `shutdown`.
+pub async fn follow(
+    data_dir: DataDir,
+    activate_opts: ActivateOpts,
+    override_configuration: Option<String>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), NpcnixError> {
+    tokio::pin!(shutdown);
+    systemd::notify("READY=1");
+    'cycles: loop {
+        systemd::watchdog_ping();
+        blocking({
+            let data_dir = data_dir.clone();
+            let activate_opts = activate_opts.clone();
+            let override_configuration = override_configuration.clone();
+            move || {
+                crate::daemon_step(
+                    &data_dir,
+                    &activate_opts,
+                    override_configuration.as_deref(),
+                    false,
+                )
+            }
+        })
+        .await?;
+
+        // reload the config, just in case it changed in the meantime
+        let sleep_time = data_dir.load_config()?.next_sleep_time();
+        let sleep = tokio::time::sleep(sleep_time);
+        tokio::pin!(sleep);
+        // keep pinging the watchdog while sleeping, like `systemd::sleep`
+        let mut watchdog = systemd::watchdog_interval().map(tokio::time::interval);
+        loop {
+            tokio::select! {
+                () = &mut shutdown => break 'cycles,
+                () = &mut sleep => break,
+                _ = async { watchdog.as_mut().expect("checked").tick().await },
+                    if watchdog.is_some() => systemd::watchdog_ping(),
+            }
+        }
+    }
+    systemd::notify("STOPPING=1");
+    Ok(())
+}
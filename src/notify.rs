@@ -0,0 +1,192 @@
+//! Webhook notifications about activation outcomes (e.g. Slack incoming
+//! webhooks)
+
+use std::fmt;
+use std::path::Path;
+#[cfg(feature = "notifications")]
+use std::time;
+
+#[cfg(feature = "notifications")]
+use anyhow::format_err;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+#[cfg(feature = "notifications")]
+use tracing::debug;
+use tracing::warn;
+use url::Url;
+
+use crate::config::Config;
+use crate::{misc, secrets};
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    /// A new configuration was activated
+    Success,
+    /// Activating a new configuration failed
+    Failure,
+    /// The system was rolled back (manually, or after a failed health check)
+    Rollback,
+    /// Activating an etag failed too many times, it won't be retried
+    Quarantine,
+}
+
+impl NotifyEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotifyEvent::Success => "success",
+            NotifyEvent::Failure => "failure",
+            NotifyEvent::Rollback => "rollback",
+            NotifyEvent::Quarantine => "quarantine",
+        }
+    }
+}
+
+impl fmt::Display for NotifyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn default_events() -> Vec<NotifyEvent> {
+    vec![
+        NotifyEvent::Success,
+        NotifyEvent::Failure,
+        NotifyEvent::Rollback,
+        NotifyEvent::Quarantine,
+    ]
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct WebhookOpts {
+    /// URL to `POST` to, with `{hostname}`, `{configuration}` and `{event}`
+    /// placeholders
+    pub url: String,
+    /// Events to notify about
+    #[serde(default = "default_events")]
+    pub events: Vec<NotifyEvent>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl WebhookOpts {
+    pub fn new(url: &str, events: Vec<NotifyEvent>) -> Self {
+        Self {
+            url: url.to_owned(),
+            events: if events.is_empty() {
+                default_events()
+            } else {
+                events
+            },
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+
+    /// The URL with the placeholders filled (and resolved if it's a
+    /// `secret:` reference)
+    fn url(
+        &self,
+        secrets_file: Option<&Path>,
+        hostname: &str,
+        configuration: &str,
+        event: NotifyEvent,
+    ) -> anyhow::Result<Url> {
+        let url = secrets::resolve(&self.url, secrets_file)?
+            .replace("{hostname}", hostname)
+            .replace("{configuration}", configuration)
+            .replace("{event}", event.as_str());
+        Url::parse(&url)
+            .with_context(|| format!("Invalid webhook URL: {}", secrets::redact(&self.url)))
+    }
+
+    #[cfg(feature = "notifications")]
+    fn send(&self, url: &Url, payload: &serde_json::Value) -> anyhow::Result<()> {
+        let redacted_url = secrets::redact(url.as_str());
+        debug!(url = %redacted_url, "Sending webhook notification");
+        // non-2xx statuses are returned as errors by `ureq`; their messages
+        // contain the (sensitive) URL
+        ureq::post(url.as_str())
+            .timeout(time::Duration::from_secs(self.timeout_secs))
+            .set("Content-Type", "application/json")
+            .send_string(&payload.to_string())
+            .map_err(|e| match e {
+                ureq::Error::Status(code, _) => format_err!("{redacted_url}: status code {code}"),
+                ureq::Error::Transport(transport) => {
+                    format_err!("{redacted_url}: {}", transport.kind())
+                }
+            })?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "notifications"))]
+    fn send(&self, _url: &Url, _payload: &serde_json::Value) -> anyhow::Result<()> {
+        Err(crate::error::feature_disabled(
+            "Webhook notifications",
+            "notifications",
+        ))
+    }
+}
+
+/// Notify all the webhooks configured for `event`
+///
+/// The payload has a `text` field, so it can be posted directly to Slack.
+/// Failures are only logged: notifications never affect the activation.
+pub fn notify(
+    config: &Config,
+    event: NotifyEvent,
+    configuration: &str,
+    etag: &str,
+    error: Option<&anyhow::Error>,
+) {
+    let webhooks: Vec<_> = config
+        .webhooks()
+        .iter()
+        .filter(|webhook| webhook.events.contains(&event))
+        .collect();
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let hostname = misc::hostname().unwrap_or_else(|| "unknown".into());
+    let error = error.map(|e| format!("{e:#}"));
+    let text = match event {
+        NotifyEvent::Success => format!("{hostname}: activated {configuration} (etag {etag})"),
+        NotifyEvent::Failure => {
+            format!("{hostname}: activating {configuration} (etag {etag}) failed")
+        }
+        NotifyEvent::Rollback => format!("{hostname}: rolled back {configuration}"),
+        NotifyEvent::Quarantine => format!(
+            "{hostname}: quarantined {configuration} (etag {etag}) after too many failed \
+             activations"
+        ),
+    };
+    let text = match &error {
+        Some(error) => format!("{text}: {error}"),
+        None => text,
+    };
+    let payload = json!({
+        "text": text,
+        "event": event,
+        "hostname": hostname,
+        "configuration": configuration,
+        "etag": etag,
+        "error": error,
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "npcnix_version": env!("CARGO_PKG_VERSION"),
+    });
+
+    for webhook in webhooks {
+        if let Err(e) = webhook
+            .url(config.secrets_file(), &hostname, configuration, event)
+            .and_then(|url| webhook.send(&url, &payload))
+        {
+            warn!(error = %e, %event, "Failed to send webhook notification");
+        }
+    }
+}
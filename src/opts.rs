@@ -2,16 +2,41 @@ use std::path::PathBuf;
 
 use clap::Parser;
 
-use crate::data_dir::DataDir;
+use crate::data_dir::{self, DataDir};
 
 #[derive(Parser, Debug, Clone)]
 pub struct Common {
-    #[arg(long, env = "NPCNIX_DATA_DIR", default_value = "/var/lib/npcnix")]
-    data_dir: PathBuf,
+    /// [default: `/var/lib/npcnix` as root, `$XDG_STATE_HOME/npcnix`
+    /// otherwise]
+    #[arg(long, env = "NPCNIX_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// TOML base settings, overridden by the ones in the data dir
+    ///
+    /// Ignored if it doesn't exist. The `config.d/*.toml` files next to it
+    /// override it, in lexical order. [default: `/etc/npcnix/config.toml` as
+    /// root, `$XDG_CONFIG_HOME/npcnix/config.toml` otherwise]
+    #[arg(long, env = "NPCNIX_CONFIG_FILE")]
+    config_file: Option<PathBuf>,
+
+    /// Use the settings of this profile (see `npcnix config profile`)
+    #[arg(long, env = "NPCNIX_PROFILE")]
+    profile: Option<String>,
 }
 
 impl Common {
     pub fn data_dir(&self) -> DataDir {
-        DataDir::new(&self.data_dir)
+        DataDir::new(
+            &self
+                .data_dir
+                .clone()
+                .unwrap_or_else(data_dir::default_data_dir),
+        )
+        .with_settings_file(Some(
+            &self
+                .config_file
+                .clone()
+                .unwrap_or_else(data_dir::default_settings_file),
+        ))
+        .with_profile(self.profile.as_deref())
     }
 }
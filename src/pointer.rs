@@ -0,0 +1,57 @@
+//! Content-addressed remote layout
+//!
+//! In this layout archives are stored under `<prefix>/by-hash/<sha256>.tar.zst`
+//! and the remote itself is a small pointer object referencing one of them.
+//! Since the pointer content depends only on the archive hash, its etag
+//! changes only when the archive content changes.
+
+use std::io::{self, Read};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// Pointer objects are tiny, anything bigger is not a pointer
+const MAX_POINTER_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename = "npcnix_pointer")]
+pub struct Pointer {
+    pub target: Url,
+    pub sha256: String,
+}
+
+impl Pointer {
+    pub fn new(remote: &Url, sha256: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            target: by_hash_url(remote, sha256)?,
+            sha256: sha256.to_owned(),
+        })
+    }
+
+    /// Does the content starting with `first_bytes` look like a pointer
+    ///
+    /// Archives start with a zstd (or `age`) magic header, so a JSON object is
+    /// unambiguous.
+    pub fn is_pointer(first_bytes: &[u8]) -> bool {
+        first_bytes.first() == Some(&b'{')
+    }
+
+    pub fn read_from(reader: impl Read) -> anyhow::Result<Self> {
+        let mut buf = vec![];
+        reader.take(MAX_POINTER_SIZE).read_to_end(&mut buf)?;
+        serde_json::from_slice(&buf).context("Failed to parse pointer object")
+    }
+}
+
+/// Location of the archive with a given hash, relative to the `remote`
+pub fn by_hash_url(remote: &Url, sha256: &str) -> anyhow::Result<Url> {
+    Ok(remote.join(&format!("by-hash/{sha256}.tar.zst"))?)
+}
+
+pub fn sha256_reader(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
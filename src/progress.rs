@@ -0,0 +1,88 @@
+//! Progress of the transfers, e.g. to render progress bars
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Clone)]
+pub struct TransferProgress {
+    /// Bytes transferred so far (restarting from 0 when a transfer is
+    /// retried, or from where it stopped when a download is resumed)
+    pub transferred: u64,
+    /// Size of the transfer, if known
+    pub total: Option<u64>,
+}
+
+/// Callback notified as the transfers progress
+#[derive(Clone)]
+pub struct ProgressFn(Arc<dyn Fn(TransferProgress) + Send + Sync>);
+
+impl ProgressFn {
+    pub fn new(f: impl Fn(TransferProgress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn report(&self, progress: TransferProgress) {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for ProgressFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressFn")
+    }
+}
+
+/// Reports the bytes read or written through it
+pub(crate) struct Counting<'a, T> {
+    inner: T,
+    progress: TransferProgress,
+    progress_fn: &'a ProgressFn,
+}
+
+impl<'a, T> Counting<'a, T> {
+    pub(crate) fn new(inner: T, total: Option<u64>, progress_fn: &'a ProgressFn) -> Self {
+        Self::resumed(inner, 0, total, progress_fn)
+    }
+
+    /// Like [`Self::new`], with `transferred` bytes already transferred
+    pub(crate) fn resumed(
+        inner: T,
+        transferred: u64,
+        total: Option<u64>,
+        progress_fn: &'a ProgressFn,
+    ) -> Self {
+        let progress = TransferProgress { transferred, total };
+        progress_fn.report(progress);
+        Self {
+            inner,
+            progress,
+            progress_fn,
+        }
+    }
+
+    fn count(&mut self, len: usize) {
+        self.progress.transferred += len as u64;
+        self.progress_fn.report(self.progress);
+    }
+}
+
+impl<T: Read> Read for Counting<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count(len);
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for Counting<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,109 @@
+//! Checks of a flake before pushing it (see [`crate::push`])
+//!
+//! Whatever is pushed reaches every host following the remote, so by default
+//! pushing a git tree with uncommitted changes, without a committed
+//! `flake.lock`, or with what looks like secrets (see [`secret_scan`]) is
+//! refused.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::activation::{self, ActivationBackend};
+use crate::{meta, secret_scan};
+
+fn default_require_flake_lock() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PushChecks {
+    /// Push git trees with uncommitted changes too
+    #[serde(default)]
+    pub allow_dirty: bool,
+    /// Require `flake.lock` to be committed (in git trees)
+    #[serde(default = "default_require_flake_lock")]
+    pub require_flake_lock: bool,
+    /// Push files that look like secrets too
+    #[serde(default)]
+    pub allow_secrets: bool,
+    /// Run `nix flake check`
+    #[serde(default)]
+    pub flake_check: bool,
+    /// Evaluate the systems of these configurations
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evaluate: Vec<String>,
+}
+
+impl Default for PushChecks {
+    fn default() -> Self {
+        Self {
+            allow_dirty: false,
+            require_flake_lock: default_require_flake_lock(),
+            allow_secrets: false,
+            flake_check: false,
+            evaluate: vec![],
+        }
+    }
+}
+
+impl PushChecks {
+    /// Run the checks on the flake in `src` (limited to `include`, as in
+    /// [`crate::push`])
+    ///
+    /// The git checks are skipped outside of git trees.
+    pub fn run(&self, src: &Path, include: &HashSet<OsString>) -> anyhow::Result<()> {
+        // limited to `src`, in case it's a subdirectory of the repository
+        if let Some(status) = meta::git_output(src, &["status", "--porcelain", "--", "."]) {
+            if !self.allow_dirty && !status.is_empty() {
+                bail!(
+                    "{} has uncommitted changes (push with `--allow-dirty` anyway):\n{status}",
+                    src.display()
+                );
+            }
+            if self.require_flake_lock {
+                let tracked = meta::git_output(src, &["ls-files", "--", "flake.lock"])
+                    .is_some_and(|files| !files.is_empty());
+                if !tracked || status.lines().any(|line| line.ends_with("flake.lock")) {
+                    bail!("`flake.lock` in {} is not committed", src.display());
+                }
+            }
+        }
+
+        if !self.allow_secrets {
+            let findings = secret_scan::scan(src, include)
+                .with_context(|| format!("Failed to scan {} for secrets", src.display()))?;
+            if !findings.is_empty() {
+                let mut message = format!(
+                    "Files in {} look like secrets (push with `--allow-secrets` anyway):",
+                    src.display()
+                );
+                for finding in &findings {
+                    let _ = write!(message, "\n{}: {}", finding.path.display(), finding.reason);
+                }
+                bail!(message);
+            }
+        }
+
+        if self.flake_check {
+            info!(src = %src.display(), "Checking flake");
+            activation::flake_check(src, &[], &Default::default())?;
+        }
+        for configuration in &self.evaluate {
+            info!(configuration, "Evaluating configuration");
+            activation::eval_drv_path(
+                src,
+                &activation::flake_target(configuration, None),
+                ActivationBackend::default(),
+            )
+            .with_context(|| format!("Failed to evaluate `{configuration}`"))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! Fleet-wide overrides of the daemon settings, published as `settings.json`
+//! next to the remote
+//!
+//! Lets operators tune e.g. the polling frequency of all the hosts following
+//! a remote at once, without touching each of them. Only followed by hosts
+//! with `remote_settings` enabled.
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::activation::ActivationMode;
+use crate::s3;
+use crate::schedule::ActivationWindow;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct RemoteSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_sleep_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sleep_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sleep_after_hours: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activation_mode: Option<ActivationMode>,
+    /// Maintenance windows, replacing the local ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activation_windows: Option<Vec<ActivationWindow>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<Vec<ActivationWindow>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staged: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout_splay_secs: Option<u64>,
+}
+
+impl RemoteSettings {
+    fn validate(&self) -> anyhow::Result<()> {
+        if let (Some(min), Some(max)) = (self.min_sleep_secs, self.max_sleep_secs) {
+            if max < min {
+                bail!("`min_sleep_secs` ({min}) is greater than `max_sleep_secs` ({max})");
+            }
+        }
+        if self.max_sleep_secs == Some(0) {
+            bail!("`max_sleep_secs` must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Location of the settings of `remote`, next to it
+pub fn settings_url(remote: &Url) -> anyhow::Result<Url> {
+    Ok(remote.join("settings.json")?)
+}
+
+/// Download the settings published for `remote`, if any
+pub fn fetch(remote: &Url) -> anyhow::Result<Option<RemoteSettings>> {
+    let url = settings_url(remote)?;
+    let Some((content, _etag)) = s3::get_object(&url)? else {
+        return Ok(None);
+    };
+    let settings: RemoteSettings = serde_json::from_slice(&content)
+        .with_context(|| format!("Invalid remote settings in {url}"))?;
+    settings
+        .validate()
+        .with_context(|| format!("Invalid remote settings in {url}"))?;
+    Ok(Some(settings))
+}
@@ -0,0 +1,231 @@
+//! Per-host status objects ("phone home"), uploaded to S3 after every daemon
+//! cycle
+//!
+//! Every host writes `<prefix>/<hostname>.json`, so listing the prefix gives
+//! an overview of the whole fleet.
+
+use std::io::{Seek, SeekFrom};
+use std::str::FromStr;
+use std::{thread, time};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::config::{ActivationFailure, Config};
+use crate::{activation, misc, s3, CycleOutcome};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct HostStatus {
+    pub hostname: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub configuration: Option<String>,
+    /// Etag of the last activated remote
+    pub etag: String,
+    /// Current generation of the system profile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    /// Outcome of the last daemon cycle
+    pub outcome: String,
+    /// When the status was reported
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub last_reconfiguration: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<ActivationFailure>,
+    pub npcnix_version: String,
+}
+
+impl HostStatus {
+    pub fn collect(config: &Config, outcome: &CycleOutcome) -> Self {
+        Self {
+            hostname: misc::hostname().unwrap_or_else(|| "unknown".into()),
+            configuration: config.configuration().ok().map(ToOwned::to_owned),
+            etag: config.last_etag().to_owned(),
+            generation: activation::current_generation_number(),
+            outcome: outcome.as_str().to_owned(),
+            timestamp: chrono::Utc::now(),
+            last_reconfiguration: config.last_reconfiguration(),
+            paused: config.is_paused(),
+            last_failure: config.last_failure().cloned(),
+            npcnix_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Time since the status was reported
+    pub fn age(&self, now: chrono::DateTime<chrono::Utc>) -> chrono::Duration {
+        now - self.timestamp
+    }
+}
+
+/// `prefix` as a "directory", so the status objects are inside it
+fn dir_url(prefix: &Url) -> Url {
+    let mut prefix = prefix.clone();
+    if !prefix.path().ends_with('/') {
+        prefix.set_path(&format!("{}/", prefix.path()));
+    }
+    prefix
+}
+
+/// Location of the status object of `hostname` under `prefix`
+pub fn status_url(prefix: &Url, hostname: &str) -> anyhow::Result<Url> {
+    Ok(dir_url(prefix).join(&format!("{hostname}.json"))?)
+}
+
+fn check_scheme(prefix: &Url) -> anyhow::Result<()> {
+    let scheme = prefix.scheme();
+    if scheme != "s3" {
+        bail!("Protocol not supported: {scheme}");
+    }
+    Ok(())
+}
+
+/// Upload the status of this host under `prefix`
+pub fn upload(prefix: &Url, status: &HostStatus) -> anyhow::Result<()> {
+    check_scheme(prefix)?;
+    let url = status_url(prefix, &status.hostname)?;
+    s3::upload_bytes(&serde_json::to_vec_pretty(status)?, &url)
+        .with_context(|| format!("Failed to upload the status to {url}"))
+}
+
+/// Download the statuses of all the hosts reporting under `prefix`
+///
+/// Objects that fail to download or parse are skipped with a warning.
+pub fn fetch_all(prefix: &Url) -> anyhow::Result<Vec<HostStatus>> {
+    check_scheme(prefix)?;
+    let mut statuses = vec![];
+    for url in s3::list(&dir_url(prefix))? {
+        if !url.path().ends_with(".json") {
+            continue;
+        }
+        match fetch(&url) {
+            Ok(status) => statuses.push(status),
+            Err(e) => warn!(error = %e, %url, "Failed to fetch host status"),
+        }
+    }
+    statuses.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+    Ok(statuses)
+}
+
+/// Hosts that need to report an etag, for [`wait_for_etag`]
+#[derive(Debug, Clone)]
+pub enum RequiredHosts {
+    /// At least this many hosts
+    Count(usize),
+    /// All of these hosts
+    Names(Vec<String>),
+}
+
+impl FromStr for RequiredHosts {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(count) = s.parse() {
+            return Ok(RequiredHosts::Count(count));
+        }
+        let names: Vec<_> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(ToOwned::to_owned)
+            .collect();
+        if names.is_empty() {
+            bail!("No hosts given");
+        }
+        Ok(RequiredHosts::Names(names))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct Convergence {
+    /// Hosts reporting the etag
+    pub converged: Vec<String>,
+    /// Hosts not reporting it (yet)
+    pub pending: Vec<String>,
+    pub done: bool,
+}
+
+impl RequiredHosts {
+    fn check(&self, statuses: &[HostStatus], etag: &str) -> Convergence {
+        let mut convergence = Convergence::default();
+        for status in statuses {
+            if let RequiredHosts::Names(names) = self {
+                if !names.contains(&status.hostname) {
+                    continue;
+                }
+            }
+            if status.etag.trim_matches('"') == etag.trim_matches('"') {
+                convergence.converged.push(status.hostname.clone());
+            } else {
+                convergence.pending.push(status.hostname.clone());
+            }
+        }
+        convergence.done = match self {
+            RequiredHosts::Count(count) => *count <= convergence.converged.len(),
+            RequiredHosts::Names(names) => {
+                // including the hosts that never reported
+                for name in names {
+                    if !convergence.converged.contains(name) && !convergence.pending.contains(name)
+                    {
+                        convergence.pending.push(name.clone());
+                    }
+                }
+                convergence.pending.is_empty()
+            }
+        };
+        convergence
+    }
+}
+
+/// Watch the statuses under `prefix` until the `required` hosts report
+/// `etag`, failing after `timeout`
+pub fn wait_for_etag(
+    prefix: &Url,
+    etag: &str,
+    required: &RequiredHosts,
+    timeout: time::Duration,
+    interval: time::Duration,
+) -> anyhow::Result<Convergence> {
+    let deadline = time::Instant::now() + timeout;
+    loop {
+        let convergence = match fetch_all(prefix) {
+            Ok(statuses) => required.check(&statuses, etag),
+            Err(e) => {
+                warn!(error = %e, "Failed to fetch the host statuses");
+                Convergence::default()
+            }
+        };
+        if convergence.done {
+            return Ok(convergence);
+        }
+        if deadline <= time::Instant::now() + interval {
+            bail!(
+                "Timed out waiting for etag {etag}, {} host(s) converged, pending: {}",
+                convergence.converged.len(),
+                if convergence.pending.is_empty() {
+                    "-".into()
+                } else {
+                    convergence.pending.join(", ")
+                }
+            );
+        }
+        info!(
+            etag,
+            converged = convergence.converged.len(),
+            pending = convergence.pending.len(),
+            "Waiting for the hosts to converge"
+        );
+        thread::sleep(interval);
+    }
+}
+
+fn fetch(url: &Url) -> anyhow::Result<HostStatus> {
+    let mut file = tempfile::tempfile()?;
+    s3::download_to(url, &file, None)?;
+    file.seek(SeekFrom::Start(0))?;
+    serde_json::from_reader(file).context("Failed to parse host status")
+}
@@ -0,0 +1,179 @@
+//! Retrying flaky operations (transfers) with exponential backoff
+//!
+//! A failed download resumes where it stopped, with a ranged request, unless
+//! the object changed in the meantime; a failed upload is restarted from
+//! scratch (or just its failed parts, for multipart uploads).
+
+use std::{cmp, thread, time};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_initial_backoff_secs() -> u64 {
+    2
+}
+
+fn default_max_backoff_secs() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct RetryOpts {
+    /// How many times to retry after the first failure
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Delay before the first retry, doubled on every next one
+    #[serde(default = "default_initial_backoff_secs")]
+    pub initial_backoff_secs: u64,
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+}
+
+impl Default for RetryOpts {
+    fn default() -> Self {
+        Self {
+            retries: default_retries(),
+            initial_backoff_secs: default_initial_backoff_secs(),
+            max_backoff_secs: default_max_backoff_secs(),
+        }
+    }
+}
+
+impl RetryOpts {
+    pub fn no_retries() -> Self {
+        Self {
+            retries: 0,
+            ..Self::default()
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> time::Duration {
+        time::Duration::from_secs(cmp::min(
+            self.initial_backoff_secs
+                .saturating_mul(2u64.saturating_pow(attempt)),
+            self.max_backoff_secs,
+        ))
+    }
+}
+
+/// A failure that retrying won't fix (e.g. a missing object, or denied
+/// access), returned right away by [`with_retry`]
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct Permanent(pub String);
+
+pub fn is_permanent(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| e.is::<Permanent>())
+}
+
+/// Call `f` until it succeeds, retries are exhausted, or it fails
+/// [permanently](Permanent)
+pub fn with_retry<T>(
+    opts: &RetryOpts,
+    what: &str,
+    mut f: impl FnMut() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(res) => return Ok(res),
+            Err(e) => thread::sleep(retry_backoff(opts, what, &mut attempt, e)?),
+        }
+    }
+}
+
+/// Like [`with_retry`], for async operations
+#[cfg(feature = "async")]
+pub async fn with_retry_async<T, F>(
+    opts: &RetryOpts,
+    what: &str,
+    mut f: impl FnMut() -> F,
+) -> anyhow::Result<T>
+where
+    F: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(res) => return Ok(res),
+            Err(e) => tokio::time::sleep(retry_backoff(opts, what, &mut attempt, e)?).await,
+        }
+    }
+}
+
+/// How long to wait before retrying after the failure `e`, or `e` if
+/// retries are exhausted
+fn retry_backoff(
+    opts: &RetryOpts,
+    what: &str,
+    attempt: &mut u32,
+    e: anyhow::Error,
+) -> anyhow::Result<time::Duration> {
+    if opts.retries <= *attempt || crate::cancel::is_cancelled(&e) || is_permanent(&e) {
+        return Err(e);
+    }
+    let backoff = opts.backoff(*attempt);
+    *attempt += 1;
+    warn!(
+        error = %e,
+        what,
+        attempt = *attempt,
+        retries = opts.retries,
+        backoff_secs = backoff.as_secs(),
+        "Retrying after failure"
+    );
+    Ok(backoff)
+}
+
+fn default_failure_initial_backoff_secs() -> u64 {
+    60
+}
+
+fn default_failure_max_backoff_secs() -> u64 {
+    60 * 60
+}
+
+fn default_max_retries_per_etag() -> u32 {
+    5
+}
+
+/// How the daemon retries failed activations
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct FailureBackoffOpts {
+    /// Delay before the first retry, doubled on every next one
+    #[serde(default = "default_failure_initial_backoff_secs")]
+    pub initial_backoff_secs: u64,
+    #[serde(default = "default_failure_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    /// Quarantine an etag after this many failed activations (until the
+    /// remote changes, or it's unquarantined)
+    #[serde(default = "default_max_retries_per_etag")]
+    pub max_retries_per_etag: u32,
+}
+
+impl Default for FailureBackoffOpts {
+    fn default() -> Self {
+        Self {
+            initial_backoff_secs: default_failure_initial_backoff_secs(),
+            max_backoff_secs: default_failure_max_backoff_secs(),
+            max_retries_per_etag: default_max_retries_per_etag(),
+        }
+    }
+}
+
+impl FailureBackoffOpts {
+    /// Delay after `failures` consecutive failures
+    pub fn backoff(&self, failures: u32) -> time::Duration {
+        time::Duration::from_secs(cmp::min(
+            self.initial_backoff_secs
+                .saturating_mul(2u64.saturating_pow(failures.saturating_sub(1))),
+            self.max_backoff_secs,
+        ))
+    }
+}
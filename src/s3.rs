@@ -0,0 +1,988 @@
+//! S3 remotes, using the `aws` cli or the native client (`native-s3`
+//! feature, see [`is_native`])
+
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::{cmp, thread};
+
+use anyhow::{format_err, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::cancel::{self, CancellationToken};
+use crate::progress::{Counting, ProgressFn, TransferProgress};
+use crate::retry::{self, RetryOpts};
+use crate::CommandExt;
+
+#[cfg(feature = "native-s3")]
+mod native;
+
+/// S3 requires all parts but the last one to be at least 5MiB
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+
+fn default_multipart_threshold_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_multipart_part_size_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_multipart_parallelism() -> usize {
+    4
+}
+
+/// Settings of the multipart upload used for large archives
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct MultipartOpts {
+    /// Archives at least this big are uploaded in parts
+    #[serde(default = "default_multipart_threshold_bytes")]
+    pub threshold_bytes: u64,
+    #[serde(default = "default_multipart_part_size_bytes")]
+    pub part_size_bytes: u64,
+    /// Number of parts uploaded concurrently
+    #[serde(default = "default_multipart_parallelism")]
+    pub parallelism: usize,
+}
+
+impl Default for MultipartOpts {
+    fn default() -> Self {
+        Self {
+            threshold_bytes: default_multipart_threshold_bytes(),
+            part_size_bytes: default_multipart_part_size_bytes(),
+            parallelism: default_multipart_parallelism(),
+        }
+    }
+}
+
+fn bucket_key(remote: &Url) -> anyhow::Result<(&str, &str)> {
+    Ok((
+        remote
+            .host_str()
+            .ok_or_else(|| format_err!("Invalid URL"))?,
+        remote
+            .path()
+            .split_once('/')
+            .ok_or_else(|| format_err!("Path doesn't start with a /"))?
+            .1,
+    ))
+}
+
+/// The `aws` cli, if S3 remotes are in the build
+#[cfg(feature = "s3-cli")]
+fn aws() -> anyhow::Result<process::Command> {
+    Ok(process::Command::new(crate::aws_cli_path()))
+}
+
+#[cfg(not(feature = "s3-cli"))]
+fn aws() -> anyhow::Result<process::Command> {
+    Err(crate::error::feature_disabled(
+        "S3 remotes",
+        "s3-cli` or `native-s3",
+    ))
+}
+
+/// Whether the requests go through the native client rather than the `aws`
+/// cli: always without the `s3-cli` feature, and with both of them when
+/// `NPCNIX_S3_CLIENT=native`
+#[cfg(feature = "native-s3")]
+pub fn is_native() -> bool {
+    !cfg!(feature = "s3-cli")
+        || std::env::var_os("NPCNIX_S3_CLIENT").is_some_and(|client| client == "native")
+}
+
+#[cfg(not(feature = "native-s3"))]
+pub fn is_native() -> bool {
+    false
+}
+
+/// Errors of the `aws` cli that retrying won't fix: missing objects or
+/// buckets, and denied access
+const PERMANENT_ERRORS: &[&str] = &[
+    "NoSuchBucket",
+    "NoSuchKey",
+    "NoSuchUpload",
+    "InvalidBucketName",
+    "AccessDenied",
+    "InvalidAccessKeyId",
+    "SignatureDoesNotMatch",
+    "(403)",
+    "(404)",
+];
+
+/// The failure of the `aws` cli command `what`, from its `output`
+///
+/// Marked as [`retry::Permanent`] if its stderr says so.
+fn command_failed(what: &str, output: &process::Output) -> anyhow::Error {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let message = format!(
+        "{what} returned code={:?} stderr={}",
+        output.status.code(),
+        stderr.trim()
+    );
+    if PERMANENT_ERRORS.iter().any(|error| stderr.contains(error)) {
+        retry::Permanent(message).into()
+    } else {
+        anyhow::Error::msg(message)
+    }
+}
+
+pub(crate) fn check_output(output: &process::Output, what: &str) -> anyhow::Result<()> {
+    if !output.status.success() {
+        return Err(command_failed(what, output));
+    }
+    Ok(())
+}
+
+/// Like [`process::Command::status`], killing the command once `cancel` is
+/// cancelled, with the stderr captured
+fn status(
+    command: &mut process::Command,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<process::Output> {
+    cancel::check(cancel)?;
+    let child = command
+        .stderr(Stdio::piped())
+        .log_debug()
+        .spawn()
+        .context("`aws` cli failed")?;
+    Ok(cancel::kill_on_cancel(child.id(), false, cancel, || {
+        child.wait_with_output()
+    })??)
+}
+
+/// Wait for the `child` (with a piped stderr) once done with its stdio
+fn wait_with_stderr(mut child: process::Child) -> io::Result<process::Output> {
+    let mut stderr = vec![];
+    if let Some(mut child_stderr) = child.stderr.take() {
+        io::Read::read_to_end(&mut child_stderr, &mut stderr)?;
+    }
+    Ok(process::Output {
+        status: child.wait()?,
+        stdout: vec![],
+        stderr,
+    })
+}
+
+/// Like [`process::Command::output`], killing the command once `cancel` is
+/// cancelled
+fn output(
+    command: &mut process::Command,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<process::Output> {
+    cancel::check(cancel)?;
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .log_debug()
+        .spawn()
+        .context("`aws` cli failed")?;
+    Ok(cancel::kill_on_cancel(child.id(), false, cancel, || {
+        child.wait_with_output()
+    })??)
+}
+
+#[derive(Deserialize)]
+struct EtagResponse {
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+pub fn get_etag(remote: &Url, region: Option<&str>) -> anyhow::Result<String> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return Ok(native::head(remote, region)?.etag);
+    }
+    let output = get_etag_command(remote, region)?
+        .log_debug()
+        .output()
+        .context("`aws` cli failed")?;
+    parse_etag_output(&output)
+}
+
+pub(crate) fn get_etag_command(
+    remote: &Url,
+    region: Option<&str>,
+) -> anyhow::Result<process::Command> {
+    let (bucket, key) = bucket_key(remote)?;
+    let mut command = aws()?;
+    command.args(
+        [
+            "s3api",
+            "get-object-attributes",
+            "--bucket",
+            bucket,
+            "--key",
+            key,
+            "--object-attributes",
+            "ETag",
+        ]
+        .into_iter()
+        .chain(if let Some(region) = region {
+            vec!["--region", region]
+        } else {
+            vec![]
+        }),
+    );
+    Ok(command)
+}
+
+pub(crate) fn parse_etag_output(output: &process::Output) -> anyhow::Result<String> {
+    check_output(output, "aws s3api get-object-attributes")?;
+    let resp: EtagResponse = serde_json::from_slice(&output.stdout)?;
+
+    Ok(resp.etag)
+}
+
+pub fn exists(remote: &Url) -> anyhow::Result<bool> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::exists(remote);
+    }
+    let output = exists_command(remote)?
+        .log_debug()
+        .output()
+        .context("`aws` cli failed")?;
+    parse_exists_output(&output)
+}
+
+pub(crate) fn exists_command(remote: &Url) -> anyhow::Result<process::Command> {
+    let (bucket, key) = bucket_key(remote)?;
+    let mut command = aws()?;
+    command.args(["s3api", "head-object", "--bucket", bucket, "--key", key]);
+    Ok(command)
+}
+
+pub(crate) fn parse_exists_output(output: &process::Output) -> anyhow::Result<bool> {
+    if output.status.success() {
+        return Ok(true);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("Not Found") || stderr.contains("404") {
+        return Ok(false);
+    }
+    Err(command_failed("aws s3api head-object", output))
+}
+
+/// Download the object at `remote` into `file`
+pub fn download_to(
+    remote: &Url,
+    file: &fs::File,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::download_to(remote, file, 0, None, None, cancel);
+    }
+    // by default this has 60s read & connect timeouts, so should not just
+    // hang, so no need for extra timeouts, I guess
+    check_output(
+        &status(&mut download_command(remote, file)?, cancel)?,
+        "aws s3 cp",
+    )
+}
+
+/// Like [`download_to`], reporting the progress to `progress`
+///
+/// `total` is the expected size, if known (see [`head`]).
+pub fn download_to_with_progress(
+    remote: &Url,
+    file: &fs::File,
+    total: Option<u64>,
+    progress: &ProgressFn,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::download_to(remote, file, 0, None, Some((progress, total)), cancel);
+    }
+    cancel::check(cancel)?;
+    let mut command = download_command(remote, file)?;
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .log_debug()
+        .spawn()
+        .context("`aws` cli failed")?;
+
+    let mut stdout = child.stdout.take().unwrap();
+    let (res, output) = cancel::kill_on_cancel(child.id(), false, cancel, || {
+        let res = io::copy(&mut stdout, &mut Counting::new(file, total, progress));
+        drop(stdout);
+        (res, wait_with_stderr(child))
+    })?;
+    let output = output?;
+    check_output(&output, "aws s3 cp")?;
+    res?;
+    Ok(())
+}
+
+/// Like [`download_to_with_progress`], resuming the partial download in
+/// `file` (with a ranged request from its end), as long as the object still
+/// has `etag`
+///
+/// A changed object fails the (retryable) request, for the next attempt to
+/// start over.
+pub fn resume_download_to(
+    remote: &Url,
+    mut file: &fs::File,
+    etag: &str,
+    progress: Option<(&ProgressFn, Option<u64>)>,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<()> {
+    let offset = file.seek(SeekFrom::End(0))?;
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::download_to(remote, file, offset, Some(etag), progress, cancel);
+    }
+    let (bucket, key) = bucket_key(remote)?;
+    let rest = tempfile::NamedTempFile::new()?;
+    let etag = format!("\"{}\"", etag.trim_matches('"'));
+    check_output(
+        &status(
+            aws()?
+                .args(["s3api", "get-object", "--bucket", bucket, "--key", key])
+                .args(["--range", &format!("bytes={offset}-"), "--if-match", &etag])
+                .arg(rest.path())
+                .stdout(Stdio::null()),
+            cancel,
+        )?,
+        "aws s3api get-object",
+    )?;
+    let mut rest = rest.reopen()?;
+    match progress {
+        Some((progress, total)) => io::copy(
+            &mut rest,
+            &mut Counting::resumed(file, offset, total, progress),
+        )?,
+        None => io::copy(&mut rest, &mut file)?,
+    };
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HeadObjectResponse {
+    content_length: u64,
+    #[serde(rename = "ETag")]
+    etag: String,
+    #[serde(default)]
+    last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    version_id: Option<String>,
+}
+
+/// Metadata of an object
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ObjectHead {
+    pub size: u64,
+    /// Same format as [`get_etag`] (without the quotes)
+    pub etag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only in versioned buckets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_id: Option<String>,
+}
+
+pub fn head(remote: &Url) -> anyhow::Result<ObjectHead> {
+    head_in_region(remote, None)
+}
+
+/// Like [`head`], with the bucket in `region` (default: from the `aws` cli
+/// config)
+pub fn head_in_region(remote: &Url, region: Option<&str>) -> anyhow::Result<ObjectHead> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::head(remote, region);
+    }
+    let mut command = head_command(remote)?;
+    if let Some(region) = region {
+        command.args(["--region", region]);
+    }
+    let output = command.log_debug().output().context("`aws` cli failed")?;
+    parse_head_output(&output)
+}
+
+pub(crate) fn head_command(remote: &Url) -> anyhow::Result<process::Command> {
+    let mut command = exists_command(remote)?;
+    command.args(["--output", "json"]);
+    Ok(command)
+}
+
+pub(crate) fn parse_head_output(output: &process::Output) -> anyhow::Result<ObjectHead> {
+    check_output(output, "aws s3api head-object")?;
+    let resp: HeadObjectResponse = serde_json::from_slice(&output.stdout)?;
+    Ok(ObjectHead {
+        size: resp.content_length,
+        etag: resp.etag.trim_matches('"').to_owned(),
+        last_modified: resp.last_modified,
+        // unversioned buckets report `"null"`
+        version_id: resp.version_id.filter(|version_id| version_id != "null"),
+    })
+}
+
+pub(crate) fn download_command(remote: &Url, file: &fs::File) -> anyhow::Result<process::Command> {
+    let mut command = aws()?;
+    command
+        .args(["s3", "cp", remote.as_str(), "-"])
+        .stdout(file.try_clone()?);
+    Ok(command)
+}
+
+pub fn upload_file(
+    path: &Path,
+    remote: &Url,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::upload_file(path, remote, None, cancel);
+    }
+    check_output(
+        &status(&mut upload_file_command(path, remote)?, cancel)?,
+        "aws s3 cp",
+    )
+}
+
+/// Like [`upload_file`], reporting the progress to `progress`
+pub fn upload_file_with_progress(
+    path: &Path,
+    remote: &Url,
+    progress: &ProgressFn,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::upload_file(path, remote, Some(progress), cancel);
+    }
+    cancel::check(cancel)?;
+    let file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut child = aws()?
+        .args(["s3", "cp", "-", remote.as_str()])
+        .args(["--expected-size", &size.to_string()])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .log_debug()
+        .spawn()
+        .context("`aws` cli failed")?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let (res, output) = cancel::kill_on_cancel(child.id(), false, cancel, || {
+        let res = io::copy(&mut Counting::new(file, Some(size), progress), &mut stdin);
+        drop(stdin);
+        (res, wait_with_stderr(child))
+    })?;
+    let output = output?;
+    check_output(&output, "aws s3 cp")?;
+    res?;
+    Ok(())
+}
+
+pub(crate) fn upload_file_command(path: &Path, remote: &Url) -> anyhow::Result<process::Command> {
+    let mut command = aws()?;
+    command.args(["s3", "cp"]).arg(path).arg(remote.as_str());
+    Ok(command)
+}
+
+pub fn upload_bytes(bytes: &[u8], remote: &Url) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        native::put_bytes(bytes, remote, None)?;
+        return Ok(());
+    }
+    let mut child = aws()?
+        .args(["s3", "cp", "-", remote.as_str()])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .log_debug()
+        .spawn()
+        .context("`aws` cli failed")?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let res = stdin.write_all(bytes);
+    drop(stdin);
+    check_output(&wait_with_stderr(child)?, "aws s3 cp")?;
+    Ok(res?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListObjectsResponse {
+    #[serde(default)]
+    contents: Vec<ListedObject>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+    key: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, rename = "ETag")]
+    etag: String,
+}
+
+/// An object listed by [`list_dir`]
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub url: Url,
+    pub size: u64,
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// Same format as [`get_etag`] (without the quotes)
+    pub etag: String,
+}
+
+/// The objects under `prefix` (only directly under it with a `delimiter`),
+/// from all the pages
+fn list_objects(prefix: &Url, delimiter: Option<&str>) -> anyhow::Result<Vec<ListedObject>> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::list_objects(prefix, delimiter);
+    }
+    let (bucket, key) = bucket_key(prefix)?;
+    let mut args = vec!["list-objects-v2", "--bucket", bucket, "--prefix", key];
+    if let Some(delimiter) = delimiter {
+        args.extend(["--delimiter", delimiter]);
+    }
+    // the `aws` cli handles pagination
+    let resp: ListObjectsResponse = s3api_json(&args, None)?;
+    Ok(resp.contents)
+}
+
+/// List the objects under the `prefix`
+pub fn list(prefix: &Url) -> anyhow::Result<Vec<Url>> {
+    let (bucket, _) = bucket_key(prefix)?;
+    list_objects(prefix, None)?
+        .into_iter()
+        .map(|object| Ok(Url::parse(&format!("s3://{bucket}/{}", object.key))?))
+        .collect()
+}
+
+/// List the objects directly in the `prefix` "directory" (so not the ones in
+/// its subdirectories), with their metadata
+pub fn list_dir(prefix: &Url) -> anyhow::Result<Vec<ObjectInfo>> {
+    let (bucket, _) = bucket_key(prefix)?;
+    list_objects(prefix, Some("/"))?
+        .into_iter()
+        .map(|object| {
+            Ok(ObjectInfo {
+                url: Url::parse(&format!("s3://{bucket}/{}", object.key))?,
+                size: object.size,
+                last_modified: object.last_modified,
+                etag: object.etag.trim_matches('"').to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Condition for [`put_conditional`]
+#[derive(Debug, Clone, Copy)]
+pub enum PutCondition<'a> {
+    /// The object does not exist yet
+    Absent,
+    /// The object still has this etag
+    Etag(&'a str),
+}
+
+/// Upload `bytes` to `remote` only if `condition` holds, returning whether it
+/// did (S3 conditional writes)
+pub fn put_conditional(
+    bytes: &[u8],
+    remote: &Url,
+    condition: PutCondition<'_>,
+) -> anyhow::Result<bool> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::put_bytes(bytes, remote, Some(condition));
+    }
+    let (bucket, key) = bucket_key(remote)?;
+    let mut body = tempfile::NamedTempFile::new()?;
+    body.write_all(bytes)?;
+    let mut cmd = aws()?;
+    cmd.args([
+        "s3api",
+        "put-object",
+        "--bucket",
+        bucket,
+        "--key",
+        key,
+        "--body",
+    ])
+    .arg(body.path());
+    match condition {
+        PutCondition::Absent => cmd.args(["--if-none-match", "*"]),
+        PutCondition::Etag(etag) => {
+            cmd.args(["--if-match", &format!("\"{}\"", etag.trim_matches('"'))])
+        }
+    };
+    let output = cmd
+        .stdout(Stdio::null())
+        .log_debug()
+        .output()
+        .context("`aws` cli failed")?;
+    if output.status.success() {
+        return Ok(true);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("PreconditionFailed")
+        || stderr.contains("ConditionalRequestConflict")
+        || stderr.contains("412")
+    {
+        return Ok(false);
+    }
+    Err(command_failed("aws s3api put-object", &output))
+}
+
+#[derive(Deserialize)]
+struct GetObjectResponse {
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+/// Download a (small) object along with its etag, `None` if it doesn't exist
+pub fn get_object(remote: &Url) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::get_object(remote);
+    }
+    let (bucket, key) = bucket_key(remote)?;
+    let dst = tempfile::NamedTempFile::new()?;
+    let output = aws()?
+        .args(["s3api", "get-object", "--bucket", bucket, "--key", key])
+        .arg(dst.path())
+        .log_debug()
+        .output()
+        .context("`aws` cli failed")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("NoSuchKey") || stderr.contains("Not Found") {
+            return Ok(None);
+        }
+        return Err(command_failed("aws s3api get-object", &output));
+    }
+    let resp: GetObjectResponse = serde_json::from_slice(&output.stdout)?;
+    Ok(Some((fs::read(dst.path())?, resp.etag)))
+}
+
+/// Delete an object
+pub fn delete(remote: &Url) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::delete(remote);
+    }
+    let output = aws()?
+        .args(["s3", "rm", remote.as_str()])
+        .log_debug()
+        .output()
+        .context("`aws` cli failed")?;
+    check_output(&output, "aws s3 rm")
+}
+
+/// Server-side copy of an object
+pub fn copy(from: &Url, to: &Url) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::copy(from, to);
+    }
+    let output = aws()?
+        .args(["s3", "cp", from.as_str(), to.as_str()])
+        .log_debug()
+        .output()
+        .context("`aws` cli failed")?;
+    check_output(&output, "aws s3 cp")
+}
+
+fn s3api_json<T>(args: &[&str], cancel: Option<&CancellationToken>) -> anyhow::Result<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let output = output(
+        aws()?.arg("s3api").args(args).args(["--output", "json"]),
+        cancel,
+    )?;
+
+    check_output(
+        &output,
+        &format!("aws s3api {}", args.first().unwrap_or(&"")),
+    )?;
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateMultipartUploadResponse {
+    upload_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+struct CompletedPart {
+    #[serde(rename = "ETag")]
+    etag: String,
+    #[serde(default)]
+    part_number: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CompletedMultipartUpload {
+    parts: Vec<CompletedPart>,
+}
+
+/// Upload a (large) file using S3 multipart upload
+///
+/// Parts are uploaded concurrently and each one is retried independently.
+/// The progress, if reported, is updated as the parts complete. On failure
+/// (or cancellation) the upload is aborted, so the parts don't linger.
+pub fn upload_file_multipart(
+    path: &Path,
+    remote: &Url,
+    multipart_opts: &MultipartOpts,
+    retry_opts: &RetryOpts,
+    progress: Option<&ProgressFn>,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<()> {
+    let (bucket, key) = bucket_key(remote)?;
+    let size = fs::metadata(path)?.len();
+    let part_size = cmp::max(
+        cmp::max(multipart_opts.part_size_bytes, MIN_PART_SIZE),
+        size.div_ceil(MAX_PARTS),
+    );
+    let parts_count = cmp::max(size.div_ceil(part_size), 1);
+
+    let upload_id = create_multipart_upload(bucket, key, cancel)?;
+    info!(%remote, size, part_size, parts_count, "Starting multipart upload");
+
+    let res = upload_parts(
+        path,
+        bucket,
+        key,
+        &upload_id,
+        part_size,
+        parts_count,
+        multipart_opts.parallelism,
+        retry_opts,
+        progress.map(|progress| (progress, size)),
+        cancel,
+    )
+    .and_then(|mut parts| {
+        parts.sort_by_key(|part| part.part_number);
+        complete_multipart_upload(bucket, key, &upload_id, parts, cancel)
+    });
+
+    if let Err(ref e) = res {
+        warn!(error = %e, "Multipart upload failed, aborting");
+        if let Err(e) = abort_multipart_upload(bucket, key, &upload_id) {
+            warn!(upload_id, error = %e, "Failed to abort multipart upload");
+        }
+    }
+
+    res
+}
+
+fn create_multipart_upload(
+    bucket: &str,
+    key: &str,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<String> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::create_multipart_upload(bucket, key, cancel);
+    }
+    let upload: CreateMultipartUploadResponse = s3api_json(
+        &["create-multipart-upload", "--bucket", bucket, "--key", key],
+        cancel,
+    )?;
+    Ok(upload.upload_id)
+}
+
+fn complete_multipart_upload(
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    parts: Vec<CompletedPart>,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::complete_multipart_upload(bucket, key, upload_id, &parts, cancel);
+    }
+    let parts_file = tempfile::NamedTempFile::new()?;
+    serde_json::to_writer(parts_file.as_file(), &CompletedMultipartUpload { parts })?;
+    let _: serde_json::Value = s3api_json(
+        &[
+            "complete-multipart-upload",
+            "--bucket",
+            bucket,
+            "--key",
+            key,
+            "--upload-id",
+            upload_id,
+            "--multipart-upload",
+            &format!("file://{}", parts_file.path().display()),
+        ],
+        cancel,
+    )?;
+    Ok(())
+}
+
+fn abort_multipart_upload(bucket: &str, key: &str, upload_id: &str) -> anyhow::Result<()> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::abort_multipart_upload(bucket, key, upload_id);
+    }
+    let output = aws()?
+        .args([
+            "s3api",
+            "abort-multipart-upload",
+            "--bucket",
+            bucket,
+            "--key",
+            key,
+            "--upload-id",
+            upload_id,
+        ])
+        .log_debug()
+        .output()
+        .context("`aws` cli failed")?;
+    check_output(&output, "aws s3api abort-multipart-upload")
+}
+
+#[allow(clippy::too_many_arguments)]
+fn upload_parts(
+    path: &Path,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    part_size: u64,
+    parts_count: u64,
+    parallelism: usize,
+    retry_opts: &RetryOpts,
+    progress: Option<(&ProgressFn, u64)>,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<Vec<CompletedPart>> {
+    let next_part = AtomicU64::new(1);
+    let completed = Mutex::new(vec![]);
+    let uploaded = AtomicU64::new(0);
+    if let Some((progress, size)) = progress {
+        progress.report(TransferProgress {
+            transferred: 0,
+            total: Some(size),
+        });
+    }
+
+    thread::scope(|scope| -> anyhow::Result<()> {
+        let workers: Vec<_> = (0..cmp::max(parallelism, 1))
+            .map(|_| {
+                scope.spawn(|| -> anyhow::Result<()> {
+                    loop {
+                        let part_number = next_part.fetch_add(1, Ordering::SeqCst);
+                        if parts_count < part_number {
+                            return Ok(());
+                        }
+                        let etag = retry::with_retry(retry_opts, "upload part", || {
+                            upload_part(
+                                path,
+                                bucket,
+                                key,
+                                upload_id,
+                                part_number,
+                                (part_number - 1) * part_size,
+                                part_size,
+                                cancel,
+                            )
+                        })
+                        .inspect_err(|_| {
+                            // make other workers stop
+                            next_part.store(parts_count + 1, Ordering::SeqCst);
+                        })?;
+                        debug!(part_number, parts_count, "Uploaded part");
+                        if let Some((progress, size)) = progress {
+                            let offset = (part_number - 1) * part_size;
+                            let len = cmp::min(part_size, size.saturating_sub(offset));
+                            progress.report(TransferProgress {
+                                transferred: uploaded.fetch_add(len, Ordering::SeqCst) + len,
+                                total: Some(size),
+                            });
+                        }
+                        completed
+                            .lock()
+                            .expect("Locking failed")
+                            .push(CompletedPart { etag, part_number });
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            worker
+                .join()
+                .map_err(|_| format_err!("Upload thread panicked"))??;
+        }
+        Ok(())
+    })?;
+
+    Ok(completed.into_inner().expect("Locking failed"))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn upload_part(
+    path: &Path,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    part_number: u64,
+    offset: u64,
+    part_size: u64,
+    cancel: Option<&CancellationToken>,
+) -> anyhow::Result<String> {
+    #[cfg(feature = "native-s3")]
+    if is_native() {
+        return native::upload_part(
+            path,
+            bucket,
+            key,
+            upload_id,
+            part_number,
+            offset,
+            part_size,
+            cancel,
+        );
+    }
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut part_file = tempfile::NamedTempFile::new()?;
+    io::copy(&mut io::Read::take(file, part_size), &mut part_file)?;
+    part_file.flush()?;
+
+    let part: CompletedPart = s3api_json(
+        &[
+            "upload-part",
+            "--bucket",
+            bucket,
+            "--key",
+            key,
+            "--upload-id",
+            upload_id,
+            "--part-number",
+            &part_number.to_string(),
+            "--body",
+            &part_file.path().to_string_lossy(),
+        ],
+        cancel,
+    )?;
+    Ok(part.etag)
+}
@@ -0,0 +1,232 @@
+//! Activation windows (maintenance windows)
+//!
+//! A window is written as `[<days>] <HH:MM>-<HH:MM>`, in UTC, e.g.
+//! `Sat..Sun 02:00-05:00`, `Mon,Wed,Fri 22:00-02:00` or `03:00-04:00`
+//! (every day). Windows ending before they start cross midnight, and are
+//! considered to start on the listed days.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, format_err, Context};
+use chrono::{Datelike, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActivationWindow {
+    /// Days the window starts on, empty meaning every day
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl ActivationWindow {
+    /// Is `time` inside the window
+    pub fn contains(&self, time: chrono::DateTime<chrono::Utc>) -> bool {
+        let on_day = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let (day, time) = (time.weekday(), time.time());
+        if self.start == self.end {
+            on_day(day)
+        } else if self.start < self.end {
+            on_day(day) && self.start <= time && time < self.end
+        } else {
+            (on_day(day) && self.start <= time) || (on_day(day.pred()) && time < self.end)
+        }
+    }
+}
+
+fn parse_days(s: &str) -> anyhow::Result<Vec<Weekday>> {
+    let parse_day =
+        |s: &str| Weekday::from_str(s).map_err(|_| format_err!("Invalid day of the week: {s}"));
+    let mut days = vec![];
+    for part in s.split(',') {
+        match part.split_once("..") {
+            Some((from, to)) => {
+                let (mut day, to) = (parse_day(from)?, parse_day(to)?);
+                days.push(day);
+                while day != to {
+                    day = day.succ();
+                    days.push(day);
+                }
+            }
+            None => days.push(parse_day(part)?),
+        }
+    }
+    Ok(days)
+}
+
+impl FromStr for ActivationWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = match s.trim().rsplit_once(' ') {
+            Some((days, times)) => (parse_days(days.trim())?, times),
+            None => (vec![], s.trim()),
+        };
+        let Some((start, end)) = times.split_once('-') else {
+            bail!("Invalid activation window: {s} (expected e.g. `Sat..Sun 02:00-05:00`)");
+        };
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .with_context(|| format!("Invalid time: {time} (expected HH:MM)"))
+        };
+        Ok(Self {
+            days,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+impl fmt::Display for ActivationWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.days.is_empty() {
+            let days: Vec<_> = self.days.iter().map(ToString::to_string).collect();
+            write!(f, "{} ", days.join(","))?;
+        }
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl Serialize for ActivationWindow {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ActivationWindow {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn parses_windows() {
+        use Weekday::*;
+        for (s, days, start, end) in [
+            ("03:00-04:00", vec![], (3, 0), (4, 0)),
+            ("Sat..Sun 02:00-05:00", vec![Sat, Sun], (2, 0), (5, 0)),
+            (
+                "Fri..Mon 02:30-05:15",
+                vec![Fri, Sat, Sun, Mon],
+                (2, 30),
+                (5, 15),
+            ),
+            (
+                "Mon,Wed,Fri 22:00-02:00",
+                vec![Mon, Wed, Fri],
+                (22, 0),
+                (2, 0),
+            ),
+            (
+                "Mon,Thu..Fri 22:00-02:00",
+                vec![Mon, Thu, Fri],
+                (22, 0),
+                (2, 0),
+            ),
+            ("  tue 00:00-00:00 ", vec![Tue], (0, 0), (0, 0)),
+        ] {
+            let time = |(h, m)| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+            assert_eq!(
+                s.parse::<ActivationWindow>().unwrap(),
+                ActivationWindow {
+                    days,
+                    start: time(start),
+                    end: time(end),
+                },
+                "{s}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        for s in [
+            "",
+            "02:00",
+            "Sat 02:00",
+            "Sat..Sun",
+            "Someday 02:00-05:00",
+            "Sat..Someday 02:00-05:00",
+            "Sat,,Sun 02:00-05:00",
+            "24:00-05:00",
+            "02:00-05:60",
+            "2am-5am",
+        ] {
+            assert!(s.parse::<ActivationWindow>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn displays_as_parsed() {
+        for s in [
+            "03:00-04:00",
+            "Sat,Sun 02:00-05:00",
+            "Mon,Wed,Fri 22:00-02:00",
+        ] {
+            assert_eq!(s.parse::<ActivationWindow>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn contains_times_across_midnight() {
+        let window: ActivationWindow = "Mon,Wed,Fri 22:00-02:00".parse().unwrap();
+        // 2024-01-01 is a Monday
+        for (day, h, m, expected) in [
+            (1, 21, 59, false),
+            (1, 22, 0, true),
+            (1, 23, 59, true),
+            // Tuesday morning, the end of Monday's window
+            (2, 0, 0, true),
+            (2, 1, 59, true),
+            (2, 2, 0, false),
+            (2, 22, 0, false),
+            // Wednesday morning: Tuesday has no window
+            (3, 1, 0, false),
+            (3, 22, 30, true),
+            (4, 1, 30, true),
+            // Saturday morning, the end of Friday's window
+            (6, 1, 0, true),
+            (6, 22, 0, false),
+            // Monday morning: Sunday has no window
+            (8, 1, 0, false),
+        ] {
+            let time = Utc.with_ymd_and_hms(2024, 1, day, h, m, 0).unwrap();
+            assert_eq!(window.contains(time), expected, "{time}");
+        }
+    }
+
+    #[test]
+    fn contains_times_on_the_listed_days() {
+        let every_day: ActivationWindow = "03:00-04:00".parse().unwrap();
+        let weekend: ActivationWindow = "Sat..Sun 03:00-04:00".parse().unwrap();
+        let all_day: ActivationWindow = "Sun 00:00-00:00".parse().unwrap();
+        for (day, h, every_day_expected, weekend_expected, all_day_expected) in [
+            (1, 3, true, false, false),
+            (1, 4, false, false, false),
+            (6, 2, false, false, false),
+            (6, 3, true, true, false),
+            (7, 0, false, false, true),
+            (7, 3, true, true, true),
+            (7, 23, false, false, true),
+        ] {
+            let time = Utc.with_ymd_and_hms(2024, 1, day, h, 0, 0).unwrap();
+            assert_eq!(every_day.contains(time), every_day_expected, "{time}");
+            assert_eq!(weekend.contains(time), weekend_expected, "{time}");
+            assert_eq!(all_day.contains(time), all_day_expected, "{time}");
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Sensitive settings (e.g. webhook URLs embedding tokens)
+//!
+//! Such settings can be set to a `secret:<name>` reference instead of the
+//! value itself, resolved from the systemd credential `<name>`
+//! (`LoadCredential=`/`LoadCredentialEncrypted=`, see `systemd-creds`) or
+//! else from `<data_dir>/secrets.json`, only readable by its owner. Either
+//! way sensitive settings are redacted when showing the config.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde_json::{Map, Value};
+use url::Url;
+
+use crate::misc;
+
+pub const SECRET_PREFIX: &str = "secret:";
+
+/// Settings with sensitive values, as paths into the serialized config (`*`
+/// for every array element)
+const SENSITIVE_SETTINGS: &[&[&str]] = &[&["webhooks", "*", "url"]];
+
+/// Replacement of the redacted values
+const REDACTED: &str = "<redacted>";
+
+fn credentials_dir() -> Option<PathBuf> {
+    std::env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from)
+}
+
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        bail!("Invalid secret name `{name}`");
+    }
+    Ok(())
+}
+
+fn read_secrets(secrets_file: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    match std::fs::read(secrets_file) {
+        Ok(content) => serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse {}", secrets_file.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", secrets_file.display())),
+    }
+}
+
+/// `value`, or the secret it refers to if it's a `secret:<name>` reference
+pub fn resolve(value: &str, secrets_file: Option<&Path>) -> anyhow::Result<String> {
+    let Some(name) = value.strip_prefix(SECRET_PREFIX) else {
+        return Ok(value.to_owned());
+    };
+    check_name(name)?;
+    if let Some(dir) = credentials_dir() {
+        match std::fs::read_to_string(dir.join(name)) {
+            Ok(secret) => return Ok(secret.trim_end_matches('\n').to_owned()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read credential `{name}`")),
+        }
+    }
+    if let Some(secrets_file) = secrets_file {
+        if let Some(secret) = read_secrets(secrets_file)?.remove(name) {
+            return Ok(secret);
+        }
+    }
+    bail!("Secret `{name}` not found");
+}
+
+/// Set the secret `name` in `secrets_file` (`None`: remove it)
+pub fn store(secrets_file: &Path, name: &str, value: Option<&str>) -> anyhow::Result<()> {
+    check_name(name)?;
+    let mut secrets = read_secrets(secrets_file)?;
+    match value {
+        Some(value) => {
+            secrets.insert(name.to_owned(), value.to_owned());
+        }
+        None => {
+            secrets.remove(name);
+        }
+    }
+    misc::store_to_file_with_mode(secrets_file, 0o600, |f| -> anyhow::Result<()> {
+        serde_json::to_writer_pretty(&mut *f, &secrets)?;
+        f.write_all(b"\n")?;
+        Ok(())
+    })??;
+    Ok(())
+}
+
+/// Names of the secrets stored in `secrets_file`
+pub fn names(secrets_file: &Path) -> anyhow::Result<Vec<String>> {
+    Ok(read_secrets(secrets_file)?.into_keys().collect())
+}
+
+/// `value` with the sensitive part hidden, keeping enough to recognize it
+///
+/// References to secrets are not sensitive, and kept as is.
+pub fn redact(value: &str) -> String {
+    if value.starts_with(SECRET_PREFIX) {
+        return value.to_owned();
+    }
+    match Url::parse(value) {
+        Ok(url) if url.has_host() => format!(
+            "{}://{}/{REDACTED}",
+            url.scheme(),
+            url.host_str().unwrap_or_default()
+        ),
+        _ => REDACTED.to_owned(),
+    }
+}
+
+fn redact_path(value: &mut Value, path: &[&str]) {
+    let Some((first, rest)) = path.split_first() else {
+        if let Value::String(s) = value {
+            *s = redact(s);
+        }
+        return;
+    };
+    match (value, *first) {
+        (Value::Array(array), "*") => {
+            for element in array {
+                redact_path(element, rest);
+            }
+        }
+        (Value::Object(map), key) => {
+            if let Some(value) = map.get_mut(key) {
+                redact_path(value, rest);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Redact the setting `key` of the serialized config, if sensitive
+pub fn redact_setting(key: &str, value: &mut Value) {
+    for path in SENSITIVE_SETTINGS {
+        if path[0] == key {
+            redact_path(value, &path[1..]);
+        }
+    }
+}
+
+/// Redact all the sensitive settings of the serialized config
+pub fn redact_settings(settings: &mut Map<String, Value>) {
+    for (key, value) in settings.iter_mut() {
+        redact_setting(key, value);
+    }
+}
@@ -0,0 +1,91 @@
+//! Status of the host, as shown by `npcnix status`
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use url::Url;
+
+use crate::config::{ActivationFailure, PendingUpdate};
+use crate::control::{self, ControlRequest};
+use crate::data_dir::DataDir;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct DaemonLiveness {
+    /// The daemon responded on the control socket
+    pub running: bool,
+    pub control_socket: PathBuf,
+    /// The status reported by the daemon, or why it's not reachable
+    pub message: String,
+}
+
+impl DaemonLiveness {
+    pub fn check(control_socket: &Path) -> Self {
+        let (running, message) = match control::send(control_socket, &ControlRequest::Status) {
+            Ok(response) => (true, response.message),
+            Err(e) => (false, format!("{e:#}")),
+        };
+        Self {
+            running,
+            control_socket: control_socket.to_owned(),
+            message,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Status {
+    /// `active`, or why paused
+    pub state: String,
+    pub paused: bool,
+    pub configuration: Option<String>,
+    /// The remote of the subscribed channel
+    pub remote: Option<Url>,
+    pub channel: Option<String>,
+    pub last_configuration: String,
+    pub last_etag: String,
+    pub last_reconfiguration: chrono::DateTime<chrono::Utc>,
+    /// Last time the daemon successfully checked the remote
+    pub last_check: Option<chrono::DateTime<chrono::Utc>>,
+    /// Seconds since `last_check`
+    pub last_check_age_secs: Option<i64>,
+    pub staged_mode: bool,
+    pub pending_update: Option<PendingUpdate>,
+    pub held_etag: Option<String>,
+    pub last_failure: Option<ActivationFailure>,
+    pub quarantined: bool,
+    pub last_activation_log: Option<PathBuf>,
+    pub expected_system: Option<PathBuf>,
+    /// Not checked if no control socket is given
+    pub daemon: Option<DaemonLiveness>,
+}
+
+impl Status {
+    pub fn collect(data_dir: &DataDir, control_socket: Option<&Path>) -> anyhow::Result<Self> {
+        let config = data_dir.load_config()?;
+        let now = chrono::Utc::now();
+        Ok(Self {
+            state: config.status_string(),
+            paused: config.is_paused(),
+            configuration: config.configuration().ok().map(ToOwned::to_owned),
+            remote: config.effective_remote().ok(),
+            channel: config.channel().map(ToOwned::to_owned),
+            last_configuration: config.last_configuration().to_owned(),
+            last_etag: config.last_etag().to_owned(),
+            last_reconfiguration: config.last_reconfiguration(),
+            last_check: config.last_check(),
+            last_check_age_secs: config
+                .last_check()
+                .map(|last_check| (now - last_check).num_seconds()),
+            staged_mode: config.staged(),
+            pending_update: config.pending_update().cloned(),
+            held_etag: config.held_etag().map(ToOwned::to_owned),
+            last_failure: config.last_failure().cloned(),
+            quarantined: config.quarantined().is_some(),
+            last_activation_log: config.last_activation_log().map(ToOwned::to_owned),
+            expected_system: config.expected_system().map(ToOwned::to_owned),
+            daemon: control_socket.map(DaemonLiveness::check),
+        })
+    }
+}
@@ -0,0 +1,75 @@
+//! systemd service notifications (`sd_notify`)
+//!
+//! Everything here is a no-op unless running under systemd with
+//! `Type=notify` (`NOTIFY_SOCKET` set). With `WatchdogSec=` set, the daemon
+//! pings the watchdog between cycles, so a hanging cycle gets it restarted.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time;
+
+use tracing::debug;
+
+use crate::misc::DaemonControl;
+
+/// Send a notification (e.g. `READY=1`) to the service manager, if any
+pub fn notify(state: &str) {
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket_path, state) {
+        debug!(error = %e, state, "Failed to notify systemd");
+    }
+}
+
+fn send(socket_path: &OsStr, state: &str) -> io::Result<()> {
+    let addr = match socket_path.as_encoded_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        _ => SocketAddr::from_pathname(socket_path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Update the status line shown by `systemctl status`
+pub fn status(status: &str) {
+    notify(&format!("STATUS={status}"));
+}
+
+/// How often the watchdog must be pinged, if it's enabled for us
+pub fn watchdog_interval() -> Option<time::Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // ping twice as often as required, to be on the safe side
+    Some(time::Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+pub fn watchdog_ping() {
+    notify("WATCHDOG=1");
+}
+
+/// Sleep for `duration` (or until interrupted through `control`), pinging
+/// the watchdog in the meantime
+pub fn sleep(duration: time::Duration, control: &DaemonControl) {
+    let Some(interval) = watchdog_interval() else {
+        control.sleep(duration);
+        return;
+    };
+    let deadline = time::Instant::now() + duration;
+    loop {
+        watchdog_ping();
+        let remaining = deadline.saturating_duration_since(time::Instant::now());
+        if remaining.is_zero() || control.sleep(remaining.min(interval)) {
+            return;
+        }
+    }
+}
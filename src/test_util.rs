@@ -0,0 +1,321 @@
+//! Fakes and fixtures to test pipelines hermetically (`test-util` feature)
+//!
+//! [`FsRemote`], [`FakeActivator`] and [`FixedClock`] plug into
+//! [`crate::DaemonEngine`], so a whole daemon cycle runs without the `aws`
+//! cli, `nix` or root, e.g.:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use npcnix::test_util::{self, FakeActivator, FsRemote};
+//!
+//! let remote = FsRemote::new(tempfile::tempdir()?.into_path());
+//! let url = url::Url::parse("s3://bucket/host")?;
+//! let src = test_util::flake_dir(&["host"])?;
+//! remote.publish(&url, src.path())?;
+//!
+//! let (_tmp, data_dir) = test_util::data_dir(&url, "host")?;
+//! let activator = FakeActivator::new();
+//! let outcome = npcnix::DaemonEngine::new(data_dir, Default::default())
+//!     .with_remote(remote)
+//!     .with_activator(activator.clone())
+//!     .step()?;
+//! assert_eq!(outcome.as_str(), "changed");
+//! assert_eq!(activator.activations().len(), 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use anyhow::{bail, format_err, Context};
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+use url::Url;
+
+use crate::activation::{ActivateOpts, ActivationResult};
+use crate::config::Config;
+use crate::data_dir::DataDir;
+use crate::engine::{Activator, Clock, Remote};
+use crate::error::NpcnixError;
+use crate::{PullOpts, PullResult};
+
+/// A [`Remote`] in a local directory: `s3://<bucket>/<key>` is the file
+/// `<root>/<bucket>/<key>`
+///
+/// The etags are the md5 of the files, like S3's for non-multipart uploads.
+#[derive(Debug, Clone)]
+pub struct FsRemote {
+    root: PathBuf,
+    pull_failure: Arc<Mutex<Option<String>>>,
+}
+
+impl FsRemote {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            pull_failure: Arc::default(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The file backing `remote`
+    pub fn path(&self, remote: &Url) -> anyhow::Result<PathBuf> {
+        let Some(bucket) = remote.host_str() else {
+            bail!("Remote {remote} has no bucket");
+        };
+        let key = remote.path().trim_start_matches('/');
+        if key.is_empty() || key.split('/').any(|part| part == "..") {
+            bail!("Invalid remote key: {remote}");
+        }
+        Ok(self.root.join(bucket).join(key))
+    }
+
+    /// Pack the flake in `src` to `remote`, returning the new etag
+    pub fn publish(&self, remote: &Url, src: &Path) -> anyhow::Result<String> {
+        let path = self.path(remote)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        crate::pack(src, &HashSet::new(), &path)?;
+        etag_of(&path)
+    }
+
+    /// Make the following pulls fail with `message`, as if the remote was
+    /// unavailable, while the etags can still be fetched (`None`: succeed
+    /// again)
+    ///
+    /// Clones share the failure.
+    pub fn set_pull_failure(&self, message: Option<&str>) {
+        *self.pull_failure.lock().unwrap_or_else(|e| e.into_inner()) =
+            message.map(ToOwned::to_owned);
+    }
+
+    /// Remove `remote`, as if it was deleted from the bucket
+    pub fn remove(&self, remote: &Url) -> anyhow::Result<()> {
+        let path = self.path(remote)?;
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))
+    }
+}
+
+fn etag_of(path: &Path) -> anyhow::Result<String> {
+    let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hex::encode(Md5::digest(content)))
+}
+
+impl Remote for FsRemote {
+    fn get_etag(&self, remote: &Url, _config: &Config) -> Result<String, NpcnixError> {
+        self.path(remote)
+            .and_then(|path| etag_of(&path))
+            .map_err(NpcnixError::etag_fetch(remote))
+    }
+
+    fn pull(
+        &self,
+        remote: &Url,
+        dst: &Path,
+        pull_opts: &PullOpts,
+    ) -> Result<PullResult, NpcnixError> {
+        if let Some(ref failure) = *self.pull_failure.lock().unwrap_or_else(|e| e.into_inner()) {
+            return Err(NpcnixError::remote_unavailable(remote)(format_err!(
+                "{failure}"
+            )));
+        }
+        let (path, etag) = self
+            .path(remote)
+            .and_then(|path| {
+                let etag = etag_of(&path)?;
+                Ok((path, etag))
+            })
+            .map_err(NpcnixError::remote_unavailable(remote))?;
+        crate::unpack(&path, dst, pull_opts)?;
+        Ok(PullResult {
+            bytes: fs::metadata(&path)?.len(),
+            etag,
+        })
+    }
+}
+
+/// An activation done by a [`FakeActivator`]
+#[derive(Debug, Clone)]
+pub struct FakeActivation {
+    pub configuration: String,
+    pub etag: Option<String>,
+    /// `flake.nix` of the activated source, as it was at the time
+    pub flake_nix: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct FakeActivatorState {
+    activations: Vec<FakeActivation>,
+    failure: Option<String>,
+    prebuilds: Vec<String>,
+    prebuild_failure: Option<String>,
+}
+
+/// An [`Activator`] which only records the activations
+///
+/// Clones share the records, so keep one to inspect them after handing the
+/// other to [`crate::DaemonEngine::with_activator`].
+#[derive(Debug, Clone, Default)]
+pub struct FakeActivator {
+    state: Arc<Mutex<FakeActivatorState>>,
+}
+
+impl FakeActivator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make the following activations fail with `message` (`None`: succeed
+    /// again)
+    ///
+    /// Failed activations are recorded too.
+    pub fn set_failure(&self, message: Option<&str>) {
+        self.lock().failure = message.map(ToOwned::to_owned);
+    }
+
+    /// Make the following pre-builds fail with `message` (`None`: succeed
+    /// again)
+    pub fn set_prebuild_failure(&self, message: Option<&str>) {
+        self.lock().prebuild_failure = message.map(ToOwned::to_owned);
+    }
+
+    /// Configurations pre-built so far, including the failed pre-builds
+    pub fn prebuilds(&self) -> Vec<String> {
+        self.lock().prebuilds.clone()
+    }
+
+    pub fn activations(&self) -> Vec<FakeActivation> {
+        self.lock().activations.clone()
+    }
+
+    pub fn last_activation(&self) -> Option<FakeActivation> {
+        self.lock().activations.last().cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FakeActivatorState> {
+        // a panicking test thread shouldn't hide the records
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Activator for FakeActivator {
+    fn activate(
+        &self,
+        src: &Path,
+        configuration: &str,
+        etag: Option<&str>,
+        _data_dir: Option<&DataDir>,
+        _activate_opts: &ActivateOpts,
+        _config: &Config,
+    ) -> anyhow::Result<ActivationResult> {
+        let mut state = self.lock();
+        state.activations.push(FakeActivation {
+            configuration: configuration.to_owned(),
+            etag: etag.map(ToOwned::to_owned),
+            flake_nix: fs::read_to_string(src.join("flake.nix")).ok(),
+        });
+        if let Some(ref failure) = state.failure {
+            bail!("{failure}");
+        }
+        Ok(ActivationResult {
+            system: None,
+            duration: time::Duration::ZERO,
+        })
+    }
+
+    fn supports_prebuild(&self, _activate_opts: &ActivateOpts, _config: &Config) -> bool {
+        true
+    }
+
+    fn prebuild(
+        &self,
+        _src: &Path,
+        configuration: &str,
+        _activate_opts: &ActivateOpts,
+        _config: &Config,
+    ) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        state.prebuilds.push(configuration.to_owned());
+        if let Some(ref failure) = state.prebuild_failure {
+            bail!("{failure}");
+        }
+        Ok(())
+    }
+}
+
+/// A [`Clock`] only moving when told to
+///
+/// Clones share the time.
+#[derive(Debug, Clone)]
+pub struct FixedClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Write a minimal `flake.nix` to `dir`, with an (unbuildable) NixOS
+/// configuration for each of `configurations`
+///
+/// Enough for packing, pushing, pulling and fake activations, and for
+/// `nix flake show`, but not to actually build the systems.
+pub fn write_flake(dir: &Path, configurations: &[&str]) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut flake = String::from("{\n  outputs = { self }: {\n    nixosConfigurations = {\n");
+    for configuration in configurations {
+        flake.push_str(&format!(
+            "      {configuration:?} = {{ config = {{ system.build.toplevel = \"/nonexistent\"; }}; }};\n"
+        ));
+    }
+    flake.push_str("    };\n  };\n}\n");
+    let path = dir.join("flake.nix");
+    fs::write(&path, flake).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// A temporary directory with a flake from [`write_flake`]
+pub fn flake_dir(configurations: &[&str]) -> anyhow::Result<tempfile::TempDir> {
+    let dir = tempfile::tempdir()?;
+    write_flake(dir.path(), configurations)?;
+    Ok(dir)
+}
+
+/// A temporary data dir following `configuration` of `remote`
+///
+/// Keep the returned `TempDir`, the directory is removed when it's dropped.
+pub fn data_dir(remote: &Url, configuration: &str) -> anyhow::Result<(tempfile::TempDir, DataDir)> {
+    let dir = tempfile::tempdir()?;
+    let data_dir = DataDir::new(dir.path());
+    data_dir.store_config(
+        &Config::default()
+            .with_remote(remote)
+            .with_configuration(configuration),
+    )?;
+    Ok((dir, data_dir))
+}
@@ -0,0 +1,87 @@
+//! TOML documents as JSON values, for the settings files
+//!
+//! Dates and times are kept as strings, as JSON has none.
+
+use anyhow::format_err;
+use serde_json::{Map, Number, Value};
+
+/// Parse a TOML document into a JSON object
+pub fn parse(content: &str) -> anyhow::Result<Map<String, Value>> {
+    table_to_json(content.parse::<::toml::Table>()?)
+}
+
+fn table_to_json(table: ::toml::Table) -> anyhow::Result<Map<String, Value>> {
+    table
+        .into_iter()
+        .map(|(key, value)| Ok((key, to_json(value)?)))
+        .collect()
+}
+
+fn to_json(value: ::toml::Value) -> anyhow::Result<Value> {
+    Ok(match value {
+        ::toml::Value::String(s) => Value::String(s),
+        ::toml::Value::Integer(i) => Value::Number(i.into()),
+        ::toml::Value::Float(f) => {
+            Value::Number(Number::from_f64(f).ok_or_else(|| format_err!("Unsupported float: {f}"))?)
+        }
+        ::toml::Value::Boolean(b) => Value::Bool(b),
+        ::toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        ::toml::Value::Array(array) => Value::Array(
+            array
+                .into_iter()
+                .map(to_json)
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        ::toml::Value::Table(table) => Value::Object(table_to_json(table)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::parse;
+
+    #[test]
+    fn parses_settings() {
+        let settings = parse(
+            r#"
+            # comment
+            remote = "s3://bucket/key"
+            min_sleep_secs = 0x10
+            paused_until = 2024-01-02 03:04:05Z
+
+            [gc]
+            enabled = true
+
+            [[webhooks]]
+            url = "https://example.com"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::Value::Object(settings),
+            json!({
+                "remote": "s3://bucket/key",
+                "min_sleep_secs": 16,
+                "paused_until": "2024-01-02T03:04:05Z",
+                "gc": { "enabled": true },
+                "webhooks": [{ "url": "https://example.com" }],
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        for content in [
+            "[gc]\nenabled = true\n[gc]\nenabled = false",
+            "a = 1\na = 2",
+            "a = infinity",
+            "a = inf",
+            "a = nan",
+            "a = ",
+        ] {
+            assert!(parse(content).is_err(), "{content:?}");
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Verification of the archive of a remote, without activating it (`npcnix
+//! verify`)
+//!
+//! Downloads the archive and checks it the way the hosts would, reporting
+//! with the same [`Check`]s as [`crate::doctor`].
+
+use std::io::{self, Seek, SeekFrom};
+use std::path::Path;
+
+use md5::{Digest, Md5};
+use url::Url;
+
+use crate::activation::{self, ActivateOpts};
+use crate::closure::ClosureRef;
+use crate::config::Config;
+use crate::doctor::{CheckStatus, Report};
+use crate::{approval, configurations, PullOpts};
+
+/// What to verify, on top of the archive itself
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOpts {
+    /// Configuration to check the flake for (default: from the config)
+    pub configuration: Option<String>,
+    /// Evaluate the derivation of the configuration's system
+    pub evaluate: bool,
+}
+
+/// Download and check the archive of `remote`
+pub fn verify(
+    remote: &Url,
+    verify_opts: &VerifyOpts,
+    pull_opts: &PullOpts,
+    config: &Config,
+) -> Report {
+    let mut report = Report::default();
+    let (mut file, etag) = match crate::download(remote, pull_opts) {
+        Ok((file, res)) => (file, res.etag),
+        Err(e) => {
+            report.push(
+                "download",
+                CheckStatus::Fail,
+                format!("{:#}", anyhow::Error::from(e)),
+            );
+            return report;
+        }
+    };
+    report.push(
+        "download",
+        CheckStatus::Pass,
+        format!("{remote} (etag {etag})"),
+    );
+
+    let (status, message) = check_checksum(&mut file, &etag);
+    report.push("checksum", status, message);
+    let pointer = match crate::read_pointer(&mut file) {
+        Ok(pointer) => pointer,
+        Err(e) => {
+            report.push(
+                "pointer",
+                CheckStatus::Fail,
+                format!("{:#}", anyhow::Error::from(e)),
+            );
+            return report;
+        }
+    };
+    if let Some(pointer) = pointer {
+        match crate::download(&pointer.target, pull_opts).and_then(|(mut pointed, _)| {
+            crate::verify_pointed(&mut pointed, &pointer)?;
+            Ok(pointed)
+        }) {
+            Ok(pointed) => {
+                report.push(
+                    "pointer",
+                    CheckStatus::Pass,
+                    format!("{} (sha256 {})", pointer.target, pointer.sha256),
+                );
+                file = pointed;
+            }
+            Err(e) => {
+                report.push(
+                    "pointer",
+                    CheckStatus::Fail,
+                    format!("{:#}", anyhow::Error::from(e)),
+                );
+                return report;
+            }
+        }
+    }
+
+    match config.approval() {
+        Some(approval_opts) => match approval::is_approved(remote, &etag, approval_opts) {
+            Ok(true) if approval_opts.allowed_signers.is_some() => report.push(
+                "approval",
+                CheckStatus::Pass,
+                "Approved, with a valid signature",
+            ),
+            Ok(true) => report.push("approval", CheckStatus::Pass, "Approved"),
+            Ok(false) => report.push(
+                "approval",
+                CheckStatus::Fail,
+                format!("Etag {etag} is not approved (or the signature is not valid)"),
+            ),
+            Err(e) => report.push("approval", CheckStatus::Fail, format!("{e:#}")),
+        },
+        None => report.push("approval", CheckStatus::Skip, "No approval required"),
+    }
+
+    let dir = match tempfile::tempdir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            report.push("unpack", CheckStatus::Fail, e.to_string());
+            return report;
+        }
+    };
+    if let Err(e) = crate::unpack_from(io::BufReader::new(file), dir.path(), pull_opts) {
+        report.push("unpack", CheckStatus::Fail, format!("{e:#}"));
+        return report;
+    }
+    report.push("unpack", CheckStatus::Pass, "Unpacked");
+
+    match ClosureRef::load_from(dir.path()) {
+        Ok(Some(closure)) => {
+            report.push(
+                "flake",
+                CheckStatus::Pass,
+                format!("Pre-built system {}", closure.store_path.display()),
+            );
+            return report;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            report.push("flake", CheckStatus::Fail, format!("{e:#}"));
+            return report;
+        }
+    }
+    report.check_flake(dir.path(), verify_opts, config);
+    report
+}
+
+/// Compare the md5 of the (single part upload) `file` with its `etag`
+fn check_checksum(file: &mut std::fs::File, etag: &str) -> (CheckStatus, String) {
+    let etag = etag.trim_matches('"');
+    if etag.contains('-') {
+        return (
+            CheckStatus::Skip,
+            "Multipart upload, the etag is not the md5 of the archive".into(),
+        );
+    }
+    let mut hasher = Md5::new();
+    let res = io::copy(file, &mut hasher).and_then(|_| file.seek(SeekFrom::Start(0)));
+    if let Err(e) = res {
+        return (CheckStatus::Fail, e.to_string());
+    }
+    let md5 = hex::encode(hasher.finalize());
+    if md5 == etag {
+        (CheckStatus::Pass, format!("md5 {md5} matches the etag"))
+    } else {
+        (
+            CheckStatus::Fail,
+            format!("md5 {md5} doesn't match the etag {etag}"),
+        )
+    }
+}
+
+impl Report {
+    fn check_flake(&mut self, src: &Path, verify_opts: &VerifyOpts, config: &Config) {
+        let backend = activation::effective_backend(&ActivateOpts::default(), config);
+        let configurations = match configurations::list(src, backend) {
+            Ok(configurations) => {
+                self.push(
+                    "flake",
+                    CheckStatus::Pass,
+                    format!("{}: {}", backend.flake_output(), configurations.join(", ")),
+                );
+                configurations
+            }
+            Err(e) => {
+                self.push("flake", CheckStatus::Fail, format!("{e:#}"));
+                return;
+            }
+        };
+
+        let configuration = match verify_opts.configuration.as_deref() {
+            Some(configuration) => configuration.to_owned(),
+            None => match config.configuration() {
+                Ok(configuration) => configuration.to_owned(),
+                Err(_) => {
+                    self.push("configuration", CheckStatus::Skip, "No configuration set");
+                    return;
+                }
+            },
+        };
+        let flake_target = activation::flake_target(&configuration, config.flake_attr());
+        // a custom `flake_attr` can point anywhere, only evaluating tells
+        if flake_target.flake() == "."
+            && config.flake_attr().is_none()
+            && !configurations.contains(&configuration)
+        {
+            self.push(
+                "configuration",
+                CheckStatus::Fail,
+                format!("`{configuration}` not in the {}", backend.flake_output()),
+            );
+            return;
+        }
+        self.push("configuration", CheckStatus::Pass, configuration.as_str());
+
+        if !verify_opts.evaluate {
+            return;
+        }
+        match activation::eval_drv_path(src, &flake_target, backend) {
+            Ok(drv_path) => self.push("evaluate", CheckStatus::Pass, drv_path),
+            Err(e) => self.push("evaluate", CheckStatus::Fail, format!("{e:#}")),
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! Archives stored under a prefix-style remote (`s3://bucket/dir/`), as
+//! listed by `npcnix list`
+//!
+//! Every archive under the prefix is a version the hosts can be pinned or
+//! rolled back to. Timestamped pushes upload
+//! `<prefix>/<timestamp>-<git rev>.tar.zst` and make `<prefix>/latest` a
+//! [`Pointer`] to it, so hosts following `latest` get every push, and
+//! `npcnix prune` deletes the old ones.
+
+use std::collections::HashSet;
+use std::io::{Seek, SeekFrom};
+use std::time;
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::meta::{self, ArchiveMeta};
+use crate::pointer::{self, Pointer};
+use crate::{retry, s3, PushOpts, PushResult};
+
+/// Name of the pointer to the last archive of a timestamped push
+pub const LATEST: &str = "latest";
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+const ARCHIVE_SUFFIX: &str = ".tar.zst";
+
+/// Is `remote` a prefix (ending with `/`) rather than a single archive
+pub fn is_prefix(remote: &Url) -> bool {
+    remote.path().ends_with('/')
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ArchiveVersion {
+    pub url: Url,
+    /// Relative to the prefix
+    pub name: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    pub etag: String,
+    /// From the metadata sidecar, if the archive has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ArchiveMeta>,
+}
+
+/// Location of the `latest` pointer under `prefix`
+pub fn latest_url(prefix: &Url) -> anyhow::Result<Url> {
+    Ok(prefix.join(LATEST)?)
+}
+
+/// Name of the archive of a timestamped push at `timestamp`
+fn archive_name(timestamp: chrono::DateTime<chrono::Utc>, meta: Option<&ArchiveMeta>) -> String {
+    let mut name = timestamp.format(TIMESTAMP_FORMAT).to_string();
+    if let Some(git_rev) = meta.and_then(|meta| meta.git_rev.as_ref()) {
+        name.push('-');
+        name.push_str(&git_rev[..git_rev.len().min(12)]);
+        if meta.and_then(|meta| meta.git_dirty) == Some(true) {
+            name.push_str("-dirty");
+        }
+    }
+    name.push_str(ARCHIVE_SUFFIX);
+    name
+}
+
+/// When the archive `name`d by a timestamped push was pushed, `None` for any
+/// other object
+pub fn pushed_at(name: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let stem = name.strip_suffix(ARCHIVE_SUFFIX)?;
+    let timestamp = stem
+        .split_once('-')
+        .map_or(stem, |(timestamp, _)| timestamp);
+    let timestamp = chrono::NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+    Some(chrono::TimeZone::from_utc_datetime(
+        &chrono::Utc,
+        &timestamp,
+    ))
+}
+
+/// Location of the archive of a timestamped push under `prefix`, right now
+pub(crate) fn timestamped_url(
+    prefix: &Url,
+    meta: Option<&ArchiveMeta>,
+    push_opts: &PushOpts,
+) -> anyhow::Result<Url> {
+    if !is_prefix(prefix) {
+        bail!("Timestamped pushes need a prefix (ending with `/`) remote: {prefix}");
+    }
+    if push_opts.content_addressed {
+        bail!("Timestamped pushes can't be content-addressed");
+    }
+    Ok(prefix.join(&archive_name(chrono::Utc::now(), meta))?)
+}
+
+/// Upload the packed `tmp_file` as a new archive under `prefix`, and point
+/// `latest` to it
+pub(crate) fn upload_timestamped(
+    mut tmp_file: tempfile::NamedTempFile,
+    prefix: &Url,
+    meta: Option<&ArchiveMeta>,
+    push_opts: &PushOpts,
+) -> anyhow::Result<PushResult> {
+    let archive = timestamped_url(prefix, meta, push_opts)?;
+    let archive_bytes = tmp_file.as_file().metadata()?.len();
+    crate::upload_file(tmp_file.path(), &archive, push_opts)?;
+    info!(%archive, "Uploaded archive");
+    if let Some(meta) = meta {
+        let head = retry::with_retry(&push_opts.retry, "check upload", || s3::head(&archive))?;
+        meta::upload_sidecar(&archive, meta, &head.etag, &push_opts.retry);
+    }
+
+    tmp_file.seek(SeekFrom::Start(0))?;
+    let pointer = Pointer {
+        target: archive,
+        sha256: pointer::sha256_reader(&mut tmp_file)?,
+    };
+    let pointer_bytes = serde_json::to_vec_pretty(&pointer)?;
+    let latest = latest_url(prefix)?;
+    retry::with_retry(&push_opts.retry, "upload pointer", || {
+        s3::upload_bytes(&pointer_bytes, &latest)
+    })?;
+    let head = retry::with_retry(&push_opts.retry, "check upload", || s3::head(&latest))?;
+    Ok(PushResult {
+        bytes: archive_bytes + pointer_bytes.len() as u64,
+        etag: head.etag,
+        unchanged: false,
+    })
+}
+
+/// What `npcnix prune` deletes
+#[derive(Debug, Clone, Default)]
+pub struct PruneOpts {
+    /// Keep at least this many of the most recent archives
+    pub keep: Option<usize>,
+    /// Delete the archives pushed longer ago than this
+    pub older_than: Option<time::Duration>,
+    /// Only report what would be deleted
+    pub dry_run: bool,
+}
+
+/// Delete the stale archives of timestamped pushes under `prefix` (along
+/// with their sidecars), returning them
+///
+/// An archive is stale when it's not among the `keep` most recent ones and
+/// was pushed longer ago than `older_than` (whichever of them is set). The
+/// one `latest` points to, and any other object, are never deleted.
+pub fn prune(prefix: &Url, prune_opts: &PruneOpts) -> anyhow::Result<Vec<ArchiveVersion>> {
+    if prune_opts.keep.is_none() && prune_opts.older_than.is_none() {
+        bail!("Pruning needs a number of archives to keep, or a maximum age");
+    }
+    let latest = match s3::get_object(&latest_url(prefix)?)? {
+        Some((bytes, _)) => Some(
+            Pointer::read_from(bytes.as_slice())
+                .with_context(|| format!("`{LATEST}` under {prefix} is not a pointer"))?
+                .target,
+        ),
+        None => None,
+    };
+    let stale = stale_versions(
+        list(prefix)?,
+        latest.as_ref(),
+        prune_opts,
+        chrono::Utc::now(),
+    )?;
+    if !prune_opts.dry_run {
+        for version in &stale {
+            info!(url = %version.url, "Deleting archive");
+            s3::delete(&version.url)?;
+            if version.meta.is_some() {
+                s3::delete(&meta::sidecar_url(&version.url))?;
+            }
+        }
+    }
+    Ok(stale)
+}
+
+/// The stale ones among `versions` as of `now`, the most recent first, see
+/// [`prune`]
+fn stale_versions(
+    versions: Vec<ArchiveVersion>,
+    latest: Option<&Url>,
+    prune_opts: &PruneOpts,
+    now: chrono::DateTime<chrono::Utc>,
+) -> anyhow::Result<Vec<ArchiveVersion>> {
+    let cutoff = prune_opts
+        .older_than
+        .map(|older_than| chrono::Duration::from_std(older_than).map(|older_than| now - older_than))
+        .transpose()
+        .context("Invalid age")?;
+
+    let mut versions: Vec<_> = versions
+        .into_iter()
+        .filter_map(|version| Some((pushed_at(&version.name)?, version)))
+        .collect();
+    versions.sort_by_key(|(pushed_at, _)| std::cmp::Reverse(*pushed_at));
+
+    Ok(versions
+        .into_iter()
+        .enumerate()
+        .filter(|(i, (pushed_at, version))| {
+            !(prune_opts.keep.is_some_and(|keep| *i < keep)
+                || cutoff.is_some_and(|cutoff| cutoff <= *pushed_at)
+                || latest == Some(&version.url))
+        })
+        .map(|(_, (_, version))| version)
+        .collect())
+}
+
+/// The archives directly under `prefix`, the most recent first
+pub fn list(prefix: &Url) -> anyhow::Result<Vec<ArchiveVersion>> {
+    if prefix.scheme() != "s3" {
+        bail!("Protocol not supported: {}", prefix.scheme());
+    }
+    if !is_prefix(prefix) {
+        bail!("Not a prefix (ending with `/`): {prefix}");
+    }
+    let objects = s3::list_dir(prefix)?;
+    let sidecars: HashSet<_> = objects
+        .iter()
+        .filter(|object| meta::is_sidecar(&object.url))
+        .map(|object| object.url.as_str())
+        .collect();
+
+    let mut versions = vec![];
+    for object in objects
+        .iter()
+        .filter(|object| !meta::is_sidecar(&object.url))
+    {
+        // only fetch the sidecars that exist, there can be many archives
+        let meta = if sidecars.contains(meta::sidecar_url(&object.url).as_str()) {
+            meta::fetch_sidecar(&object.url)
+                .map(|sidecar| sidecar.map(|sidecar| sidecar.meta))
+                .unwrap_or_else(|e| {
+                    warn!(error = %e, url = %object.url, "Failed to fetch the metadata sidecar");
+                    None
+                })
+        } else {
+            None
+        };
+        versions.push(ArchiveVersion {
+            url: object.url.clone(),
+            name: object
+                .url
+                .path()
+                .strip_prefix(prefix.path())
+                .unwrap_or(object.url.path())
+                .to_owned(),
+            size: object.size,
+            last_modified: object.last_modified,
+            etag: object.etag.clone(),
+            meta,
+        });
+    }
+    versions.sort_by_key(|version| std::cmp::Reverse(version.last_modified));
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use url::Url;
+
+    use super::{stale_versions, ArchiveVersion, PruneOpts};
+
+    const PREFIX: &str = "s3://bucket/hosts/";
+
+    fn version(name: &str) -> ArchiveVersion {
+        ArchiveVersion {
+            url: Url::parse(PREFIX).unwrap().join(name).unwrap(),
+            name: name.to_owned(),
+            size: 0,
+            last_modified: None,
+            etag: String::new(),
+            meta: None,
+        }
+    }
+
+    /// Days 1 to 5 of January, pushed at noon, in no particular order
+    fn versions() -> Vec<ArchiveVersion> {
+        [
+            "20240103T120000Z-abcdef.tar.zst",
+            "20240101T120000Z.tar.zst",
+            "20240105T120000Z-abcdef-dirty.tar.zst",
+            "20240102T120000Z.tar.zst",
+            "20240104T120000Z.tar.zst",
+            // not timestamped pushes
+            "latest",
+            "by-hash/0123.tar.zst",
+            "manual.tar.zst",
+        ]
+        .into_iter()
+        .map(version)
+        .collect()
+    }
+
+    fn stale_days(latest: Option<&str>, prune_opts: &PruneOpts) -> Vec<String> {
+        let now = Utc.with_ymd_and_hms(2024, 1, 6, 12, 0, 0).unwrap();
+        let latest = latest.map(|name| version(name).url);
+        stale_versions(versions(), latest.as_ref(), prune_opts, now)
+            .unwrap()
+            .into_iter()
+            .map(|version| version.name[6..8].to_owned())
+            .collect()
+    }
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    #[test]
+    fn keeps_the_most_recent_archives() {
+        for (keep, stale) in [
+            (0, vec!["05", "04", "03", "02", "01"]),
+            (2, vec!["03", "02", "01"]),
+            (5, vec![]),
+            (10, vec![]),
+        ] {
+            let prune_opts = PruneOpts {
+                keep: Some(keep),
+                ..Default::default()
+            };
+            assert_eq!(stale_days(None, &prune_opts), stale, "keep {keep}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_recent_enough_archives() {
+        for (older_than, stale) in [
+            (Duration::ZERO, vec!["05", "04", "03", "02", "01"]),
+            // the cutoff itself is kept
+            (DAY, vec!["04", "03", "02", "01"]),
+            (3 * DAY + Duration::from_secs(1), vec!["02", "01"]),
+            (10 * DAY, vec![]),
+        ] {
+            let prune_opts = PruneOpts {
+                older_than: Some(older_than),
+                ..Default::default()
+            };
+            assert_eq!(
+                stale_days(None, &prune_opts),
+                stale,
+                "older than {older_than:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_the_archives_kept_by_either_option() {
+        for (keep, older_than, stale) in [
+            // keeping more than are recent enough
+            (3, 1, vec!["02", "01"]),
+            // fewer
+            (1, 3, vec!["02", "01"]),
+            (0, 10, vec![]),
+            (10, 0, vec![]),
+        ] {
+            let prune_opts = PruneOpts {
+                keep: Some(keep),
+                older_than: Some(older_than * DAY),
+                ..Default::default()
+            };
+            assert_eq!(
+                stale_days(None, &prune_opts),
+                stale,
+                "keep {keep}, older than {older_than} days"
+            );
+        }
+    }
+
+    #[test]
+    fn never_prunes_latest() {
+        let prune_opts = PruneOpts {
+            keep: Some(0),
+            older_than: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        // e.g. after rolling back to an older archive
+        assert_eq!(
+            stale_days(Some("20240102T120000Z.tar.zst"), &prune_opts),
+            ["05", "04", "03", "01"]
+        );
+        assert_eq!(
+            stale_days(Some("20240105T120000Z-abcdef-dirty.tar.zst"), &prune_opts),
+            ["04", "03", "02", "01"]
+        );
+    }
+}